@@ -0,0 +1,683 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+// RetroAchievements の実績を rcheevos と同じ規則で評価する
+// 条件式 (MemAddr) はよく使われるもの (比較、ヒット数、ResetIf、PauseIf、Delta/Prior、Alt) だけ扱う
+// サーバーとの通信はしないので、実績はローカルのファイルから読み、解除はコールバックで知らせる
+
+// ログイン情報
+// 1行に1つ: username=<名前> / token=<APIトークン>、'#' 以降はコメント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub token: String,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> Result<Credentials> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Credentials::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Credentials> {
+        let mut username = None;
+        let mut token = None;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("Expected key=value: {}", line))?;
+
+            match key.trim() {
+                "username" => username = Some(value.trim().to_string()),
+                "token" => token = Some(value.trim().to_string()),
+                key => bail!("Unknown key {}", key),
+            }
+        }
+
+        match (username, token) {
+            (Some(username), Some(token)) if !username.is_empty() && !token.is_empty() => {
+                Ok(Credentials { username, token })
+            }
+            _ => bail!("username and token are required"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Size {
+    Bit(u8),
+    LowNibble,
+    HighNibble,
+    Byte,
+    Halfword,
+    Tribyte,
+    Word,
+    // 立っているビットの数
+    BitCount,
+}
+
+impl Size {
+    fn bytes(self) -> u32 {
+        match self {
+            Size::Halfword => 2,
+            Size::Tribyte => 3,
+            Size::Word => 4,
+            _ => 1,
+        }
+    }
+
+    fn extract(self, raw: u32) -> u32 {
+        match self {
+            Size::Bit(bit) => (raw >> bit) & 1,
+            Size::LowNibble => raw & 0xF,
+            Size::HighNibble => (raw >> 4) & 0xF,
+            Size::BitCount => (raw & 0xFF).count_ones(),
+            _ => raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Value,
+    // 前のフレームの値
+    Delta,
+    // 最後に変わる前の値
+    Prior,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MemRef {
+    address: u32,
+    size: Size,
+    kind: Kind,
+    value: u32,
+    delta: u32,
+    prior: u32,
+}
+
+impl MemRef {
+    // 読めないアドレスは0とする。末尾を越える読み出しは0番地に回り込む
+    fn update(&mut self, memory: &dyn Fn(u32) -> Option<u8>) {
+        let raw = (0..self.size.bytes()).fold(0, |acc, i| {
+            let byte = memory(self.address.wrapping_add(i)).unwrap_or(0) as u32;
+            acc | (byte << (i * 8))
+        });
+        let value = self.size.extract(raw);
+
+        self.delta = self.value;
+        if value != self.value {
+            self.prior = self.value;
+        }
+        self.value = value;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Const(u32),
+    Mem(MemRef),
+}
+
+impl Operand {
+    fn update(&mut self, memory: &dyn Fn(u32) -> Option<u8>) {
+        if let Operand::Mem(mem) = self {
+            mem.update(memory);
+        }
+    }
+
+    fn value(&self) -> u32 {
+        match self {
+            Operand::Const(val) => *val,
+            Operand::Mem(mem) => match mem.kind {
+                Kind::Value => mem.value,
+                Kind::Delta => mem.delta,
+                Kind::Prior => mem.prior,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn compare(self, left: u32, right: u32) -> bool {
+        match self {
+            Op::Eq => left == right,
+            Op::Ne => left != right,
+            Op::Lt => left < right,
+            Op::Le => left <= right,
+            Op::Gt => left > right,
+            Op::Ge => left >= right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flag {
+    None,
+    // 成り立ったら全ての条件のヒット数を戻す
+    ResetIf,
+    // 成り立っている間はグループを評価しない
+    PauseIf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    flag: Flag,
+    left: Operand,
+    op: Op,
+    right: Operand,
+    // 0 ならヒット数を数えない
+    target: u32,
+    hits: u32,
+}
+
+impl Condition {
+    fn test(&mut self) -> bool {
+        let ok = self.op.compare(self.left.value(), self.right.value());
+
+        if self.target == 0 {
+            return ok;
+        }
+
+        if ok && self.hits < self.target {
+            self.hits += 1;
+        }
+
+        self.hits >= self.target
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Group {
+    conditions: Vec<Condition>,
+}
+
+impl Group {
+    fn test(&mut self, reset: &mut bool) -> bool {
+        // ヒット数を数えるため、途中で止めずに全て評価する
+        let paused = self
+            .conditions
+            .iter_mut()
+            .filter(|cond| cond.flag == Flag::PauseIf)
+            .fold(false, |paused, cond| cond.test() | paused);
+
+        if paused {
+            return false;
+        }
+
+        let mut result = true;
+        for cond in &mut self.conditions {
+            match cond.flag {
+                Flag::None => result &= cond.test(),
+                Flag::ResetIf => *reset |= cond.test(),
+                Flag::PauseIf => {}
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Trigger {
+    core: Group,
+    alts: Vec<Group>,
+}
+
+impl Trigger {
+    fn groups_mut(&mut self) -> impl Iterator<Item = &mut Group> + '_ {
+        std::iter::once(&mut self.core).chain(self.alts.iter_mut())
+    }
+
+    // フレームごとに呼ぶ。リセットされたフレームは false
+    fn test(&mut self, memory: &dyn Fn(u32) -> Option<u8>) -> bool {
+        for cond in self.groups_mut().flat_map(|g| &mut g.conditions) {
+            cond.left.update(memory);
+            cond.right.update(memory);
+        }
+
+        let mut reset = false;
+        let core = self.core.test(&mut reset);
+        let alt = self
+            .alts
+            .iter_mut()
+            .fold(false, |alt, group| group.test(&mut reset) | alt);
+
+        if reset {
+            for cond in self.groups_mut().flat_map(|g| &mut g.conditions) {
+                cond.hits = 0;
+            }
+            return false;
+        }
+
+        core && (self.alts.is_empty() || alt)
+    }
+}
+
+// MemAddr の字句を先頭から読む
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let matched = self.s[self.pos..].starts_with(token.as_bytes());
+        if matched {
+            self.pos += token.len();
+        }
+
+        matched
+    }
+
+    fn number(&mut self, radix: u32) -> Result<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| (c as char).is_digit(radix)) {
+            self.pos += 1;
+        }
+
+        let digits = std::str::from_utf8(&self.s[start..self.pos]).unwrap();
+        u32::from_str_radix(digits, radix)
+            .with_context(|| format!("Expected a number at {}", start))
+    }
+
+    fn trigger(&mut self) -> Result<Trigger> {
+        // コアが空でAltだけの場合がある
+        let core = match self.peek() {
+            Some(b'S') | None => Group { conditions: vec![] },
+            _ => self.group()?,
+        };
+
+        let mut alts = vec![];
+        while self.eat("S") {
+            alts.push(self.group()?);
+        }
+
+        if let Some(c) = self.peek() {
+            bail!("Unexpected {:?} at {}", c as char, self.pos);
+        }
+
+        Ok(Trigger { core, alts })
+    }
+
+    fn group(&mut self) -> Result<Group> {
+        let mut conditions = vec![self.condition()?];
+        while self.eat("_") {
+            conditions.push(self.condition()?);
+        }
+
+        Ok(Group { conditions })
+    }
+
+    fn condition(&mut self) -> Result<Condition> {
+        let flag = match self.s.get(self.pos..self.pos + 2) {
+            Some([flag, b':']) => {
+                self.pos += 2;
+                match flag {
+                    b'R' => Flag::ResetIf,
+                    b'P' => Flag::PauseIf,
+                    _ => bail!("Unsupported flag {}:", *flag as char),
+                }
+            }
+            _ => Flag::None,
+        };
+
+        let left = self.operand()?;
+
+        let op = if self.eat("==") || self.eat("=") {
+            Op::Eq
+        } else if self.eat("!=") {
+            Op::Ne
+        } else if self.eat("<=") {
+            Op::Le
+        } else if self.eat("<") {
+            Op::Lt
+        } else if self.eat(">=") {
+            Op::Ge
+        } else if self.eat(">") {
+            Op::Gt
+        } else {
+            bail!("Expected a comparison at {}", self.pos);
+        };
+
+        let right = self.operand()?;
+
+        let target = if self.eat(".") {
+            let target = self.number(10)?;
+            if !self.eat(".") {
+                bail!("Expected '.' at {}", self.pos);
+            }
+            target
+        } else if self.eat("(") {
+            let target = self.number(10)?;
+            if !self.eat(")") {
+                bail!("Expected ')' at {}", self.pos);
+            }
+            target
+        } else {
+            0
+        };
+
+        Ok(Condition {
+            flag,
+            left,
+            op,
+            right,
+            target,
+            hits: 0,
+        })
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let kind = if self.eat("d") {
+            Kind::Delta
+        } else if self.eat("p") {
+            Kind::Prior
+        } else {
+            Kind::Value
+        };
+
+        if self.eat("0x") || self.eat("0X") {
+            let size = match self.peek() {
+                Some(b'H' | b'h') => Size::Byte,
+                Some(b'W' | b'w') => Size::Tribyte,
+                Some(b'X' | b'x') => Size::Word,
+                Some(b'L' | b'l') => Size::LowNibble,
+                Some(b'U' | b'u') => Size::HighNibble,
+                Some(b'K' | b'k') => Size::BitCount,
+                Some(c @ (b'M'..=b'T' | b'm'..=b't')) => Size::Bit(c.to_ascii_uppercase() - b'M'),
+                Some(b' ') => Size::Halfword,
+                // サイズの指定がなければ16bit
+                Some(c) if c.is_ascii_hexdigit() => return self.memref(Size::Halfword, kind),
+                _ => bail!("Unknown memory size at {}", self.pos),
+            };
+            self.pos += 1;
+
+            return self.memref(size, kind);
+        }
+
+        if kind != Kind::Value {
+            bail!("Delta and prior need a memory reference at {}", self.pos);
+        }
+
+        if self.eat("h") || self.eat("H") {
+            return Ok(Operand::Const(self.number(16)?));
+        }
+
+        if self.eat("-") {
+            return Ok(Operand::Const(self.number(10)?.wrapping_neg()));
+        }
+
+        Ok(Operand::Const(self.number(10)?))
+    }
+
+    fn memref(&mut self, size: Size, kind: Kind) -> Result<Operand> {
+        Ok(Operand::Mem(MemRef {
+            address: self.number(16)?,
+            size,
+            kind,
+            value: 0,
+            delta: 0,
+            prior: 0,
+        }))
+    }
+}
+
+fn parse_trigger(memaddr: &str) -> Result<Trigger> {
+    Parser {
+        s: memaddr.as_bytes(),
+        pos: 0,
+    }
+    .trigger()
+    .with_context(|| format!("Invalid trigger {}", memaddr))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // 読み込んだ直後に解除されないよう、一度条件が偽になるのを待つ
+    Waiting,
+    Active,
+    Unlocked,
+}
+
+#[derive(Debug)]
+pub struct Achievement {
+    pub id: u32,
+    pub title: String,
+    pub description: String,
+    pub points: u32,
+    trigger: Trigger,
+    state: State,
+}
+
+// "" の中の ':' では区切らない
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ':' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+
+    fields
+}
+
+// RetroAchievements のローカルの実績ファイル (XXX-User.txt) の形式
+// ID:"MemAddr":"Title":"Description":::::Author:Points:... の行を読み、
+// 先頭のバージョンやゲーム名の行は読み飛ばす
+pub fn parse_achievements(text: &str) -> Result<Vec<Achievement>> {
+    let mut achievements = vec![];
+
+    for (n, line) in text.lines().enumerate() {
+        let fields = split_fields(line.trim());
+
+        let id = match fields[0].parse() {
+            Ok(id) if fields.len() >= 4 => id,
+            _ => continue,
+        };
+
+        let trigger = parse_trigger(&fields[1]).with_context(|| format!("line {}", n + 1))?;
+
+        achievements.push(Achievement {
+            id,
+            title: fields[2].clone(),
+            description: fields[3].clone(),
+            points: fields.get(8).and_then(|p| p.parse().ok()).unwrap_or(0),
+            trigger,
+            state: State::Waiting,
+        });
+    }
+
+    Ok(achievements)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Unlock<'a> {
+    pub achievement: &'a Achievement,
+    pub frame: u32,
+}
+
+pub type UnlockCallback = dyn FnMut(&Unlock) + Send;
+
+// フレームごとに全ての実績を評価する
+pub struct Runtime {
+    achievements: Vec<Achievement>,
+    user: Option<Credentials>,
+    callback: Box<UnlockCallback>,
+}
+
+impl Runtime {
+    pub fn new(achievements: Vec<Achievement>, callback: Box<UnlockCallback>) -> Runtime {
+        Runtime {
+            achievements,
+            user: None,
+            callback,
+        }
+    }
+
+    // 解除した実績を誰のものとして扱うか
+    pub fn login(&mut self, credentials: Credentials) {
+        info!("Achievements: logged in as {}", credentials.username);
+        self.user = Some(credentials);
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(|c| c.username.as_str())
+    }
+
+    pub fn achievements(&self) -> &[Achievement] {
+        &self.achievements
+    }
+
+    pub fn unlocked(&self) -> impl Iterator<Item = &Achievement> {
+        self.achievements
+            .iter()
+            .filter(|a| a.state == State::Unlocked)
+    }
+
+    // memory は rcheevos のアドレスで1byte読む
+    pub fn do_frame(&mut self, memory: &dyn Fn(u32) -> Option<u8>, frame: u32) {
+        for achievement in &mut self.achievements {
+            if achievement.state == State::Unlocked {
+                continue;
+            }
+
+            let triggered = achievement.trigger.test(memory);
+
+            match (achievement.state, triggered) {
+                (State::Waiting, false) => achievement.state = State::Active,
+                (State::Active, true) => {
+                    achievement.state = State::Unlocked;
+                    (self.callback)(&Unlock { achievement, frame });
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // フレームごとのメモリの内容を与えて、解除されたフレームを返す
+    fn run(memaddr: &str, frames: &[&[u8]]) -> Vec<u32> {
+        let line = format!("1:\"{}\":\"Title\":\"Description\"::::author:5", memaddr);
+        let unlocked = Arc::new(Mutex::new(vec![]));
+
+        let log = Arc::clone(&unlocked);
+        let mut runtime = Runtime::new(
+            parse_achievements(&line).unwrap(),
+            Box::new(move |unlock| log.lock().unwrap().push(unlock.frame)),
+        );
+
+        for (frame, memory) in frames.iter().enumerate() {
+            runtime.do_frame(&|addr| memory.get(addr as usize).copied(), frame as u32);
+        }
+
+        drop(runtime);
+        Arc::try_unwrap(unlocked).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn parses_achievement_file() {
+        let text = "1.0\nGame\n42:\"R:0xH0001=1_0xX0010>d0xX0010S0xS0002=1\":\"A: B\":\"Desc\"::::me:10:0:0:0:0:00001\n";
+        let achievements = parse_achievements(text).unwrap();
+
+        assert_eq!(achievements.len(), 1);
+        assert_eq!(achievements[0].id, 42);
+        assert_eq!(achievements[0].title, "A: B");
+        assert_eq!(achievements[0].points, 10);
+
+        let trigger = &achievements[0].trigger;
+        assert_eq!(trigger.core.conditions.len(), 2);
+        assert_eq!(trigger.core.conditions[0].flag, Flag::ResetIf);
+        assert_eq!(trigger.alts.len(), 1);
+        assert!(matches!(
+            trigger.alts[0].conditions[0].left,
+            Operand::Mem(MemRef {
+                size: Size::Bit(6),
+                ..
+            })
+        ));
+
+        assert!(parse_trigger("A:0xH0001=1").is_err());
+    }
+
+    #[test]
+    fn waits_for_false_before_unlocking() {
+        // 読み込んだ時点で条件を満たしていても解除しない
+        assert_eq!(run("0xH0000=1", &[&[1], &[1], &[0], &[1]]), vec![3]);
+    }
+
+    #[test]
+    fn delta_detects_transitions() {
+        let frames: [&[u8]; 5] = [&[0], &[0], &[1], &[1], &[0]];
+
+        assert_eq!(run("0xH0000=1_d0xH0000=0", &frames), vec![2]);
+    }
+
+    #[test]
+    fn hits_reset_and_pause() {
+        // 3フレーム 0x00 が 1 になったら解除。0x01 が 1 になったら数え直し
+        let frames: [&[u8]; 6] = [&[0, 0], &[1, 0], &[1, 1], &[1, 0], &[1, 0], &[1, 0]];
+        assert_eq!(run("0xH0000=1.3._R:0xH0001=1", &frames), vec![5]);
+
+        // 0x01 が 1 の間は数えない
+        let frames: [&[u8]; 6] = [&[0, 0], &[1, 0], &[1, 1], &[1, 1], &[1, 0], &[1, 0]];
+        assert_eq!(run("0xH0000=1.3._P:0xH0001=1", &frames), vec![5]);
+    }
+
+    #[test]
+    fn reads_across_the_end_of_the_address_space() {
+        // 0xFFFFFFFF の32bit読み出しは 0x00-0x02 を上位バイトに読む
+        assert_eq!(run("0xXFFFFFFFF=0", &[&[1], &[0]]), vec![1]);
+    }
+
+    #[test]
+    fn alt_groups() {
+        let frames: [&[u8]; 4] = [&[0, 0, 0], &[1, 0, 0], &[1, 0, 1], &[1, 1, 0]];
+
+        assert_eq!(run("0xH0000=1S0xH0001=1S0xH0002=1", &frames), vec![2]);
+    }
+
+    #[test]
+    fn credentials() {
+        let credentials =
+            Credentials::parse("# RetroAchievements\nusername = player\ntoken=abc\n").unwrap();
+        assert_eq!(credentials.username, "player");
+        assert_eq!(credentials.token, "abc");
+
+        assert!(Credentials::parse("username=player").is_err());
+    }
+}
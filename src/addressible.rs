@@ -65,3 +65,48 @@ impl Addressible for u32 {
         panic!("invalid u16 unwrap of u32");
     }
 }
+
+// リトルエンディアンのバイト列から読む
+// メモリアクセスで一番よく通るので幅ごとに直接組み立てる
+pub fn read_le<T: Addressible>(data: &[u8], offset: usize) -> T {
+    let val = match T::width() {
+        AccessWidth::Byte => data[offset] as u32,
+        AccessWidth::Halfword => u16::from_le_bytes([data[offset], data[offset + 1]]) as u32,
+        AccessWidth::Word => u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()),
+    };
+
+    Addressible::from_u32(val)
+}
+
+pub fn write_le<T: Addressible>(data: &mut [u8], offset: usize, val: T) {
+    let val = val.as_u32();
+
+    match T::width() {
+        AccessWidth::Byte => data[offset] = val as u8,
+        AccessWidth::Halfword => {
+            data[offset..offset + 2].copy_from_slice(&(val as u16).to_le_bytes())
+        }
+        AccessWidth::Word => data[offset..offset + 4].copy_from_slice(&val.to_le_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn little_endian_round_trip() {
+        let mut data = [0; 8];
+
+        write_le::<u32>(&mut data, 0, 0x12345678);
+        write_le::<u16>(&mut data, 4, 0xBEEF);
+        write_le::<u8>(&mut data, 7, 0x9A);
+
+        assert_eq!(data, [0x78, 0x56, 0x34, 0x12, 0xEF, 0xBE, 0x00, 0x9A]);
+
+        assert_eq!(read_le::<u32>(&data, 0), 0x12345678);
+        assert_eq!(read_le::<u16>(&data, 1), 0x3456);
+        assert_eq!(read_le::<u8>(&data, 7), 0x9A);
+        assert_eq!(read_le::<u32>(&data, 4), 0x9A00BEEF);
+    }
+}
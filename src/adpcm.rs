@@ -0,0 +1,82 @@
+// SPU のボイスと XA-ADPCM が共通で使う ADPCM のデコード
+
+// 予測フィルタの係数 (/64)。XA-ADPCM は 0-3 だけを使う
+const POS_TABLE: [i32; 5] = [0, 60, 115, 98, 122];
+const NEG_TABLE: [i32; 5] = [0, 0, -52, -55, -60];
+
+// ブロック (サウンドユニット) のヘッダ。下位4bit がシフト量、bit4- がフィルタ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    shift: u32,
+    filter: usize,
+}
+
+impl Header {
+    // filter_mask はフィルタのビット (SPU は3bit、XA-ADPCM は2bit)。5 以上は 4 として扱う
+    pub fn new(val: u8, filter_mask: u8) -> Header {
+        // 12 を超えるシフト量は 9 として扱われる
+        let shift = match val & 0x0F {
+            shift if shift > 12 => 9,
+            shift => shift,
+        };
+
+        Header {
+            shift: shift as u32,
+            filter: (((val >> 4) & filter_mask) as usize).min(4),
+        }
+    }
+
+    // sample は上位に詰めた 16bit の値。history は [直前, その前] で、デコードした値に置き換わる
+    pub fn decode(self, sample: i16, history: &mut [i16; 2]) -> i16 {
+        let [old, older] = history;
+
+        let prediction =
+            *old as i32 * POS_TABLE[self.filter] + *older as i32 * NEG_TABLE[self.filter];
+        let sample = (sample >> self.shift) as i32 + ((prediction + 32) >> 6);
+        let sample = sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        *older = *old;
+        *old = sample;
+
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(header: Header, samples: &[i16], history: &mut [i16; 2]) -> Vec<i16> {
+        samples
+            .iter()
+            .map(|&sample| header.decode(sample, history))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_with_filter_and_shift() {
+        let mut history = [0; 2];
+
+        // シフト0, フィルタ1。ニブルの 1 は 0x1000
+        let samples = decode(Header::new(0x10, 0x07), &[0x1000; 3], &mut history);
+        assert_eq!(samples[0], 4096);
+        // 4096 + 4096 * 60 / 64
+        assert_eq!(samples[1], 7936);
+        assert_eq!(history, [samples[2], samples[1]]);
+
+        // シフト4, フィルタなし。-1 は -256
+        let samples = decode(Header::new(0x04, 0x07), &[-0x1000; 2], &mut history);
+        assert_eq!(samples, [-256; 2]);
+
+        // 12 を超えるシフト量は 9
+        assert_eq!(Header::new(0x0D, 0x07), Header::new(0x09, 0x07));
+
+        // フィルタ 5 以上は 4。XA-ADPCM では bit6 を見ない
+        assert_eq!(Header::new(0x70, 0x07), Header::new(0x40, 0x07));
+        assert_eq!(Header::new(0x50, 0x03), Header::new(0x10, 0x03));
+
+        // 大きすぎる値は飽和する
+        let samples = decode(Header::new(0x40, 0x07), &[0x7000], &mut [i16::MAX; 2]);
+        assert_eq!(samples[0], i16::MAX);
+    }
+}
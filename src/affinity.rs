@@ -0,0 +1,166 @@
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+
+// ホストのスレッドの優先度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    // Linux では nice 値を下げる。権限がなければ警告して通常のまま
+    High,
+}
+
+// スレッドを走らせるコアと優先度。既定ではOSに任せる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadConfig {
+    pub cores: Option<Vec<usize>>,
+    pub priority: Priority,
+}
+
+impl Default for ThreadConfig {
+    fn default() -> Self {
+        ThreadConfig {
+            cores: None,
+            priority: Priority::Normal,
+        }
+    }
+}
+
+// 上げるときの nice 値
+#[cfg(target_os = "linux")]
+const HIGH_NICE: i32 = -10;
+
+impl ThreadConfig {
+    // 呼び出したスレッドに適用する
+    // 失敗してもエミュレーションは続けられるので警告だけ出す
+    pub fn apply(&self, name: &str) {
+        if let Some(cores) = &self.cores {
+            match set_affinity(cores) {
+                Ok(()) => info!("{} thread pinned to cores {:?}", name, cores),
+                Err(e) => warn!("Failed to pin {} thread: {}", name, e),
+            }
+        }
+
+        if self.priority == Priority::High {
+            match raise_priority() {
+                Ok(()) => info!("{} thread priority raised", name),
+                Err(e) => warn!("Failed to raise {} thread priority: {}", name, e),
+            }
+        }
+    }
+}
+
+// cpu_set_t に入るコアの数 (glibc の CPU_SETSIZE)
+const MAX_CORES: usize = 1024;
+
+// "0,2-3" のようなコアの一覧
+pub fn parse_cores(s: &str) -> Result<Vec<usize>> {
+    let mut cores = vec![];
+
+    for part in s.split(',').map(str::trim) {
+        let parse = |n: &str| {
+            let core = n
+                .trim()
+                .parse::<usize>()
+                .with_context(|| format!("Invalid core {}", n))?;
+            if core >= MAX_CORES {
+                bail!("Core {} is out of range (0-{})", core, MAX_CORES - 1);
+            }
+
+            Ok(core)
+        };
+
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    bail!("Invalid core range {}", part);
+                }
+                cores.extend(start..=end);
+            }
+            None => cores.push(parse(part)?),
+        }
+    }
+
+    cores.sort_unstable();
+    cores.dedup();
+
+    Ok(cores)
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cores: &[usize]) -> Result<()> {
+    // CPU_SET は範囲外を書き込んでしまうので弾く。存在しないコアは sched_setaffinity が弾く
+    let size = libc::CPU_SETSIZE as usize;
+    if let Some(core) = cores.iter().find(|&&core| core >= size) {
+        bail!("Core {} is out of range (0-{})", core, size - 1);
+    }
+
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &core in cores {
+            libc::CPU_SET(core, &mut set);
+        }
+
+        // pid 0 は呼び出したスレッド
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn raise_priority() -> Result<()> {
+    // Linux の setpriority はスレッドIDを指定するとそのスレッドだけに効く
+    unsafe {
+        let tid = libc::gettid();
+        if libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, HIGH_NICE) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cores: &[usize]) -> Result<()> {
+    bail!("Thread affinity is not supported on this platform")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn raise_priority() -> Result<()> {
+    bail!("Thread priority is not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_core_lists() {
+        assert_eq!(parse_cores("2").unwrap(), vec![2]);
+        assert_eq!(parse_cores("0, 2-4").unwrap(), vec![0, 2, 3, 4]);
+        assert_eq!(parse_cores("3,1,3").unwrap(), vec![1, 3]);
+
+        assert!(parse_cores("").is_err());
+        assert!(parse_cores("4-2").is_err());
+        assert!(parse_cores("a").is_err());
+
+        // 巨大な範囲を展開しない
+        assert_eq!(parse_cores("1023").unwrap(), vec![1023]);
+        assert!(parse_cores("1024").is_err());
+        assert!(parse_cores("0-18446744073709551615").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rejects_missing_cores() {
+        assert!(set_affinity(&[usize::MAX >> 1]).is_err());
+
+        // cpu_set_t には入るが存在しないコアはカーネルが弾く
+        if std::thread::available_parallelism().map_or(1, |n| n.get()) < 1000 {
+            assert!(set_affinity(&[1023]).is_err());
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use crate::ps::SharedPs;
+
+const PREFIX: &str = "autosave-";
+const EXTENSION: &str = ".state";
+
+// 一定間隔でメモリーカードを書き戻してステートを保存し、最新の keep 個だけを残す
+pub struct Autosave {
+    dir: PathBuf,
+    interval: Duration,
+    keep: usize,
+}
+
+impl Autosave {
+    pub fn new(dir: PathBuf, interval: Duration, keep: usize) -> Result<Autosave> {
+        if interval.is_zero() {
+            bail!("Autosave interval must be positive");
+        }
+
+        if keep == 0 {
+            bail!("Autosave must keep at least one state");
+        }
+
+        Ok(Autosave {
+            dir,
+            interval,
+            keep,
+        })
+    }
+
+    pub fn spawn(self, shared: Arc<SharedPs>) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            thread::sleep(self.interval);
+
+            // パニック後の状態は壊れているので保存しない
+            if shared.is_poisoned() {
+                return;
+            }
+
+            if let Err(e) = self.save(&shared) {
+                warn!("Autosave failed: {}", e);
+            }
+        })
+    }
+
+    fn save(&self, shared: &SharedPs) -> Result<()> {
+        let state = {
+            let mut ps = shared.pause();
+            ps.flush_memory_cards()?;
+            ps.save_state()
+        };
+
+        fs::create_dir_all(&self.dir)?;
+
+        // 名前順が保存順になるようにゼロ埋めする
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let path = self
+            .dir
+            .join(format!("{}{:020}{}", PREFIX, timestamp, EXTENSION));
+
+        // 書き込み中に落ちても古いステートが壊れないように一時ファイルから移す
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, state)?;
+        fs::rename(&tmp, &path)?;
+
+        info!("Autosaved {}", path.display());
+
+        self.prune()
+    }
+
+    fn prune(&self) -> Result<()> {
+        let mut saves = vec![];
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+
+            if let Some(name) = name {
+                if name.starts_with(PREFIX) && name.ends_with(EXTENSION) {
+                    saves.push(path);
+                }
+            }
+        }
+
+        saves.sort();
+
+        let excess = saves.len().saturating_sub(self.keep);
+        for path in &saves[..excess] {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
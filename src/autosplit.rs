@@ -0,0 +1,231 @@
+use std::{
+    io::Write,
+    net::TcpStream,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+
+use crate::trigger::{Condition, Triggers, Width};
+
+pub const DEFAULT_SERVER: &str = "127.0.0.1:16834";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Start,
+    Split,
+    Reset,
+}
+
+impl Action {
+    // LiveSplit Server のコマンド
+    fn command(self) -> &'static str {
+        match self {
+            Action::Start => "starttimer",
+            Action::Split => "split",
+            Action::Reset => "reset",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SplitRule {
+    pub action: Action,
+    pub addr: u32,
+    pub width: Width,
+    pub condition: Condition,
+}
+
+fn parse_number(s: &str) -> Result<u32> {
+    let val = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    val.with_context(|| format!("Invalid number {}", s))
+}
+
+// 1行に1つ: <start|split|reset> <addr> <u8|u16|u32> <eq|ne|lt|gt|bits|changed> [value]
+// '#' 以降はコメント
+pub fn parse_rules(text: &str) -> Result<Vec<SplitRule>> {
+    let mut rules = vec![];
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let rule = parse_rule(line).with_context(|| format!("line {}: {}", n + 1, line))?;
+        rules.push(rule);
+    }
+
+    Ok(rules)
+}
+
+fn parse_rule(line: &str) -> Result<SplitRule> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+
+    let (action, addr, width, condition, value) = match fields.as_slice() {
+        [action, addr, width, condition] => (*action, *addr, *width, *condition, None),
+        [action, addr, width, condition, value] => (
+            *action,
+            *addr,
+            *width,
+            *condition,
+            Some(parse_number(value)?),
+        ),
+        _ => bail!("Expected <action> <addr> <width> <condition> [value]"),
+    };
+
+    let action = match action {
+        "start" => Action::Start,
+        "split" => Action::Split,
+        "reset" => Action::Reset,
+        _ => bail!("Unknown action {}", action),
+    };
+
+    let width = match width {
+        "u8" => Width::Byte,
+        "u16" => Width::Halfword,
+        "u32" => Width::Word,
+        _ => bail!("Unknown width {}", width),
+    };
+
+    let condition = match (condition, value) {
+        ("eq", Some(x)) => Condition::Equal(x),
+        ("ne", Some(x)) => Condition::NotEqual(x),
+        ("lt", Some(x)) => Condition::Less(x),
+        ("gt", Some(x)) => Condition::Greater(x),
+        ("bits", Some(x)) => Condition::BitsSet(x),
+        ("changed", None) => Condition::Changed,
+        _ => bail!("Invalid condition {}", condition),
+    };
+
+    Ok(SplitRule {
+        action,
+        addr: parse_number(addr)?,
+        width,
+        condition,
+    })
+}
+
+// トリガーの結果を LiveSplit Server に送る
+// エミュレーションスレッドを止めないように送信は別スレッドで行う
+pub struct LiveSplit {
+    sender: Sender<Action>,
+}
+
+impl LiveSplit {
+    pub fn connect(server: &str) -> Result<LiveSplit> {
+        let server = server.to_string();
+        let mut stream = Some(
+            TcpStream::connect(&server)
+                .with_context(|| format!("Failed to connect to LiveSplit at {}", server))?,
+        );
+
+        info!("Connected to LiveSplit at {}", server);
+
+        let (sender, receiver) = mpsc::channel::<Action>();
+
+        thread::spawn(move || {
+            for action in receiver {
+                // 切断されていたら送るたびに繋ぎ直す
+                if stream.is_none() {
+                    stream = TcpStream::connect(&server).ok();
+                }
+
+                let sent = match &mut stream {
+                    Some(stream) => writeln!(stream, "{}\r", action.command()),
+                    None => continue,
+                };
+
+                if let Err(e) = sent {
+                    warn!("LiveSplit {:?} failed: {}", action, e);
+                    stream = None;
+                }
+            }
+        });
+
+        Ok(LiveSplit { sender })
+    }
+
+    pub fn install(&self, rules: &[SplitRule], triggers: &mut Triggers) {
+        for rule in rules {
+            let sender = self.sender.clone();
+            let action = rule.action;
+
+            triggers.add(
+                rule.addr,
+                rule.width,
+                rule.condition,
+                Box::new(move |event| {
+                    info!("Autosplit {:?} at frame {}", action, event.frame);
+                    let _ = sender.send(action);
+                }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(line: &str) -> (Action, u32, Width, Condition) {
+        let rule = parse_rule(line).unwrap();
+        (rule.action, rule.addr, rule.width, rule.condition)
+    }
+
+    #[test]
+    fn parses_hex_and_decimal_values() {
+        assert_eq!(
+            rule("split 0x800A1234 u8 eq 0x1F"),
+            (
+                Action::Split,
+                0x800A1234,
+                Width::Byte,
+                Condition::Equal(0x1F)
+            )
+        );
+        assert_eq!(
+            rule("start 2147483648 u16 gt 31"),
+            (
+                Action::Start,
+                0x80000000,
+                Width::Halfword,
+                Condition::Greater(31)
+            )
+        );
+        assert_eq!(
+            rule("reset 0x80001000 u32 changed"),
+            (Action::Reset, 0x80001000, Width::Word, Condition::Changed)
+        );
+
+        assert!(parse_rule("split 0x80001000 u8 eq 0xZZ").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_or_extra_values() {
+        // eq には値が要る
+        assert!(parse_rule("split 0x80001000 u8 eq").is_err());
+        // changed は値を取らない
+        assert!(parse_rule("split 0x80001000 u8 changed 1").is_err());
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let text = "# 最初のボス\n\nsplit 0x80001000 u8 eq 1 # 倒した\n   # インデントしたコメント\nsplit 0x80001004 u8 bits 0x80\n";
+        let rules = parse_rules(text).unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].condition, Condition::Equal(1));
+        assert_eq!(rules[1].condition, Condition::BitsSet(0x80));
+
+        // エラーには行番号が付く
+        let err = parse_rules("# コメント\nsplit 0x80001000 u8 eq\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"));
+    }
+}
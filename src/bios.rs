@@ -2,7 +2,10 @@ use anyhow::{bail, Result};
 use log::trace;
 use std::{fs::File, io::Read, path::Path};
 
-use crate::addressible::Addressible;
+use crate::{
+    addressible::{read_le, Addressible},
+    config::Region,
+};
 
 const BIOS_SIZE: u64 = 512 * 1024;
 
@@ -25,17 +28,36 @@ impl Bios {
         Ok(Bios { data })
     }
 
+    // "System ROM Version 4.1 12/16/97 A" の最後の文字 (J/A/E)
+    // 初期のBIOSにはこの文字列がないので None
+    pub fn region(&self) -> Option<Region> {
+        let pattern = b"System ROM Version";
+        let start = self
+            .data
+            .windows(pattern.len())
+            .position(|window| window == pattern)?;
+        let version = self.data[start..].split(|&byte| byte == 0).next()?;
+
+        match version.trim_ascii_end().last()? {
+            b'J' => Some(Region::Japan),
+            b'A' => Some(Region::NorthAmerica),
+            b'E' => Some(Region::Europe),
+            _ => None,
+        }
+    }
+
     pub fn load<T: Addressible>(&self, offset: u32) -> T {
         let offset = offset as usize;
 
-        let mut v = 0;
-
-        for i in 0..T::width() as usize {
-            v |= (self.data[offset + i] as u32) << (i * 8);
-        }
+        let v = read_le::<T>(&self.data, offset);
 
-        trace!("BIOS{:?} load {:08x} => {:08x}", T::width(), offset, v);
+        trace!(
+            "BIOS{:?} load {:08x} => {:08x}",
+            T::width(),
+            offset,
+            v.as_u32()
+        );
 
-        Addressible::from_u32(v)
+        v
     }
 }
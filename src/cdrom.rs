@@ -1,9 +1,25 @@
 use std::{collections::VecDeque, io::BufReader, process::Command};
 
+use anyhow::{bail, Result};
 use log::{debug, warn};
+use num_derive::FromPrimitive;
 
-use crate::addressible::{AccessWidth, Addressible};
+use crate::{
+    addressible::{AccessWidth, Addressible},
+    gpu::CPU_CLOCK,
+    interrupts::{Irq, IrqBus, IrqLine},
+    savestate::{Reader, Savestate, Writer},
+    spu::AUDIO_BUFFER_LEN,
+};
 
+use self::image::{bcd, from_bcd, is_bcd, Image, Msf, TrackKind, DATA_SIZE, SECTOR_SIZE, SYNC};
+
+pub mod edc;
+pub mod image;
+pub mod subq;
+pub mod xa;
+
+#[derive(Clone, Copy, FromPrimitive)]
 enum ControllerStatus {
     Idle,
     ParamPush,
@@ -14,28 +30,101 @@ enum ControllerStatus {
     IrqDelay,
 }
 
+#[derive(Clone, Copy, FromPrimitive)]
 enum CdRomStatus {
     Idle,
     Seeking,
     Reading,
+    // CD-DA の再生
+    Playing,
 }
 
-#[derive(Clone, Copy, Debug)]
+// Forward / Backward による早送りと巻き戻し
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+enum Scan {
+    Normal,
+    Forward,
+    Backward,
+}
+
+#[derive(Clone, Copy, Debug, FromPrimitive)]
 enum CdRomIrq {
     ReadReady = 1,
     SecondOk = 2,
     FirstOk = 3,
+    DataEnd = 4,
     Error = 5,
 }
 
-type AsyncCallback = dyn Fn(&mut CdRom);
+// 時間を置いて返すコマンドの応答。コマンドを受けた時点で決まる値を持っておく
+// Start は最初の応答 (INT3)、End は2回目の応答 (INT2)
+#[derive(Clone, Copy, Debug)]
+enum Task {
+    GetStat,
+    // stat だけを返す
+    Stat(CdRomIrq),
+    // エラーコード
+    Error(u8),
+    GetLocL,
+    GetLocP([u8; 10]),
+    // セッションの先頭。なければ None
+    SetSessionEnd(Option<u32>),
+    // 最初と最後のトラック番号
+    GetTN(Option<(u8, u8)>),
+    // トラックの開始位置
+    GetTD(Option<u32>),
+    InitStart,
+    InitEnd,
+    Mute(bool),
+    SetMode(u8),
+    SetFilter { file: u8, channel: u8 },
+    SetLoc(Msf),
+    Read { seek: u32 },
+    Play { start: Option<u32>, seek: u32 },
+    Scan(Scan),
+    MotorOnEnd,
+    StopStart,
+    StopEnd,
+    PauseStart,
+    SeekStart,
+    SeekEnd { logical: bool, audio: bool },
+    TestVersion,
+    GetIdEnd,
+}
+
+// INT5 のエラーコード
+const ERROR_INVALID_PARAMETER: u8 = 0x10;
+const ERROR_WRONG_PARAMETER_COUNT: u8 = 0x20;
+const ERROR_INVALID_COMMAND: u8 = 0x40;
+// 今の状態では受け付けない
+const ERROR_NOT_READY: u8 = 0x80;
+
+// シーク・読み込みの開始時に先読みしておくセクタ数 (2倍速で約0.5秒分)
+const PREFETCH_SECTORS: usize = 75;
+
+// 等倍速では1秒に75セクタ
+const SECTOR_CYCLES: u32 = (CPU_CLOCK / 75) as u32;
+
+// シークの時間。数セクタ先なら回転を待つだけ、遠ければ距離に比例して端から端まで約1秒
+const SEEK_BASE_CYCLES: u32 = (CPU_CLOCK / 10) as u32;
+const SEEK_FULL_CYCLES: u32 = CPU_CLOCK as u32;
+// 74分のディスクの端から端
+const SEEK_FULL_SECTORS: u32 = 74 * 60 * 75;
+// 止まっているモーターを回し始める時間と、等倍速で止めるまでの時間 (2倍速ではその倍)
+const SPIN_UP_CYCLES: u32 = CPU_CLOCK as u32;
+const SPIN_DOWN_CYCLES: u32 = (CPU_CLOCK / 10) as u32;
+// 読み込み中に速度を変えたとき、回転が落ち着くまでの時間
+const SPEED_CHANGE_CYCLES: u32 = (CPU_CLOCK / 5) as u32;
+
+// 早送り・巻き戻しで1セクタの時間に進むセクタ数
+const SCAN_SECTORS: u32 = 8;
 
 pub struct CdRom {
     index: u8,
 
     controller: Controller,
 
-    disc: Option<Vec<u8>>,
+    disc: Option<Image>,
 
     parameter_fifo: VecDeque<u8>,
     response_fifo: VecDeque<u8>,
@@ -44,26 +133,63 @@ pub struct CdRom {
     // stat
     status: CdRomStatus,
     stat_updated: bool,
+    // ふたが開いている。閉じた後も次の GetStat まではシェルオープンのビットが残る
+    shell_open: bool,
 
     // mode
     double_speed: bool,
     raw_sector: bool,
+    // トラックの終わりで再生を止めて INT4
+    auto_pause: bool,
+    // 再生中に位置とピークを INT1 で知らせる
+    report: bool,
+    // 読み込み中の XA-ADPCM セクタをデータではなく音声として扱う
+    xa_adpcm: bool,
+    // SetFilter のファイルとチャンネルに合う XA-ADPCM セクタだけ鳴らす
+    xa_filter: bool,
+    filter_file: u8,
+    filter_channel: u8,
+
+    motor_on: bool,
+    scan: Scan,
+    // Mute 中は CD-DA と XA-ADPCM を無音にする
+    muted: bool,
 
     // request register
     read_active: bool,
 
-    seek_position: Option<Mss>,
-    current_position: Mss,
-    read_index: u16,
+    seek_position: Option<Msf>,
+    // 最後に読んだセクタ
+    current_position: Msf,
+    // 最後に読めたサブチャンネルQ (CRCの合わないものは無視される)
+    last_subq: [u8; 10],
+    // ReadN/ReadS で次に読むセクタと、それまでのサイクル
+    read_lba: u32,
+    read_cycles: u32,
+    // 最後に読んだセクタのデータ。要求レジスタで data_fifo に移す
+    sector: Vec<u8>,
+    // 最後に読んだセクタのヘッダとサブヘッダ (GetLocL)。まだ読んでいなければ None
+    sector_header: Option<[u8; 8]>,
+
+    // 届けるデータセクタの EDC/ECC を確かめて、壊れていれば警告する
+    verify_sectors: bool,
+
+    // 再生した CD-DA と XA-ADPCM のサンプル (左, 右)。SPU が pop_audio で1つずつ取り込んで混ぜる
+    audio: VecDeque<[i16; 2]>,
+    xa: xa::Decoder,
 
     ie: u8,
     irq: u8,
+    // irq & ie が立ったら INT を上げる
+    irq_line: IrqLine,
 
-    tasks: VecDeque<(u32, Box<AsyncCallback>)>,
+    tasks: VecDeque<(u32, Task)>,
 }
 
 impl CdRom {
-    pub fn new(disc: Option<Vec<u8>>) -> Self {
+    pub fn new(disc: Option<Image>) -> Self {
+        let last_subq = disc.as_ref().map_or([0; 10], |disc| disc.subq(0).data);
+
         Self {
             index: 0,
             disc,
@@ -73,18 +199,32 @@ impl CdRom {
             data_fifo: VecDeque::with_capacity(934),
             status: CdRomStatus::Idle,
             stat_updated: false,
+            shell_open: false,
             double_speed: false,
             raw_sector: false,
+            auto_pause: false,
+            report: false,
+            xa_adpcm: false,
+            xa_filter: false,
+            filter_file: 0,
+            filter_channel: 0,
+            motor_on: true,
+            scan: Scan::Normal,
+            muted: false,
             read_active: false,
             seek_position: None,
-            current_position: Mss {
-                min: 0,
-                sec: 0,
-                sector: 0,
-            },
-            read_index: 0,
+            current_position: Msf::new(0, 0, 0),
+            last_subq,
+            read_lba: 0,
+            read_cycles: 0,
+            sector: vec![],
+            sector_header: None,
+            verify_sectors: false,
+            audio: VecDeque::with_capacity(AUDIO_BUFFER_LEN),
+            xa: xa::Decoder::new(),
             ie: 0,
             irq: 0,
+            irq_line: IrqLine::new(Irq::CdRom),
             tasks: VecDeque::with_capacity(16),
         }
     }
@@ -144,18 +284,79 @@ impl CdRom {
         }
     }
 
-    pub fn tick(&mut self) {
+    pub fn tick(&mut self, irqs: &mut IrqBus) {
         if self.tasks.len() > 0 {
             if self.tasks[0].0 > 0 {
                 self.tasks[0].0 -= 1;
             } else {
-                let task = self.tasks.pop_front().unwrap();
-                let f = task.1;
-                f(self);
+                let (_, task) = self.tasks.pop_front().unwrap();
+                self.run_task(task);
             }
         }
 
+        self.step_read();
+
         self.controller.tick();
+
+        self.irq_line.set(irqs, self.check_irq());
+    }
+
+    // 再生したサンプルを古い順に1つ取り出す。鳴らしていなければ None
+    pub fn pop_audio(&mut self) -> Option<[i16; 2]> {
+        self.audio.pop_front()
+    }
+
+    // ふたを開けてディスクを取り出す。読み込み中や再生中なら INT5 で止まる
+    pub fn eject(&mut self) {
+        if self.shell_open {
+            return;
+        }
+
+        let busy = !matches!(self.status, CdRomStatus::Idle);
+
+        self.shell_open = true;
+        self.disc = None;
+        self.status = CdRomStatus::Idle;
+        self.scan = Scan::Normal;
+        self.motor_on = false;
+        self.seek_position = None;
+        self.sector_header = None;
+
+        if busy {
+            let stat = self.stat(false);
+            self.response_fifo.extend([stat | 0x01, 0x08]);
+            self.raise_irq(CdRomIrq::Error);
+        }
+    }
+
+    // ディスクを入れてふたを閉じる。開いていなければ先に取り出す
+    pub fn insert(&mut self, disc: Image) {
+        self.eject();
+
+        self.disc = Some(disc);
+        self.shell_open = false;
+        self.stat_updated = false;
+        self.motor_on = true;
+        self.set_position(Msf::from_lba(0));
+        self.read_lba = 0;
+    }
+
+    pub fn set_verify_sectors(&mut self, enabled: bool) {
+        self.verify_sectors = enabled;
+    }
+
+    pub fn shell_open(&self) -> bool {
+        self.shell_open
+    }
+
+    // DMAのDREQ。要求レジスタで移したセクタが残っている
+    pub fn dma_request(&self) -> bool {
+        !self.data_fifo.is_empty()
+    }
+
+    // DMAで1ワード読む。セクタより多く読むと足りない分は0になる
+    pub fn dma_read(&mut self) -> u32 {
+        self.data_fifo_word()
     }
 
     pub fn check_irq(&self) -> bool {
@@ -177,7 +378,7 @@ impl CdRom {
         result |= (self.parameter_fifo.is_empty() as u8) << 3;
         result |= ((self.parameter_fifo.len() < 16) as u8) << 4;
         result |= (!self.response_fifo.is_empty() as u8) << 5;
-        result |= (self.read_active as u8) << 6;
+        result |= (!self.data_fifo.is_empty() as u8) << 6;
         result |= (self.busy() as u8) << 7;
 
         debug!("CD-ROM status read {:02x}", result);
@@ -241,6 +442,12 @@ impl CdRom {
     fn raise_irq(&mut self, irq: CdRomIrq) {
         debug!("CD-ROM raise irq {:?}", irq);
 
+        // 最初の応答 (INT3 か INT5) でコマンドの実行が終わる
+        if let CdRomIrq::FirstOk | CdRomIrq::Error = irq {
+            self.controller.command = None;
+            self.controller.status = ControllerStatus::Idle;
+        }
+
         self.irq &= 0xF8;
         self.irq |= (irq as u8) & 0x7;
     }
@@ -250,8 +457,15 @@ impl CdRom {
         self.parameter_fifo.push_back(val);
     }
 
+    // bit7 を立てると最後に読んだセクタが data_fifo に入り、落とすと捨てられる
     fn set_request_register(&mut self, val: u8) {
         self.read_active = val & 0x80 != 0;
+
+        if !self.read_active {
+            self.data_fifo.clear();
+        } else if self.data_fifo.is_empty() {
+            self.data_fifo.extend(self.sector.iter());
+        }
     }
 
     fn response_fifo(&mut self) -> u8 {
@@ -262,61 +476,273 @@ impl CdRom {
         self.response_fifo.pop_front().unwrap_or(0)
     }
 
-    fn data_at(&self, offset: u16) -> u8 {
-        let base = self.current_position.into_addr(self.raw_sector) as usize;
-        let disc = self.disc.as_ref().unwrap();
+    // ヘッドを移す。CRCの合うサブチャンネルQだけ覚える
+    fn set_position(&mut self, position: Msf) {
+        self.current_position = position;
+
+        let lba = self.current_lba();
+        if let Some(subq) = self.disc.as_ref().map(|disc| disc.subq(lba)) {
+            match subq.crc_valid() {
+                true => self.last_subq = subq.data,
+                false => debug!("CD-ROM subchannel Q CRC error at {}", lba),
+            }
+        }
+    }
 
-        disc[base + offset as usize]
+    // リードインの中は先頭のセクタとして扱う
+    fn current_lba(&self) -> u32 {
+        self.current_position.lba().unwrap_or(0)
     }
 
-    fn data_fifo(&mut self) -> u8 {
-        if !self.read_active {
-            warn!("inactive data fifo access")
+    // 生セクタの中で読み出す範囲
+    // 生セクタのモードでは同期パターンの後ろの 0x924byte、そうでなければユーザーデータの 0x800byte
+    fn sector_window(&self, raw: &[u8]) -> std::ops::Range<usize> {
+        if self.raw_sector {
+            return SYNC.len()..SECTOR_SIZE;
         }
 
-        let val = self.data_at(self.read_index);
+        // モード2 (フォーム1) はサブヘッダの8byteも飛ばす
+        match raw[15] {
+            1 => 16..16 + DATA_SIZE,
+            _ => 24..24 + DATA_SIZE,
+        }
+    }
 
-        debug!("CD-ROM data pop {:02x}", val);
+    // 続けて読まれるセクタをホスト側で読んでおく。届くデータには影響しない
+    fn prefetch(&self, lba: u32) {
+        if let Some(disc) = &self.disc {
+            disc.prefetch(lba, PREFETCH_SECTORS);
+        }
+    }
 
-        self.read_index += 1;
+    fn sector_cycles(&self) -> u32 {
+        match self.double_speed {
+            true => SECTOR_CYCLES / 2,
+            false => SECTOR_CYCLES,
+        }
+    }
 
-        val
+    // from から to のセクタへ移るまでのサイクル
+    fn seek_cycles(&self, from: u32, to: u32) -> u32 {
+        let distance = from.abs_diff(to);
+
+        let long = SEEK_BASE_CYCLES as u64
+            + (SEEK_FULL_CYCLES - SEEK_BASE_CYCLES) as u64 * distance.min(SEEK_FULL_SECTORS) as u64
+                / SEEK_FULL_SECTORS as u64;
+        let short = self.sector_cycles() as u64 * distance.max(2) as u64;
+
+        let cycles = short.min(long) as u32;
+
+        match self.motor_on {
+            true => cycles,
+            false => cycles + SPIN_UP_CYCLES,
+        }
     }
 
-    fn data_fifo_halfword(&mut self) -> u16 {
-        if !self.read_active {
-            warn!("inactive data fifo access")
+    // 最初のセクタはシークの後
+    fn start_read(&mut self, seek_cycles: u32) {
+        self.status = CdRomStatus::Reading;
+        self.motor_on = true;
+        self.read_cycles = seek_cycles + self.sector_cycles();
+    }
+
+    fn start_play(&mut self, seek_cycles: u32) {
+        self.status = CdRomStatus::Playing;
+        self.scan = Scan::Normal;
+        self.read_cycles = seek_cycles + self.sector_cycles();
+    }
+
+    // 読み込み中は1セクタの時間ごとに次のセクタを届けて INT1 を立てる
+    // 再生中は同じ間隔で次のセクタを鳴らす
+    fn step_read(&mut self) {
+        if !matches!(self.status, CdRomStatus::Reading | CdRomStatus::Playing) {
+            return;
+        }
+
+        if self.read_cycles > 0 {
+            self.read_cycles -= 1;
+            return;
         }
 
-        let lower = self.data_at(self.read_index) as u16;
-        let higher = self.data_at(self.read_index + 1) as u16;
-        let val = (higher << 8) | lower;
+        match self.status {
+            CdRomStatus::Playing => self.play_sector(),
+            // 前の割り込みが ack されるまで待つ
+            _ if self.irq & 0x7 != 0 => return,
+            _ => self.deliver_sector(),
+        }
 
-        debug!("CD-ROM data pop {:04x}", val);
+        self.read_cycles = self.sector_cycles();
+    }
 
-        self.read_index += 2;
+    // ディスクの終わりか、オートポーズでトラックの終わりに来たら止めて INT4
+    fn play_sector(&mut self) {
+        let lba = self.read_lba;
+
+        let track = self
+            .disc
+            .as_ref()
+            .and_then(|disc| disc.track_at(lba))
+            .map(|track| (track.number, track.kind.is_data()));
+        let previous = self
+            .disc
+            .as_ref()
+            .and_then(|disc| disc.track_at(self.current_lba()))
+            .map(|track| track.number);
+
+        let is_data = match track {
+            Some((number, is_data)) if !self.auto_pause || Some(number) == previous => is_data,
+            _ => {
+                debug!("CD-ROM play end at {}", lba);
 
-        val
+                self.status = CdRomStatus::Idle;
+                let stat = self.stat(false);
+                self.response_fifo.push_back(stat);
+                self.raise_irq(CdRomIrq::DataEnd);
+                return;
+            }
+        };
+
+        // データトラックは鳴らさない
+        let samples = match is_data {
+            true => vec![],
+            false => self
+                .disc
+                .as_ref()
+                .and_then(|disc| disc.read_sector(lba))
+                .map(|raw| {
+                    raw.chunks_exact(4)
+                        .map(|s| {
+                            [
+                                i16::from_le_bytes([s[0], s[1]]),
+                                i16::from_le_bytes([s[2], s[3]]),
+                            ]
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default(),
+        };
+
+        self.push_audio(&samples);
+
+        self.set_position(Msf::from_lba(lba));
+        self.read_lba = match self.scan {
+            Scan::Normal => lba + 1,
+            Scan::Forward => lba + SCAN_SECTORS,
+            Scan::Backward => lba.saturating_sub(SCAN_SECTORS),
+        };
+        self.prefetch(self.read_lba);
+
+        if self.report {
+            self.report_position(&samples);
+        }
     }
 
-    fn data_fifo_word(&mut self) -> u32 {
-        if !self.read_active {
-            warn!("inactive data fifo access")
+    // 出力先の時間がずれないよう、Mute 中も同じ数の無音を積む
+    fn push_audio(&mut self, samples: &[[i16; 2]]) {
+        let overflow = (self.audio.len() + samples.len()).saturating_sub(AUDIO_BUFFER_LEN);
+        self.audio.drain(..overflow.min(self.audio.len()));
+
+        match self.muted {
+            true => self.audio.extend(samples.iter().map(|_| [0; 2])),
+            false => self.audio.extend(samples),
+        }
+    }
+
+    // 10セクタごとに INT1 で [stat, トラック, インデックス, 分, 秒, セクタ, ピーク(16bit)]
+    // 絶対位置と、秒の bit7 を立てたトラック内の位置を交互に送る。ピークの bit15 は右チャンネル
+    fn report_position(&mut self, samples: &[[i16; 2]]) {
+        let q = self.last_subq;
+
+        let frame = from_bcd(q[9]);
+        if !frame.is_multiple_of(10) || self.irq & 0x7 != 0 {
+            return;
+        }
+
+        let right = (frame / 10) & 1 == 1;
+        let position = match right {
+            false => [q[7], q[8], q[9]],
+            true => [q[3], q[4] | 0x80, q[5]],
+        };
+
+        let peak = samples
+            .iter()
+            .map(|sample| sample[right as usize].unsigned_abs().min(0x7FFF))
+            .max()
+            .unwrap_or(0)
+            | (right as u16) << 15;
+
+        let stat = self.stat(false);
+        self.response_fifo.extend([stat, q[1], q[2]]);
+        self.response_fifo.extend(position);
+        self.response_fifo.extend(peak.to_le_bytes());
+        self.raise_irq(CdRomIrq::ReadReady);
+    }
+
+    fn deliver_sector(&mut self) {
+        let lba = self.read_lba;
+
+        debug!("CD-ROM deliver sector {}", lba);
+
+        let raw = self
+            .disc
+            .as_ref()
+            .and_then(|disc| disc.read_sector(lba))
+            .unwrap_or_else(|| vec![0; SECTOR_SIZE]);
+
+        if self.verify_sectors {
+            if let Err(damage) = edc::verify(&raw) {
+                warn!("CD-ROM sector {} is corrupted ({:?} mismatch)", lba, damage);
+            }
+        }
+
+        self.sector_header = Some(raw[12..20].try_into().unwrap());
+        self.set_position(Msf::from_lba(lba));
+        self.read_lba = lba + 1;
+
+        self.prefetch(self.read_lba);
+
+        // XA-ADPCM のセクタは INT1 を立てずに鳴らす
+        if self.xa_adpcm && raw[15] == 2 && raw[18] & xa::SUBMODE_AUDIO == xa::SUBMODE_AUDIO {
+            if !self.xa_filter || (raw[16], raw[17]) == (self.filter_file, self.filter_channel) {
+                let samples = self.xa.decode_sector(&raw);
+                self.push_audio(&samples);
+            }
+            return;
         }
 
-        let lowest = self.data_at(self.read_index) as u32;
-        let lower = self.data_at(self.read_index + 1) as u32;
-        let higher = self.data_at(self.read_index + 2) as u32;
-        let highest = self.data_at(self.read_index + 3) as u32;
-        let val = (highest << 24) | (higher << 16) | (lower << 8) | lowest;
+        self.sector = raw[self.sector_window(&raw)].to_vec();
+
+        let stat = self.stat(false);
+        self.response_fifo.push_back(stat);
+        self.raise_irq(CdRomIrq::ReadReady);
+    }
+
+    fn data_fifo(&mut self) -> u8 {
+        if self.data_fifo.is_empty() {
+            warn!("empty data fifo access")
+        }
 
-        debug!("CD-ROM data pop {:08x}", val);
+        let val = self.data_fifo.pop_front().unwrap_or(0);
 
-        self.read_index += 4;
+        debug!("CD-ROM data pop {:02x}", val);
 
         val
     }
 
+    fn data_fifo_halfword(&mut self) -> u16 {
+        let lower = self.data_fifo() as u16;
+        let higher = self.data_fifo() as u16;
+
+        (higher << 8) | lower
+    }
+
+    fn data_fifo_word(&mut self) -> u32 {
+        let lower = self.data_fifo_halfword() as u32;
+        let higher = self.data_fifo_halfword() as u32;
+
+        (higher << 16) | lower
+    }
+
     fn stat(&mut self, update: bool) -> u8 {
         let stat_updated = self.stat_updated;
 
@@ -324,35 +750,79 @@ impl CdRom {
             self.stat_updated = true;
         }
 
-        // motor onの分+2してる
-        if self.disc == None || !stat_updated {
+        if self.shell_open {
+            0x10 // shell opened, motor stopped
+        } else if self.disc.is_none() || !stat_updated {
             0x12 // shell opened
         } else {
-            match self.status {
-                CdRomStatus::Idle => 0x02,
-                CdRomStatus::Seeking => 0x42,
-                CdRomStatus::Reading => 0x22,
-            }
+            let state = match self.status {
+                CdRomStatus::Idle => 0x00,
+                CdRomStatus::Seeking => 0x40,
+                CdRomStatus::Reading => 0x20,
+                CdRomStatus::Playing => 0x80,
+            };
+
+            state | (self.motor_on as u8) << 1
         }
     }
 
     fn command(&mut self, val: u8) {
+        // 前のコマンドの最初の応答がまだ返っていない
+        if self.busy() {
+            warn!("CD-ROM command {:02x} while busy", val);
+            self.reject_command(ERROR_NOT_READY);
+            return;
+        }
+
+        self.controller.command = Some(val as u32);
+        self.controller.status = ControllerStatus::Execution;
+
+        let count = match parameter_count(val) {
+            Some(count) => count,
+            None => {
+                warn!("unsupported CD-ROM command {:02x}", val);
+                self.reject_command(ERROR_INVALID_COMMAND);
+                return;
+            }
+        };
+
+        if !count.contains(&self.parameter_fifo.len()) {
+            warn!(
+                "CD-ROM command {:02x} with {} parameters",
+                val,
+                self.parameter_fifo.len()
+            );
+            self.reject_command(ERROR_WRONG_PARAMETER_COUNT);
+            return;
+        }
+
         match val {
             0x01 => self.get_stat(),
             0x02 => self.set_loc(),
+            0x03 => self.play(),
+            0x04 => self.scan(Scan::Forward),
+            0x05 => self.scan(Scan::Backward),
             0x06 => self.read_n(),
+            0x07 => self.motor_on(),
+            0x08 => self.stop(),
             0x09 => self.pause(),
             0x0A => self.init(),
+            0x0B => self.mute(true),
+            0x0C => self.mute(false),
+            0x0D => self.set_filter(),
             0x0E => self.set_mode(),
-            0x15 => self.seek_l(),
+            0x10 => self.get_loc_l(),
+            0x11 => self.get_loc_p(),
+            0x12 => self.set_session(),
+            0x13 => self.get_tn(),
+            0x14 => self.get_td(),
+            0x15 => self.seek(true),
+            0x16 => self.seek(false),
             0x19 => self.test(),
             0x1A => self.get_id(),
             0x1B => self.read_s(),
             0x1E => self.read_toc(),
-            _ => {
-                warn!("unsupported CD-ROM command {:02x}", val);
-                return;
-            }
+            _ => unreachable!(),
         }
 
         self.parameter_fifo.clear();
@@ -364,38 +834,108 @@ impl CdRom {
 
     fn get_stat(&mut self) {
         debug!("CD-ROM command getStat");
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                let stat = this.stat(true);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
+        self.tasks.push_back((50000, Task::GetStat));
+    }
+
+    // 実行しないコマンドにも、ほかのコマンドと同じ間を置いて INT5 を返す
+    fn reject_command(&mut self, code: u8) {
+        self.parameter_fifo.clear();
+        self.tasks.push_back((50000, Task::Error(code)));
+    }
+
+    // stat のエラービットを立てて、エラーコードと一緒に INT5 で返す
+    fn error(&mut self, code: u8) {
+        let stat = self.stat(false);
+        self.response_fifo.extend([stat | 0x01, code]);
+        self.raise_irq(CdRomIrq::Error);
+    }
+
+    // stat だけの応答
+    fn respond_stat(&mut self, irq: CdRomIrq) {
+        let stat = self.stat(false);
+        self.response_fifo.push_back(stat);
+        self.raise_irq(irq);
+    }
+
+    // 最後に読んだデータセクタのヘッダ (分, 秒, セクタ, モード) とサブヘッダ (4byte)
+    fn get_loc_l(&mut self) {
+        debug!("CD-ROM command getLocL");
+
+        self.tasks.push_back((50000, Task::GetLocL));
+    }
+
+    // 最後に読めたサブチャンネルQのトラック, インデックス, トラック内の位置, ディスク上の位置 (すべてBCD)
+    fn get_loc_p(&mut self) {
+        debug!("CD-ROM command getLocP");
+
+        self.tasks.push_back((50000, Task::GetLocP(self.last_subq)));
+    }
+
+    // セッションの先頭へ移る。ないセッションならシークエラー
+    fn set_session(&mut self) {
+        let session = self.parameter_fifo.front().copied().unwrap_or(0);
+
+        debug!("CD-ROM command setSession {}", session);
+
+        if session == 0 {
+            self.tasks
+                .push_back((50000, Task::Error(ERROR_INVALID_PARAMETER)));
+            return;
+        }
+
+        let start = self
+            .disc
+            .as_ref()
+            .and_then(|disc| disc.session(session))
+            .map(|track| track.start);
+        let seek = self.seek_cycles(self.current_lba(), start.unwrap_or(0));
+
+        self.tasks.push_back((50000, Task::SeekStart));
+        self.tasks.push_back((seek, Task::SetSessionEnd(start)));
+    }
+
+    // 最初と最後のトラック番号 (BCD)
+    fn get_tn(&mut self) {
+        debug!("CD-ROM command getTN");
+
+        let tracks = self.disc.as_ref().and_then(|disc| {
+            let tracks = disc.tracks();
+            Some((tracks.first()?.number, tracks.last()?.number))
+        });
+
+        self.tasks.push_back((50000, Task::GetTN(tracks)));
+    }
+
+    // トラックの開始位置の分と秒 (BCD)。トラック0はリードアウト
+    fn get_td(&mut self) {
+        let track = self.parameter_fifo.front().copied().map(from_bcd);
+
+        debug!("CD-ROM command getTD {:?}", track);
+
+        let lba = self
+            .disc
+            .as_ref()
+            .zip(track)
+            .and_then(|(disc, track)| match track {
+                0 => Some(disc.sectors()),
+                n => disc.track(n).map(|track| track.start),
+            });
+
+        self.tasks.push_back((50000, Task::GetTD(lba)));
     }
 
     fn init(&mut self) {
         debug!("CD-ROM command init");
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
-
-        self.tasks.push_back((
-            900000,
-            Box::new(|this| {
-                this.double_speed = false;
-                this.raw_sector = false;
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::SecondOk);
-            }),
-        ));
+        self.tasks.push_back((50000, Task::InitStart));
+        self.tasks.push_back((900000, Task::InitEnd));
+    }
+
+    // Mute / Demute
+    fn mute(&mut self, muted: bool) {
+        debug!("CD-ROM command mute {}", muted);
+
+        self.tasks.push_back((50000, Task::Mute(muted)));
     }
 
     fn set_mode(&mut self) {
@@ -403,260 +943,1614 @@ impl CdRom {
 
         debug!("CD-ROM command setMode {:02x}", mode);
 
-        self.tasks.push_back((
-            50000,
-            Box::new(move |this| {
-                this.double_speed = mode & 0x80 != 0;
-                this.raw_sector = mode & 0x20 != 0;
+        self.tasks.push_back((50000, Task::SetMode(mode)));
+    }
+
+    // XA-ADPCM のファイルとチャンネル
+    fn set_filter(&mut self) {
+        let file = self.parameter_fifo.front().copied().unwrap_or(0);
+        let channel = self.parameter_fifo.get(1).copied().unwrap_or(0);
 
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
+        debug!("CD-ROM command setFilter {:02x} {:02x}", file, channel);
+
+        self.tasks
+            .push_back((50000, Task::SetFilter { file, channel }));
     }
 
     fn set_loc(&mut self) {
-        let addr = Mss {
-            min: self.parameter_fifo[0],
-            sec: self.parameter_fifo[1],
-            sector: self.parameter_fifo[2],
-        };
+        let [min, sec, frame] = [0, 1, 2].map(|i| self.parameter_fifo[i]);
+
+        // BCD でないか、秒やセクタが範囲を超えている
+        if ![min, sec, frame].into_iter().all(is_bcd) || sec >= 0x60 || frame >= 0x75 {
+            warn!(
+                "CD-ROM setLoc invalid position {:02x}:{:02x}:{:02x}",
+                min, sec, frame
+            );
+            self.tasks
+                .push_back((50000, Task::Error(ERROR_INVALID_PARAMETER)));
+            return;
+        }
+
+        let addr = Msf::from_bcd(min, sec, frame);
 
         debug!("CD-ROM command setLoc {:?}", addr);
 
-        self.tasks.push_back((
-            50000,
-            Box::new(move |this| {
-                this.seek_position = Some(addr);
+        // シークが終わるまでに読み始める位置をホスト側で読んでおく
+        if let Some(lba) = addr.lba() {
+            self.prefetch(lba);
+        }
+
+        self.tasks.push_back((50000, Task::SetLoc(addr)));
+    }
+
+    // SetLoc の位置がまだ使われていなければそこへ移り、かかるサイクルを返す
+    // 使われていても読み込みを再開する位置へ戻る分のシークはある
+    fn seek_to_target(&mut self) -> u32 {
+        let from = self.current_lba();
+
+        if let Some(position) = self.seek_position.take() {
+            self.set_position(position);
+            self.read_lba = self.current_lba();
+        }
+
+        self.prefetch(self.read_lba);
 
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
+        self.seek_cycles(from, self.read_lba)
     }
 
+    // 最初の INT3 の後は Pause されるまでセクタごとに INT1 が続く
     fn read_n(&mut self) {
         debug!("CD-ROM command readN");
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
+        let seek = self.seek_to_target();
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                this.status = CdRomStatus::Reading;
-
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::ReadReady);
-            }),
-        ));
+        self.tasks.push_back((50000, Task::Read { seek }));
     }
 
-    fn pause(&mut self) {
-        debug!("CD-ROM command pause");
+    // 引数のトラック (BCD) の先頭から、なければ SetLoc の位置か今の位置から再生する
+    fn play(&mut self) {
+        let track = self
+            .parameter_fifo
+            .front()
+            .copied()
+            .map(from_bcd)
+            .filter(|&track| track != 0);
+
+        debug!("CD-ROM command play {:?}", track);
+
+        let (start, seek) = match track {
+            Some(track) => match self.disc.as_ref().and_then(|disc| disc.track(track)) {
+                Some(track) => (
+                    Some(track.start),
+                    self.seek_cycles(self.current_lba(), track.start),
+                ),
+                None => {
+                    self.tasks
+                        .push_back((50000, Task::Error(ERROR_INVALID_PARAMETER)));
+                    return;
+                }
+            },
+            None => (None, self.seek_to_target()),
+        };
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
+        self.tasks.push_back((50000, Task::Play { start, seek }));
+    }
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                this.status = CdRomStatus::Idle;
+    // 再生中だけ受け付ける。もう一度 Play するか Pause すると元に戻る
+    fn scan(&mut self, scan: Scan) {
+        debug!("CD-ROM command scan {}", scan as u8);
 
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::SecondOk);
-            }),
-        ));
+        self.tasks.push_back((50000, Task::Scan(scan)));
     }
 
-    fn read_s(&mut self) {
-        self.read_n()
+    // 止まっていれば回し始める。INT2 は回りきってから
+    fn motor_on(&mut self) {
+        debug!("CD-ROM command motorOn");
+
+        let spin_up = match self.motor_on {
+            true => 50000,
+            false => SPIN_UP_CYCLES,
+        };
+
+        self.tasks.push_back((50000, Task::Stat(CdRomIrq::FirstOk)));
+        self.tasks.push_back((spin_up, Task::MotorOnEnd));
     }
 
-    fn read_toc(&mut self) {
-        debug!("CD-ROM command readToc");
+    // 読み込みや再生を止め、ヘッドを先頭に戻してモーターも止める
+    fn stop(&mut self) {
+        debug!("CD-ROM command stop");
 
-        let stat = self.stat(false);
-        self.response_fifo.push_back(stat);
-        self.raise_irq(CdRomIrq::FirstOk);
+        let spin_down = match (self.motor_on, self.double_speed) {
+            (false, _) => 50000,
+            (true, false) => SPIN_DOWN_CYCLES,
+            (true, true) => SPIN_DOWN_CYCLES * 2,
+        };
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::SecondOk);
-            }),
-        ));
+        self.tasks.push_back((50000, Task::StopStart));
+        self.tasks.push_back((spin_down, Task::StopEnd));
     }
 
-    fn seek_l(&mut self) {
-        debug!("CD-ROM command seekL");
+    fn pause(&mut self) {
+        debug!("CD-ROM command pause");
+
+        self.tasks.push_back((50000, Task::PauseStart));
+        self.tasks
+            .push_back((50000, Task::Stat(CdRomIrq::SecondOk)));
+    }
 
-        if let Some(position) = self.seek_position {
-            self.current_position = position;
-        }
+    fn read_s(&mut self) {
+        self.read_n()
+    }
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                this.status = CdRomStatus::Seeking;
+    fn read_toc(&mut self) {
+        debug!("CD-ROM command readToc");
 
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
+        self.respond_stat(CdRomIrq::FirstOk);
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                this.status = CdRomStatus::Idle;
-                this.read_index = 0;
+        self.tasks
+            .push_back((50000, Task::Stat(CdRomIrq::SecondOk)));
+    }
 
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::SecondOk);
-            }),
-        ));
+    // SeekL (logical) はデータセクタのヘッダで位置を合わせるので CD-DA には移れない
+    // SeekP はサブチャンネルQで合わせるのでどちらにも移れる
+    fn seek(&mut self, logical: bool) {
+        debug!("CD-ROM command seek{}", if logical { "L" } else { "P" });
+
+        let seek = self.seek_to_target();
+        let audio = self
+            .disc
+            .as_ref()
+            .and_then(|disc| disc.track_at(self.read_lba))
+            .is_some_and(|track| !track.kind.is_data());
+
+        self.tasks.push_back((50000, Task::SeekStart));
+        self.tasks
+            .push_back((seek, Task::SeekEnd { logical, audio }));
     }
 
     fn test(&mut self) {
         debug!("CD-ROM command test 0x{:02x}", self.parameter_fifo[0]);
 
         match self.parameter_fifo.pop_front() {
-            Some(0x20) => self.test_version(),
-            Some(n) => warn!("unsupported CD-ROM test func {:02x}", n),
-            _ => warn!("CD-ROM test func missing params"),
+            Some(0x20) => self.tasks.push_back((50000, Task::TestVersion)),
+            n => {
+                warn!("unsupported CD-ROM test func {:02x?}", n);
+                self.tasks
+                    .push_back((50000, Task::Error(ERROR_INVALID_PARAMETER)));
+            }
         }
     }
 
-    fn test_version(&mut self) {
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                this.response_fifo.push_back(0x96);
-                this.response_fifo.push_back(0x09);
-                this.response_fifo.push_back(0x12);
-                this.response_fifo.push_back(0xC2);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
-    }
-
     fn get_id(&mut self) {
         debug!("CD-ROM command getId");
 
-        self.tasks.push_back((
-            50000,
-            Box::new(|this| {
-                let stat = this.stat(false);
-                this.response_fifo.push_back(stat);
-                this.raise_irq(CdRomIrq::FirstOk);
-            }),
-        ));
-
-        if self.disc == None {
-            self.tasks.push_back((
-                50000,
-                Box::new(|this| {
-                    this.response_fifo.push_back(0x08);
-                    this.response_fifo.push_back(0x40);
-
-                    this.response_fifo.push_back(0x00);
-                    this.response_fifo.push_back(0x00);
-
-                    this.response_fifo.push_back(0x00);
-                    this.response_fifo.push_back(0x00);
-                    this.response_fifo.push_back(0x00);
-                    this.response_fifo.push_back(0x00);
-                    this.raise_irq(CdRomIrq::Error);
-                }),
-            ));
-        } else {
-            self.tasks.push_back((
-                50000,
-                Box::new(|this| {
-                    this.response_fifo.push_back(0x02);
-                    this.response_fifo.push_back(0x00);
-
-                    this.response_fifo.push_back(0x20);
-                    this.response_fifo.push_back(0x00);
-
-                    // SCEI
-                    this.response_fifo.push_back(0x53);
-                    this.response_fifo.push_back(0x43);
-                    this.response_fifo.push_back(0x45);
-                    this.response_fifo.push_back(0x49);
-                    this.raise_irq(CdRomIrq::SecondOk);
-                }),
-            ));
+        // ふたが開いていれば最初の応答でエラー
+        if self.shell_open {
+            self.tasks.push_back((50000, Task::Error(ERROR_NOT_READY)));
+            return;
         }
+
+        self.tasks.push_back((50000, Task::Stat(CdRomIrq::FirstOk)));
+        self.tasks.push_back((50000, Task::GetIdEnd));
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-struct Mss {
-    min: u8,
-    sec: u8,
-    sector: u8,
-}
+    // 時間の来た応答を返す
+    fn run_task(&mut self, task: Task) {
+        match task {
+            Task::GetStat => {
+                let stat = self.stat(true);
+                self.response_fifo.push_back(stat);
+                self.raise_irq(CdRomIrq::FirstOk);
+            }
+            Task::Stat(irq) => self.respond_stat(irq),
+            Task::Error(code) => self.error(code),
+            Task::GetLocL => match self.sector_header {
+                Some(header) => {
+                    self.response_fifo.extend(header);
+                    self.raise_irq(CdRomIrq::FirstOk);
+                }
+                None => self.error(ERROR_NOT_READY),
+            },
+            Task::GetLocP(q) => {
+                self.response_fifo.extend(&q[1..6]);
+                self.response_fifo.extend(&q[7..10]);
+                self.raise_irq(CdRomIrq::FirstOk);
+            }
+            Task::SetSessionEnd(start) => {
+                self.status = CdRomStatus::Idle;
+                self.motor_on = true;
+
+                let stat = self.stat(false);
+                match start {
+                    Some(lba) => {
+                        self.seek_position = None;
+                        self.set_position(Msf::from_lba(lba));
+                        self.read_lba = lba;
+
+                        self.response_fifo.push_back(stat);
+                        self.raise_irq(CdRomIrq::SecondOk);
+                    }
+                    None => {
+                        self.response_fifo.extend([stat | 0x04, 0x40]);
+                        self.raise_irq(CdRomIrq::Error);
+                    }
+                }
+            }
+            Task::GetTN(tracks) => match tracks {
+                Some((first, last)) => {
+                    let stat = self.stat(false);
+                    self.response_fifo.extend([stat, bcd(first), bcd(last)]);
+                    self.raise_irq(CdRomIrq::FirstOk);
+                }
+                None => self.error(ERROR_INVALID_PARAMETER),
+            },
+            Task::GetTD(lba) => match lba {
+                Some(lba) => {
+                    let msf = Msf::from_lba(lba);
+                    let stat = self.stat(false);
+                    self.response_fifo
+                        .extend([stat, bcd(msf.min), bcd(msf.sec)]);
+                    self.raise_irq(CdRomIrq::FirstOk);
+                }
+                None => self.error(ERROR_INVALID_PARAMETER),
+            },
+            Task::InitStart => {
+                self.respond_stat(CdRomIrq::FirstOk);
 
-impl Mss {
-    fn into_addr(&self, raw: bool) -> u32 {
-        (self.sector as u32) * if raw { 924 } else { 800 }
-    }
-}
+                self.status = CdRomStatus::Idle;
+            }
+            Task::InitEnd => {
+                self.double_speed = false;
+                self.raw_sector = false;
+                self.auto_pause = false;
+                self.report = false;
+                self.xa_adpcm = false;
+                self.xa_filter = false;
+                self.motor_on = true;
+
+                self.respond_stat(CdRomIrq::SecondOk);
+            }
+            Task::Mute(muted) => {
+                self.muted = muted;
 
-struct Controller {
-    command: Option<u32>,
-    status: ControllerStatus,
-    stalls: u32,
-    param: VecDeque<u8>,
-    response: VecDeque<u8>,
-    irq: Option<CdRomIrq>,
-    task: Option<(u16, Box<AsyncCallback>)>,
-}
+                self.respond_stat(CdRomIrq::FirstOk);
+            }
+            Task::SetMode(mode) => {
+                let double_speed = mode & 0x80 != 0;
+                if double_speed != self.double_speed
+                    && matches!(self.status, CdRomStatus::Reading | CdRomStatus::Playing)
+                {
+                    self.read_cycles += SPEED_CHANGE_CYCLES;
+                }
+
+                self.double_speed = double_speed;
+                self.raw_sector = mode & 0x20 != 0;
+                self.auto_pause = mode & 0x02 != 0;
+                self.report = mode & 0x04 != 0;
+                self.xa_filter = mode & 0x08 != 0;
+                self.xa_adpcm = mode & 0x40 != 0;
+
+                self.respond_stat(CdRomIrq::FirstOk);
+            }
+            Task::SetFilter { file, channel } => {
+                self.filter_file = file;
+                self.filter_channel = channel;
 
-impl Controller {
-    fn new() -> Self {
-        Self {
-            command: None,
-            status: ControllerStatus::Idle,
-            stalls: 0,
-            param: VecDeque::new(),
-            response: VecDeque::new(),
-            irq: None,
-            task: None,
-        }
-    }
+                self.respond_stat(CdRomIrq::FirstOk);
+            }
+            Task::SetLoc(addr) => {
+                self.seek_position = Some(addr);
 
-    fn tick(&mut self) {
-        if self.stalls > 0 {
-            self.stalls -= 1;
-            return;
+                self.respond_stat(CdRomIrq::FirstOk);
+            }
+            Task::Read { seek } => {
+                self.respond_stat(CdRomIrq::FirstOk);
+
+                self.start_read(seek);
+            }
+            Task::Play { start, seek } => {
+                if let Some(lba) = start {
+                    self.seek_position = None;
+                    self.set_position(Msf::from_lba(lba));
+                    self.read_lba = lba;
+                    self.prefetch(lba);
+                }
+                self.motor_on = true;
+
+                self.respond_stat(CdRomIrq::FirstOk);
+
+                self.start_play(seek);
+            }
+            Task::Scan(scan) => {
+                if !matches!(self.status, CdRomStatus::Playing) {
+                    self.error(ERROR_NOT_READY);
+                    return;
+                }
+
+                self.scan = scan;
+
+                self.respond_stat(CdRomIrq::FirstOk);
+            }
+            Task::MotorOnEnd => {
+                self.motor_on = true;
+
+                self.respond_stat(CdRomIrq::SecondOk);
+            }
+            Task::StopStart => {
+                self.status = CdRomStatus::Idle;
+                self.scan = Scan::Normal;
+
+                self.respond_stat(CdRomIrq::FirstOk);
+            }
+            Task::StopEnd => {
+                self.motor_on = false;
+                self.set_position(Msf::from_lba(0));
+                self.read_lba = 0;
+
+                self.respond_stat(CdRomIrq::SecondOk);
+            }
+            Task::PauseStart => {
+                self.respond_stat(CdRomIrq::FirstOk);
+
+                // 最初の応答はまだ読み込み中
+                self.status = CdRomStatus::Idle;
+            }
+            Task::SeekStart => {
+                self.status = CdRomStatus::Seeking;
+
+                self.respond_stat(CdRomIrq::FirstOk);
+            }
+            Task::SeekEnd { logical, audio } => {
+                self.status = CdRomStatus::Idle;
+                self.motor_on = true;
+
+                let stat = self.stat(false);
+                if logical && audio {
+                    self.response_fifo.extend([stat | 0x04, 0x04]);
+                    self.raise_irq(CdRomIrq::Error);
+                    return;
+                }
+
+                self.response_fifo.push_back(stat);
+                self.raise_irq(CdRomIrq::SecondOk);
+            }
+            Task::TestVersion => {
+                self.response_fifo.extend([0x96, 0x09, 0x12, 0xC2]);
+                self.raise_irq(CdRomIrq::FirstOk);
+            }
+            Task::GetIdEnd => {
+                let (irq, response) = self.disc_id();
+                self.response_fifo.extend(response);
+                self.raise_irq(irq);
+            }
+        }
+    }
+
+    // GetID の2回目の応答
+    // [stat (識別できなければ bit3), フラグ (bit7 ライセンスなし, bit6 ディスクなし, bit4 音楽CD),
+    //  最初のトラックがモード2 なら 0x20, 0, ライセンス文字列 (SCEI / SCEA / SCEE)]
+    fn disc_id(&mut self) -> (CdRomIrq, [u8; 8]) {
+        let stat = self.stat(false);
+
+        let disc = match &self.disc {
+            Some(disc) => disc,
+            None => return (CdRomIrq::Error, [0x08, 0x40, 0, 0, 0, 0, 0, 0]),
+        };
+
+        let disc_type = match disc.tracks().first().map(|track| track.kind) {
+            Some(TrackKind::Mode2) => 0x20,
+            _ => 0x00,
+        };
+        let audio = disc
+            .tracks()
+            .iter()
+            .any(|track| track.kind == TrackKind::Audio);
+
+        match disc.region() {
+            Some(region) => {
+                let [a, b, c, d] = *region.license();
+                (
+                    CdRomIrq::SecondOk,
+                    [stat, 0x00, disc_type, 0x00, a, b, c, d],
+                )
+            }
+            None => {
+                let flags = 0x80 | (audio as u8) << 4;
+                (
+                    CdRomIrq::Error,
+                    [stat | 0x08, flags, disc_type, 0, 0, 0, 0, 0],
+                )
+            }
+        }
+    }
+}
+
+struct Controller {
+    command: Option<u32>,
+    status: ControllerStatus,
+    stalls: u32,
+}
+
+impl Controller {
+    fn new() -> Self {
+        Self {
+            command: None,
+            status: ControllerStatus::Idle,
+            stalls: 0,
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.stalls > 0 {
+            self.stalls -= 1;
+        }
+    }
+}
+
+// コマンドが受け付ける引数の数。知らないコマンドは None
+fn parameter_count(command: u8) -> Option<std::ops::RangeInclusive<usize>> {
+    match command {
+        0x01 | 0x04..=0x0C | 0x10 | 0x11 | 0x13 | 0x15 | 0x16 | 0x1A | 0x1B | 0x1E => Some(0..=0),
+        // Play のトラックは省略できる
+        0x03 => Some(0..=1),
+        0x0E | 0x12 | 0x14 | 0x19 => Some(1..=1),
+        0x0D => Some(2..=2),
+        0x02 => Some(3..=3),
+        _ => None,
+    }
+}
+
+fn save_msf(w: &mut Writer, msf: Msf) {
+    w.u8(msf.min);
+    w.u8(msf.sec);
+    w.u8(msf.frame);
+}
+
+fn load_msf(r: &mut Reader) -> Result<Msf> {
+    Ok(Msf::new(r.u8()?, r.u8()?, r.u8()?))
+}
+
+fn save_option_u32(w: &mut Writer, val: Option<u32>) {
+    w.bool(val.is_some());
+    w.u32(val.unwrap_or(0));
+}
+
+fn load_option_u32(r: &mut Reader) -> Result<Option<u32>> {
+    let some = r.bool()?;
+    let val = r.u32()?;
+    Ok(some.then_some(val))
+}
+
+// 種類の番号の後に中身を続ける
+fn save_task(w: &mut Writer, task: Task) {
+    match task {
+        Task::GetStat => w.u8(0),
+        Task::Stat(irq) => {
+            w.u8(1);
+            w.u8(irq as u8);
+        }
+        Task::Error(code) => {
+            w.u8(2);
+            w.u8(code);
+        }
+        Task::GetLocL => w.u8(3),
+        Task::GetLocP(q) => {
+            w.u8(4);
+            w.bytes(&q);
+        }
+        Task::SetSessionEnd(start) => {
+            w.u8(5);
+            save_option_u32(w, start);
+        }
+        Task::GetTN(tracks) => {
+            w.u8(6);
+            w.bool(tracks.is_some());
+            let (first, last) = tracks.unwrap_or_default();
+            w.u8(first);
+            w.u8(last);
+        }
+        Task::GetTD(lba) => {
+            w.u8(7);
+            save_option_u32(w, lba);
+        }
+        Task::InitStart => w.u8(8),
+        Task::InitEnd => w.u8(9),
+        Task::Mute(muted) => {
+            w.u8(10);
+            w.bool(muted);
+        }
+        Task::SetMode(mode) => {
+            w.u8(11);
+            w.u8(mode);
+        }
+        Task::SetFilter { file, channel } => {
+            w.u8(12);
+            w.u8(file);
+            w.u8(channel);
+        }
+        Task::SetLoc(addr) => {
+            w.u8(13);
+            save_msf(w, addr);
+        }
+        Task::Read { seek } => {
+            w.u8(14);
+            w.u32(seek);
+        }
+        Task::Play { start, seek } => {
+            w.u8(15);
+            save_option_u32(w, start);
+            w.u32(seek);
+        }
+        Task::Scan(scan) => {
+            w.u8(16);
+            w.u8(scan as u8);
+        }
+        Task::MotorOnEnd => w.u8(17),
+        Task::StopStart => w.u8(18),
+        Task::StopEnd => w.u8(19),
+        Task::PauseStart => w.u8(20),
+        Task::SeekStart => w.u8(21),
+        Task::SeekEnd { logical, audio } => {
+            w.u8(22);
+            w.bool(logical);
+            w.bool(audio);
+        }
+        Task::TestVersion => w.u8(23),
+        Task::GetIdEnd => w.u8(24),
+    }
+}
+
+fn load_task(r: &mut Reader) -> Result<Task> {
+    let task = match r.u8()? {
+        0 => Task::GetStat,
+        1 => Task::Stat(r.variant()?),
+        2 => Task::Error(r.u8()?),
+        3 => Task::GetLocL,
+        4 => {
+            let mut q = [0; 10];
+            r.bytes_into(&mut q)?;
+            Task::GetLocP(q)
+        }
+        5 => Task::SetSessionEnd(load_option_u32(r)?),
+        6 => {
+            let some = r.bool()?;
+            let tracks = (r.u8()?, r.u8()?);
+            Task::GetTN(some.then_some(tracks))
+        }
+        7 => Task::GetTD(load_option_u32(r)?),
+        8 => Task::InitStart,
+        9 => Task::InitEnd,
+        10 => Task::Mute(r.bool()?),
+        11 => Task::SetMode(r.u8()?),
+        12 => Task::SetFilter {
+            file: r.u8()?,
+            channel: r.u8()?,
+        },
+        13 => Task::SetLoc(load_msf(r)?),
+        14 => Task::Read { seek: r.u32()? },
+        15 => Task::Play {
+            start: load_option_u32(r)?,
+            seek: r.u32()?,
+        },
+        16 => Task::Scan(r.variant()?),
+        17 => Task::MotorOnEnd,
+        18 => Task::StopStart,
+        19 => Task::StopEnd,
+        20 => Task::PauseStart,
+        21 => Task::SeekStart,
+        22 => Task::SeekEnd {
+            logical: r.bool()?,
+            audio: r.bool()?,
+        },
+        23 => Task::TestVersion,
+        24 => Task::GetIdEnd,
+        n => bail!("Invalid CD-ROM task {}", n),
+    };
+
+    Ok(task)
+}
+
+// 出力待ちの音声と XA-ADPCM のデコーダの状態は保存しない
+impl Savestate for CdRom {
+    fn save_state(&self, w: &mut Writer) {
+        w.u8(self.index);
+
+        w.bool(self.controller.command.is_some());
+        w.u32(self.controller.command.unwrap_or(0));
+        w.u8(self.controller.status as u8);
+        w.u32(self.controller.stalls);
+
+        w.fifo(&self.parameter_fifo);
+        w.fifo(&self.response_fifo);
+        w.fifo(&self.data_fifo);
+        w.u8(self.status as u8);
+        w.bool(self.stat_updated);
+        w.bool(self.shell_open);
+        w.bool(self.double_speed);
+        w.bool(self.raw_sector);
+        w.bool(self.auto_pause);
+        w.bool(self.report);
+        w.bool(self.xa_adpcm);
+        w.bool(self.xa_filter);
+        w.u8(self.filter_file);
+        w.u8(self.filter_channel);
+        w.bool(self.motor_on);
+        w.u8(self.scan as u8);
+        w.bool(self.muted);
+        w.bool(self.read_active);
+        w.bool(self.seek_position.is_some());
+        save_msf(w, self.seek_position.unwrap_or(Msf::new(0, 0, 0)));
+        save_msf(w, self.current_position);
+        w.bytes(&self.last_subq);
+        w.u32(self.read_lba);
+        w.u32(self.read_cycles);
+        w.bytes(&self.sector);
+        w.bool(self.sector_header.is_some());
+        w.bytes(&self.sector_header.unwrap_or_default());
+        w.u8(self.ie);
+        w.u8(self.irq);
+        self.irq_line.save_state(w);
+
+        w.u32(self.tasks.len() as u32);
+        for &(delay, task) in &self.tasks {
+            w.u32(delay);
+            save_task(w, task);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.index = r.u8()? & 0b11;
+
+        let has_command = r.bool()?;
+        let command = r.u32()?;
+        self.controller.command = has_command.then_some(command);
+        self.controller.status = r.variant()?;
+        self.controller.stalls = r.u32()?;
+
+        self.parameter_fifo = r.fifo()?;
+        self.response_fifo = r.fifo()?;
+        self.data_fifo = r.fifo()?;
+        self.status = r.variant()?;
+        self.stat_updated = r.bool()?;
+        self.shell_open = r.bool()?;
+        self.double_speed = r.bool()?;
+        self.raw_sector = r.bool()?;
+        self.auto_pause = r.bool()?;
+        self.report = r.bool()?;
+        self.xa_adpcm = r.bool()?;
+        self.xa_filter = r.bool()?;
+        self.filter_file = r.u8()?;
+        self.filter_channel = r.u8()?;
+        self.motor_on = r.bool()?;
+        self.scan = r.variant()?;
+        self.muted = r.bool()?;
+        self.read_active = r.bool()?;
+        let has_seek_position = r.bool()?;
+        let seek_position = load_msf(r)?;
+        self.seek_position = has_seek_position.then_some(seek_position);
+        self.current_position = load_msf(r)?;
+        r.bytes_into(&mut self.last_subq)?;
+        self.read_lba = r.u32()?;
+        self.read_cycles = r.u32()?;
+        self.sector = r.bytes()?;
+        let has_sector_header = r.bool()?;
+        let mut sector_header = [0; 8];
+        r.bytes_into(&mut sector_header)?;
+        self.sector_header = has_sector_header.then_some(sector_header);
+        self.ie = r.u8()?;
+        self.irq = r.u8()?;
+        self.irq_line.load_state(r)?;
+
+        let len = r.u32()?;
+        self.tasks.clear();
+        for _ in 0..len {
+            let delay = r.u32()?;
+            self.tasks.push_back((delay, load_task(r)?));
+        }
+
+        self.audio.clear();
+        self.xa = xa::Decoder::new();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::Disc;
+
+    const STAT_SHELL_OPEN: u8 = 0x12;
+    const STAT_IDLE: u8 = 0x02;
+    const STAT_SEEKING: u8 = 0x42;
+    const STAT_READING: u8 = 0x22;
+
+    // Init の2回目の応答や、等倍速での近いシークと最初のセクタより十分長く
+    const TIMEOUT: u32 = 10_000_000;
+
+    fn disc() -> Vec<u8> {
+        (0..2352 * 16).map(|i| (i * 7) as u8).collect()
+    }
+
+    // システム領域 (セクタ4) のモード2 のユーザーデータにライセンス文字列を書く
+    fn licensed(mut disc: Vec<u8>, region: &str) -> Vec<u8> {
+        let license = format!(
+            "          Licensed  by          Sony Computer Entertainment {}",
+            region
+        );
+        let start = SECTOR_SIZE * 4 + 24;
+        disc[SECTOR_SIZE * 4 + 15] = 2;
+        disc[start..start + license.len()].copy_from_slice(license.as_bytes());
+        disc
+    }
+
+    fn cdrom(disc: Option<Vec<u8>>) -> CdRom {
+        let disc = disc.map(|disc| Image::from_disc(Disc::from_bytes(disc)));
+        let mut cdrom = CdRom::new(disc);
+
+        cdrom.store::<u8>(0, 1);
+        cdrom.store::<u8>(2, 0x1F);
+        cdrom.store::<u8>(0, 0);
+
+        cdrom
+    }
+
+    fn status(cdrom: &mut CdRom) -> u8 {
+        cdrom.load::<u8>(0)
+    }
+
+    fn wait_irq(cdrom: &mut CdRom) -> u8 {
+        for _ in 0..TIMEOUT {
+            if cdrom.check_irq() {
+                cdrom.store::<u8>(0, 1);
+                let irq = cdrom.load::<u8>(3) & 0x7;
+                cdrom.store::<u8>(0, 0);
+
+                return irq;
+            }
+
+            cdrom.tick(&mut IrqBus::new());
+        }
+
+        panic!("CD-ROM irq timed out");
+    }
+
+    fn read_response(cdrom: &mut CdRom) -> Vec<u8> {
+        let mut response = vec![];
+
+        while status(cdrom) & 0x20 != 0 {
+            response.push(cdrom.load::<u8>(1));
+        }
+
+        response
+    }
+
+    fn ack(cdrom: &mut CdRom) {
+        cdrom.store::<u8>(0, 1);
+        cdrom.store::<u8>(3, 0x1F);
+        cdrom.store::<u8>(0, 0);
+
+        assert!(!cdrom.check_irq());
+    }
+
+    // BIOS と同じようにパラメーターを積んでからコマンドを書き、応答を1つずつ受け取って ack する
+    fn execute(
+        cdrom: &mut CdRom,
+        command: u8,
+        params: &[u8],
+        responses: usize,
+    ) -> Vec<(u8, Vec<u8>)> {
+        assert_eq!(status(cdrom) & 0x18, 0x18, "parameter fifo must be empty");
+
+        for &param in params {
+            cdrom.store::<u8>(2, param);
+        }
+
+        if !params.is_empty() {
+            assert_eq!(status(cdrom) & 0x08, 0);
+        }
+
+        cdrom.store::<u8>(1, command);
+
+        assert_eq!(status(cdrom) & 0x08, 0x08, "parameter fifo must be cleared");
+
+        (0..responses)
+            .map(|_| {
+                let irq = wait_irq(cdrom);
+                let response = read_response(cdrom);
+                ack(cdrom);
+
+                (irq, response)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn boot_sequence() {
+        let disc = licensed(disc(), "Amer  ica ");
+        let mut cdrom = cdrom(Some(disc.clone()));
+
+        assert_eq!(status(&mut cdrom), 0x18);
+
+        // 最初の GetStat はシェルが開いていた扱い
+        assert_eq!(
+            execute(&mut cdrom, 0x01, &[], 1),
+            vec![(3, vec![STAT_SHELL_OPEN])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x01, &[], 1),
+            vec![(3, vec![STAT_IDLE])]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x0A, &[], 2),
+            vec![(3, vec![STAT_IDLE]), (2, vec![STAT_IDLE])]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x1A, &[], 2),
+            vec![
+                (3, vec![STAT_IDLE]),
+                (2, vec![0x02, 0x00, 0x20, 0x00, b'S', b'C', b'E', b'A'])
+            ]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x00], 1),
+            vec![(3, vec![STAT_IDLE])]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x15, &[], 2),
+            vec![(3, vec![STAT_SEEKING]), (2, vec![STAT_IDLE])]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x06, &[], 2),
+            vec![(3, vec![STAT_IDLE]), (1, vec![STAT_READING])]
+        );
+
+        // 読み込み要求を立ててから DMA と同じくワード単位で読む
+        assert_eq!(status(&mut cdrom) & 0x40, 0);
+        cdrom.store::<u8>(3, 0x80);
+        assert_eq!(status(&mut cdrom) & 0x40, 0x40);
+
+        // モード2のユーザーデータはサブヘッダの後ろから
+        for chunk in disc[24..88].chunks(4) {
+            let expected = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            assert_eq!(cdrom.load::<u32>(2), expected);
+        }
+
+        cdrom.store::<u8>(3, 0x00);
+        assert_eq!(status(&mut cdrom) & 0x40, 0);
+    }
+
+    // 同期パターンとヘッダの付いたモード2のセクタ。ユーザーデータの先頭はセクタ番号
+    fn mode2_disc(sectors: u32) -> Vec<u8> {
+        (0..sectors)
+            .flat_map(|lba| {
+                let msf = Msf::from_lba(lba);
+                let mut sector = SYNC.to_vec();
+                sector.extend([msf.min, msf.sec, msf.frame].map(bcd));
+                sector.push(2);
+                // ファイル1, チャンネル0, データ (2回繰り返す)
+                sector.extend([1, 0, 0x08, 0, 1, 0, 0x08, 0]);
+                sector.extend_from_slice(&lba.to_le_bytes());
+                sector.resize(SECTOR_SIZE, 0);
+                sector
+            })
+            .collect()
+    }
+
+    // 受け取ったセクタの先頭のワードを読む
+    fn sector_word(cdrom: &mut CdRom) -> u32 {
+        cdrom.store::<u8>(3, 0x80);
+        let val = cdrom.load::<u32>(2);
+        cdrom.store::<u8>(3, 0x00);
+
+        val
+    }
+
+    // SPU が取り込む前の、鳴らしたサンプルをすべて取り出す
+    fn played_samples(cdrom: &mut CdRom) -> Vec<[i16; 2]> {
+        std::iter::from_fn(|| cdrom.pop_audio()).collect()
+    }
+
+    fn read_first_word(cdrom: &mut CdRom, msf: [u8; 3]) -> u32 {
+        execute(cdrom, 0x02, &msf, 1);
+        execute(cdrom, 0x15, &[], 2);
+        execute(cdrom, 0x06, &[], 2);
+
+        let val = sector_word(cdrom);
+
+        execute(cdrom, 0x09, &[], 2);
+
+        val
+    }
+
+    #[test]
+    fn set_loc_reads_requested_sector() {
+        let mut cdrom = cdrom(Some(mode2_disc(20)));
+
+        // SetLoc の引数はBCD
+        assert_eq!(read_first_word(&mut cdrom, [0x00, 0x02, 0x12]), 12);
+        assert_eq!(read_first_word(&mut cdrom, [0x00, 0x02, 0x00]), 0);
+
+        // 生セクタのモードではヘッダから読める
+        execute(&mut cdrom, 0x0E, &[0x20], 1);
+        assert_eq!(
+            read_first_word(&mut cdrom, [0x00, 0x02, 0x17]),
+            u32::from_le_bytes([0x00, 0x02, 0x17, 0x02])
+        );
+    }
+
+    #[test]
+    fn read_n_delivers_sectors_until_pause() {
+        let mut cdrom = cdrom(Some(mode2_disc(20)));
+
+        // シェルが開いていた扱いを解く
+        execute(&mut cdrom, 0x01, &[], 1);
+        execute(&mut cdrom, 0x0E, &[0x80], 1);
+        execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x03], 1);
+        assert_eq!(
+            execute(&mut cdrom, 0x06, &[], 2),
+            vec![(3, vec![STAT_IDLE]), (1, vec![STAT_READING])]
+        );
+        assert_eq!(sector_word(&mut cdrom), 3);
+
+        for lba in 4..7 {
+            // 2倍速では1/150秒ごと
+            let mut cycles = 0;
+            while !cdrom.check_irq() {
+                cdrom.tick(&mut IrqBus::new());
+                cycles += 1;
+            }
+            assert_eq!(cycles, SECTOR_CYCLES / 2 + 1);
+
+            assert_eq!(wait_irq(&mut cdrom), 1);
+            assert_eq!(read_response(&mut cdrom), vec![STAT_READING]);
+            ack(&mut cdrom);
+
+            assert_eq!(sector_word(&mut cdrom), lba);
+        }
+
+        assert_eq!(
+            execute(&mut cdrom, 0x09, &[], 2),
+            vec![(3, vec![STAT_READING]), (2, vec![STAT_IDLE])]
+        );
+
+        for _ in 0..SECTOR_CYCLES * 2 {
+            cdrom.tick(&mut IrqBus::new());
+        }
+        assert!(!cdrom.check_irq());
+
+        // 止めた所から続きを読む
+        execute(&mut cdrom, 0x06, &[], 2);
+        assert_eq!(sector_word(&mut cdrom), 7);
+    }
+
+    #[test]
+    fn xa_adpcm_sectors_are_played_instead_of_delivered() {
+        // LBA 0 はチャンネル0、LBA 2 はチャンネル1 の XA-ADPCM (37800Hz モノラル 4bit)
+        let mut disc = mode2_disc(8);
+        for (lba, channel) in [(0, 0), (2, 1)] {
+            let subheader = [1, channel, xa::SUBMODE_AUDIO, 0x00];
+            let offset = lba * SECTOR_SIZE + 16;
+            disc[offset..offset + 4].copy_from_slice(&subheader);
+            disc[offset + 4..offset + 8].copy_from_slice(&subheader);
         }
 
-        //if let Some((delay, handler)) = self.task {
-        //    if delay == 0 {
-        //        handler()
-        //    }
-        //}
+        let mut cdrom = cdrom(Some(disc));
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        // 2倍速, XA-ADPCM, フィルタ
+        execute(&mut cdrom, 0x0E, &[0xC8], 1);
+        execute(&mut cdrom, 0x0D, &[0x01, 0x00], 1);
+        execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x00], 1);
+
+        // 最初に届くのはデータの LBA 1、次は LBA 3
+        assert_eq!(
+            execute(&mut cdrom, 0x1B, &[], 2),
+            vec![(3, vec![STAT_IDLE]), (1, vec![STAT_READING])]
+        );
+        assert_eq!(sector_word(&mut cdrom), 1);
+        assert_eq!(wait_irq(&mut cdrom), 1);
+        ack(&mut cdrom);
+        assert_eq!(sector_word(&mut cdrom), 3);
+
+        execute(&mut cdrom, 0x09, &[], 2);
+
+        // 鳴らしたのは LBA 0 の1セクタだけ (4032 サンプルを 44.1kHz に)
+        assert_eq!(played_samples(&mut cdrom).len(), 4032 * 44100 / 37800);
+    }
+
+    #[test]
+    fn seek_time_depends_on_distance() {
+        let mut cdrom = cdrom(Some(mode2_disc(20)));
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        // 近ければ通り過ぎるセクタの分だけ
+        execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x05], 1);
+        execute(&mut cdrom, 0x15, &[], 1);
+
+        let mut cycles = 0;
+        while !cdrom.check_irq() {
+            cdrom.tick(&mut IrqBus::new());
+            cycles += 1;
+        }
+        assert_eq!(cycles, SECTOR_CYCLES * 5 + 1);
+        assert_eq!(wait_irq(&mut cdrom), 2);
+        assert_eq!(read_response(&mut cdrom), vec![STAT_IDLE]);
+        ack(&mut cdrom);
+
+        assert_eq!(cdrom.seek_cycles(5, 5), SECTOR_CYCLES * 2);
+
+        // 遠ければ距離に比例する
+        let far = cdrom.seek_cycles(0, SEEK_FULL_SECTORS);
+        assert_eq!(far, SEEK_FULL_CYCLES);
+        let middle = cdrom.seek_cycles(SEEK_FULL_SECTORS / 2, 0);
+        assert!(SEEK_BASE_CYCLES < middle && middle < far);
+
+        // モーターが止まっていれば回し始める分もかかる
+        cdrom.motor_on = false;
+        assert_eq!(
+            cdrom.seek_cycles(0, SEEK_FULL_SECTORS),
+            SEEK_FULL_CYCLES + SPIN_UP_CYCLES
+        );
+    }
+
+    // TIMEOUT を超えて待つときに使う
+    fn cycles_until_irq(cdrom: &mut CdRom) -> u32 {
+        let mut cycles = 0;
+        while !cdrom.check_irq() {
+            cdrom.tick(&mut IrqBus::new());
+            cycles += 1;
+        }
+
+        cycles
+    }
+
+    // 次の INT1 までのサイクル
+    fn cycles_until_sector(cdrom: &mut CdRom) -> u32 {
+        let cycles = cycles_until_irq(cdrom);
+
+        assert_eq!(wait_irq(cdrom), 1);
+        ack(cdrom);
+
+        cycles
+    }
+
+    #[test]
+    fn read_pacing_follows_speed() {
+        let mut cdrom = cdrom(Some(mode2_disc(20)));
+        execute(&mut cdrom, 0x01, &[], 1);
+        execute(&mut cdrom, 0x06, &[], 2);
+
+        // 等倍速では1/75秒ごと
+        assert_eq!(cycles_until_sector(&mut cdrom), SECTOR_CYCLES + 1);
+
+        // 読み込み中に2倍速にすると、次のセクタは回転が落ち着くまで遅れる
+        execute(&mut cdrom, 0x0E, &[0x80], 1);
+        assert!(cycles_until_sector(&mut cdrom) > SPEED_CHANGE_CYCLES);
+        assert_eq!(cycles_until_sector(&mut cdrom), SECTOR_CYCLES / 2 + 1);
+
+        execute(&mut cdrom, 0x09, &[], 2);
+    }
+
+    #[test]
+    fn stop_and_motor_on_spin_the_drive() {
+        let mut cdrom = cdrom(Some(mode2_disc(20)));
+        execute(&mut cdrom, 0x01, &[], 1);
+        read_first_word(&mut cdrom, [0x00, 0x02, 0x05]);
+
+        assert_eq!(
+            execute(&mut cdrom, 0x08, &[], 2),
+            vec![(3, vec![STAT_IDLE]), (2, vec![0x00])]
+        );
+
+        // ヘッドは先頭に戻る
+        assert_eq!(
+            execute(&mut cdrom, 0x11, &[], 1),
+            vec![(3, vec![0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00])]
+        );
+
+        // 回りきるまで INT2 は来ない
+        assert_eq!(execute(&mut cdrom, 0x07, &[], 1), vec![(3, vec![0x00])]);
+        assert_eq!(cycles_until_irq(&mut cdrom), SPIN_UP_CYCLES + 1);
+        assert_eq!(wait_irq(&mut cdrom), 2);
+        assert_eq!(read_response(&mut cdrom), vec![STAT_IDLE]);
+        ack(&mut cdrom);
+
+        // 回っていればすぐ
+        assert_eq!(
+            execute(&mut cdrom, 0x07, &[], 2),
+            vec![(3, vec![STAT_IDLE]), (2, vec![STAT_IDLE])]
+        );
+    }
+
+    #[test]
+    fn get_loc_reports_last_sector() {
+        let mut cdrom = cdrom(Some(mode2_disc(20)));
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        // まだ何も読んでいない
+        assert_eq!(
+            execute(&mut cdrom, 0x10, &[], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x80])]
+        );
+
+        execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x05], 1);
+        execute(&mut cdrom, 0x06, &[], 2);
+
+        assert_eq!(
+            execute(&mut cdrom, 0x10, &[], 1),
+            vec![(3, vec![0x00, 0x02, 0x05, 0x02, 1, 0, 0x08, 0])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x11, &[], 1),
+            vec![(3, vec![0x01, 0x01, 0x00, 0x00, 0x05, 0x00, 0x02, 0x05])]
+        );
+
+        execute(&mut cdrom, 0x09, &[], 2);
+    }
+
+    #[test]
+    fn get_loc_p_ignores_subq_with_bad_crc() {
+        // LibCrypt のように 00:02:06 のQを置き換える
+        let mut image = Image::from_disc(Disc::from_bytes(mode2_disc(20)));
+        let mut sbi = b"SBI\0".to_vec();
+        sbi.extend([0x00, 0x02, 0x06, 3, 0x00, 0x02, 0x46]);
+        image.set_sbi(subq::Sbi::parse(&sbi).unwrap());
+
+        let mut cdrom = CdRom::new(Some(image));
+        cdrom.store::<u8>(0, 1);
+        cdrom.store::<u8>(2, 0x1F);
+        cdrom.store::<u8>(0, 0);
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x05], 1);
+        execute(&mut cdrom, 0x06, &[], 2);
+
+        // 00:02:06 を読んだ後も 00:02:05 のまま
+        assert_eq!(wait_irq(&mut cdrom), 1);
+        ack(&mut cdrom);
+        assert_eq!(sector_word(&mut cdrom), 6);
+        assert_eq!(
+            execute(&mut cdrom, 0x11, &[], 1),
+            vec![(3, vec![0x01, 0x01, 0x00, 0x00, 0x05, 0x00, 0x02, 0x05])]
+        );
+
+        assert_eq!(wait_irq(&mut cdrom), 1);
+        ack(&mut cdrom);
+        assert_eq!(
+            execute(&mut cdrom, 0x11, &[], 1),
+            vec![(3, vec![0x01, 0x01, 0x00, 0x00, 0x07, 0x00, 0x02, 0x07])]
+        );
+
+        execute(&mut cdrom, 0x09, &[], 2);
+    }
+
+    #[test]
+    fn get_tn_and_get_td_follow_cue_sheet() {
+        let sheet = "FILE \"game.bin\" BINARY\n\
+                     TRACK 01 MODE2/2352\n\
+                     INDEX 01 00:00:00\n\
+                     TRACK 02 AUDIO\n\
+                     INDEX 01 00:10:00\n\
+                     TRACK 03 AUDIO\n\
+                     INDEX 01 00:20:00\n";
+        let image =
+            Image::parse_cue(sheet, |_| Ok(Disc::from_bytes(vec![0; SECTOR_SIZE * 2000]))).unwrap();
+
+        let mut cdrom = CdRom::new(Some(image));
+        cdrom.store::<u8>(0, 1);
+        cdrom.store::<u8>(2, 0x1F);
+        cdrom.store::<u8>(0, 0);
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        assert_eq!(
+            execute(&mut cdrom, 0x13, &[], 1),
+            vec![(3, vec![STAT_IDLE, 0x01, 0x03])]
+        );
+
+        // 位置は2秒のリードインを足したBCD
+        assert_eq!(
+            execute(&mut cdrom, 0x14, &[0x01], 1),
+            vec![(3, vec![STAT_IDLE, 0x00, 0x02])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x14, &[0x02], 1),
+            vec![(3, vec![STAT_IDLE, 0x00, 0x12])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x14, &[0x03], 1),
+            vec![(3, vec![STAT_IDLE, 0x00, 0x22])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x14, &[0x00], 1),
+            vec![(3, vec![STAT_IDLE, 0x00, 0x28])]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x14, &[0x04], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x10])]
+        );
+    }
+
+    // トラック2 (LBA 20..40) とトラック3 (LBA 40..60) が CD-DA
+    // 左チャンネルは lba * 100、右はその符号を反転したもの
+    fn audio_cdrom() -> CdRom {
+        let sheet = "FILE \"game.bin\" BINARY\n\
+                     TRACK 01 MODE2/2352\n\
+                     INDEX 01 00:00:00\n\
+                     TRACK 02 AUDIO\n\
+                     INDEX 01 00:00:20\n\
+                     TRACK 03 AUDIO\n\
+                     INDEX 01 00:00:40\n";
+        let bin: Vec<u8> = (0..60i16)
+            .flat_map(|lba| {
+                let sample = [lba * 100, -lba * 100].map(i16::to_le_bytes).concat();
+                sample.repeat(SECTOR_SIZE / 4)
+            })
+            .collect();
+        let image = Image::parse_cue(sheet, |_| Ok(Disc::from_bytes(bin.clone()))).unwrap();
+
+        let mut cdrom = CdRom::new(Some(image));
+        cdrom.store::<u8>(0, 1);
+        cdrom.store::<u8>(2, 0x1F);
+        cdrom.store::<u8>(0, 0);
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        cdrom
+    }
+
+    #[test]
+    fn play_reports_and_auto_pauses_at_end_of_track() {
+        let mut cdrom = audio_cdrom();
+
+        // 再生中でなければ早送りできない
+        assert_eq!(
+            execute(&mut cdrom, 0x04, &[], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x80])]
+        );
+
+        // 2倍速, オートポーズ, レポート
+        execute(&mut cdrom, 0x0E, &[0x86], 1);
+        assert_eq!(
+            execute(&mut cdrom, 0x03, &[0x02], 1),
+            vec![(3, vec![STAT_IDLE])]
+        );
+
+        // レポートの間隔は TIMEOUT より長い
+        let mut responses = vec![];
+        loop {
+            for _ in 0..SECTOR_CYCLES * 10 {
+                if cdrom.check_irq() {
+                    break;
+                }
+                cdrom.tick(&mut IrqBus::new());
+            }
+            let irq = wait_irq(&mut cdrom);
+            responses.push((irq, read_response(&mut cdrom)));
+            ack(&mut cdrom);
+
+            if irq != 1 {
+                break;
+            }
+        }
+
+        // 00:02:20 で絶対位置と左のピーク、00:02:30 でトラック内の 00:00:10 と右のピーク
+        // 3000 = 0x0BB8
+        assert_eq!(
+            responses,
+            vec![
+                (1, vec![0x82, 0x02, 0x01, 0x00, 0x02, 0x20, 0xD0, 0x07]),
+                (1, vec![0x82, 0x02, 0x01, 0x00, 0x80, 0x10, 0xB8, 0x8B]),
+                (4, vec![STAT_IDLE]),
+            ]
+        );
+
+        let samples = played_samples(&mut cdrom);
+        assert_eq!(samples.len(), 20 * 588);
+        assert_eq!(samples[0], [2000, -2000]);
+        assert_eq!(samples[samples.len() - 1], [3900, -3900]);
+
+        assert_eq!(
+            execute(&mut cdrom, 0x08, &[], 2),
+            vec![(3, vec![STAT_IDLE]), (2, vec![0x00])]
+        );
+    }
+
+    #[test]
+    fn seek_p_reaches_audio_sectors() {
+        let mut cdrom = audio_cdrom();
+        execute(&mut cdrom, 0x0E, &[0x80], 1);
+
+        // SeekL は CD-DA のセクタでシークエラー
+        execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x25], 1);
+        assert_eq!(
+            execute(&mut cdrom, 0x15, &[], 2),
+            vec![(3, vec![STAT_SEEKING]), (5, vec![STAT_IDLE | 0x04, 0x04])]
+        );
+
+        execute(&mut cdrom, 0x02, &[0x00, 0x02, 0x25], 1);
+        assert_eq!(
+            execute(&mut cdrom, 0x16, &[], 2),
+            vec![(3, vec![STAT_SEEKING]), (2, vec![STAT_IDLE])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x11, &[], 1),
+            vec![(3, vec![0x02, 0x01, 0x00, 0x00, 0x05, 0x00, 0x02, 0x25])]
+        );
+
+        // 引数なしの Play はそこから
+        execute(&mut cdrom, 0x03, &[], 1);
+        for _ in 0..SECTOR_CYCLES * 2 {
+            cdrom.tick(&mut IrqBus::new());
+        }
+        execute(&mut cdrom, 0x09, &[], 2);
+        assert_eq!(cdrom.pop_audio(), Some([2500, -2500]));
+    }
+
+    #[test]
+    fn mute_silences_cd_audio() {
+        let mut cdrom = audio_cdrom();
+        execute(&mut cdrom, 0x0E, &[0x80], 1);
+
+        // シークの後で何セクタか鳴らして止める
+        let play = |cdrom: &mut CdRom| {
+            execute(cdrom, 0x03, &[0x02], 1);
+            for _ in 0..SECTOR_CYCLES * 10 {
+                cdrom.tick(&mut IrqBus::new());
+            }
+            execute(cdrom, 0x09, &[], 2);
+
+            played_samples(cdrom)
+        };
+
+        execute(&mut cdrom, 0x0B, &[], 1);
+        let samples = play(&mut cdrom);
+        assert!(!samples.is_empty());
+        assert!(samples.iter().all(|&sample| sample == [0, 0]));
+
+        execute(&mut cdrom, 0x0C, &[], 1);
+        assert!(play(&mut cdrom).contains(&[2000, -2000]));
+    }
+
+    #[test]
+    fn set_session_moves_to_its_first_track() {
+        let sheet = "FILE \"a.bin\" BINARY\n\
+                     TRACK 01 MODE2/2352\n\
+                     INDEX 01 00:00:00\n\
+                     REM SESSION 02\n\
+                     FILE \"b.bin\" BINARY\n\
+                     TRACK 02 MODE2/2352\n\
+                     INDEX 01 00:00:00\n";
+        let image = Image::parse_cue(sheet, |_| Ok(Disc::from_bytes(mode2_disc(10)))).unwrap();
+
+        let mut cdrom = CdRom::new(Some(image));
+        cdrom.store::<u8>(0, 1);
+        cdrom.store::<u8>(2, 0x1F);
+        cdrom.store::<u8>(0, 0);
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        assert_eq!(
+            execute(&mut cdrom, 0x12, &[0x02], 2),
+            vec![(3, vec![STAT_SEEKING]), (2, vec![STAT_IDLE])]
+        );
+        // LBA 10 + SESSION_GAP は 02:34:10
+        assert_eq!(
+            execute(&mut cdrom, 0x11, &[], 1),
+            vec![(3, vec![0x02, 0x01, 0x00, 0x00, 0x00, 0x02, 0x34, 0x10])]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x12, &[0x03], 2),
+            vec![(3, vec![STAT_SEEKING]), (5, vec![STAT_IDLE | 0x04, 0x40])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x12, &[0x00], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x10])]
+        );
+    }
+
+    #[test]
+    fn get_id_reflects_the_disc() {
+        let mut licensed = cdrom(Some(licensed(mode2_disc(16), "Euro pe")));
+        execute(&mut licensed, 0x01, &[], 1);
+        assert_eq!(
+            execute(&mut licensed, 0x1A, &[], 2)[1],
+            (2, vec![0x02, 0x00, 0x20, 0x00, b'S', b'C', b'E', b'E'])
+        );
+
+        let mut unlicensed = cdrom(Some(mode2_disc(16)));
+        execute(&mut unlicensed, 0x01, &[], 1);
+        assert_eq!(
+            execute(&mut unlicensed, 0x1A, &[], 2)[1],
+            (5, vec![0x0A, 0x80, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00])
+        );
+
+        // 音楽CD
+        let sheet = "FILE \"music.bin\" BINARY\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n";
+        let image = Image::parse_cue(sheet, |_| Ok(Disc::from_bytes(vec![0; SECTOR_SIZE * 16])));
+        let mut music = CdRom::new(Some(image.unwrap()));
+        music.store::<u8>(0, 1);
+        music.store::<u8>(2, 0x1F);
+        music.store::<u8>(0, 0);
+        execute(&mut music, 0x01, &[], 1);
+        assert_eq!(
+            execute(&mut music, 0x1A, &[], 2)[1],
+            (5, vec![0x0A, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+        );
+
+        // ふたが開いている
+        music.eject();
+        assert_eq!(
+            execute(&mut music, 0x1A, &[], 1),
+            vec![(5, vec![0x11, 0x80])]
+        );
+    }
+
+    #[test]
+    fn get_id_without_disc() {
+        let mut cdrom = cdrom(None);
+
+        assert_eq!(
+            execute(&mut cdrom, 0x01, &[], 1),
+            vec![(3, vec![STAT_SHELL_OPEN])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x01, &[], 1),
+            vec![(3, vec![STAT_SHELL_OPEN])]
+        );
+
+        assert_eq!(
+            execute(&mut cdrom, 0x1A, &[], 2),
+            vec![
+                (3, vec![STAT_SHELL_OPEN]),
+                (5, vec![0x08, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            ]
+        );
+    }
+
+    #[test]
+    fn swapping_discs_opens_and_closes_the_shell() {
+        let mut cdrom = cdrom(Some(disc()));
+
+        execute(&mut cdrom, 0x01, &[], 1);
+        execute(&mut cdrom, 0x06, &[], 2);
+
+        // 読み込み中にふたを開けると止まる
+        cdrom.eject();
+        assert!(cdrom.shell_open());
+        assert_eq!(wait_irq(&mut cdrom), 5);
+        assert_eq!(read_response(&mut cdrom), vec![0x11, 0x08]);
+        ack(&mut cdrom);
+
+        assert_eq!(execute(&mut cdrom, 0x01, &[], 1), vec![(3, vec![0x10])]);
+
+        // 閉じた後の最初の GetStat だけシェルオープンのビットが残る
+        cdrom.insert(Image::from_disc(Disc::from_bytes(disc())));
+        assert!(!cdrom.shell_open());
+        assert_eq!(
+            execute(&mut cdrom, 0x01, &[], 1),
+            vec![(3, vec![STAT_SHELL_OPEN])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x01, &[], 1),
+            vec![(3, vec![STAT_IDLE])]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands_with_int5() {
+        let mut cdrom = cdrom(Some(disc()));
+        execute(&mut cdrom, 0x01, &[], 1);
+
+        assert_eq!(
+            execute(&mut cdrom, 0x1F, &[], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x40])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x02, &[0x00, 0x02], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x20])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x01, &[0x00], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x20])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x02, &[0x00, 0x60, 0x00], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x10])]
+        );
+        assert_eq!(
+            execute(&mut cdrom, 0x19, &[0x99], 1),
+            vec![(5, vec![STAT_IDLE | 0x01, 0x10])]
+        );
+
+        // 最初の応答の前に次のコマンドを送る
+        cdrom.store::<u8>(1, 0x01);
+        assert_eq!(status(&mut cdrom) & 0x80, 0x80);
+        cdrom.store::<u8>(1, 0x0A);
+
+        assert_eq!(wait_irq(&mut cdrom), 3);
+        assert_eq!(read_response(&mut cdrom), vec![STAT_IDLE]);
+        assert_eq!(status(&mut cdrom) & 0x80, 0);
+        ack(&mut cdrom);
+
+        assert_eq!(wait_irq(&mut cdrom), 5);
+        assert_eq!(read_response(&mut cdrom), vec![STAT_IDLE | 0x01, 0x80]);
+    }
+
+    // 同じディスクを入れた別のドライブに状態を移す
+    fn restore(cdrom: &CdRom, disc: Vec<u8>) -> CdRom {
+        let mut w = Writer::new();
+        cdrom.save_state(&mut w);
+        let state = w.into_inner();
+
+        let mut restored = CdRom::new(Some(Image::from_disc(Disc::from_bytes(disc))));
+        restored.load_state(&mut Reader::new(&state)).unwrap();
+
+        restored
+    }
+
+    #[test]
+    fn pending_responses_survive_savestates() {
+        let mut cdrom = cdrom(Some(mode2_disc(20)));
+        execute(&mut cdrom, 0x01, &[], 1);
+        execute(&mut cdrom, 0x08, &[], 2);
+
+        // MotorOn の2回目の応答を待っている間に保存する
+        execute(&mut cdrom, 0x07, &[], 1);
+        for _ in 0..1000 {
+            cdrom.tick(&mut IrqBus::new());
+        }
+
+        let mut restored = restore(&cdrom, mode2_disc(20));
+        assert_eq!(
+            cycles_until_irq(&mut restored),
+            cycles_until_irq(&mut cdrom)
+        );
+        assert_eq!(wait_irq(&mut restored), 2);
+        assert_eq!(read_response(&mut restored), vec![STAT_IDLE]);
+        ack(&mut restored);
+
+        // SetLoc の位置は最初の応答と一緒に決まる
+        for param in [0x00, 0x02, 0x12] {
+            restored.store::<u8>(2, param);
+        }
+        restored.store::<u8>(1, 0x02);
+        for _ in 0..10 {
+            restored.tick(&mut IrqBus::new());
+        }
+
+        let mut restored = restore(&restored, mode2_disc(20));
+        assert_eq!(wait_irq(&mut restored), 3);
+        assert_eq!(read_response(&mut restored), vec![STAT_IDLE]);
+        ack(&mut restored);
+
+        execute(&mut restored, 0x15, &[], 2);
+        execute(&mut restored, 0x06, &[], 2);
+        assert_eq!(sector_word(&mut restored), 12);
+    }
+
+    #[test]
+    fn ack_clears_response_and_parameter_fifo() {
+        let mut cdrom = cdrom(Some(disc()));
+
+        cdrom.store::<u8>(1, 0x01);
+        wait_irq(&mut cdrom);
+        assert_eq!(status(&mut cdrom) & 0x20, 0x20);
+
+        ack(&mut cdrom);
+        assert_eq!(status(&mut cdrom) & 0x20, 0);
+
+        cdrom.store::<u8>(2, 0x00);
+        assert_eq!(status(&mut cdrom) & 0x08, 0);
+
+        cdrom.store::<u8>(0, 1);
+        cdrom.store::<u8>(3, 0x40);
+        cdrom.store::<u8>(0, 0);
+        assert_eq!(status(&mut cdrom) & 0x08, 0x08);
     }
 }
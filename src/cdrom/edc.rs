@@ -0,0 +1,202 @@
+// データセクタの EDC (CRC32) と ECC (P/Q パリティ, RSPC)
+
+use super::image::{SECTOR_SIZE, SYNC};
+
+// EDC とパリティの位置
+const MODE1_EDC: usize = 0x810;
+const FORM1_EDC: usize = 0x818;
+const FORM2_EDC: usize = 0x92C;
+const P_PARITY: usize = 0x81C;
+const Q_PARITY: usize = 0x8C8;
+
+// サブモードのフォーム2 のビット
+const SUBMODE_FORM2: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    Edc,
+    Ecc,
+}
+
+struct Tables {
+    edc: [u32; 256],
+    // GF(2^8) で2倍する表と、その逆
+    ecc_f: [u8; 256],
+    ecc_b: [u8; 256],
+}
+
+const fn tables() -> Tables {
+    let mut edc = [0; 256];
+    let mut ecc_f = [0; 256];
+    let mut ecc_b = [0; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xD8018001,
+            };
+            bit += 1;
+        }
+        edc[i] = crc;
+
+        let j = match i & 0x80 {
+            0 => i << 1,
+            _ => (i << 1) ^ 0x11D,
+        };
+        ecc_f[i] = j as u8;
+        ecc_b[i ^ j] = i as u8;
+
+        i += 1;
+    }
+
+    Tables { edc, ecc_f, ecc_b }
+}
+
+static TABLES: Tables = tables();
+
+pub fn edc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc >> 8) ^ TABLES.edc[((crc ^ byte as u32) & 0xFF) as usize]
+    })
+}
+
+// 同期パターンの後ろ (ヘッダから) を major_count 列に分けたパリティ
+fn parity(
+    src: &[u8],
+    major_count: usize,
+    minor_count: usize,
+    major_mult: usize,
+    minor_inc: usize,
+    dest: &mut [u8],
+) {
+    let size = major_count * minor_count;
+
+    for major in 0..major_count {
+        let mut index = (major >> 1) * major_mult + (major & 1);
+        let mut a = 0u8;
+        let mut b = 0u8;
+
+        for _ in 0..minor_count {
+            let byte = src[index];
+            index += minor_inc;
+            if index >= size {
+                index -= size;
+            }
+
+            a ^= byte;
+            b ^= byte;
+            a = TABLES.ecc_f[a as usize];
+        }
+
+        a = TABLES.ecc_b[(TABLES.ecc_f[a as usize] ^ b) as usize];
+        dest[major] = a;
+        dest[major + major_count] = a ^ b;
+    }
+}
+
+// P と Q のパリティ (172 + 104 byte)。モード2 ではヘッダを0として計算する
+fn ecc(sector: &[u8]) -> Vec<u8> {
+    let mut buf = sector[SYNC.len()..P_PARITY].to_vec();
+    if sector[15] == 2 {
+        buf[..4].fill(0);
+    }
+
+    let mut p = [0; Q_PARITY - P_PARITY];
+    parity(&buf, 86, 24, 2, 86, &mut p);
+    buf.extend_from_slice(&p);
+
+    let mut q = [0; SECTOR_SIZE - Q_PARITY];
+    parity(&buf, 52, 43, 86, 88, &mut q);
+
+    [&p[..], &q[..]].concat()
+}
+
+// 生セクタを確かめる。モード1 とモード2 (フォーム1/2) 以外はそのまま通す
+// フォーム2 の EDC は省略 (0) してよい
+pub fn verify(sector: &[u8]) -> Result<(), Damage> {
+    if sector.len() < SECTOR_SIZE || sector[..SYNC.len()] != SYNC {
+        return Ok(());
+    }
+
+    let (edc_offset, ecc) = match sector[15] {
+        1 => (MODE1_EDC, true),
+        2 if sector[18] & SUBMODE_FORM2 != 0 => (FORM2_EDC, false),
+        2 => (FORM1_EDC, true),
+        _ => return Ok(()),
+    };
+
+    let start = match sector[15] {
+        1 => 0,
+        _ => 16,
+    };
+    let stored = u32::from_le_bytes(sector[edc_offset..edc_offset + 4].try_into().unwrap());
+    let optional = !ecc && stored == 0;
+
+    if !optional && edc(&sector[start..edc_offset]) != stored {
+        return Err(Damage::Edc);
+    }
+
+    if ecc && self::ecc(sector) != sector[P_PARITY..] {
+        return Err(Damage::Ecc);
+    }
+
+    Ok(())
+}
+
+// モード1 のセクタに EDC と ECC を書き込む
+pub fn fill_mode1(sector: &mut [u8]) {
+    let edc = edc(&sector[..MODE1_EDC]);
+    sector[MODE1_EDC..MODE1_EDC + 4].copy_from_slice(&edc.to_le_bytes());
+    sector[MODE1_EDC + 4..P_PARITY].fill(0);
+
+    let ecc = ecc(sector);
+    sector[P_PARITY..].copy_from_slice(&ecc);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode1() -> Vec<u8> {
+        let mut sector = SYNC.to_vec();
+        sector.extend([0x00, 0x02, 0x16, 1]);
+        sector.extend((0..SECTOR_SIZE - 16).map(|i| (i * 7) as u8));
+        fill_mode1(&mut sector);
+
+        sector
+    }
+
+    #[test]
+    fn edc_is_crc32_of_the_cdrom_polynomial() {
+        assert_eq!(edc(&[]), 0);
+        assert_eq!(edc(&[1]), TABLES.edc[1]);
+        assert_eq!(TABLES.edc[0x80], 0xD8018001);
+    }
+
+    #[test]
+    fn detects_damaged_sectors() {
+        let sector = mode1();
+        assert_eq!(verify(&sector), Ok(()));
+
+        let mut damaged = sector.clone();
+        damaged[100] ^= 1;
+        assert_eq!(verify(&damaged), Err(Damage::Edc));
+
+        let mut damaged = sector.clone();
+        damaged[P_PARITY + 3] ^= 1;
+        assert_eq!(verify(&damaged), Err(Damage::Ecc));
+
+        // フォーム2 で EDC が0なら確かめない
+        let mut form2 = sector;
+        form2[15] = 2;
+        form2[18] = SUBMODE_FORM2;
+        form2[FORM2_EDC..].fill(0);
+        assert_eq!(verify(&form2), Ok(()));
+        form2[FORM2_EDC] = 1;
+        assert_eq!(verify(&form2), Err(Damage::Edc));
+    }
+}
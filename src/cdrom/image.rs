@@ -0,0 +1,793 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+
+use crate::{config::Region, disc::Disc};
+
+use super::{
+    edc,
+    subq::{Sbi, SubQ},
+};
+
+// データトラックのシステム領域でライセンス文字列のあるセクタ
+const LICENSE_SECTOR: u32 = 4;
+
+// 生セクタの大きさ
+pub const SECTOR_SIZE: usize = 2352;
+// ISOイメージ (MODE1/2048) のセクタの大きさ
+pub const DATA_SIZE: usize = 2048;
+// LBA 0 は 00:02:00。手前の2秒は1曲目のプリギャップでイメージには含まれない
+pub const LEAD_IN: u32 = 150;
+// 前のセッションのリードアウト (1分30秒) と次のセッションのリードイン (1分)、最初のトラックのプリギャップ
+pub const SESSION_GAP: u32 = 6750 + 4500 + LEAD_IN;
+
+pub const SYNC: [u8; 12] = [
+    0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00,
+];
+
+// ディスク上の絶対位置 (BCDではなく2進数)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msf {
+    pub min: u8,
+    pub sec: u8,
+    pub frame: u8,
+}
+
+impl Msf {
+    pub fn new(min: u8, sec: u8, frame: u8) -> Msf {
+        Msf { min, sec, frame }
+    }
+
+    // SetLoc などのコマンドの引数はBCD
+    pub fn from_bcd(min: u8, sec: u8, frame: u8) -> Msf {
+        Msf {
+            min: from_bcd(min),
+            sec: from_bcd(sec),
+            frame: from_bcd(frame),
+        }
+    }
+
+    pub fn from_lba(lba: u32) -> Msf {
+        Msf::from_frames(lba + LEAD_IN)
+    }
+
+    // 00:00:00 からのセクタ数から
+    pub fn from_frames(frames: u32) -> Msf {
+        Msf {
+            min: (frames / (60 * 75)) as u8,
+            sec: (frames / 75 % 60) as u8,
+            frame: (frames % 75) as u8,
+        }
+    }
+
+    pub fn frames(self) -> u32 {
+        self.min as u32 * 60 * 75 + self.sec as u32 * 75 + self.frame as u32
+    }
+
+    // リードインの中は None
+    pub fn lba(self) -> Option<u32> {
+        self.frames().checked_sub(LEAD_IN)
+    }
+
+    // "mm:ss:ff"
+    fn parse(s: &str) -> Result<Msf> {
+        let fields: Vec<u8> = s
+            .split(':')
+            .map(|field| field.parse::<u8>())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid MSF {}", s))?;
+
+        match fields[..] {
+            [min, sec, frame] if sec < 60 && frame < 75 => Ok(Msf { min, sec, frame }),
+            _ => bail!("Invalid MSF {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Mode1,
+    Mode2,
+    Audio,
+}
+
+impl TrackKind {
+    pub fn is_data(self) -> bool {
+        !matches!(self, TrackKind::Audio)
+    }
+}
+
+#[derive(Clone)]
+pub struct Track {
+    pub number: u8,
+    pub kind: TrackKind,
+    // 1 から
+    pub session: u8,
+    // INDEX 01 のLBA
+    pub start: u32,
+    // INDEX 00 から INDEX 01 までのセクタ数 (ファイルにないPREGAPを含む)
+    pub pregap: u32,
+    // INDEX 01 からのセクタ数
+    pub length: u32,
+    // 後ろに続くファイルにない無音部分
+    postgap: u32,
+    // プリギャップのうちファイルにある分
+    file_pregap: u32,
+    file: Disc,
+    // INDEX 01 のファイル内の位置 (セクタ)
+    file_sector: u32,
+    // ファイル内の1セクタの大きさ (2352 か 2048)
+    sector_size: usize,
+}
+
+impl Track {
+    // プリギャップを含む範囲
+    fn contains(&self, lba: u32) -> bool {
+        lba + self.pregap >= self.start && lba < self.start + self.length + self.postgap
+    }
+
+    // ファイル内のバイト位置。ファイルにないギャップは None
+    fn file_offset(&self, lba: u32) -> Option<usize> {
+        let rel = lba as i64 - self.start as i64;
+
+        if rel < -(self.file_pregap as i64) || rel >= self.length as i64 {
+            return None;
+        }
+
+        Some((self.file_sector as i64 + rel) as usize * self.sector_size)
+    }
+}
+
+// ディスクイメージ (単体の .bin/.iso か .cue で束ねた複数トラック)
+// ドライブからはLBAで2352byteの生セクタとして読む
+#[derive(Clone)]
+pub struct Image {
+    tracks: Vec<Track>,
+    sbi: Sbi,
+}
+
+impl Image {
+    // .cue ならシートに従い、それ以外は1トラックのデータディスクとして開く
+    // 同じ名前の .sbi があればサブチャンネルQの置き換えも読む
+    pub fn open(path: &Path, precache: bool) -> Result<Image> {
+        let mut image = Image::open_tracks(path, precache)?;
+
+        let sbi = path.with_extension("sbi");
+        if sbi.is_file() {
+            image.sbi = Sbi::load(&sbi)?;
+            info!(
+                "Loaded {} subchannel Q patches from {}",
+                image.sbi.len(),
+                sbi.display()
+            );
+        }
+
+        Ok(image)
+    }
+
+    fn open_tracks(path: &Path, precache: bool) -> Result<Image> {
+        let open = |path: &Path| match precache {
+            true => Disc::load(path),
+            false => Disc::open(path),
+        };
+
+        let is_cue = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"));
+
+        if !is_cue {
+            return Ok(Image::from_disc(open(path)?));
+        }
+
+        let sheet = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        Image::parse_cue(&sheet, |name| open(&dir.join(name)))
+    }
+
+    // 同期パターンで始まるか、2048byte単位でなければ生イメージとみなす
+    pub fn from_disc(disc: Disc) -> Image {
+        let raw = disc.read(0, SYNC.len()).is_some_and(|sync| sync == SYNC)
+            || !disc.len().is_multiple_of(DATA_SIZE);
+
+        let (kind, sector_size) = match raw {
+            true if disc.byte(15) == Some(1) => (TrackKind::Mode1, SECTOR_SIZE),
+            true => (TrackKind::Mode2, SECTOR_SIZE),
+            false => (TrackKind::Mode1, DATA_SIZE),
+        };
+
+        Image {
+            tracks: vec![Track {
+                number: 1,
+                kind,
+                session: 1,
+                start: 0,
+                pregap: 0,
+                length: (disc.len() / sector_size) as u32,
+                postgap: 0,
+                file_pregap: 0,
+                file: disc,
+                file_sector: 0,
+                sector_size,
+            }],
+            sbi: Sbi::default(),
+        }
+    }
+
+    // open はシートの FILE に書かれた名前からファイルを開く
+    pub fn parse_cue(sheet: &str, mut open: impl FnMut(&str) -> Result<Disc>) -> Result<Image> {
+        let mut entries: Vec<CueTrack> = vec![];
+        // REM SESSION nn (CDRWIN のマルチセッション)
+        let mut session = 1;
+        // 開いたファイルと、何番目の FILE か
+        let mut file: Option<(usize, Disc)> = None;
+
+        for (i, line) in sheet.lines().enumerate() {
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+
+            let context = || format!("cue sheet line {}: {}", i + 1, line);
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    let (name, kind) = split_file(rest).with_context(context)?;
+                    if !kind.eq_ignore_ascii_case("BINARY") {
+                        bail!("Unsupported file type {} ({})", kind, context());
+                    }
+                    let index = file.as_ref().map_or(0, |(index, _)| index + 1);
+                    file = Some((index, open(name).with_context(context)?));
+                }
+                "TRACK" => {
+                    let (number, mode) = rest
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(|| anyhow!("Missing track mode ({})", context()))?;
+
+                    let (kind, sector_size) = match mode.trim().to_ascii_uppercase().as_str() {
+                        "MODE1/2352" => (TrackKind::Mode1, SECTOR_SIZE),
+                        "MODE2/2352" => (TrackKind::Mode2, SECTOR_SIZE),
+                        "MODE1/2048" => (TrackKind::Mode1, DATA_SIZE),
+                        "AUDIO" => (TrackKind::Audio, SECTOR_SIZE),
+                        mode => bail!("Unsupported track mode {} ({})", mode, context()),
+                    };
+
+                    let (file_index, file) = file
+                        .clone()
+                        .ok_or_else(|| anyhow!("TRACK before FILE ({})", context()))?;
+
+                    entries.push(CueTrack {
+                        number: number.parse().with_context(context)?,
+                        kind,
+                        session,
+                        sector_size,
+                        file,
+                        file_index,
+                        index0: None,
+                        index1: None,
+                        pregap: 0,
+                        postgap: 0,
+                    });
+                }
+                "INDEX" | "PREGAP" | "POSTGAP" => {
+                    let track = entries
+                        .last_mut()
+                        .ok_or_else(|| anyhow!("{} before TRACK ({})", command, context()))?;
+
+                    match command.to_ascii_uppercase().as_str() {
+                        "INDEX" => {
+                            let (number, msf) = rest
+                                .split_once(char::is_whitespace)
+                                .ok_or_else(|| anyhow!("Missing index position ({})", context()))?;
+                            let frames = Msf::parse(msf.trim()).with_context(context)?.frames();
+
+                            match number.parse::<u8>().with_context(context)? {
+                                0 => track.index0 = Some(frames),
+                                1 => track.index1 = Some(frames),
+                                // サブインデックスは位置に影響しない
+                                _ => {}
+                            }
+                        }
+                        "PREGAP" => track.pregap = Msf::parse(rest).with_context(context)?.frames(),
+                        _ => track.postgap = Msf::parse(rest).with_context(context)?.frames(),
+                    }
+                }
+                "REM" => {
+                    let (key, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    if key.eq_ignore_ascii_case("SESSION") {
+                        let number: u8 = value.trim().parse().with_context(context)?;
+                        if number < session {
+                            bail!("Sessions out of order ({})", context());
+                        }
+                        session = number;
+                    }
+                }
+                // CATALOG, TITLE, PERFORMER, FLAGS, ISRC など
+                _ => {}
+            }
+        }
+
+        Image::layout(entries)
+    }
+
+    // ファイル内の位置からディスク上の位置を決める
+    fn layout(entries: Vec<CueTrack>) -> Result<Image> {
+        if entries.is_empty() {
+            bail!("No tracks in cue sheet");
+        }
+
+        let mut tracks = Vec::with_capacity(entries.len());
+        let mut lba = 0;
+
+        for (i, entry) in entries.iter().enumerate() {
+            let index1 = entry
+                .index1
+                .ok_or_else(|| anyhow!("Track {} has no INDEX 01", entry.number))?;
+            let index0 = entry.index0.unwrap_or(index1);
+
+            if index0 > index1 {
+                bail!("Track {} has INDEX 00 after INDEX 01", entry.number);
+            }
+
+            // 同じファイルの次のトラックの手前か、ファイルの終わりまで
+            let file_sectors = (entry.file.len() / entry.sector_size) as u32;
+            let end = match entries.get(i + 1) {
+                Some(next) if next.file_index == entry.file_index => {
+                    next.index0.or(next.index1).unwrap_or(file_sectors)
+                }
+                _ => file_sectors,
+            };
+
+            if end < index1 || end > file_sectors {
+                bail!("Track {} is outside of its file", entry.number);
+            }
+
+            // 前のセッションとの間はどのトラックにも含まれない
+            if i > 0 && entry.session != entries[i - 1].session {
+                lba += SESSION_GAP;
+            }
+
+            let file_pregap = index1 - index0;
+            lba += entry.pregap + file_pregap;
+
+            tracks.push(Track {
+                number: entry.number,
+                kind: entry.kind,
+                session: entry.session,
+                start: lba,
+                pregap: entry.pregap + file_pregap,
+                length: end - index1,
+                postgap: entry.postgap,
+                file_pregap,
+                file: entry.file.clone(),
+                file_sector: index1,
+                sector_size: entry.sector_size,
+            });
+
+            lba += end - index1 + entry.postgap;
+        }
+
+        Ok(Image {
+            tracks,
+            sbi: Sbi::default(),
+        })
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn track(&self, number: u8) -> Option<&Track> {
+        self.tracks.iter().find(|track| track.number == number)
+    }
+
+    // 最初のデータトラック (ISO9660 のファイルシステムがある)
+    pub fn data_track(&self) -> Option<&Track> {
+        self.tracks.iter().find(|track| track.kind.is_data())
+    }
+
+    pub fn sessions(&self) -> u8 {
+        self.tracks.last().map_or(0, |track| track.session)
+    }
+
+    // セッションの最初のトラック
+    pub fn session(&self, number: u8) -> Option<&Track> {
+        self.tracks.iter().find(|track| track.session == number)
+    }
+
+    // プリギャップを含めて lba を持つトラック
+    pub fn track_at(&self, lba: u32) -> Option<&Track> {
+        self.tracks.iter().find(|track| track.contains(lba))
+    }
+
+    // 最後のトラックの終わり (リードアウトの開始位置)
+    pub fn sectors(&self) -> u32 {
+        self.tracks
+            .last()
+            .map_or(0, |track| track.start + track.length + track.postgap)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sectors() == 0
+    }
+
+    pub fn set_sbi(&mut self, sbi: Sbi) {
+        self.sbi = sbi;
+    }
+
+    // lba を読んでいるときのサブチャンネルQ
+    // プリギャップ (インデックス0) のトラック内の位置はトラックの先頭までの残り
+    pub fn subq(&self, lba: u32) -> SubQ {
+        let (control, track, index, relative) = match self.track_at(lba) {
+            Some(track) => {
+                let control = match track.kind.is_data() {
+                    true => 0x41,
+                    false => 0x01,
+                };
+
+                match lba.checked_sub(track.start) {
+                    Some(relative) => (control, bcd(track.number), 1, relative),
+                    None => (control, bcd(track.number), 0, track.start - lba),
+                }
+            }
+            // リードアウト
+            None => (0x01, 0xAA, 1, lba.saturating_sub(self.sectors())),
+        };
+
+        let relative = Msf::from_frames(relative);
+        let absolute = Msf::from_lba(lba);
+
+        let subq = SubQ::new([
+            control,
+            track,
+            index,
+            bcd(relative.min),
+            bcd(relative.sec),
+            bcd(relative.frame),
+            0,
+            bcd(absolute.min),
+            bcd(absolute.sec),
+            bcd(absolute.frame),
+        ]);
+
+        self.sbi.apply(lba, subq)
+    }
+
+    // システム領域のライセンス文字列から地域を決める
+    // "Licensed by Sony Computer Entertainment Inc." のように空白を挟んで続く
+    pub fn region(&self) -> Option<Region> {
+        let raw = self.read_sector(self.data_track()?.start + LICENSE_SECTOR)?;
+        let offset = match raw[15] {
+            1 => 16,
+            2 => 24,
+            _ => return None,
+        };
+
+        let license: Vec<u8> = raw[offset..offset + DATA_SIZE]
+            .iter()
+            .copied()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .collect();
+        let rest = license.strip_prefix(b"LicensedbySonyComputerEntertainment")?;
+
+        if rest.starts_with(b"Inc") {
+            Some(Region::Japan)
+        } else if rest.starts_with(b"America") {
+            Some(Region::NorthAmerica)
+        } else if rest.starts_with(b"Europe") {
+            Some(Region::Europe)
+        } else {
+            None
+        }
+    }
+
+    // 2352byteの生セクタ。ファイルにないギャップは0で埋める
+    pub fn read_sector(&self, lba: u32) -> Option<Vec<u8>> {
+        let track = self.track_at(lba)?;
+
+        let offset = match track.file_offset(lba) {
+            Some(offset) => offset,
+            None => return Some(vec![0; SECTOR_SIZE]),
+        };
+
+        match track.sector_size {
+            SECTOR_SIZE => track.file.read(offset, SECTOR_SIZE),
+            _ => {
+                let data = track.file.read(offset, DATA_SIZE)?;
+                Some(mode1_sector(lba, &data))
+            }
+        }
+    }
+
+    // 生セクタの1byte。読み出しのたびにセクタ全体を組み立てないよう、生イメージは直接読む
+    pub fn byte(&self, lba: u32, offset: usize) -> Option<u8> {
+        let track = self.track_at(lba)?;
+
+        match (track.file_offset(lba), track.sector_size) {
+            (None, _) => Some(0),
+            (Some(base), SECTOR_SIZE) => track.file.byte(base + offset),
+            (Some(_), _) => self.read_sector(lba)?.get(offset).copied(),
+        }
+    }
+
+    // lba から count セクタ分をファイルから先読みしておく
+    pub fn prefetch(&self, lba: u32, count: usize) {
+        if let Some(track) = self.track_at(lba) {
+            if let Some(offset) = track.file_offset(lba) {
+                // 先読みの単位は生セクタ
+                let count = count * track.sector_size / SECTOR_SIZE;
+                track.file.prefetch(offset, count.max(1));
+            }
+        }
+    }
+}
+
+struct CueTrack {
+    number: u8,
+    kind: TrackKind,
+    session: u8,
+    sector_size: usize,
+    file: Disc,
+    file_index: usize,
+    // ファイル内の位置 (セクタ)
+    index0: Option<u32>,
+    index1: Option<u32>,
+    pregap: u32,
+    postgap: u32,
+}
+
+// FILE "name with spaces.bin" BINARY
+fn split_file(rest: &str) -> Result<(&str, &str)> {
+    let (name, kind) = match rest.strip_prefix('"') {
+        Some(quoted) => quoted
+            .split_once('"')
+            .ok_or_else(|| anyhow!("Unterminated file name"))?,
+        None => rest
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("Missing file type"))?,
+    };
+
+    Ok((name.trim(), kind.trim()))
+}
+
+pub fn bcd(val: u8) -> u8 {
+    ((val / 10) << 4) | (val % 10)
+}
+
+pub fn is_bcd(val: u8) -> bool {
+    val >> 4 < 10 && val & 0xF < 10
+}
+
+pub fn from_bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0xF)
+}
+
+// ISOイメージの2048byteに同期パターンとヘッダ、EDC/ECCを付ける
+fn mode1_sector(lba: u32, data: &[u8]) -> Vec<u8> {
+    let msf = Msf::from_lba(lba);
+
+    let mut sector = Vec::with_capacity(SECTOR_SIZE);
+    sector.extend_from_slice(&SYNC);
+    sector.extend([bcd(msf.min), bcd(msf.sec), bcd(msf.frame), 1]);
+    sector.extend_from_slice(data);
+    sector.resize(SECTOR_SIZE, 0);
+    edc::fill_mode1(&mut sector);
+
+    sector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 各セクタの先頭にファイル内のセクタ番号を入れる
+    fn file(sectors: usize, tag: u8) -> Disc {
+        let mut data = vec![0; sectors * SECTOR_SIZE];
+        for (i, sector) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+            sector[0] = tag;
+            sector[1] = i as u8;
+        }
+        Disc::from_bytes(data)
+    }
+
+    fn tag(image: &Image, lba: u32) -> Option<(u8, u8)> {
+        image.read_sector(lba).map(|sector| (sector[0], sector[1]))
+    }
+
+    #[test]
+    fn msf_converts_to_lba() {
+        assert_eq!(Msf::new(0, 2, 0).lba(), Some(0));
+        assert_eq!(Msf::new(1, 2, 3).lba(), Some(4500 + 3));
+        assert_eq!(Msf::new(0, 1, 74).lba(), None);
+        assert_eq!(Msf::from_lba(4503), Msf::new(1, 2, 3));
+        assert_eq!(Msf::from_bcd(0x01, 0x02, 0x74), Msf::new(1, 2, 74));
+    }
+
+    #[test]
+    fn single_file_tracks_with_index_pregap() {
+        let sheet = r#"
+            FILE "game.bin" BINARY
+              TRACK 01 MODE2/2352
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                INDEX 00 00:00:10
+                INDEX 01 00:00:12
+        "#;
+
+        let image = Image::parse_cue(sheet, |name| {
+            assert_eq!(name, "game.bin");
+            Ok(file(20, 1))
+        })
+        .unwrap();
+
+        let tracks = image.tracks();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!((tracks[0].start, tracks[0].length), (0, 10));
+        assert_eq!(tracks[1].kind, TrackKind::Audio);
+        assert_eq!(
+            (tracks[1].start, tracks[1].pregap, tracks[1].length),
+            (12, 2, 8)
+        );
+
+        // INDEX 00 からのプリギャップはファイルにある
+        assert_eq!(tag(&image, 10), Some((1, 10)));
+        assert_eq!(image.track_at(10).unwrap().number, 2);
+        assert_eq!(tag(&image, 19), Some((1, 19)));
+        assert_eq!(image.sectors(), 20);
+        assert_eq!(tag(&image, 20), None);
+    }
+
+    #[test]
+    fn multiple_files_with_pregap_command() {
+        let sheet = "FILE \"Track 1.bin\" BINARY\n\
+                     TRACK 01 MODE2/2352\n\
+                     INDEX 01 00:00:00\n\
+                     FILE \"Track 2.bin\" BINARY\n\
+                     TRACK 02 AUDIO\n\
+                     PREGAP 00:00:05\n\
+                     INDEX 01 00:00:00\n";
+
+        let image = Image::parse_cue(sheet, |name| match name {
+            "Track 1.bin" => Ok(file(10, 1)),
+            "Track 2.bin" => Ok(file(4, 2)),
+            name => bail!("unexpected {}", name),
+        })
+        .unwrap();
+
+        let track = image.track(2).unwrap();
+        assert_eq!((track.start, track.pregap, track.length), (15, 5, 4));
+
+        // PREGAP の分はファイルにないので無音
+        assert_eq!(tag(&image, 9), Some((1, 9)));
+        assert_eq!(image.read_sector(10), Some(vec![0; SECTOR_SIZE]));
+        assert_eq!(tag(&image, 15), Some((2, 0)));
+        assert_eq!(image.byte(18, 1), Some(3));
+        assert_eq!(Msf::from_lba(track.start), Msf::new(0, 2, 15));
+    }
+
+    #[test]
+    fn subq_follows_tracks() {
+        let sheet = "FILE \"game.bin\" BINARY\n\
+                     TRACK 01 MODE2/2352\n\
+                     INDEX 01 00:00:00\n\
+                     TRACK 02 AUDIO\n\
+                     INDEX 00 00:00:10\n\
+                     INDEX 01 00:00:12\n";
+        let mut image = Image::parse_cue(sheet, |_| Ok(file(20, 1))).unwrap();
+
+        assert_eq!(
+            image.subq(5).data,
+            [0x41, 0x01, 0x01, 0, 0, 0x05, 0, 0, 0x02, 0x05]
+        );
+        // プリギャップは残りを数える
+        assert_eq!(
+            image.subq(10).data,
+            [0x01, 0x02, 0x00, 0, 0, 0x02, 0, 0, 0x02, 0x10]
+        );
+        assert_eq!(
+            image.subq(13).data,
+            [0x01, 0x02, 0x01, 0, 0, 0x01, 0, 0, 0x02, 0x13]
+        );
+        assert_eq!(
+            image.subq(21).data,
+            [0x01, 0xAA, 0x01, 0, 0, 0x01, 0, 0, 0x02, 0x21]
+        );
+        assert!(image.subq(13).crc_valid());
+
+        let mut sbi = b"SBI\0".to_vec();
+        sbi.extend([0x00, 0x02, 0x13, 2, 0x00, 0x00, 0x09]);
+        image.set_sbi(Sbi::parse(&sbi).unwrap());
+
+        assert_eq!(image.subq(13).data[3..6], [0x00, 0x00, 0x09]);
+        assert!(!image.subq(13).crc_valid());
+        assert!(image.subq(14).crc_valid());
+    }
+
+    #[test]
+    fn sessions_are_separated_by_lead_out_and_lead_in() {
+        let sheet = "REM SESSION 01\n\
+                     FILE \"a.bin\" BINARY\n\
+                     TRACK 01 AUDIO\n\
+                     INDEX 01 00:00:00\n\
+                     TRACK 02 AUDIO\n\
+                     INDEX 01 00:00:05\n\
+                     REM SESSION 02\n\
+                     FILE \"b.bin\" BINARY\n\
+                     TRACK 03 MODE2/2352\n\
+                     INDEX 01 00:00:00\n";
+
+        let image = Image::parse_cue(sheet, |name| match name {
+            "a.bin" => Ok(file(10, 1)),
+            _ => Ok(file(4, 2)),
+        })
+        .unwrap();
+
+        assert_eq!(image.sessions(), 2);
+        assert_eq!(image.session(1).unwrap().number, 1);
+        assert_eq!(image.data_track().unwrap().number, 3);
+
+        let track = image.session(2).unwrap();
+        assert_eq!((track.number, track.start), (3, 10 + SESSION_GAP));
+        assert_eq!(tag(&image, track.start), Some((2, 0)));
+
+        // セッションの間は読めない
+        assert!(image.track_at(10).is_none());
+        assert!(image.track_at(track.start - 1).is_none());
+        assert_eq!(image.sectors(), track.start + 4);
+
+        assert!(
+            Image::parse_cue(&sheet.replace("SESSION 01", "SESSION 03"), |_| Ok(file(
+                10, 1
+            )))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn iso_sectors_get_headers() {
+        let image = Image::from_disc(Disc::from_bytes(vec![0xAB; DATA_SIZE * 20]));
+        let sector = image.read_sector(16).unwrap();
+
+        assert_eq!(sector.len(), SECTOR_SIZE);
+        assert_eq!(sector[..12], SYNC);
+        assert_eq!(sector[12..16], [0x00, 0x02, 0x16, 1]);
+        assert_eq!(sector[16], 0xAB);
+        assert_eq!(edc::verify(&sector), Ok(()));
+        assert_eq!(image.byte(16, 16 + DATA_SIZE), Some(sector[16 + DATA_SIZE]));
+    }
+
+    #[test]
+    fn region_from_license_string() {
+        let image = |license: &[u8]| {
+            let mut data = vec![b' '; DATA_SIZE * 20];
+            data[DATA_SIZE * 4..DATA_SIZE * 4 + license.len()].copy_from_slice(license);
+            Image::from_disc(Disc::from_bytes(data))
+        };
+
+        assert_eq!(
+            image(b"          Licensed  by          Sony Computer Entertainment Inc.").region(),
+            Some(Region::Japan)
+        );
+        assert_eq!(
+            image(b"          Licensed  by          Sony Computer Entertainment Amer  ica ")
+                .region(),
+            Some(Region::NorthAmerica)
+        );
+        assert_eq!(
+            image(b"          Licensed  by          Sony Computer Entertainment Euro pe").region(),
+            Some(Region::Europe)
+        );
+        assert_eq!(image(b"").region(), None);
+    }
+
+    #[test]
+    fn rejects_broken_sheets() {
+        let open = |_: &str| Ok(file(4, 0));
+
+        assert!(Image::parse_cue("TRACK 01 AUDIO", open).is_err());
+        assert!(Image::parse_cue("FILE \"a.bin\" WAVE", open).is_err());
+        assert!(Image::parse_cue("FILE a.bin BINARY\nTRACK 01 AUDIO", open).is_err());
+        assert!(Image::parse_cue("", open).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+use super::image::Msf;
+
+// サブチャンネルQ (ADR 1) の10byte とCRC
+// [コントロール/ADR, トラック, インデックス, 分, 秒, セクタ, 0, 絶対分, 絶対秒, 絶対セクタ] (BCD)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubQ {
+    pub data: [u8; 10],
+    pub crc: u16,
+}
+
+impl SubQ {
+    pub fn new(data: [u8; 10]) -> SubQ {
+        SubQ {
+            data,
+            crc: crc16(&data),
+        }
+    }
+
+    // ドライブはCRCの合わないQを無視する (LibCrypt はこれを使う)
+    pub fn crc_valid(&self) -> bool {
+        self.crc == crc16(&self.data)
+    }
+}
+
+// CRC-16-CCITT の反転
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+
+    !crc
+}
+
+// .sbi に書かれたQの置き換え (LibCrypt で保護されたディスク)
+#[derive(Debug, Clone, Default)]
+pub struct Sbi {
+    // LBA ごとの Q の中の位置と置き換えるバイト
+    patches: HashMap<u32, (usize, Vec<u8>)>,
+}
+
+impl Sbi {
+    pub fn load(path: &Path) -> Result<Sbi> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Sbi::parse(&bytes).with_context(|| format!("Invalid sbi file {}", path.display()))
+    }
+
+    // "SBI\0" の後に [分, 秒, セクタ (BCD), 種類] と中身が続く
+    // 種類 1 は Q の10byte、2 はトラック内の位置、3 は絶対位置の3byte
+    pub fn parse(bytes: &[u8]) -> Result<Sbi> {
+        let mut rest = match bytes.strip_prefix(b"SBI\0") {
+            Some(rest) => rest,
+            None => bail!("Missing SBI header"),
+        };
+
+        let mut patches = HashMap::new();
+
+        while !rest.is_empty() {
+            let (min, sec, frame, kind, tail) = match rest {
+                [min, sec, frame, kind, tail @ ..] => (*min, *sec, *frame, *kind, tail),
+                _ => bail!("Truncated sbi entry"),
+            };
+
+            let (offset, len) = match kind {
+                1 => (0, 10),
+                2 => (3, 3),
+                3 => (7, 3),
+                kind => bail!("Unknown sbi entry type {}", kind),
+            };
+            if tail.len() < len {
+                bail!("Truncated sbi entry");
+            }
+
+            let lba = Msf::from_bcd(min, sec, frame).lba().with_context(|| {
+                format!("sbi entry in lead-in {:02x}:{:02x}:{:02x}", min, sec, frame)
+            })?;
+            patches.insert(lba, (offset, tail[..len].to_vec()));
+
+            rest = &tail[len..];
+        }
+
+        Ok(Sbi { patches })
+    }
+
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    // 置き換えた Q のCRCは元のままなので合わなくなる
+    pub fn apply(&self, lba: u32, subq: SubQ) -> SubQ {
+        match self.patches.get(&lba) {
+            Some((offset, bytes)) => {
+                let mut data = subq.data;
+                data[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+
+                SubQ {
+                    data,
+                    crc: subq.crc,
+                }
+            }
+            None => subq,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_detects_changes() {
+        // トラック1 インデックス1 00:00:00 / 00:02:00
+        let subq = SubQ::new([0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+
+        assert!(subq.crc_valid());
+        assert_ne!(
+            subq.crc,
+            SubQ::new([0x41, 0x01, 0x01, 0, 0, 1, 0, 0, 2, 1]).crc
+        );
+    }
+
+    #[test]
+    fn sbi_patches_break_crc() {
+        let mut bytes = b"SBI\0".to_vec();
+        // 00:02:05 を丸ごと、00:02:07 は絶対位置だけ
+        bytes.extend([0x00, 0x02, 0x05, 1]);
+        bytes.extend([0x41, 0x01, 0x01, 0x00, 0x00, 0x15, 0x00, 0x00, 0x02, 0x25]);
+        bytes.extend([0x00, 0x02, 0x07, 3, 0x00, 0x02, 0x27]);
+
+        let sbi = Sbi::parse(&bytes).unwrap();
+        assert_eq!(sbi.len(), 2);
+
+        let subq = SubQ::new([0x41, 0x01, 0x01, 0x00, 0x00, 0x07, 0x00, 0x00, 0x02, 0x07]);
+        let patched = sbi.apply(7, subq);
+        assert_eq!(patched.data[7..10], [0x00, 0x02, 0x27]);
+        assert!(!patched.crc_valid());
+
+        assert_eq!(sbi.apply(6, subq), subq);
+
+        assert!(Sbi::parse(b"SBI").is_err());
+        assert!(Sbi::parse(b"SBI\0\x00\x02\x05\x01\x41").is_err());
+        assert!(Sbi::parse(b"SBI\0\x00\x02\x05\x09").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+// XA-ADPCM (モード2 フォーム2 の音声セクタ) のデコード
+
+use crate::adpcm::Header;
+
+// 音声セクタのサブモード (音声, フォーム2, リアルタイム)
+pub const SUBMODE_AUDIO: u8 = 0x64;
+
+// 出力は CD-DA と同じ 44.1kHz
+pub const OUTPUT_RATE: u32 = 44100;
+
+// 1セクタのサウンドグループ数と大きさ
+const GROUPS: usize = 18;
+const GROUP_SIZE: usize = 128;
+// 1サウンドユニットのサンプル数
+const UNIT_SAMPLES: usize = 28;
+// 生セクタの中のデータの先頭 (サブヘッダの後ろ)
+const DATA_OFFSET: usize = 24;
+
+// サブヘッダのコーディング情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coding {
+    pub stereo: bool,
+    // 18900Hz。そうでなければ 37800Hz
+    pub half_rate: bool,
+    pub eight_bit: bool,
+}
+
+impl Coding {
+    pub fn new(val: u8) -> Coding {
+        Coding {
+            stereo: val & 0x03 == 0x01,
+            half_rate: val & 0x0C == 0x04,
+            eight_bit: val & 0x30 == 0x10,
+        }
+    }
+
+    pub fn rate(self) -> u32 {
+        match self.half_rate {
+            true => 18900,
+            false => 37800,
+        }
+    }
+}
+
+pub struct Decoder {
+    // チャンネルごとの直前の2サンプル
+    history: [[i16; 2]; 2],
+    resampler: Resampler,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            history: [[0; 2]; 2],
+            resampler: Resampler::new(),
+        }
+    }
+
+    // 生セクタをデコードして 44.1kHz のステレオで返す
+    pub fn decode_sector(&mut self, raw: &[u8]) -> Vec<[i16; 2]> {
+        let coding = Coding::new(raw[19]);
+        let samples = self.decode(coding, &raw[DATA_OFFSET..DATA_OFFSET + GROUPS * GROUP_SIZE]);
+
+        let mut out = vec![];
+        self.resampler.push(coding.rate(), &samples, &mut out);
+
+        out
+    }
+
+    // 元のサンプリング周波数のまま。モノラルは両チャンネルに同じ値を入れる
+    pub fn decode(&mut self, coding: Coding, data: &[u8]) -> Vec<[i16; 2]> {
+        let units = match coding.eight_bit {
+            true => 4,
+            false => 8,
+        };
+
+        let mut channels: [Vec<i16>; 2] = [vec![], vec![]];
+
+        for group in data.chunks_exact(GROUP_SIZE) {
+            for unit in 0..units {
+                // ステレオでは偶数番目のユニットが左、奇数番目が右
+                let channel = match coding.stereo {
+                    true => unit & 1,
+                    false => 0,
+                };
+                let header = Header::new(group[4 + unit], 0x03);
+
+                let samples = (0..UNIT_SAMPLES).map(|i| {
+                    let byte = group[16 + i * 4 + unit / (units / 4)];
+                    // 上位に詰めた 16bit にする。下位ニブルが偶数番目のユニット
+                    match coding.eight_bit {
+                        true => ((byte as u16) << 8) as i16,
+                        false => (((byte >> ((unit & 1) * 4)) as u16) << 12) as i16,
+                    }
+                });
+
+                let history = &mut self.history[channel];
+                channels[channel].extend(samples.map(|sample| header.decode(sample, history)));
+            }
+        }
+
+        match coding.stereo {
+            true => channels[0]
+                .iter()
+                .zip(&channels[1])
+                .map(|(&left, &right)| [left, right])
+                .collect(),
+            false => channels[0].iter().map(|&sample| [sample, sample]).collect(),
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+// 37800Hz / 18900Hz から 44.1kHz への線形補間
+struct Resampler {
+    // 次に出力する位置。入力1サンプルの間を OUTPUT_RATE に分けた単位
+    phase: u32,
+    last: [i16; 2],
+}
+
+impl Resampler {
+    fn new() -> Resampler {
+        Resampler {
+            phase: 0,
+            last: [0; 2],
+        }
+    }
+
+    fn push(&mut self, rate: u32, input: &[[i16; 2]], out: &mut Vec<[i16; 2]>) {
+        for &sample in input {
+            while self.phase < OUTPUT_RATE {
+                let t = self.phase as i32;
+                let lerp = |a: i16, b: i16| {
+                    (a as i32 + (b as i32 - a as i32) * t / OUTPUT_RATE as i32) as i16
+                };
+                out.push([lerp(self.last[0], sample[0]), lerp(self.last[1], sample[1])]);
+
+                self.phase += rate;
+            }
+
+            self.phase -= OUTPUT_RATE;
+            self.last = sample;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 全ユニットが同じヘッダとデータのサウンドグループ
+    fn group(header: u8, byte: u8) -> Vec<u8> {
+        let mut group = vec![header; 16];
+        group.resize(GROUP_SIZE, byte);
+        group
+    }
+
+    #[test]
+    fn decodes_4bit_mono_with_filter() {
+        let mut decoder = Decoder::new();
+        let coding = Coding::new(0x00);
+
+        // シフト0, フィルタ1。ニブルはどれも1
+        let samples = decoder.decode(coding, &group(0x10, 0x11));
+
+        assert_eq!(samples.len(), 8 * UNIT_SAMPLES);
+        assert_eq!(samples[0], [4096, 4096]);
+        assert!(samples[1][0] > samples[0][0]);
+        assert!(samples.iter().all(|sample| sample[0] > 0));
+    }
+
+    #[test]
+    fn decodes_stereo_units_into_channels() {
+        let mut decoder = Decoder::new();
+        let coding = Coding::new(0x01);
+        assert!(coding.stereo);
+
+        // 左 (下位ニブル) は 1、右 (上位ニブル) は -1。シフト4
+        let samples = decoder.decode(coding, &group(0x04, 0xF1));
+
+        assert_eq!(samples.len(), 4 * UNIT_SAMPLES);
+        assert_eq!(samples[0], [256, -256]);
+    }
+
+    #[test]
+    fn resamples_to_output_rate() {
+        let mut resampler = Resampler::new();
+        let mut out = vec![];
+
+        resampler.push(37800, &[[600, -600]; 6 * 100], &mut out);
+        assert_eq!(out.len(), 7 * 100);
+        assert_eq!(out[out.len() - 1], [600, -600]);
+
+        out.clear();
+        resampler.push(18900, &[[0, 0]; 3 * 100], &mut out);
+        assert_eq!(out.len(), 7 * 100);
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::{bail, Result};
+use log::warn;
+
+use crate::{bios::Bios, cdrom::image::Image, gpu::VMode, memcard::MemoryCard};
+
+pub const RAM_SIZE_RETAIL: usize = 2 * 1024 * 1024;
+pub const RAM_SIZE_DEVELOPMENT: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Japan,
+    NorthAmerica,
+    Europe,
+}
+
+impl Region {
+    // GetIDで返されるライセンス文字列
+    pub fn license(self) -> &'static [u8; 4] {
+        match self {
+            Region::Japan => b"SCEI",
+            Region::NorthAmerica => b"SCEA",
+            Region::Europe => b"SCEE",
+        }
+    }
+
+    // 電源投入時の映像の方式。ヨーロッパだけ PAL
+    pub fn video_mode(self) -> VMode {
+        match self {
+            Region::Europe => VMode::Pal,
+            _ => VMode::Ntsc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accuracy {
+    // メモリアクセスのストールを無視する
+    Fast,
+    Accurate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    None,
+    DigitalPad,
+    // アナログスティックと振動モーター付き (スティックは常に中央)
+    DualShock,
+}
+
+// 電源投入時のRAM・スクラッチパッド・CPUレジスタの中身
+// 実機では不定だが、初期値に依存してしまっているゲームがある
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerOnState {
+    Zeros,
+    // 固定シードの疑似乱数 (実行ごとに同じ)
+    Garbage,
+    // RAMは0xCA、レジスタは0xDEADBEEFで埋める (デフォルト)
+    Pattern,
+}
+
+impl PowerOnState {
+    pub fn fill_bytes(self, buf: &mut [u8]) {
+        match self {
+            PowerOnState::Zeros => buf.fill(0),
+            PowerOnState::Garbage => {
+                let mut rng = XorShift(0x2545F491);
+                buf.fill_with(|| rng.next() as u8);
+            }
+            PowerOnState::Pattern => buf.fill(0xCA),
+        }
+    }
+
+    pub fn fill_words(self, buf: &mut [u32]) {
+        match self {
+            PowerOnState::Zeros => buf.fill(0),
+            PowerOnState::Garbage => {
+                let mut rng = XorShift(0x9E3779B9);
+                buf.fill_with(|| rng.next());
+            }
+            PowerOnState::Pattern => buf.fill(0xDEADBEEF),
+        }
+    }
+}
+
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+
+        x
+    }
+}
+
+pub enum BootMode {
+    Bios,
+    // シェルを飛ばしてディスクのEXEを直接起動する
+    FastBoot,
+    // 指定したEXEを直接起動する
+    Sideload(Vec<u8>),
+}
+
+pub struct MachineConfig {
+    pub region: Region,
+    pub ram_size: usize,
+    pub accuracy: Accuracy,
+    pub devices: [Device; 2],
+    pub memory_cards: [Option<MemoryCard>; 2],
+    pub disc: Option<Image>,
+    pub bios: Bios,
+    pub boot: BootMode,
+    pub power_on: PowerOnState,
+    // 読んだデータセクタの EDC/ECC を確かめる
+    pub verify_sectors: bool,
+}
+
+impl MachineConfig {
+    pub fn new(bios: Bios) -> MachineConfig {
+        MachineConfig {
+            region: Region::Japan,
+            ram_size: RAM_SIZE_RETAIL,
+            accuracy: Accuracy::Accurate,
+            devices: [Device::DigitalPad, Device::None],
+            memory_cards: [None, None],
+            disc: None,
+            bios,
+            boot: BootMode::Bios,
+            power_on: PowerOnState::Pattern,
+            verify_sectors: false,
+        }
+    }
+
+    // ディスクのライセンス文字列、なければBIOSのバージョン文字列から地域を決める
+    // どちらもわからなければそのまま
+    pub fn detect_region(&mut self) {
+        let disc = self.disc.as_ref().and_then(Image::region);
+        let bios = self.bios.region();
+
+        if let (Some(disc), Some(bios)) = (disc, bios) {
+            if disc != bios {
+                warn!(
+                    "Disc region {:?} does not match BIOS region {:?}",
+                    disc, bios
+                );
+            }
+        }
+
+        if let Some(region) = disc.or(bios) {
+            self.region = region;
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.ram_size != RAM_SIZE_RETAIL && self.ram_size != RAM_SIZE_DEVELOPMENT {
+            bail!("Invalid RAM size: {} bytes", self.ram_size);
+        }
+
+        if let Some(disc) = &self.disc {
+            if disc.is_empty() {
+                bail!("Disc image is empty");
+            }
+        }
+
+        if let BootMode::FastBoot = self.boot {
+            if self.disc.is_none() {
+                bail!("Fast boot requires a disc");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,23 @@
-use std::{thread, time::Duration};
+use std::{cmp::Reverse, ops::Range, thread, time::Duration};
 
 use log::{debug, info, trace, warn};
 
-use crate::{addressible::Addressible, gte::Gte, interconnect::Interconnect};
+use anyhow::Result;
 
-use super::{instruction::Instruction, RegisterIndex};
+use crate::{
+    addressible::Addressible,
+    config::{Accuracy, PowerOnState},
+    exe::Exe,
+    gte::Gte,
+    interconnect::{map, Interconnect},
+    savestate::{Reader, Savestate, Writer},
+};
+
+use super::{
+    instruction::Instruction,
+    printf::{self, Args},
+    RegisterIndex,
+};
 
 pub enum RunEvent {
     IncomingData,
@@ -18,6 +31,21 @@ pub enum Event {
     Break,
     WatchWrite(u32),
     WatchRead(u32),
+    WriteProtected(u32),
+}
+
+impl Event {
+    // 同じステップで複数起きた場合は大きいものを報告する
+    fn priority(self) -> u8 {
+        match self {
+            Event::DoneStep => 0,
+            Event::WatchRead(_) => 1,
+            Event::WatchWrite(_) => 2,
+            Event::WriteProtected(_) => 3,
+            Event::Break => 4,
+            Event::Halted => 5,
+        }
+    }
 }
 
 pub enum ExecMode {
@@ -64,17 +92,38 @@ pub struct Cpu {
     pub exec_mode: ExecMode,
     pub breakpoints: Vec<u32>,
     pub watchpoints: Vec<u32>,
-    event: Option<Event>,
+    // ゲームから書き込めない物理アドレスの範囲 (デバッグ用)
+    pub write_protected: Vec<Range<u32>>,
+    // 1ステップの間に起きたイベント
+    events: Vec<Event>,
 
     tty_buffer: String,
+    // std_out_putchar / std_out_puts で出た全文 (テストROMの判定用)
+    tty_output: String,
+
+    // 直近に実行した命令のアドレス (クラッシュ時の調査用)
+    trace: [u32; TRACE_LEN],
+    trace_pos: usize,
+
+    // シェルのエントリポイントに到達したら起動するEXE
+    pub(crate) boot_exe: Option<Exe>,
 }
 
+const TRACE_LEN: usize = 256;
+
+// BIOSがカーネルの初期化を終えてシェルを呼ぶアドレス
+const SHELL_ENTRY: u32 = 0x80030000;
+
 impl Cpu {
-    pub fn new(inter: Interconnect) -> Cpu {
-        let mut regs = [0xDEADBEEFu32; 32];
+    pub fn new(inter: Interconnect, power_on: PowerOnState) -> Cpu {
+        let mut regs = [0; 32];
+        power_on.fill_words(&mut regs);
 
         regs[0] = 0;
 
+        let mut hi_lo = [0; 2];
+        power_on.fill_words(&mut hi_lo);
+
         let pc = 0xbfc00000;
 
         Cpu {
@@ -85,8 +134,8 @@ impl Cpu {
             inter,
             load: (RegisterIndex(0), 0),
             sr: 0,
-            hi: 0xDEADBEEFu32,
-            lo: 0xDEADBEEFu32,
+            hi: hi_lo[0],
+            lo: hi_lo[1],
             current_pc: 0,
             cause: 0,
             epc: 0,
@@ -96,9 +145,14 @@ impl Cpu {
             exec_mode: ExecMode::Continue,
             breakpoints: vec![],
             watchpoints: vec![],
-            event: None,
+            write_protected: vec![],
+            events: Vec::with_capacity(4),
             tty_buffer: String::new(),
+            tty_output: String::new(),
+            trace: [0; TRACE_LEN],
+            trace_pos: 0,
             stalls: 0,
+            boot_exe: None,
         }
     }
 
@@ -175,24 +229,35 @@ impl Cpu {
             thread::sleep(Duration::from_secs(3));
         }
 
-        self.event = None;
+        self.events.clear();
 
         self.inter.tick();
 
         if self.stalls > 0 {
             self.stalls -= 1;
 
-            return self.event;
+            return self.reported_event();
+        }
+
+        if self.pc == SHELL_ENTRY {
+            if let Some(exe) = self.boot_exe.take() {
+                self.sideload(exe);
+            }
         }
 
         self.current_pc = self.pc;
 
+        self.trace[self.trace_pos] = self.current_pc;
+        self.trace_pos = (self.trace_pos + 1) % TRACE_LEN;
+
         if self.current_pc % 4 != 0 {
             self.exception(Exception::LoadAddressError);
-            return Some(self.event.unwrap_or(Event::DoneStep));
+            return Some(self.reported_event().unwrap_or(Event::DoneStep));
         }
 
-        self.stalls += 4; // TODO: cacheの考慮
+        if self.inter.accuracy == Accuracy::Accurate {
+            self.stalls += 4; // TODO: cacheの考慮
+        }
         let instruction = Instruction(self.load::<u32>(self.pc));
 
         self.pc = self.next_pc;
@@ -217,41 +282,117 @@ impl Cpu {
 
         if self.breakpoints.contains(&self.pc) {
             debug!("BREAK {:08x}", self.pc);
-            self.event = Some(Event::Break);
-            return self.event;
+            self.raise(Event::Break);
+            return self.reported_event();
         }
 
         // if !self.breakpoints.is_empty() {
         //     debug!("PC: {:08x}, instr: {:08x}", self.current_pc, instruction);
         // }
 
-        return Some(self.event.unwrap_or(Event::DoneStep));
+        return Some(self.reported_event().unwrap_or(Event::DoneStep));
+    }
+
+    fn raise(&mut self, event: Event) {
+        if !self.events.contains(&event) {
+            self.events.push(event);
+        }
+    }
+
+    // 優先度が同じなら先に起きたもの
+    fn reported_event(&self) -> Option<Event> {
+        self.events
+            .iter()
+            .copied()
+            .min_by_key(|event| Reverse(event.priority()))
+    }
+
+    // 直前のステップで起きた全てのイベント
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    fn sideload(&mut self, exe: Exe) {
+        info!(
+            "sideload EXE pc: {:08x}, load: {:08x}, size: {:08x}",
+            exe.pc,
+            exe.load_addr,
+            exe.data.len()
+        );
+
+        for (i, &b) in exe.data.iter().enumerate() {
+            self.put(exe.load_addr.wrapping_add(i as u32), b);
+        }
+
+        self.regs[28] = exe.gp;
+        if exe.sp != 0 {
+            self.regs[29] = exe.sp;
+            self.regs[30] = exe.sp;
+        }
+        self.out_regs = self.regs;
+
+        self.set_pc(exe.pc);
     }
 
     pub fn pc(&self) -> u32 {
         self.pc
     }
 
+    // 分岐の遅延スロットを持たない状態で指定したアドレスから実行する
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.next_pc = pc.wrapping_add(4);
+    }
+
+    // 古い順
+    pub fn trace(&self) -> impl Iterator<Item = u32> + '_ {
+        self.trace[self.trace_pos..]
+            .iter()
+            .chain(&self.trace[..self.trace_pos])
+            .copied()
+    }
+
+    pub fn tty_output(&self) -> &str {
+        &self.tty_output
+    }
+
+    // 直前の命令が自分自身への分岐 (テストROMの終了時など)
+    pub fn is_spinning(&self) -> bool {
+        self.branch && self.next_pc == self.current_pc
+    }
+
     pub fn load<T: Addressible>(&mut self, addr: u32) -> T {
         if self.watchpoints.contains(&addr) {
-            self.event = Some(Event::WatchRead(addr));
+            self.raise(Event::WatchRead(addr));
         }
-        if addr == 0x1F801800 {
+        if addr == map::CDROM.start {
             debug!("CD-ROM Status read at {:08x}", self.current_pc);
         }
-        self.stalls += 2;
+        if self.inter.accuracy == Accuracy::Accurate {
+            self.stalls += 2;
+        }
         self.inter.load(addr)
     }
 
     pub fn store<T: Addressible>(&mut self, addr: u32, val: T) {
         if self.watchpoints.contains(&addr) {
-            self.event = Some(Event::WatchWrite(addr));
+            self.raise(Event::WatchWrite(addr));
         }
         if self.sr & 0x10000 != 0 {
             // warn!("Ignoring store while cache is isolated");
             return;
         }
-        if addr == 0x1F801801 {
+        if self.is_write_protected(addr, T::width() as u32) {
+            warn!(
+                "Write to protected address {:08x} ({:08x}) at {:08x}",
+                addr,
+                val.as_u32(),
+                self.current_pc
+            );
+            self.raise(Event::WriteProtected(addr));
+            return;
+        }
+        if addr == map::CDROM.start + 1 {
             debug!(
                 "CD-ROM Command send: {:01x} at {:08x}",
                 val.as_u32() as u8,
@@ -261,6 +402,23 @@ impl Cpu {
         self.inter.store(addr, val)
     }
 
+    // 書き込みの範囲が保護範囲と少しでも重なるか
+    fn is_write_protected(&self, addr: u32, width: u32) -> bool {
+        let start = map::mask_region(addr);
+        let end = start.saturating_add(width);
+
+        self.write_protected
+            .iter()
+            .any(|range| start < range.end && range.start < end)
+    }
+
+    // KSEG0/KSEG1 のアドレスも物理アドレスとして登録する
+    pub fn protect(&mut self, addr: u32, len: u32) {
+        let start = map::mask_region(addr);
+
+        self.write_protected.push(start..start.saturating_add(len));
+    }
+
     pub fn examine<T: Addressible>(&mut self, addr: u32) -> T {
         self.inter.load(addr)
     }
@@ -432,12 +590,10 @@ impl Cpu {
                     "BIOS A InitHeap addr: {:08x}, size: {:08x}",
                     self.regs[4], self.regs[5]
                 ),
-                0x3F => debug!(
-                    "BIOS A printf txt: {}, param1: {}, param2: {}",
-                    self.debug_string(self.regs[4]),
-                    self.regs[5],
-                    self.regs[6]
-                ),
+                0x3F => {
+                    let fmt = self.debug_string(self.regs[4]);
+                    debug!("BIOS A printf {}", printf::format(&fmt, self));
+                }
                 0x44 => debug!("BIOS A FlushCache"),
                 0x49 => debug!("BIOS A GPU_cw gp0cmd: {:08x}", self.regs[4]),
                 0x4A => debug!(
@@ -483,6 +639,7 @@ impl Cpu {
                 0x3D => {
                     let c = (self.regs[4] as u8) as char;
                     debug!("BIOS B std_out_putchar {}", c);
+                    self.tty_output.push(c);
 
                     if c as u8 == 0x0A {
                         info!("STDOUT: {}", self.tty_buffer);
@@ -492,7 +649,10 @@ impl Cpu {
                     }
                 }
                 0x3F => {
-                    debug!("BIOS B std_out_puts {}", self.debug_string(self.regs[4]));
+                    let s = self.debug_string(self.regs[4]);
+                    debug!("BIOS B std_out_puts {}", s);
+                    self.tty_output.push_str(&s);
+                    self.tty_output.push('\n');
                 }
                 0x47 => debug!("BIOS B AddDevice device_info: {:08x}", self.regs[4]),
                 0x5B => debug!("BIOS B ChangeClearPad int: {:08x}", self.regs[4]),
@@ -892,7 +1052,7 @@ impl Cpu {
     }
 
     fn op_break(&mut self, _: Instruction) {
-        self.event = Some(Event::Break);
+        self.raise(Event::Break);
         self.exception(Exception::Break);
     }
 
@@ -1456,3 +1616,127 @@ impl Cpu {
         self.exception(Exception::IllegalInstruction);
     }
 }
+
+impl Savestate for Cpu {
+    fn save_state(&self, w: &mut Writer) {
+        w.u32(self.pc);
+        w.u32(self.next_pc);
+        w.u32(self.current_pc);
+        for (reg, out_reg) in self.regs.iter().zip(self.out_regs.iter()) {
+            w.u32(*reg);
+            w.u32(*out_reg);
+        }
+        w.u32(self.load.0 .0);
+        w.u32(self.load.1);
+        w.bool(self.branch);
+        w.bool(self.delay_slot);
+        w.u16(self.stalls);
+        w.u32(self.hi);
+        w.u32(self.lo);
+        w.u32(self.sr);
+        w.u32(self.cause);
+        w.u32(self.epc);
+        self.gte.save_state(w);
+
+        self.inter.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.pc = r.u32()?;
+        self.next_pc = r.u32()?;
+        self.current_pc = r.u32()?;
+        for i in 0..32 {
+            self.regs[i] = r.u32()?;
+            self.out_regs[i] = r.u32()?;
+        }
+        self.load = (RegisterIndex(r.u32()? & 0x1F), r.u32()?);
+        self.branch = r.bool()?;
+        self.delay_slot = r.bool()?;
+        self.stalls = r.u16()?;
+        self.hi = r.u32()?;
+        self.lo = r.u32()?;
+        self.sr = r.u32()?;
+        self.cause = r.u32()?;
+        self.epc = r.u32()?;
+        self.gte.load_state(r)?;
+
+        self.inter.load_state(r)
+    }
+}
+
+// printf の引数は書式の後ろの a1-a3 から、残りはスタックから読む
+// 呼び出し側はスタックに a0-a3 の分の領域を取っているので、5つ目は sp+16 にある
+impl Args for Cpu {
+    fn word(&mut self, index: usize) -> u32 {
+        let position = index + 1;
+
+        if position < 4 {
+            self.regs[4 + position]
+        } else {
+            // ログのための読み出しなので、ストールやウォッチポイントを起こさない
+            let addr = self.regs[29].wrapping_add(position as u32 * 4);
+            self.inter.peek::<u32>(addr).unwrap_or(0)
+        }
+    }
+
+    fn string(&mut self, addr: u32) -> String {
+        self.debug_string(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestMachineBuilder;
+
+    // sw $zero, 0x1000($zero) で 0xAAAAAAAA を消す
+    fn store_machine() -> Cpu {
+        TestMachineBuilder::new()
+            .ram(0x1000, &[0xAA; 4])
+            .program(0x80010000, &[0xAC001000, 0x00000000])
+            .build()
+    }
+
+    // ストールの間は何も起きないので、最初の命令を実行し終えるまで回す
+    fn step_store(cpu: &mut Cpu) -> Option<Event> {
+        for _ in 0..100 {
+            let event = cpu.step();
+            if cpu.pc() != 0x80010000 {
+                return event;
+            }
+        }
+
+        panic!("the store was never executed");
+    }
+
+    #[test]
+    fn reports_highest_priority_event_of_a_step() {
+        let mut cpu = store_machine();
+        cpu.watchpoints.push(0x1000);
+        cpu.protect(0x1000, 4);
+        cpu.breakpoints.push(0x80010004);
+
+        assert_eq!(step_store(&mut cpu), Some(Event::Break));
+        assert_eq!(
+            cpu.events(),
+            [
+                Event::WatchWrite(0x1000),
+                Event::WriteProtected(0x1000),
+                Event::Break
+            ]
+        );
+
+        // ブレークポイントがなければ書き込み保護が勝つ
+        let mut cpu = store_machine();
+        cpu.watchpoints.push(0x1000);
+        cpu.protect(0x1000, 4);
+
+        assert_eq!(step_store(&mut cpu), Some(Event::WriteProtected(0x1000)));
+        assert_eq!(
+            cpu.events(),
+            [Event::WatchWrite(0x1000), Event::WriteProtected(0x1000)]
+        );
+        // 保護されていたので書き込まれない
+        assert_eq!(cpu.examine::<u32>(0x1000), 0xAAAAAAAA);
+    }
+}
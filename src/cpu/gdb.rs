@@ -1,4 +1,7 @@
 use super::cpu::{Cpu, ExecMode};
+use super::RegisterIndex;
+use crate::gte::{CONTROL_REGISTER_NAMES, DATA_REGISTER_NAMES};
+use crate::interconnect::map;
 
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccess;
 use gdbstub::target::ext::base::singlethread::SingleThreadBase;
@@ -12,6 +15,7 @@ use gdbstub::target::ext::host_io::{
     HostIoSetfs, HostIoStat, HostIoUnlink,
 };
 use gdbstub::target::ext::memory_map::MemoryMap;
+use gdbstub::target::ext::monitor_cmd::{outputln, ConsoleOutput, MonitorCmd};
 use gdbstub::target::{self, Target, TargetError, TargetResult};
 use gdbstub_arch::mips;
 use log::debug;
@@ -64,6 +68,11 @@ impl Target for Cpu {
         Some(self)
     }
 
+    #[inline(always)]
+    fn support_monitor_cmd(&mut self) -> Option<target::ext::monitor_cmd::MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
+
     fn guard_rail_single_step_gdb_behavior(&self) -> gdbstub::arch::SingleStepGdbBehavior {
         gdbstub::arch::SingleStepGdbBehavior::Optional
     }
@@ -224,16 +233,146 @@ impl MemoryMap for Cpu {
         length: usize,
         buf: &mut [u8],
     ) -> TargetResult<usize, Self> {
-        let memory_map = r#"<?xml version="1.0"?>
-<!DOCTYPE memory-map
-    PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN"
-            "http://sourceware.org/gdb/gdb-memory-map.dtd">
-<memory-map>
-    <memory type="ram" start="0x0" length="0x100000000"/>
-</memory-map>"#
-            .trim()
-            .as_bytes();
-        Ok(copy_range_to_buf(memory_map, offset, length, buf))
+        let memory_map = map::gdb_memory_map(self.inter.ram_size());
+        Ok(copy_range_to_buf(
+            memory_map.as_bytes(),
+            offset,
+            length,
+            buf,
+        ))
+    }
+}
+
+// monitor gte                       全レジスタの表示
+// monitor gte data|ctrl <reg>       レジスタの読み込み
+// monitor gte data|ctrl <reg> <val> レジスタの書き込み
+// monitor protect                   書き込み保護の一覧
+// monitor protect <addr> <len>      書き込み保護の追加
+// monitor unprotect                 書き込み保護の解除
+// monitor map                       メモリマップの表示
+// monitor map <addr>                アドレスの属する範囲
+impl MonitorCmd for Cpu {
+    fn handle_monitor_cmd(
+        &mut self,
+        cmd: &[u8],
+        mut out: ConsoleOutput<'_>,
+    ) -> Result<(), Self::Error> {
+        let cmd = match std::str::from_utf8(cmd) {
+            Ok(cmd) => cmd,
+            Err(_) => {
+                outputln!(out, "command must be valid UTF-8");
+                return Ok(());
+            }
+        };
+
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+
+        match args.as_slice() {
+            ["gte"] => {
+                for (i, name) in DATA_REGISTER_NAMES.iter().enumerate() {
+                    let val: u32 = self.gte.load_data(RegisterIndex(i as u32));
+                    outputln!(out, "data {:2} {:<8} {:08x}", i, name, val);
+                }
+                for (i, name) in CONTROL_REGISTER_NAMES.iter().enumerate() {
+                    let val: u32 = self.gte.load_control(RegisterIndex(i as u32));
+                    outputln!(out, "ctrl {:2} {:<8} {:08x}", i, name, val);
+                }
+            }
+            ["gte", "view"] => {
+                for line in self.gte.to_string().lines() {
+                    outputln!(out, "{}", line);
+                }
+            }
+            ["gte", bank, reg] => match parse_gte_register(bank, reg) {
+                Some((true, index)) => {
+                    let val: u32 = self.gte.load_control(index);
+                    outputln!(out, "{:08x}", val);
+                }
+                Some((false, index)) => {
+                    let val: u32 = self.gte.load_data(index);
+                    outputln!(out, "{:08x}", val);
+                }
+                None => outputln!(out, "unknown GTE register {} {}", bank, reg),
+            },
+            ["gte", bank, reg, val] => {
+                let val = match parse_number(val) {
+                    Some(val) => val,
+                    None => {
+                        outputln!(out, "invalid value {}", val);
+                        return Ok(());
+                    }
+                };
+
+                match parse_gte_register(bank, reg) {
+                    Some((true, index)) => self.gte.store_control(index, val),
+                    Some((false, index)) => self.gte.store_data(index, val),
+                    None => outputln!(out, "unknown GTE register {} {}", bank, reg),
+                }
+            }
+            ["protect"] => {
+                for range in &self.write_protected {
+                    outputln!(out, "{:08x}-{:08x}", range.start, range.end);
+                }
+            }
+            ["protect", addr, len] => match (parse_number(addr), parse_number(len)) {
+                (Some(addr), Some(len)) => self.protect(addr, len),
+                _ => outputln!(out, "invalid range {} {}", addr, len),
+            },
+            ["unprotect"] => self.write_protected.clear(),
+            ["map"] => {
+                for range in map::RANGES {
+                    outputln!(
+                        out,
+                        "{:08x}-{:08x} {:<12} {:?}",
+                        range.start,
+                        range.start.wrapping_add(range.length),
+                        range.name,
+                        range.kind
+                    );
+                }
+            }
+            ["map", addr] => match parse_number(addr) {
+                Some(addr) => outputln!(out, "{}", map::describe(addr)),
+                None => outputln!(out, "invalid address {}", addr),
+            },
+            _ => {
+                outputln!(out, "usage:");
+                outputln!(out, "  monitor gte");
+                outputln!(out, "  monitor gte view");
+                outputln!(out, "  monitor gte data|ctrl <reg>");
+                outputln!(out, "  monitor gte data|ctrl <reg> <value>");
+                outputln!(out, "  monitor protect");
+                outputln!(out, "  monitor protect <addr> <len>");
+                outputln!(out, "  monitor unprotect");
+                outputln!(out, "  monitor map");
+                outputln!(out, "  monitor map <addr>");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// (control?, index)
+fn parse_gte_register(bank: &str, reg: &str) -> Option<(bool, RegisterIndex)> {
+    let (control, names) = match bank {
+        "data" => (false, &DATA_REGISTER_NAMES),
+        "ctrl" => (true, &CONTROL_REGISTER_NAMES),
+        _ => return None,
+    };
+
+    let index = match names.iter().position(|name| name.eq_ignore_ascii_case(reg)) {
+        Some(index) => index as u32,
+        None => parse_number(reg).filter(|&index| index < 32)?,
+    };
+
+    Some((control, RegisterIndex(index)))
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
     }
 }
 
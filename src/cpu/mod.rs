@@ -4,3 +4,4 @@ pub struct RegisterIndex(pub u32);
 pub mod cpu;
 pub mod gdb;
 mod instruction;
+mod printf;
@@ -0,0 +1,295 @@
+// BIOSのprintf (A(0x3F)) の書式を展開してログに出す
+// %d/%i/%u/%x/%X/%o/%c/%s/%p/%% と、フラグ (-0+ #)、幅、精度、長さ (h/l) に対応する
+
+// 書式の後ろの引数を読む
+pub trait Args {
+    // index 番目の引数 (書式の次が0)
+    fn word(&mut self, index: usize) -> u32;
+    // %s の指す文字列
+    fn string(&mut self, addr: u32) -> String;
+}
+
+// 幅と精度はゲームのメモリから来るので、巨大な値で確保しすぎないように抑える
+const MAX_WIDTH: usize = 256;
+
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+pub fn format<A: Args>(fmt: &str, args: &mut A) -> String {
+    let mut out = String::new();
+    let mut chars = fmt.chars().peekable();
+    let mut index = 0;
+
+    let mut next = |args: &mut A| {
+        let word = args.word(index);
+        index += 1;
+        word
+    };
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut spec = Spec::default();
+
+        while let Some(&c) = chars.peek() {
+            match c {
+                '-' => spec.left = true,
+                '0' => spec.zero = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alt = true,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        if chars.peek() == Some(&'*') {
+            chars.next();
+            let width = next(args) as i32;
+            spec.left |= width < 0;
+            spec.width = (width.unsigned_abs() as usize).min(MAX_WIDTH);
+        } else {
+            spec.width = digits(&mut chars).min(MAX_WIDTH);
+        }
+
+        if chars.peek() == Some(&'.') {
+            chars.next();
+
+            spec.precision = if chars.peek() == Some(&'*') {
+                chars.next();
+                // 負の精度は指定がないのと同じ
+                let precision = next(args) as i32;
+                (precision >= 0).then(|| (precision as usize).min(MAX_WIDTH))
+            } else {
+                Some(digits(&mut chars).min(MAX_WIDTH))
+            };
+        }
+
+        // 32bit機なので長さの指定は読み飛ばすだけ
+        while matches!(chars.peek(), Some('h' | 'l')) {
+            chars.next();
+        }
+
+        let (prefix, body) = match chars.next() {
+            Some('%') => {
+                out.push('%');
+                continue;
+            }
+            Some('d' | 'i') => {
+                let val = next(args) as i32;
+
+                let sign = if val < 0 {
+                    "-"
+                } else if spec.plus {
+                    "+"
+                } else if spec.space {
+                    " "
+                } else {
+                    ""
+                };
+
+                (sign, number(val.unsigned_abs().to_string(), &spec))
+            }
+            Some('u') => ("", number(next(args).to_string(), &spec)),
+            Some('x') => {
+                let val = next(args);
+                let prefix = if spec.alt && val != 0 { "0x" } else { "" };
+                (prefix, number(format!("{:x}", val), &spec))
+            }
+            Some('X') => {
+                let val = next(args);
+                let prefix = if spec.alt && val != 0 { "0X" } else { "" };
+                (prefix, number(format!("{:X}", val), &spec))
+            }
+            Some('o') => {
+                let val = next(args);
+                let prefix = if spec.alt && val != 0 { "0" } else { "" };
+                (prefix, number(format!("{:o}", val), &spec))
+            }
+            Some('p') => ("", format!("{:08x}", next(args))),
+            Some('c') => ("", ((next(args) as u8) as char).to_string()),
+            Some('s') => {
+                let addr = next(args);
+                let mut s = args.string(addr);
+                if let Some(precision) = spec.precision {
+                    s = s.chars().take(precision).collect();
+                }
+                // 文字列では0埋めしない
+                spec.zero = false;
+                ("", s)
+            }
+            // 知らない変換はそのまま出す
+            Some(c) => {
+                out.push('%');
+                out.push(c);
+                continue;
+            }
+            None => {
+                out.push('%');
+                break;
+            }
+        };
+
+        pad(&mut out, prefix, &body, &spec);
+    }
+
+    out
+}
+
+fn digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> usize {
+    let mut n: usize = 0;
+
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        n = n.saturating_mul(10).saturating_add(d as usize);
+        chars.next();
+    }
+
+    n
+}
+
+// 数値の精度は最小の桁数
+fn number(digits: String, spec: &Spec) -> String {
+    match spec.precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(precision) if digits.len() < precision => {
+            format!("{}{}", "0".repeat(precision - digits.len()), digits)
+        }
+        _ => digits,
+    }
+}
+
+fn pad(out: &mut String, prefix: &str, body: &str, spec: &Spec) {
+    let len = prefix.chars().count() + body.chars().count();
+    let fill = spec.width.saturating_sub(len);
+
+    if spec.left {
+        out.push_str(prefix);
+        out.push_str(body);
+        out.push_str(&" ".repeat(fill));
+    } else if spec.zero && spec.precision.is_none() {
+        // 0埋めは符号や 0x の後ろに入る
+        out.push_str(prefix);
+        out.push_str(&"0".repeat(fill));
+        out.push_str(body);
+    } else {
+        out.push_str(&" ".repeat(fill));
+        out.push_str(prefix);
+        out.push_str(body);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::TestMachineBuilder;
+
+    struct TestArgs {
+        words: Vec<u32>,
+        strings: HashMap<u32, &'static str>,
+    }
+
+    impl Args for TestArgs {
+        fn word(&mut self, index: usize) -> u32 {
+            self.words.get(index).copied().unwrap_or(0)
+        }
+
+        fn string(&mut self, addr: u32) -> String {
+            self.strings.get(&addr).unwrap_or(&"").to_string()
+        }
+    }
+
+    fn printf(fmt: &str, words: &[u32]) -> String {
+        let mut args = TestArgs {
+            words: words.to_vec(),
+            strings: HashMap::from([(0x80001000, "hello")]),
+        };
+
+        format(fmt, &mut args)
+    }
+
+    #[test]
+    fn formats_integers() {
+        assert_eq!(
+            printf("%d %i %u", &[-5i32 as u32, 7, -1i32 as u32]),
+            "-5 7 4294967295"
+        );
+        assert_eq!(
+            printf("%x %X %#x %o", &[0xbeef, 0xbeef, 0x1f, 8]),
+            "beef BEEF 0x1f 10"
+        );
+        assert_eq!(printf("%ld %hx", &[12, 0xff]), "12 ff");
+        assert_eq!(printf("100%%", &[]), "100%");
+    }
+
+    #[test]
+    fn pads_to_width() {
+        assert_eq!(printf("[%5d]", &[42]), "[   42]");
+        assert_eq!(printf("[%-5d]", &[42]), "[42   ]");
+        assert_eq!(printf("[%05d]", &[-42i32 as u32]), "[-0042]");
+        assert_eq!(printf("[%08X]", &[0x1234]), "[00001234]");
+        assert_eq!(printf("[%.3d]", &[7]), "[007]");
+        assert_eq!(printf("[%*d]", &[4, 1]), "[   1]");
+    }
+
+    #[test]
+    fn clamps_width_and_precision() {
+        assert_eq!(printf("%*d", &[0x7FFFFFFF, 1]).len(), MAX_WIDTH);
+        assert_eq!(printf("%*d", &[0x80000000, 1]).len(), MAX_WIDTH);
+        assert_eq!(printf("%.*d", &[0x7FFFFFFF, 1]).len(), MAX_WIDTH);
+        assert_eq!(printf("%99999999999999999999999d", &[1]).len(), MAX_WIDTH);
+        assert_eq!(printf("%.99999999999999999999999d", &[1]).len(), MAX_WIDTH);
+
+        // 負の精度は無視される
+        assert_eq!(printf("[%.*d]", &[-1i32 as u32, 0]), "[0]");
+        assert_eq!(printf("[%.*s]", &[-3i32 as u32, 0x80001000]), "[hello]");
+    }
+
+    #[test]
+    fn formats_strings_and_chars() {
+        assert_eq!(
+            printf(
+                "%s, %c%c! %.3s|%-7s|",
+                &[0x80001000, 'P' as u32, 'S' as u32, 0x80001000, 0x80001000]
+            ),
+            "hello, PS! hel|hello  |"
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_conversions() {
+        assert_eq!(printf("%q %", &[]), "%q %");
+    }
+
+    #[test]
+    fn reads_register_and_stack_arguments() {
+        let stack: Vec<u8> = [0, 0, 0, 0, 4, 0xbeef]
+            .iter()
+            .flat_map(|word: &u32| word.to_le_bytes())
+            .collect();
+
+        let mut cpu = TestMachineBuilder::new()
+            .ram(0x80001000, b"PS1\0")
+            .ram(0x801FFF00, &stack)
+            .build();
+
+        cpu.regs[5] = 0x80001000;
+        cpu.regs[6] = 2;
+        cpu.regs[7] = 3;
+        cpu.regs[29] = 0x801FFF00;
+
+        assert_eq!(format("%s %d %d %d %x", &mut cpu), "PS1 2 3 4 beef");
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::ps::Ps;
+
+pub const DEFAULT_DIR: &str = "crash";
+
+// 最後にパニックしたスレッドのメッセージ
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+// 既定のフックに加えて、レポート用にメッセージを残しておく
+pub fn install_hook() {
+    let default = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        let location = info
+            .location()
+            .map(|l| format!(" at {}:{}", l.file(), l.line()))
+            .unwrap_or_default();
+
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+
+        if let Ok(mut last) = LAST_PANIC.lock() {
+            *last = Some(format!("{}{}", message, location));
+        }
+
+        default(info);
+    }));
+}
+
+pub fn panic_message() -> String {
+    LAST_PANIC
+        .lock()
+        .ok()
+        .and_then(|mut last| last.take())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+// パニック直後のマシンからトレースとステートを書き出す
+pub fn write_report(dir: &Path, ps: &mut Ps, message: &str) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let base = dir.join(format!("crash-{:020}", timestamp));
+
+    let cpu = ps.cpu();
+    let mut report = String::new();
+
+    writeln!(report, "panic: {}", message)?;
+    writeln!(
+        report,
+        "pc: {:08x} hi: {:08x} lo: {:08x}",
+        cpu.pc(),
+        cpu.hi,
+        cpu.lo
+    )?;
+    for (i, reg) in cpu.regs.iter().enumerate() {
+        writeln!(report, "r{:02}: {:08x}", i, reg)?;
+    }
+
+    writeln!(report, "trace:")?;
+    for pc in cpu.trace() {
+        writeln!(report, "  {:08x}", pc)?;
+    }
+
+    writeln!(report, "irqs:")?;
+    for event in cpu.inter.interrupts.events() {
+        writeln!(report, "  {:>12} {:?}", event.cycle, event.irq)?;
+    }
+
+    // 途中でパニックした状態なので保存自体が失敗することもある
+    match panic::catch_unwind(AssertUnwindSafe(|| ps.save_state())) {
+        Ok(state) => fs::write(base.with_extension("state"), state)?,
+        Err(_) => writeln!(report, "state: failed to save")?,
+    }
+
+    fs::write(base.with_extension("txt"), report)?;
+
+    Ok(base.with_extension("txt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestMachineBuilder;
+
+    #[test]
+    fn writes_report_and_state() {
+        let dir = std::env::temp_dir().join(format!("rps-crash-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut ps = TestMachineBuilder::new().build_ps();
+
+        let path = write_report(&dir, &mut ps, "boom").unwrap();
+        let report = fs::read_to_string(&path).unwrap();
+        let state = path.with_extension("state").exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(path.extension().unwrap(), "txt");
+        assert!(report.starts_with("panic: boom\n"));
+        assert!(state);
+    }
+}
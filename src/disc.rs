@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+
+// キャッシュの単位。生イメージの1セクタ
+pub const CHUNK_SIZE: usize = 2352;
+
+// ストリーミング中のキャッシュの上限 (約2.4MB)
+const CACHE_CHUNKS: usize = 1024;
+
+// ディスクイメージ
+// 全体をメモリに読み込む (precache) か、ファイルから必要な所だけ読んでキャッシュする
+// 後者では先読みを別スレッドで行い、ホストのディスクの遅延を隠す
+// どちらでも返す内容は同じで、先読みはいつ読むかにしか影響しない
+#[derive(Clone)]
+pub struct Disc {
+    data: Arc<Data>,
+    // 先読みする範囲 (チャンク番号, 数) を送る
+    prefetch: Option<Sender<(usize, usize)>>,
+}
+
+enum Data {
+    Memory(Vec<u8>),
+    File(Stream),
+}
+
+struct Stream {
+    file: Mutex<File>,
+    len: usize,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    chunks: HashMap<usize, Arc<[u8]>>,
+    // 古いものから捨てる
+    order: VecDeque<usize>,
+}
+
+impl Cache {
+    fn insert(&mut self, index: usize, chunk: Arc<[u8]>) {
+        if self.chunks.insert(index, chunk).is_some() {
+            return;
+        }
+
+        self.order.push_back(index);
+        if self.order.len() > CACHE_CHUNKS {
+            let old = self.order.pop_front().unwrap();
+            self.chunks.remove(&old);
+        }
+    }
+}
+
+impl Stream {
+    fn cached_chunk(&self, index: usize) -> Option<Arc<[u8]>> {
+        self.cache
+            .lock()
+            .unwrap()
+            .chunks
+            .get(&index)
+            .map(Arc::clone)
+    }
+
+    fn chunk(&self, index: usize) -> Option<Arc<[u8]>> {
+        match self.cached_chunk(index) {
+            Some(chunk) => Some(chunk),
+            None => self.fetch(index),
+        }
+    }
+
+    // ファイルから読んでキャッシュに入れる
+    // 先読みのスレッドが同じチャンクを読んでいる間はファイルのロックで待ち、読み直さずにそれを使う
+    fn fetch(&self, index: usize) -> Option<Arc<[u8]>> {
+        let start = index * CHUNK_SIZE;
+        if start >= self.len {
+            return None;
+        }
+
+        let mut file = self.file.lock().unwrap();
+
+        if let Some(chunk) = self.cached_chunk(index) {
+            return Some(chunk);
+        }
+
+        let mut buf = vec![0; CHUNK_SIZE.min(self.len - start)];
+        let read = file
+            .seek(SeekFrom::Start(start as u64))
+            .and_then(|_| file.read_exact(&mut buf));
+
+        match read {
+            Ok(()) => {
+                let chunk: Arc<[u8]> = buf.into();
+                self.cache.lock().unwrap().insert(index, Arc::clone(&chunk));
+
+                Some(chunk)
+            }
+            Err(e) => {
+                warn!("Failed to read disc at {}: {}", start, e);
+                None
+            }
+        }
+    }
+
+    fn cached(&self, index: usize) -> bool {
+        self.cache.lock().unwrap().chunks.contains_key(&index)
+    }
+}
+
+impl Disc {
+    // 全体を読み込んだイメージ
+    pub fn from_bytes(data: Vec<u8>) -> Disc {
+        Disc {
+            data: Arc::new(Data::Memory(data)),
+            prefetch: None,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Disc> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Ok(Disc::from_bytes(data))
+    }
+
+    // 必要な所だけ読むイメージ。先読みのスレッドを立てる
+    pub fn open(path: &Path) -> Result<Disc> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let len = file.metadata()?.len() as usize;
+
+        let data = Arc::new(Data::File(Stream {
+            file: Mutex::new(file),
+            len,
+            cache: Mutex::new(Cache::default()),
+        }));
+
+        let (sender, receiver) = mpsc::channel::<(usize, usize)>();
+
+        // Disc が全て捨てられると送り手がなくなって終わる
+        let shared = Arc::clone(&data);
+        thread::Builder::new()
+            .name("disc prefetch".to_string())
+            .spawn(move || {
+                let stream = match &*shared {
+                    Data::File(stream) => stream,
+                    Data::Memory(_) => unreachable!(),
+                };
+
+                let mut next = None;
+
+                while let Some((start, count)) = next.take().or_else(|| receiver.recv().ok()) {
+                    for index in start..start + count {
+                        // シークで新しい位置を要求されたら、古い範囲は捨ててそちらを先に読む
+                        if let Some(newer) = receiver.try_iter().last() {
+                            next = Some(newer);
+                            break;
+                        }
+
+                        if !stream.cached(index) && stream.fetch(index).is_none() {
+                            break;
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Disc {
+            data,
+            prefetch: Some(sender),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        match &*self.data {
+            Data::Memory(data) => data.len(),
+            Data::File(stream) => stream.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        match &*self.data {
+            Data::Memory(data) => data.get(offset).copied(),
+            Data::File(stream) => {
+                let chunk = stream.chunk(offset / CHUNK_SIZE)?;
+                chunk.get(offset % CHUNK_SIZE).copied()
+            }
+        }
+    }
+
+    // [offset, offset + len) を読む。範囲の外に出る場合は None
+    pub fn read(&self, offset: usize, len: usize) -> Option<Vec<u8>> {
+        if offset.checked_add(len)? > self.len() {
+            return None;
+        }
+
+        match &*self.data {
+            Data::Memory(data) => Some(data[offset..offset + len].to_vec()),
+            Data::File(stream) => {
+                let mut buf = Vec::with_capacity(len);
+                let mut pos = offset;
+
+                while buf.len() < len {
+                    let chunk = stream.chunk(pos / CHUNK_SIZE)?;
+                    let start = pos % CHUNK_SIZE;
+                    let end = chunk.len().min(start + len - buf.len());
+
+                    buf.extend_from_slice(&chunk[start..end]);
+                    pos += end - start;
+                }
+
+                Some(buf)
+            }
+        }
+    }
+
+    // offset から count セクタ分を裏で読んでおく。読み込み済みのイメージでは何もしない
+    pub fn prefetch(&self, offset: usize, count: usize) {
+        if let Some(sender) = &self.prefetch {
+            let _ = sender.send((offset / CHUNK_SIZE, count));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, time::Duration};
+
+    use super::*;
+
+    fn image() -> Vec<u8> {
+        (0..CHUNK_SIZE * 8 + 100).map(|i| (i * 13) as u8).collect()
+    }
+
+    #[test]
+    fn streaming_matches_memory() {
+        let data = image();
+        let path = env::temp_dir().join(format!("rps-disc-{}.bin", std::process::id()));
+        fs::write(&path, &data).unwrap();
+
+        let memory = Disc::from_bytes(data.clone());
+        let stream = Disc::open(&path).unwrap();
+
+        stream.prefetch(CHUNK_SIZE * 2, 4);
+
+        assert_eq!(stream.len(), data.len());
+        for offset in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE * 3 + 5, data.len() - 1] {
+            assert_eq!(stream.byte(offset), memory.byte(offset));
+        }
+        assert_eq!(stream.byte(data.len()), None);
+
+        // チャンクを跨いで読む
+        let range = CHUNK_SIZE * 7 + 10..data.len();
+        assert_eq!(
+            stream.read(range.start, range.len()).unwrap(),
+            data[range.clone()]
+        );
+        assert_eq!(stream.read(range.start, range.len() + 1), None);
+
+        drop(stream);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prefetch_fills_cache() {
+        let path = env::temp_dir().join(format!("rps-prefetch-{}.bin", std::process::id()));
+        fs::write(&path, image()).unwrap();
+
+        let disc = Disc::open(&path).unwrap();
+        disc.prefetch(CHUNK_SIZE, 3);
+
+        let stream = match &*disc.data {
+            Data::File(stream) => stream,
+            Data::Memory(_) => unreachable!(),
+        };
+
+        // 先読みのスレッドを待つ
+        for _ in 0..100 {
+            if (1..4).all(|index| stream.cached(index)) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!((1..4).all(|index| stream.cached(index)));
+        assert!(!stream.cached(0));
+
+        drop(disc);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,10 @@
+use anyhow::Result;
+
+use crate::{
+    interrupts::{Irq, IrqBus, IrqLine},
+    savestate::{Reader, Savestate, Writer},
+};
+
 pub struct Dma {
     control: u32,
     irq_en: bool,
@@ -5,6 +12,7 @@ pub struct Dma {
     channel_irq_flags: u8,
     force_irq: bool,
     irq_dummy: u8,
+    irq_line: IrqLine,
 
     channels: [Channel; 7],
 }
@@ -18,6 +26,7 @@ impl Dma {
             channel_irq_flags: 0,
             force_irq: false,
             irq_dummy: 0,
+            irq_line: IrqLine::new(Irq::Dma),
             channels: [
                 Channel::new(),
                 Channel::new(),
@@ -38,6 +47,11 @@ impl Dma {
         self.control = val;
     }
 
+    // DICR bit31 が立ったら irqs に上げる
+    pub fn tick(&mut self, irqs: &mut IrqBus) {
+        self.irq_line.set(irqs, self.check_irq());
+    }
+
     pub fn check_irq(&self) -> bool {
         let channel_irq = self.channel_irq_flags & self.channel_irq_en;
         self.force_irq || (self.irq_en && channel_irq != 0)
@@ -66,6 +80,16 @@ impl Dma {
         self.channel_irq_flags &= !ack;
     }
 
+    // 転送を終えたチャンネルを止め、そのチャンネルの割り込みが有効ならフラグを立てる
+    pub fn complete(&mut self, port: Port) {
+        self.channel_mut(port).done();
+
+        let bit = 1 << port as u8;
+        if self.channel_irq_en & bit != 0 {
+            self.channel_irq_flags |= bit;
+        }
+    }
+
     pub fn channel(&self, port: Port) -> &Channel {
         &self.channels[port as usize]
     }
@@ -115,6 +139,15 @@ pub struct Channel {
     base: u32,
     block_size: u16,
     block_count: u16,
+
+    // リンクリストモードで送っているノードの残りワード数と次に読むアドレス
+    // ノードを送り終えるまで base はそのノードのヘッダを指す
+    list_words: u32,
+    list_addr: u32,
+
+    // DREQに合わせて送っているブロック転送の残りワード数と次のアドレス
+    request_words: u32,
+    request_addr: u32,
 }
 
 impl Channel {
@@ -133,6 +166,12 @@ impl Channel {
             base: 0,
             block_size: 0,
             block_count: 0,
+
+            list_words: 0,
+            list_addr: 0,
+
+            request_words: 0,
+            request_addr: 0,
         }
     }
 
@@ -172,6 +211,61 @@ impl Channel {
     pub fn done(&mut self) {
         self.enable = false;
         self.trigger = false;
+        self.list_words = 0;
+        self.request_words = 0;
+    }
+
+    // base のノードを送り始める
+    pub fn start_node(&mut self, words: u32) {
+        self.list_words = words;
+        self.list_addr = self.base & 0x1FFFFC;
+    }
+
+    // 送っているノードの次のワードのアドレス
+    pub fn next_word(&mut self) -> u32 {
+        self.list_words -= 1;
+        self.list_addr = (self.list_addr + 4) & 0x1FFFFC;
+
+        self.list_addr
+    }
+
+    pub fn node_remaining(&self) -> u32 {
+        self.list_words
+    }
+
+    // DREQを待ちながら transfer_size 分を送り始める
+    pub fn start_request(&mut self) {
+        self.request_words = self.transfer_size().unwrap_or(0);
+        self.request_addr = self.base & 0x1FFFFC;
+    }
+
+    // 次に送るワードのアドレス
+    pub fn next_request_word(&mut self) -> u32 {
+        let addr = self.request_addr;
+
+        self.request_words -= 1;
+        self.request_addr = match self.step {
+            Step::Increment => addr.wrapping_add(4),
+            Step::Decrement => addr.wrapping_sub(4),
+        } & 0x1FFFFC;
+
+        addr
+    }
+
+    pub fn request_remaining(&self) -> u32 {
+        self.request_words
+    }
+
+    // 転送の途中でブロックの切れ目にいる (次のDREQを待つ)
+    // Manual では全体が1ブロック
+    pub fn request_block_start(&self) -> bool {
+        let total = self.transfer_size().unwrap_or(0);
+        let block = match self.sync {
+            Sync::Request => self.block_size as u32,
+            _ => total,
+        };
+
+        block == 0 || (total - self.request_words).is_multiple_of(block)
     }
 
     pub fn base(&self) -> u32 {
@@ -258,3 +352,48 @@ pub enum Sync {
     Request = 1,
     LinkedList = 2,
 }
+
+impl Savestate for Dma {
+    fn save_state(&self, w: &mut Writer) {
+        w.u32(self.control);
+        w.bool(self.irq_en);
+        w.u8(self.channel_irq_en);
+        w.u8(self.channel_irq_flags);
+        w.bool(self.force_irq);
+        w.u8(self.irq_dummy);
+        self.irq_line.save_state(w);
+
+        // 転送の途中の位置以外はレジスタの値で表せる
+        for channel in &self.channels {
+            w.u32(channel.base());
+            w.u32(channel.block_control());
+            w.u32(channel.control());
+            w.u32(channel.list_words);
+            w.u32(channel.list_addr);
+            w.u32(channel.request_words);
+            w.u32(channel.request_addr);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.control = r.u32()?;
+        self.irq_en = r.bool()?;
+        self.channel_irq_en = r.u8()?;
+        self.channel_irq_flags = r.u8()?;
+        self.force_irq = r.bool()?;
+        self.irq_dummy = r.u8()?;
+        self.irq_line.load_state(r)?;
+
+        for channel in &mut self.channels {
+            channel.set_base(r.u32()?);
+            channel.set_block_control(r.u32()?);
+            channel.set_control(r.u32()?);
+            channel.list_words = r.u32()?;
+            channel.list_addr = r.u32()?;
+            channel.request_words = r.u32()?;
+            channel.request_addr = r.u32()?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::{bail, Result};
+
+const HEADER_SIZE: usize = 0x800;
+
+// PS-X EXE
+pub struct Exe {
+    pub pc: u32,
+    pub gp: u32,
+    pub load_addr: u32,
+    pub sp: u32,
+    pub data: Vec<u8>,
+}
+
+impl Exe {
+    pub fn parse(file: &[u8]) -> Result<Exe> {
+        if file.len() < HEADER_SIZE || &file[0..8] != b"PS-X EXE" {
+            bail!("Invalid PS-X EXE header");
+        }
+
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                file[offset],
+                file[offset + 1],
+                file[offset + 2],
+                file[offset + 3],
+            ])
+        };
+
+        let pc = word(0x10);
+        let gp = word(0x14);
+        let load_addr = word(0x18);
+        let size = word(0x1C) as usize;
+        let sp_base = word(0x30);
+        let sp_offset = word(0x34);
+
+        let end = HEADER_SIZE + size;
+        if end > file.len() {
+            bail!("PS-X EXE is truncated ({} < {})", file.len(), end);
+        }
+
+        let sp = match sp_base {
+            0 => 0,
+            base => base.wrapping_add(sp_offset),
+        };
+
+        Ok(Exe {
+            pc,
+            gp,
+            load_addr,
+            sp,
+            data: file[HEADER_SIZE..end].to_vec(),
+        })
+    }
+}
@@ -1,3 +1,7 @@
+use anyhow::Result;
+
+use crate::savestate::{Reader, Savestate, Writer};
+
 pub struct CommandBuffer {
     buffer: [u32; 12],
     len: u8,
@@ -40,3 +44,21 @@ impl std::ops::Index<usize> for CommandBuffer {
         &self.buffer[index]
     }
 }
+
+impl Savestate for CommandBuffer {
+    fn save_state(&self, w: &mut Writer) {
+        w.u8(self.len);
+        for word in &self.buffer {
+            w.u32(*word);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.len = r.u8()?.min(self.buffer.len() as u8);
+        for word in &mut self.buffer {
+            *word = r.u32()?;
+        }
+
+        Ok(())
+    }
+}
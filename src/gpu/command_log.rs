@@ -0,0 +1,245 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    interrupts::IrqBus,
+    savestate::{self, Reader, Savestate, Writer},
+};
+
+use super::gpu::Gpu;
+
+// GP0/GP1 への書き込み (DMA からのものを含む) とGPUREADの読み出しを記録し、
+// CPUなしでGPUに流し直す。描画のバグの切り分けやレンダラのベンチマーク用
+const MAGIC: &[u8; 4] = b"RPSG";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    Gp0(u32),
+    Gp1(u32),
+    Read,
+    // 新しいフレームが始まった (画面を出した)
+    Frame,
+}
+
+impl Entry {
+    fn write(self, w: &mut impl Write) -> io::Result<()> {
+        match self {
+            Entry::Gp0(val) => {
+                w.write_all(&[0])?;
+                w.write_all(&val.to_le_bytes())
+            }
+            Entry::Gp1(val) => {
+                w.write_all(&[1])?;
+                w.write_all(&val.to_le_bytes())
+            }
+            Entry::Read => w.write_all(&[2]),
+            Entry::Frame => w.write_all(&[3]),
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Entry> {
+        Ok(match r.u8()? {
+            0 => Entry::Gp0(r.u32()?),
+            1 => Entry::Gp1(r.u32()?),
+            2 => Entry::Read,
+            3 => Entry::Frame,
+            tag => bail!("Invalid GPU command log entry {}", tag),
+        })
+    }
+}
+
+// ファイルに逐次書き出す
+// 終了時に drop されないこともあるので、フレームごとに flush する
+pub struct CommandRecorder {
+    file: BufWriter<File>,
+}
+
+impl CommandRecorder {
+    // 記録を始めた時点のGPUの状態 (VRAMを含む) を先頭に書く
+    pub fn create(path: &Path, gpu: &Gpu) -> Result<CommandRecorder> {
+        let mut w = Writer::new();
+        w.u32(u32::from_le_bytes(*MAGIC));
+        w.u32(VERSION);
+        w.u32(savestate::VERSION);
+
+        let mut state = Writer::new();
+        gpu.save_state(&mut state);
+        w.bytes(&state.into_inner());
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&w.into_inner())?;
+
+        Ok(CommandRecorder { file })
+    }
+
+    pub fn record(&mut self, entry: Entry) -> io::Result<()> {
+        entry.write(&mut self.file)?;
+
+        if entry == Entry::Frame {
+            self.file.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+pub struct CommandLog {
+    state: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+impl CommandLog {
+    pub fn read(path: &Path) -> Result<CommandLog> {
+        CommandLog::decode(&fs::read(path)?)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<CommandLog> {
+        let mut r = Reader::new(data);
+
+        if r.u32()? != u32::from_le_bytes(*MAGIC) {
+            bail!("Not a GPU command log");
+        }
+
+        let version = r.u32()?;
+        if version != VERSION {
+            bail!("Unsupported GPU command log version {}", version);
+        }
+
+        // GPUの状態はセーブステートと同じ形式
+        let version = r.u32()?;
+        if version != savestate::VERSION {
+            bail!(
+                "GPU command log has unsupported savestate version {}",
+                version
+            );
+        }
+
+        let state = r.bytes()?;
+
+        let mut entries = vec![];
+        while !r.is_empty() {
+            entries.push(Entry::read(&mut r)?);
+        }
+
+        Ok(CommandLog { state, entries })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.entries.iter().filter(|&&e| e == Entry::Frame).count()
+    }
+}
+
+// 記録したコマンドをフレーム単位で流す
+pub struct Replayer {
+    log: CommandLog,
+    pos: usize,
+}
+
+impl Replayer {
+    pub fn new(log: CommandLog, gpu: &mut Gpu) -> Result<Replayer> {
+        gpu.load_state(&mut Reader::new(&log.state))?;
+
+        Ok(Replayer { log, pos: 0 })
+    }
+
+    // 次のフレームの区切りまで流し、画面を出すまでGPUを進める
+    // 記録の最後まで流し終わっていたら false
+    pub fn run_frame(&mut self, gpu: &mut Gpu) -> bool {
+        if self.pos == self.log.entries.len() {
+            return false;
+        }
+
+        while let Some(&entry) = self.log.entries.get(self.pos) {
+            self.pos += 1;
+
+            match entry {
+                Entry::Gp0(val) => gpu.gp0(val),
+                Entry::Gp1(val) => gpu.gp1(val),
+                Entry::Read => {
+                    gpu.read();
+                }
+                Entry::Frame => break,
+            }
+        }
+
+        // CPUがいないので割り込みは捨てる
+        let mut irqs = IrqBus::new();
+        let frame = gpu.frames();
+        while gpu.frames() == frame {
+            gpu.tick(&mut irqs);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::gpu::renderer::Renderer;
+
+    // 左上を塗りつぶしてフレームを進める
+    fn draw(gpu: &mut Gpu, color: u32) {
+        gpu.gp1(0x03000000);
+        gpu.gp0(0x02000000 | color);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00100010);
+
+        let frame = gpu.frames();
+        while gpu.frames() == frame {
+            gpu.tick(&mut IrqBus::new());
+        }
+    }
+
+    #[test]
+    fn replay_reproduces_vram() {
+        let path = env::temp_dir().join(format!("rps-gpu-log-{}.bin", std::process::id()));
+
+        let mut live = Gpu::new(Renderer::null());
+        draw(&mut live, 0x0000FF);
+
+        live.start_recording(&path).unwrap();
+        draw(&mut live, 0x00FF00);
+        draw(&mut live, 0xFF0000);
+        live.stop_recording().unwrap();
+
+        let log = CommandLog::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(log.frames(), 2);
+
+        let mut gpu = Gpu::new(Renderer::null());
+        let mut replayer = Replayer::new(log, &mut gpu).unwrap();
+        assert_eq!(gpu.vram().read(0, 0), 0x001F);
+
+        assert!(replayer.run_frame(&mut gpu));
+        assert_eq!(gpu.vram().read(0, 0), 0x03E0);
+        assert!(replayer.run_frame(&mut gpu));
+        assert!(!replayer.run_frame(&mut gpu));
+
+        assert_eq!(gpu.vram().data(), live.vram().data());
+    }
+
+    #[test]
+    fn rejects_unknown_entries() {
+        let mut w = Writer::new();
+        w.u32(u32::from_le_bytes(*MAGIC));
+        w.u32(VERSION);
+        w.u32(savestate::VERSION);
+        w.bytes(&[]);
+        w.u8(4);
+
+        assert!(CommandLog::decode(&w.into_inner()).is_err());
+    }
+}
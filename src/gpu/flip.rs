@@ -0,0 +1,125 @@
+// 連続してこの回数だけ2つの矩形を行き来したらダブルバッファとみなす
+const LOCK_TOGGLES: u8 = 3;
+
+// 30fps / 20fps のゲームでも切り替えはこの間隔に収まる
+// これより長く切り替わらなければ、シングルバッファに戻ったとみなす
+const MAX_FLIP_INTERVAL: u8 = 4;
+
+// ダブルバッファの切り替え (表示開始位置が2つの矩形を交互に行き来する) を検出し、
+// 切り替わったフレームだけ画面に出すことで描画途中のバッファを見せないようにする
+// 表示だけに関わる状態なのでセーブステートには含めない
+pub struct FlipDetector {
+    // 現在と1つ前の表示開始位置
+    starts: [(u16, u16); 2],
+    // 交互に切り替わった回数 (LOCK_TOGGLES で飽和する)
+    toggles: u8,
+    // 前回画面に出してから切り替わったか
+    flipped: bool,
+    // 最後に切り替わってからのフレーム数
+    frames_since_flip: u8,
+}
+
+impl FlipDetector {
+    pub fn new() -> FlipDetector {
+        FlipDetector {
+            starts: [(0, 0); 2],
+            toggles: 0,
+            flipped: false,
+            frames_since_flip: 0,
+        }
+    }
+
+    // GP1(0x05) で表示開始位置が設定されたとき
+    pub fn set_start(&mut self, x: u16, y: u16) {
+        let start = (x, y);
+
+        if start == self.starts[0] {
+            return;
+        }
+
+        self.toggles = match start == self.starts[1] {
+            true => (self.toggles + 1).min(LOCK_TOGGLES),
+            false => 0,
+        };
+
+        self.starts = [start, self.starts[0]];
+        self.flipped = true;
+        self.frames_since_flip = 0;
+    }
+
+    pub fn ping_pong(&self) -> bool {
+        self.toggles >= LOCK_TOGGLES
+    }
+
+    // vblankごとに呼び、このフレームを画面に出すかを返す
+    pub fn should_present(&mut self) -> bool {
+        self.frames_since_flip = self.frames_since_flip.saturating_add(1);
+
+        if self.frames_since_flip > MAX_FLIP_INTERVAL {
+            self.toggles = 0;
+        }
+
+        let present = !self.ping_pong() || self.flipped;
+        self.flipped = false;
+
+        present
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRONT: (u16, u16) = (0, 0);
+    const BACK: (u16, u16) = (0, 240);
+
+    // 2フレームに1回切り替える (30fps)
+    fn run(detector: &mut FlipDetector, flips: usize) -> Vec<bool> {
+        let mut presented = vec![];
+
+        for i in 0..flips {
+            let (x, y) = if i % 2 == 0 { BACK } else { FRONT };
+            detector.set_start(x, y);
+
+            presented.push(detector.should_present());
+            presented.push(detector.should_present());
+        }
+
+        presented
+    }
+
+    #[test]
+    fn presents_only_flipped_frames_when_double_buffered() {
+        let mut detector = FlipDetector::new();
+
+        // 検出するまでは毎フレーム出す
+        let presented = run(&mut detector, LOCK_TOGGLES as usize + 1);
+        assert!(presented[..LOCK_TOGGLES as usize * 2].iter().all(|&p| p));
+        assert!(detector.ping_pong());
+
+        assert_eq!(run(&mut detector, 2), vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn falls_back_when_flips_stop() {
+        let mut detector = FlipDetector::new();
+        run(&mut detector, LOCK_TOGGLES as usize + 1);
+
+        for _ in 0..MAX_FLIP_INTERVAL {
+            detector.should_present();
+        }
+
+        assert!(detector.should_present());
+        assert!(!detector.ping_pong());
+    }
+
+    #[test]
+    fn other_rectangles_reset_detection() {
+        let mut detector = FlipDetector::new();
+        run(&mut detector, LOCK_TOGGLES as usize + 1);
+
+        detector.set_start(320, 0);
+
+        assert!(!detector.ping_pong());
+    }
+}
@@ -1,14 +1,57 @@
+use std::{collections::VecDeque, fmt, path::Path};
+
+use anyhow::{bail, Result};
 use log::{debug, trace, warn};
+use num_derive::FromPrimitive;
 
 use crate::{
     addressible::{AccessWidth, Addressible},
-    gpu::primitive::{Color, Position},
+    gpu::primitive::{Color, Position, Texture, TextureWindow},
+    interrupts::{Irq, IrqBus, IrqLine},
+    savestate::{Reader, Savestate, Writer},
+};
+
+use super::{
+    command::CommandBuffer,
+    command_log::{CommandRecorder, Entry},
+    flip::FlipDetector,
+    postprocess::Filter,
+    renderer::{Renderer, Scaling},
+    screenshot::RgbaImage,
+    timing::{Timing, VMode},
+    vram::{Vram, VRAM_HEIGHT, VRAM_WIDTH},
 };
 
-use super::{command::CommandBuffer, renderer::Renderer};
+// 1フレームに実行した描画コマンドの数 (ポリラインは線分ごとに数える)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrimitiveCounts {
+    pub polygons: u32,
+    pub lines: u32,
+    pub rects: u32,
+    pub fills: u32,
+    pub image_loads: u32,
+}
+
+impl fmt::Display for PrimitiveCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "poly {} line {} rect {} fill {} load {}",
+            self.polygons, self.lines, self.rects, self.fills, self.image_loads
+        )
+    }
+}
+
+// 表示範囲のVRAMの内容 (15bit, 行優先)
+pub struct Frame {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u16>,
+}
 
 pub struct Gpu {
     page_base_x: u8,
+    // bit0: 256ライン単位, bit1: 512ライン単位 (2MB VRAMのみ)
     page_base_y: u8,
     semi_transparency: u8,
     texture_depth: TextureDepth,
@@ -24,18 +67,19 @@ pub struct Gpu {
     display_depth: DisplayDepth,
     interlaced: bool,
     display_disabled: bool,
-    pub interrupt: bool,
+    interrupt: bool,
+    irq_line: IrqLine,
+    vblank_line: IrqLine,
     dma_direction: DmaDirection,
     rectangle_texture_x_flip: bool,
     rectangle_texture_y_flip: bool,
-    texture_window_x_mask: u8,
-    texture_window_y_mask: u8,
-    texture_window_x_offset: u8,
-    texture_window_y_offset: u8,
+    texture_window: TextureWindow,
     drawing_area_left: u16,
     drawing_area_top: u16,
     drawing_area_right: u16,
     drawing_area_bottom: u16,
+    drawing_x_offset: i16,
+    drawing_y_offset: i16,
     display_vram_x_start: u16,
     display_vram_y_start: u16,
     display_horiz_start: u16,
@@ -43,18 +87,31 @@ pub struct Gpu {
     display_line_start: u16,
     display_line_end: u16,
 
-    pub hblank: bool,
-    pub vblank: bool,
-    pub dotclock: bool,
-
-    cycles: u16,
-    scanlines: u16,
+    timing: Timing,
+    // 起動してから描画したフレーム数 (ステートには含めない)
+    frames: u32,
+    // ダブルバッファの検出 (ステートには含めない)
+    flips: FlipDetector,
+    // 描画中のフレームと直前のフレームのコマンド数 (ステートには含めない)
+    primitives: PrimitiveCounts,
+    last_primitives: PrimitiveCounts,
+    // GP0/GP1 コマンドの記録先 (ステートには含めない)
+    recorder: Option<CommandRecorder>,
 
     gp0_mode: Gp0Mode,
+    // 描画中に受け取ったワード
+    gp0_fifo: VecDeque<u32>,
+    // 描画が終わるまでのCPUサイクル数 (おおよそ)
+    busy_cycles: u32,
     gp0_words_remaining: u32,
     gp0_command: CommandBuffer,
     gp0_command_method: fn(&mut Gpu),
+    polyline: PolyLine,
+    image_load: ImageTransfer,
+    image_store: ImageTransfer,
+    gpuread: u32,
 
+    vram: Vram,
     renderer: Renderer,
 }
 
@@ -78,17 +135,18 @@ impl Gpu {
             interlaced: true,
             display_disabled: true,
             interrupt: false,
+            irq_line: IrqLine::new(Irq::Gpu),
+            vblank_line: IrqLine::new(Irq::VBlank),
             dma_direction: DmaDirection::Off,
             rectangle_texture_x_flip: false,
             rectangle_texture_y_flip: false,
-            texture_window_x_mask: 0,
-            texture_window_y_mask: 0,
-            texture_window_x_offset: 0,
-            texture_window_y_offset: 0,
+            texture_window: TextureWindow::default(),
             drawing_area_left: 0,
             drawing_area_top: 0,
             drawing_area_right: 0,
             drawing_area_bottom: 0,
+            drawing_x_offset: 0,
+            drawing_y_offset: 0,
             display_vram_x_start: 0,
             display_vram_y_start: 0,
             display_horiz_start: 0,
@@ -96,19 +154,31 @@ impl Gpu {
             display_line_start: 0,
             display_line_end: 0,
             gp0_command: CommandBuffer::new(),
+            gp0_fifo: VecDeque::with_capacity(GP0_FIFO_LEN),
+            busy_cycles: 0,
             gp0_words_remaining: 0,
             gp0_command_method: |&mut _| {},
             gp0_mode: Gp0Mode::Command,
+            polyline: PolyLine::new(),
+            image_load: ImageTransfer::idle(),
+            image_store: ImageTransfer::idle(),
+            gpuread: 0,
+            vram: Vram::new(),
             renderer,
-            hblank: false,
-            vblank: false,
-            dotclock: false,
-            cycles: 0,
-            scanlines: 0,
+            timing: Timing::new(),
+            frames: 0,
+            flips: FlipDetector::new(),
+            primitives: PrimitiveCounts::default(),
+            last_primitives: PrimitiveCounts::default(),
+            recorder: None,
         }
     }
 
-    pub fn load<T: Addressible>(&self, offset: u32) -> T {
+    pub fn vram(&self) -> &Vram {
+        &self.vram
+    }
+
+    pub fn load<T: Addressible>(&mut self, offset: u32) -> T {
         if T::width() != AccessWidth::Word {
             panic!("Unhandled {:?} GPU load", T::width());
         }
@@ -134,58 +204,212 @@ impl Gpu {
         }
     }
 
-    pub fn tick(&mut self) {
-        self.cycles += 1;
-
-        let cycles_per_line = match self.vmode {
-            VMode::Pal => 3406,
-            VMode::Ntsc => 3413,
-        };
+    // GP0(0x1F) の割り込みと vblank の開始を irqs に上げる
+    pub fn tick(&mut self, irqs: &mut IrqBus) {
+        if self.busy_cycles > 0 {
+            self.busy_cycles -= 1;
+        }
 
-        if self.cycles == cycles_per_line {
-            self.scanlines += 1;
-            self.cycles = 0;
+        while self.busy_cycles == 0 {
+            match self.gp0_fifo.pop_front() {
+                Some(val) => self.execute_gp0(val),
+                None => break,
+            }
         }
 
-        self.dotclock = self.cycles
-            % match self.hres.width() {
-                256 => 10,
-                320 => 8,
-                368 => 7,
-                512 => 5,
-                640 => 3,
-                _ => unreachable!(),
+        let new_frame =
+            self.timing
+                .tick(self.vmode, self.hres.width(), self.hres.dotclock_divider());
+
+        self.irq_line.set(irqs, self.interrupt);
+        self.vblank_line.set(irqs, self.timing.vblank());
+
+        if new_frame {
+            let (x, y, width, height) = self.display_area();
+            self.renderer.set_display_area(x, y, width, height);
+            self.renderer.set_display_enabled(!self.display_disabled);
+            self.renderer
+                .set_display_depth24(matches!(self.display_depth, DisplayDepth::D24Bits));
+            self.renderer.update_vram(self.vram.data());
+
+            // VRAMビューアではバッファの切り替えに関係なく毎フレーム出す
+            if self.flips.should_present() || self.renderer.vram_view() {
+                self.renderer.render().unwrap();
+            } else {
+                self.renderer.flush().unwrap();
             }
-            == 0;
 
-        self.hblank = self.cycles >= self.hres.width();
+            self.frames = self.frames.wrapping_add(1);
+            self.last_primitives = std::mem::take(&mut self.primitives);
 
-        let lines_per_frame = match self.vmode {
-            VMode::Pal => 314,
-            VMode::Ntsc => 263,
-        };
+            self.record(Entry::Frame);
+        }
+    }
 
-        let visible_lines = match self.vres {
-            VerticalRes::Y240Lines => 240,
-            VerticalRes::Y480Lines => 240, // TODO: インターレース時の解像度を確認する
-        };
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    // 電源投入時のビデオ方式。GP1(08) で上書きされる
+    pub fn set_vmode(&mut self, vmode: VMode) {
+        self.vmode = vmode;
+    }
+
+    // 現在のビデオ方式での1秒あたりのフレーム数
+    pub fn refresh_rate(&self) -> f64 {
+        self.vmode.refresh_rate()
+    }
+
+    pub fn cycles_per_frame(&self) -> f64 {
+        self.vmode.cycles_per_frame()
+    }
+
+    pub fn hblank(&self) -> bool {
+        self.timing.hblank()
+    }
 
-        self.vblank = self.scanlines >= visible_lines;
+    pub fn vblank(&self) -> bool {
+        self.timing.vblank()
+    }
+
+    pub fn dotclock(&self) -> bool {
+        self.timing.dotclock()
+    }
+
+    // 直前のフレームで実行した描画コマンドの数
+    pub fn primitive_counts(&self) -> PrimitiveCounts {
+        self.last_primitives
+    }
+
+    // プリミティブの輪郭を色分けして重ねる
+    pub fn set_primitive_debug(&mut self, enabled: bool) {
+        self.renderer.set_primitive_debug(enabled);
+    }
+
+    pub fn primitive_debug(&self) -> bool {
+        self.renderer.primitive_debug()
+    }
+
+    // ウィンドウの大きさ (物理ピクセル) が変わったとき
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.renderer.resize(width, height);
+    }
+
+    // 15bitへの切り捨てとディザをやめて滑らかな色で描く
+    pub fn set_true_color(&mut self, enabled: bool) {
+        self.renderer.set_true_color(enabled);
+    }
+
+    pub fn true_color(&self) -> bool {
+        self.renderer.true_color()
+    }
 
-        if self.scanlines == lines_per_frame {
-            self.scanlines = 0;
+    pub fn set_scaling(&mut self, scaling: Scaling) {
+        self.renderer.set_scaling(scaling);
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.renderer.set_filter(filter);
+    }
+
+    // 表示範囲の代わりにVRAM全体 (1024x512) を画面に出す
+    pub fn set_vram_view(&mut self, enabled: bool) {
+        self.renderer.set_vram_view(enabled);
+    }
+
+    pub fn vram_view(&self) -> bool {
+        self.renderer.vram_view()
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.interrupt
+    }
+
+    // これ以降のGP0/GP1への書き込みとGPUREADの読み出しをファイルに記録する
+    pub fn start_recording(&mut self, path: &Path) -> Result<()> {
+        self.sync_vram();
+        self.recorder = Some(CommandRecorder::create(path, self)?);
+
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<()> {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish()?;
         }
 
-        if self.cycles == 0 && self.scanlines == 0 {
-            self.renderer.render().unwrap();
+        Ok(())
+    }
+
+    // 書き込みに失敗したらエミュレーションは止めずに記録だけやめる
+    fn record(&mut self, entry: Entry) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(entry) {
+                warn!("GPU command recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    // 24bit表示でもVRAMのハーフワードをそのまま返す
+    pub fn frame(&mut self) -> Frame {
+        self.sync_vram();
+
+        let (x, y, width, height) = self.display_area();
+        let vram = &self.vram;
+
+        let pixels = (0..height)
+            .flat_map(|dy| (0..width).map(move |dx| vram.read(x + dx, y + dy)))
+            .collect();
+
+        Frame {
+            width,
+            height,
+            pixels,
         }
     }
 
+    // 最後に描画した画面。ウィンドウを持たない場合はVRAMの表示範囲を15bitとして変換する
+    pub fn capture_frame(&mut self) -> RgbaImage {
+        match self.renderer.capture_frame() {
+            Some(image) => image,
+            None => RgbaImage::from_frame(&self.frame()),
+        }
+    }
+
+    // 表示範囲のレジスタから実際に表示されるVRAM上の矩形 (x, y, width, height) を求める
+    pub fn display_area(&self) -> (u16, u16, u16, u16) {
+        let dots = self
+            .display_horiz_end
+            .saturating_sub(self.display_horiz_start)
+            / self.hres.dotclock_divider();
+
+        // 幅は4ピクセル単位に丸められる
+        let width = ((dots + 2) & !3).min(self.hres.width());
+
+        let lines = self
+            .display_line_end
+            .saturating_sub(self.display_line_start)
+            .min(self.vmode.visible_lines());
+
+        let height = match (self.vres, self.interlaced) {
+            (VerticalRes::Y480Lines, true) => lines * 2,
+            _ => lines,
+        };
+
+        (
+            self.display_vram_x_start,
+            self.display_vram_y_start,
+            width,
+            height,
+        )
+    }
+
     fn status(&self) -> u32 {
         let mut r = 0u32;
 
         r |= (self.page_base_x as u32) << 0;
-        r |= (self.page_base_y as u32) << 4;
+        r |= ((self.page_base_y & 1) as u32) << 4;
         r |= (self.semi_transparency as u32) << 5;
         r |= (self.texture_depth as u32) << 7;
         r |= (self.dithering as u32) << 9;
@@ -200,60 +424,81 @@ impl Gpu {
         r |= (self.display_depth as u32) << 21;
         r |= (self.interlaced as u32) << 22;
         r |= (self.display_disabled as u32) << 23;
-        r |= (self.interlaced as u32) << 24;
+        r |= (self.interrupt as u32) << 24;
 
-        r |= 1 << 26; // 描画コマンドready
-        r |= 1 << 27; // vram to cpu ready
-        r |= 1 << 28; // DMA block ready
+        let idle = self.busy_cycles == 0 && self.gp0_fifo.is_empty();
+        r |= (idle as u32) << 26; // 描画コマンドready
+        r |= (self.image_store.is_active() as u32) << 27; // vram to cpu ready
+        r |= (self.fifo_ready() as u32) << 28; // DMA block ready
 
         r |= (self.dma_direction as u32) << 29;
 
-        r |= 0 << 31;
+        let interlaced480 = self.interlaced && matches!(self.vres, VerticalRes::Y480Lines);
+        r |= (self.timing.odd_line(interlaced480) as u32) << 31;
 
-        let dma_request = match self.dma_direction {
-            DmaDirection::Off => 0,
-            DmaDirection::Fifo => 1,
-            DmaDirection::CpuToGp0 => (r >> 28) & 1,
-            DmaDirection::VramToCpu => (r >> 27) & 1,
-        };
-
-        r |= dma_request << 25;
+        r |= (self.dma_request() as u32) << 25;
 
         r
     }
 
-    fn read(&self) -> u32 {
-        0
+    // GPUSTAT bit25 (DMAのDREQ)。GP1(0x04)の方向ごとに実際の転送の状態を見る
+    pub fn dma_request(&self) -> bool {
+        match self.dma_direction {
+            DmaDirection::Off => false,
+            // bit28 と同じくFIFOに空きがあるか
+            DmaDirection::Fifo | DmaDirection::CpuToGp0 => self.fifo_ready(),
+            // bit27 と同じくGPUREADに読むものがあるか
+            DmaDirection::VramToCpu => self.image_store.is_active(),
+        }
+    }
+
+    // GPUREAD
+    pub fn read(&mut self) -> u32 {
+        self.record(Entry::Read);
+
+        if self.image_store.is_active() {
+            let lo = self.image_store_pixel();
+            let hi = self.image_store_pixel();
+
+            self.gpuread = (lo as u32) | ((hi as u32) << 16);
+        }
+
+        self.gpuread
+    }
+
+    // GPUSTAT bit28 (FIFOに空きがある)
+    pub fn fifo_ready(&self) -> bool {
+        self.gp0_fifo.len() < GP0_FIFO_LEN
     }
 
     pub fn gp0(&mut self, val: u32) {
+        self.record(Entry::Gp0(val));
+
+        if self.busy_cycles == 0 && self.gp0_fifo.is_empty() {
+            self.execute_gp0(val);
+            return;
+        }
+
+        // 実機では書き込み側が待たされるので、溢れる分は描画の完了を待たずに処理する
+        if self.gp0_fifo.len() == GP0_FIFO_LEN {
+            self.busy_cycles = 0;
+
+            while let Some(val) = self.gp0_fifo.pop_front() {
+                self.execute_gp0(val);
+            }
+        }
+
+        self.gp0_fifo.push_back(val);
+    }
+
+    fn execute_gp0(&mut self, val: u32) {
+        if let Gp0Mode::PolyLine = self.gp0_mode {
+            self.polyline_word(val);
+            return;
+        }
+
         if self.gp0_words_remaining == 0 {
-            let opcode = (val >> 24) & 0xFF;
-            let (len, method) = match opcode {
-                0x00 => (1, Gpu::gp0_nop as fn(&mut Gpu)),
-                0x01 => (1, Gpu::gp0_clear_cache as fn(&mut Gpu)),
-                0x02 => (3, Gpu::gp0_fill_rect as fn(&mut Gpu)),
-                0x28 => (5, Gpu::gp0_quad_mono_opaque as fn(&mut Gpu)),
-                0x2C => (9, Gpu::gp0_quad_texture_blend_opaque as fn(&mut Gpu)),
-                0x2D => (9, Gpu::gp0_quad_texture_blend_opaque as fn(&mut Gpu)),
-                0x2E => (9, Gpu::gp0_quad_texture_blend_opaque as fn(&mut Gpu)),
-                0x2F => (9, Gpu::gp0_quad_texture_blend_opaque as fn(&mut Gpu)),
-                0x30 => (6, Gpu::gp0_triangle_shaded_opaque as fn(&mut Gpu)),
-                0x38 => (8, Gpu::gp0_quad_shaded_opaque as fn(&mut Gpu)),
-                0x64 => (4, Gpu::gp0_textured_rect as fn(&mut Gpu)),
-                0x65 => (4, Gpu::gp0_textured_rect as fn(&mut Gpu)),
-                0x66 => (4, Gpu::gp0_textured_rect as fn(&mut Gpu)),
-                0x67 => (4, Gpu::gp0_textured_rect as fn(&mut Gpu)),
-                0xA0 => (3, Gpu::gp0_image_load as fn(&mut Gpu)),
-                0xC0 => (3, Gpu::gp0_image_store as fn(&mut Gpu)),
-                0xE1 => (1, Gpu::gp0_draw_mode as fn(&mut Gpu)),
-                0xE2 => (1, Gpu::gp0_texture_window as fn(&mut Gpu)),
-                0xE3 => (1, Gpu::gp0_drawing_area_top_left as fn(&mut Gpu)),
-                0xE4 => (1, Gpu::gp0_drawing_area_bottom_right as fn(&mut Gpu)),
-                0xE5 => (1, Gpu::gp0_drawing_offset as fn(&mut Gpu)),
-                0xE6 => (1, Gpu::gp0_mask_bit_setting as fn(&mut Gpu)),
-                _ => panic!("Unhandled GP0 command {:08x}", val),
-            };
+            let (len, method) = Gpu::gp0_decode(val);
 
             self.gp0_words_remaining = len;
             self.gp0_command_method = method;
@@ -272,10 +517,38 @@ impl Gpu {
                 }
             }
             Gp0Mode::ImageLoad => {
+                self.image_load_pixel(val as u16);
+                self.image_load_pixel((val >> 16) as u16);
+
                 if self.gp0_words_remaining == 0 {
                     self.gp0_mode = Gp0Mode::Command;
                 }
             }
+            Gp0Mode::PolyLine => unreachable!(),
+        }
+    }
+
+    // コマンドの語数と処理
+    fn gp0_decode(val: u32) -> (u32, fn(&mut Gpu)) {
+        let opcode = (val >> 24) & 0xFF;
+
+        match opcode {
+            0x00 => (1, Gpu::gp0_nop as fn(&mut Gpu)),
+            0x01 => (1, Gpu::gp0_clear_cache as fn(&mut Gpu)),
+            0x02 => (3, Gpu::gp0_fill_rect as fn(&mut Gpu)),
+            0x1F => (1, Gpu::gp0_interrupt_request as fn(&mut Gpu)),
+            0x20..=0x3F => (Gpu::polygon_len(opcode), Gpu::gp0_polygon as fn(&mut Gpu)),
+            0x40..=0x5F => (Gpu::line_len(opcode), Gpu::gp0_line as fn(&mut Gpu)),
+            0x60..=0x7F => (Gpu::rect_len(opcode), Gpu::gp0_rect as fn(&mut Gpu)),
+            0xA0 => (3, Gpu::gp0_image_load as fn(&mut Gpu)),
+            0xC0 => (3, Gpu::gp0_image_store as fn(&mut Gpu)),
+            0xE1 => (1, Gpu::gp0_draw_mode as fn(&mut Gpu)),
+            0xE2 => (1, Gpu::gp0_texture_window as fn(&mut Gpu)),
+            0xE3 => (1, Gpu::gp0_drawing_area_top_left as fn(&mut Gpu)),
+            0xE4 => (1, Gpu::gp0_drawing_area_bottom_right as fn(&mut Gpu)),
+            0xE5 => (1, Gpu::gp0_drawing_offset as fn(&mut Gpu)),
+            0xE6 => (1, Gpu::gp0_mask_bit_setting as fn(&mut Gpu)),
+            _ => panic!("Unhandled GP0 command {:08x}", val),
         }
     }
 
@@ -289,8 +562,16 @@ impl Gpu {
         debug!("GPU gp0 clear cache");
     }
 
+    // GP0(0x1F) interrupt request
+    // GP1(0x02) で確認されるまで立ったまま
+    fn gp0_interrupt_request(&mut self) {
+        debug!("GPU gp0 interrupt request");
+        self.interrupt = true;
+    }
+
     // GP0(0x02) fill rect
     fn gp0_fill_rect(&mut self) {
+        self.primitives.fills += 1;
         debug!("GPU gp0 fill rect");
 
         let top_left = Position::from_gp0(self.gp0_command[1]);
@@ -299,151 +580,408 @@ impl Gpu {
 
         let top_left = Position(top_left.0 & 0x3F0, top_left.1 & 0x1FF);
         let size = Position(((size.0 & 0x3FF) + 0x0F) & !0x0F, size.1 & 0x1FF);
+
+        // マスクビットは無視され、VRAMの端で折り返す
+        let pixel = color.to_vram();
+        for dy in 0..size.1 {
+            for dx in 0..size.0 {
+                let x = (top_left.0 + dx) as u16;
+                let y = (top_left.1 + dy) as u16;
+
+                self.vram.write(x, y, pixel);
+            }
+        }
+
+        self.busy_for(Gpu::fill_cycles(size.0 as u32 * size.1 as u32));
+
+        // 描画オフセットもマスクも無視されるのでVRAMの内容をそのまま写す
         let right_bottom = top_left.inflate(size.0, size.1).limit(0x400, 0x200);
         let size = right_bottom.deflate(top_left.0, top_left.1);
 
-        self.renderer.fill_rect(color, top_left, size);
+        self.renderer.push_vram_copy(
+            top_left.0 as u16,
+            top_left.1 as u16,
+            size.0 as u16,
+            size.1 as u16,
+        );
+    }
+
+    // 描画にかかる時間の目安。この間に来たワードはFIFOに溜める
+    fn busy_for(&mut self, cycles: u32) {
+        self.busy_cycles = self.busy_cycles.saturating_add(cycles);
     }
 
-    // GP0(0x28) monochrome opaque quad
-    fn gp0_quad_mono_opaque(&mut self) {
-        debug!("GPU gp0 quad mono opaque");
+    // 1 CPUサイクルあたりおよそ2ピクセル (テクスチャ付きはその半分)
+    fn draw_cycles(pixels: u32, textured: bool) -> u32 {
+        if textured {
+            pixels
+        } else {
+            pixels / 2
+        }
+    }
 
-        let positions = [
-            Position::from_gp0(self.gp0_command[1]),
-            Position::from_gp0(self.gp0_command[2]),
-            Position::from_gp0(self.gp0_command[3]),
-            Position::from_gp0(self.gp0_command[4]),
-        ];
+    // 塗りつぶしは1 CPUサイクルあたりおよそ8ピクセル
+    fn fill_cycles(pixels: u32) -> u32 {
+        pixels / 8
+    }
 
-        let colors = [Color::from_gp0(self.gp0_command[0]); 4];
+    // 頂点ごとに (色) + 座標 (+UV)。先頭の色はコマンドと同じワード
+    fn polygon_len(opcode: u32) -> u32 {
+        let vertices = if opcode & 0x08 != 0 { 4 } else { 3 };
+        let textured = (opcode >> 2) & 1;
+        let shaded = (opcode >> 4) & 1;
 
-        self.renderer.push_quad(positions, colors);
+        1 + vertices * (1 + textured) + (vertices - 1) * shaded
     }
 
-    // GP0(0x2C) texture blend opaque qud
-    fn gp0_quad_texture_blend_opaque(&mut self) {
-        debug!("GPU gp0 quad texture blend opaque");
+    // GP0(0x20-0x3F) polygon
+    // bit0: テクスチャの輝度変調なし, bit1: 半透明, bit2: テクスチャ, bit3: 四角形, bit4: グーロー
+    // レンダラーのブレンドは BlendState::REPLACE だけなので、半透明のポリゴンも不透明で描く
+    fn gp0_polygon(&mut self) {
+        self.primitives.polygons += 1;
+        let opcode = self.gp0_command[0] >> 24;
+        let textured = opcode & 0x04 != 0;
+        let quad = opcode & 0x08 != 0;
+        let shaded = opcode & 0x10 != 0;
+
+        debug!("GPU gp0 polygon {:02x}", opcode);
+
+        let nvertices = if quad { 4 } else { 3 };
+
+        let mut positions = [Position(0, 0); 4];
+        let mut colors = [Color::from_gp0(self.gp0_command[0]); 4];
+        let mut uvs = [0; 4];
+
+        let mut i = 1;
+        for v in 0..nvertices {
+            if shaded && v > 0 {
+                colors[v] = Color::from_gp0(self.gp0_command[i]);
+                i += 1;
+            }
 
-        let positions = [
-            Position::from_gp0(self.gp0_command[1]),
-            Position::from_gp0(self.gp0_command[3]),
-            Position::from_gp0(self.gp0_command[5]),
-            Position::from_gp0(self.gp0_command[7]),
-        ];
+            positions[v] = Position::from_gp0(self.gp0_command[i]);
+            i += 1;
 
-        // FIXME: テクスチャの実装
-        let colors = [Color(0x80, 0x00, 0x00); 4];
+            if textured {
+                uvs[v] = self.gp0_command[i];
+                i += 1;
+            }
+        }
 
-        self.renderer.push_quad(positions, colors);
+        let mut area = Position::triangle_area(positions[0], positions[1], positions[2]);
+        if quad {
+            area += Position::triangle_area(positions[1], positions[2], positions[3]);
+        }
+        self.busy_for(Gpu::draw_cycles(area, textured));
+
+        // ディザはグーローか輝度変調をする場合だけかかる
+        let raw = opcode & 0x01 != 0;
+        self.renderer
+            .set_dithering(self.dithering && (shaded || (textured && !raw)));
+
+        if !textured {
+            if quad {
+                self.renderer.push_quad(positions, colors);
+            } else {
+                self.renderer.push_triangles(
+                    [positions[0], positions[1], positions[2]],
+                    [colors[0], colors[1], colors[2]],
+                );
+            }
+            return;
+        }
+
+        // 1番目のUVの上位16bitがCLUT、2番目がtexpage
+        self.set_texture_page(uvs[1] >> 16);
+        let (page_x, page_y) = self.texture_page_base();
+        debug!("GPU texture page ({}, {})", page_x, page_y);
+
+        let texture = Texture {
+            page: self.texpage(),
+            clut: (uvs[0] >> 16) as u16,
+            window: self.texture_window.pack(),
+            raw,
+        };
+
+        let texcoords = uvs.map(|uv| [(uv & 0xFF) as f32, ((uv >> 8) & 0xFF) as f32]);
+
+        if quad {
+            self.renderer
+                .push_textured_quad(positions, colors, texcoords, texture);
+        } else {
+            self.renderer.push_textured_triangle(
+                [positions[0], positions[1], positions[2]],
+                [colors[0], colors[1], colors[2]],
+                [texcoords[0], texcoords[1], texcoords[2]],
+                texture,
+            );
+        }
+    }
+
+    // 最初の線分の色+座標 (グーローの場合は終点の色も)
+    fn line_len(opcode: u32) -> u32 {
+        if opcode & 0x10 != 0 {
+            4
+        } else {
+            3
+        }
     }
 
-    // GP0(0x30) shaded opaque triangle
-    fn gp0_triangle_shaded_opaque(&mut self) {
-        debug!("GPU gp0 triangle shaded opaque");
+    // GP0(0x40-0x5F) line
+    // bit1: 半透明, bit3: ポリライン, bit4: グーロー
+    fn gp0_line(&mut self) {
+        self.primitives.lines += 1;
+        let opcode = self.gp0_command[0] >> 24;
+        let shaded = opcode & 0x10 != 0;
+
+        debug!("GPU gp0 line {:02x}", opcode);
+
+        let start_color = Color::from_gp0(self.gp0_command[0]);
+        let start = Position::from_gp0(self.gp0_command[1]);
+
+        let (end_color, end) = if shaded {
+            (
+                Color::from_gp0(self.gp0_command[2]),
+                Position::from_gp0(self.gp0_command[3]),
+            )
+        } else {
+            (start_color, Position::from_gp0(self.gp0_command[2]))
+        };
 
-        let positions = [
-            Position::from_gp0(self.gp0_command[1]),
-            Position::from_gp0(self.gp0_command[3]),
-            Position::from_gp0(self.gp0_command[5]),
-        ];
+        self.busy_for(Gpu::draw_cycles(Position::line_length(start, end), false));
 
-        let colors = [
-            Color::from_gp0(self.gp0_command[0]),
-            Color::from_gp0(self.gp0_command[2]),
-            Color::from_gp0(self.gp0_command[4]),
-        ];
+        self.renderer.set_dithering(self.dithering && shaded);
+        self.renderer
+            .push_line([start, end], [start_color, end_color]);
 
-        self.renderer.push_triangles(positions, colors);
+        if opcode & 0x08 != 0 {
+            self.polyline = PolyLine {
+                shaded,
+                position: end,
+                color: end_color,
+                next_color: None,
+            };
+            self.gp0_mode = Gp0Mode::PolyLine;
+        }
     }
 
-    // GP0(0x38) shaded opaque quad
-    fn gp0_quad_shaded_opaque(&mut self) {
-        debug!("GPU gp0 shaded opaque");
+    // ポリラインの2本目以降の頂点 (0x5XXX5XXXで終了)
+    fn polyline_word(&mut self, val: u32) {
+        if val & 0xF000F000 == 0x50005000 {
+            self.gp0_mode = Gp0Mode::Command;
+            return;
+        }
 
-        let positions = [
-            Position::from_gp0(self.gp0_command[1]),
-            Position::from_gp0(self.gp0_command[3]),
-            Position::from_gp0(self.gp0_command[5]),
-            Position::from_gp0(self.gp0_command[7]),
-        ];
+        if self.polyline.shaded && self.polyline.next_color.is_none() {
+            self.polyline.next_color = Some(Color::from_gp0(val));
+            return;
+        }
 
-        let colors = [
-            Color::from_gp0(self.gp0_command[0]),
-            Color::from_gp0(self.gp0_command[2]),
-            Color::from_gp0(self.gp0_command[4]),
-            Color::from_gp0(self.gp0_command[6]),
-        ];
+        let position = Position::from_gp0(val);
+        self.primitives.lines += 1;
+        self.busy_for(Gpu::draw_cycles(
+            Position::line_length(self.polyline.position, position),
+            false,
+        ));
+
+        let color = self
+            .polyline
+            .next_color
+            .take()
+            .unwrap_or(self.polyline.color);
+
+        self.renderer.push_line(
+            [self.polyline.position, position],
+            [self.polyline.color, color],
+        );
 
-        self.renderer.push_quad(positions, colors);
+        self.polyline.position = position;
+        self.polyline.color = color;
     }
 
-    // GP0(0x64) textured rect
-    fn gp0_textured_rect(&mut self) {
-        debug!("GPU gp0 textured rect");
+    // 色+座標 (+UV/CLUT) (+サイズ)
+    fn rect_len(opcode: u32) -> u32 {
+        let textured = (opcode >> 2) & 1;
+        let variable_size = ((opcode >> 3) & 3 == 0) as u32;
+
+        2 + textured + variable_size
+    }
 
+    // GP0(0x60-0x7F) rectangle
+    // bit0: テクスチャの輝度変調なし, bit1: 半透明, bit2: テクスチャ, bit3-4: サイズ
+    fn gp0_rect(&mut self) {
+        self.primitives.rects += 1;
+        let opcode = self.gp0_command[0] >> 24;
+        let textured = opcode & 0x04 != 0;
+
+        debug!("GPU gp0 rect {:02x}", opcode);
+
+        let color = Color::from_gp0(self.gp0_command[0]);
         let top_left = Position::from_gp0(self.gp0_command[1]);
-        let size = Position::from_gp0(self.gp0_command[3]);
+
+        let (width, height) = match (opcode >> 3) & 3 {
+            0 => {
+                let size = self.gp0_command[if textured { 3 } else { 2 }];
+
+                ((size & 0x3FF) as i16, ((size >> 16) & 0x1FF) as i16)
+            }
+            1 => (1, 1),
+            2 => (8, 8),
+            3 => (16, 16),
+            _ => unreachable!(),
+        };
 
         let positions = [
             top_left,
-            top_left.inflate(size.0, 0),
-            top_left.inflate(0, size.1),
-            top_left.inflate(size.0, size.1),
+            top_left.inflate(width, 0),
+            top_left.inflate(0, height),
+            top_left.inflate(width, height),
         ];
 
-        let colors = [Color(0x80, 0x00, 0x00); 4];
+        self.busy_for(Gpu::draw_cycles(width as u32 * height as u32, textured));
+
+        // 矩形にはディザがかからない
+        self.renderer.set_dithering(false);
+
+        if !textured {
+            self.renderer.push_quad(positions, [color; 4]);
+            return;
+        }
+
+        let uv = self.gp0_command[2];
+        let texture = Texture {
+            page: self.texpage(),
+            clut: (uv >> 16) as u16,
+            window: self.texture_window.pack(),
+            raw: opcode & 0x01 != 0,
+        };
+
+        let (u0, u1) = Gpu::rect_texcoords(uv as u8, width, self.rectangle_texture_x_flip);
+        let (v0, v1) = Gpu::rect_texcoords((uv >> 8) as u8, height, self.rectangle_texture_y_flip);
 
-        self.renderer.push_quad(positions, colors)
+        let texcoords = [[u0, v0], [u1, v0], [u0, v1], [u1, v1]];
+
+        self.renderer
+            .push_textured_quad(positions, [color; 4], texcoords, texture);
+    }
+
+    // 矩形の両端のテクスチャ座標
+    // ピクセル中心で補間されるので、反転時は1つずらして先頭ピクセルがstartを指すようにする
+    fn rect_texcoords(start: u8, len: i16, flip: bool) -> (f32, f32) {
+        let start = start as f32;
+        let len = len as f32;
+
+        if flip {
+            (start + 1.0, start + 1.0 - len)
+        } else {
+            (start, start + len)
+        }
     }
 
     // GP0(0xA0) image load
     fn gp0_image_load(&mut self) {
-        let res = self.gp0_command[2];
+        self.primitives.image_loads += 1;
+
+        // マスクビットはCPU側のVRAMで確かめるので、描いた内容を先に戻しておく
+        if self.preserve_masked_pixels {
+            self.sync_vram();
+        }
 
-        let width = res & 0xFFFF;
-        let height = res >> 16;
+        self.image_load = ImageTransfer::new(self.gp0_command[1], self.gp0_command[2]);
 
-        let imgsize = width * height;
+        let imgsize = self.image_load.len();
         let imgsize = (imgsize + 1) & !1;
 
         self.gp0_words_remaining = imgsize / 2;
 
         self.gp0_mode = Gp0Mode::ImageLoad;
 
-        debug!("GPU gp0 image load ({}, {})", width, height);
+        // 転送が終わる頃にはVRAMに書き込まれている
+        self.renderer.push_vram_copy(
+            self.image_load.x,
+            self.image_load.y,
+            self.image_load.width,
+            self.image_load.height,
+        );
+
+        debug!(
+            "GPU gp0 image load ({}, {}) {}x{}",
+            self.image_load.x, self.image_load.y, self.image_load.width, self.image_load.height
+        );
+    }
+
+    fn image_load_pixel(&mut self, val: u16) {
+        // 奇数サイズの場合の最後のパディングは捨てる
+        if let Some((x, y)) = self.image_load.next() {
+            self.write_vram_masked(x, y, val);
+        }
     }
 
     // GP0(0xC0) image store
     fn gp0_image_store(&mut self) {
-        let res = self.gp0_command[2];
-
-        let width = res & 0xFFFF;
-        let height = res >> 16;
+        self.sync_vram();
+        self.image_store = ImageTransfer::new(self.gp0_command[1], self.gp0_command[2]);
 
-        debug!("GPU gp0 res ({}, {})", width, height);
-
-        warn!("Unhandled image store: {}x{}", width, height);
+        debug!(
+            "GPU gp0 image store ({}, {}) {}x{}",
+            self.image_store.x, self.image_store.y, self.image_store.width, self.image_store.height
+        );
     }
 
-    // GP0(0xE1) draw command
-    fn gp0_draw_mode(&mut self) {
-        let val = self.gp0_command.val1();
+    fn image_store_pixel(&mut self) -> u16 {
+        // 奇数サイズの場合の最後の半分は0
+        match self.image_store.next() {
+            Some((x, y)) => self.vram.read(x, y),
+            None => 0,
+        }
+    }
 
+    // GP0(0xE1) とテクスチャ付きポリゴンのtexpage属性で共通のビット
+    fn set_texture_page(&mut self, val: u32) {
         self.page_base_x = (val & 0xF) as u8;
-        self.page_base_y = ((val >> 4) & 1) as u8;
+        self.page_base_y = (((val >> 4) & 1) | ((val >> 10) & 2)) as u8;
         self.semi_transparency = ((val >> 5) & 3) as u8;
 
         self.texture_depth = match (val >> 7) & 3 {
             0 => TextureDepth::T4Bit,
             1 => TextureDepth::T8Bit,
-            2 => TextureDepth::T15Bit,
-            n => panic!("Unhandled texture depth {}", n),
+            // 3 は予約済みで 15bit と同じに扱われる
+            _ => TextureDepth::T15Bit,
         };
 
+        // bit11は1MB VRAMではテクスチャ無効化, 2MB VRAMではY base 2として扱われる
+        self.texture_disable = ((val >> 11) & 1) != 0;
+    }
+
+    // GP0(0xE1)と同じ形式の現在のtexpage
+    fn texpage(&self) -> u16 {
+        let mut r = 0;
+
+        r |= self.page_base_x as u16;
+        r |= ((self.page_base_y & 1) as u16) << 4;
+        r |= (self.semi_transparency as u16) << 5;
+        r |= (self.texture_depth as u16) << 7;
+
+        r
+    }
+
+    // テクスチャページの左上のVRAM座標
+    fn texture_page_base(&self) -> (u16, u16) {
+        let x = self.page_base_x as u16 * 64;
+        let y = (self.page_base_y & 1) as u16 * 256 + (self.page_base_y >> 1) as u16 * 512;
+
+        // 1MB VRAMでは512ライン目以降は折り返す
+        (x, y % VRAM_HEIGHT)
+    }
+
+    // GP0(0xE1) draw command
+    fn gp0_draw_mode(&mut self) {
+        let val = self.gp0_command.val1();
+
+        self.set_texture_page(val);
+
         self.dithering = ((val >> 9) & 1) != 0;
         self.draw_to_display = ((val >> 10) & 1) != 0;
-        self.texture_disable = ((val >> 11) & 1) != 0;
         self.rectangle_texture_x_flip = ((val >> 12) & 1) != 0;
         self.rectangle_texture_y_flip = ((val >> 13) & 1) != 0;
 
@@ -454,18 +992,9 @@ impl Gpu {
     fn gp0_texture_window(&mut self) {
         let val = self.gp0_command.val1();
 
-        self.texture_window_x_mask = (val & 0x1F) as u8;
-        self.texture_window_y_mask = ((val >> 5) & 0x1F) as u8;
-        self.texture_window_x_offset = ((val >> 10) & 0x1F) as u8;
-        self.texture_window_y_offset = ((val >> 15) & 0x1F) as u8;
+        self.texture_window = TextureWindow::from_command(val);
 
-        debug!(
-            "GPU gp0 texture window mask:({}, {}), offset:({}, {})",
-            self.texture_window_x_mask,
-            self.texture_window_y_mask,
-            self.texture_window_x_offset,
-            self.texture_window_y_offset
-        );
+        debug!("GPU gp0 texture window {:?}", self.texture_window);
     }
 
     // GP0(0xE3) set drawing area top left
@@ -502,6 +1031,8 @@ impl Gpu {
 
         debug!("GPU gp0 drawing offset ({}, {})", x, y);
 
+        self.drawing_x_offset = x;
+        self.drawing_y_offset = y;
         self.renderer.set_draw_offset(x, y);
     }
 
@@ -513,9 +1044,57 @@ impl Gpu {
 
         self.force_set_mask_bit = (val & 1) != 0;
         self.preserve_masked_pixels = (val & 2) != 0;
+
+        self.renderer.set_mask_check(self.preserve_masked_pixels);
+        self.renderer.set_force_set_mask(self.force_set_mask_bit);
+    }
+
+    // 積んである描画を反映してから描画先をCPU側のVRAMに読み戻す
+    // 読み戻しはGPUとの同期待ちになるので、VRAMをCPUから見るときだけ呼ぶ
+    pub fn sync_vram(&mut self) {
+        self.renderer.update_vram(self.vram.data());
+        self.renderer.flush().unwrap();
+        self.read_back_vram();
+    }
+
+    // 描画はGPU側の描画先にしか書かれないので、CPUから読む前にVRAMへ戻す
+    fn read_back_vram(&mut self) {
+        let data = match self.renderer.read_vram() {
+            Some(data) => data,
+            None => return,
+        };
+
+        self.vram.data_mut().copy_from_slice(&data);
+
+        // 転送中の残りのピクセルはこれから書かれるので、もう一度描画先に写す
+        if self.image_load.is_active() {
+            self.renderer.push_vram_copy(
+                self.image_load.x,
+                self.image_load.y,
+                self.image_load.width,
+                self.image_load.height,
+            );
+        }
+    }
+
+    // マスクビットの設定に従ってVRAMに書き込む
+    fn write_vram_masked(&mut self, x: u16, y: u16, val: u16) {
+        if self.preserve_masked_pixels && self.vram.read(x, y) & 0x8000 != 0 {
+            return;
+        }
+
+        let val = if self.force_set_mask_bit {
+            val | 0x8000
+        } else {
+            val
+        };
+
+        self.vram.write(x, y, val);
     }
 
-    fn gp1(&mut self, val: u32) {
+    pub fn gp1(&mut self, val: u32) {
+        self.record(Entry::Gp1(val));
+
         let opcode = (val >> 24) & 0xFF;
 
         match opcode {
@@ -528,6 +1107,7 @@ impl Gpu {
             0x06 => self.gp1_display_horizontal_range(val),
             0x07 => self.gp1_display_vertical_range(val),
             0x08 => self.gp1_display_mode(val),
+            0x10..=0x1F => self.gp1_get_info(val),
             _ => panic!("Unhandled GP1 command {:08x}", val),
         }
     }
@@ -542,10 +1122,7 @@ impl Gpu {
         self.page_base_y = 0;
         self.semi_transparency = 0;
         self.texture_depth = TextureDepth::T4Bit;
-        self.texture_window_x_mask = 0;
-        self.texture_window_y_mask = 0;
-        self.texture_window_x_offset = 0;
-        self.texture_window_y_offset = 0;
+        self.texture_window = TextureWindow::default();
         self.dithering = false;
         self.draw_to_display = false;
         self.texture_disable = false;
@@ -561,6 +1138,7 @@ impl Gpu {
         self.dma_direction = DmaDirection::Off;
 
         self.display_disabled = true;
+        self.flips = FlipDetector::new();
 
         self.display_vram_x_start = 0;
         self.display_vram_y_start = 0;
@@ -575,7 +1153,11 @@ impl Gpu {
         self.display_line_end = 0x100;
         self.display_depth = DisplayDepth::D15Bits;
 
+        self.drawing_x_offset = 0;
+        self.drawing_y_offset = 0;
         self.renderer.set_draw_offset(0, 0);
+        self.renderer.set_mask_check(false);
+        self.renderer.set_force_set_mask(false);
 
         self.gp1_reset_command_buffer(0);
     }
@@ -583,6 +1165,8 @@ impl Gpu {
     // GP1(0x01) reset command buffer
     fn gp1_reset_command_buffer(&mut self, _: u32) {
         debug!("GPU gp1 reset command buffer");
+        self.gp0_fifo.clear();
+        self.busy_cycles = 0;
         self.gp0_command.clear();
         self.gp0_words_remaining = 0;
         self.gp0_mode = Gp0Mode::Command;
@@ -616,6 +1200,8 @@ impl Gpu {
     fn gp1_display_vram_start(&mut self, val: u32) {
         self.display_vram_x_start = (val & 0x3FE) as u16;
         self.display_vram_y_start = ((val >> 10) & 0x1FF) as u16;
+        self.flips
+            .set_start(self.display_vram_x_start, self.display_vram_y_start);
         debug!(
             "GPU gp1 display vram start ({}, {})",
             self.display_vram_x_start, self.display_vram_y_start
@@ -660,8 +1246,8 @@ impl Gpu {
         };
 
         self.display_depth = match val & 0x10 != 0 {
-            false => DisplayDepth::D24Bits,
-            true => DisplayDepth::D15Bits,
+            false => DisplayDepth::D15Bits,
+            true => DisplayDepth::D24Bits,
         };
 
         self.interlaced = val & 0x20 != 0;
@@ -670,16 +1256,39 @@ impl Gpu {
             panic!("Unsuuported display mode {:08x}", val);
         }
     }
+
+    // GP1(0x10) get GPU info
+    // 結果はGPUREADで読む。対象外の番号では前の値が残る
+    fn gp1_get_info(&mut self, val: u32) {
+        debug!("GPU gp1 get info {:08x}", val);
+
+        self.gpuread = match val & 0xF {
+            0x2 => self.texture_window.pack(),
+            0x3 => ((self.drawing_area_top as u32) << 10) | self.drawing_area_left as u32,
+            0x4 => ((self.drawing_area_bottom as u32) << 10) | self.drawing_area_right as u32,
+            0x5 => {
+                let x = (self.drawing_x_offset as u32) & 0x7FF;
+                let y = (self.drawing_y_offset as u32) & 0x7FF;
+
+                (y << 11) | x
+            }
+            // GPUのバージョン
+            0x7 => 2,
+            _ => return,
+        };
+    }
 }
 
-#[derive(Clone, Copy)]
+const GP0_FIFO_LEN: usize = 16;
+
+#[derive(Clone, Copy, FromPrimitive)]
 enum TextureDepth {
     T4Bit = 0,
     T8Bit = 1,
     T15Bit = 2,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, FromPrimitive)]
 enum Field {
     Top = 1,
     Bottom = 0,
@@ -704,36 +1313,46 @@ impl HorizontalRes {
     fn width(&self) -> u16 {
         let HorizontalRes(hr) = self;
 
-        match hr {
-            0b000 => 256,
-            0b001 => 368,
-            0b010 => 320,
-            0b100 => 512,
-            0b110 => 640,
+        // hr2が立っている場合はhr1に関係なく368
+        if hr & 1 != 0 {
+            return 368;
+        }
+
+        match hr >> 1 {
+            0 => 256,
+            1 => 320,
+            2 => 512,
+            3 => 640,
+            _ => unreachable!(),
+        }
+    }
+
+    // 1ドットあたりのGPUクロック数
+    fn dotclock_divider(&self) -> u16 {
+        match self.width() {
+            256 => 10,
+            320 => 8,
+            368 => 7,
+            512 => 5,
+            640 => 4,
             _ => unreachable!(),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, FromPrimitive)]
 enum VerticalRes {
     Y240Lines = 0,
     Y480Lines = 1,
 }
 
-#[derive(Clone, Copy)]
-enum VMode {
-    Ntsc = 0,
-    Pal = 1,
-}
-
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, FromPrimitive)]
 enum DisplayDepth {
     D15Bits = 0,
     D24Bits = 1,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, FromPrimitive)]
 enum DmaDirection {
     Off = 0,
     Fifo = 1,
@@ -741,7 +1360,867 @@ enum DmaDirection {
     VramToCpu = 3,
 }
 
+// GP0(0xA0)/GP0(0xC0) で転送する矩形
+struct ImageTransfer {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    index: u32,
+}
+
+impl ImageTransfer {
+    fn idle() -> ImageTransfer {
+        ImageTransfer {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            index: 0,
+        }
+    }
+
+    fn new(position: u32, size: u32) -> ImageTransfer {
+        let x = (position & 0x3FF) as u16;
+        let y = ((position >> 16) & 0x1FF) as u16;
+
+        // 0は最大サイズとして扱われる
+        let width = (((size & 0xFFFF).wrapping_sub(1) & 0x3FF) + 1) as u16;
+        let height = (((size >> 16).wrapping_sub(1) & 0x1FF) + 1) as u16;
+
+        ImageTransfer {
+            x,
+            y,
+            width,
+            height,
+            index: 0,
+        }
+    }
+
+    fn len(&self) -> u32 {
+        self.width as u32 * self.height as u32
+    }
+
+    fn is_active(&self) -> bool {
+        self.index < self.len()
+    }
+
+    // 次に転送するVRAM座標 (VRAMの端で折り返す)
+    fn next(&mut self) -> Option<(u16, u16)> {
+        if self.index >= self.len() {
+            return None;
+        }
+
+        let dx = (self.index % self.width as u32) as u16;
+        let dy = (self.index / self.width as u32) as u16;
+
+        self.index += 1;
+
+        Some((self.x + dx, self.y + dy))
+    }
+}
+
+#[derive(Clone, Copy, FromPrimitive)]
 enum Gp0Mode {
     Command,
     ImageLoad,
+    PolyLine,
+}
+
+// ポリラインの直前の頂点
+struct PolyLine {
+    shaded: bool,
+    position: Position,
+    color: Color,
+    // グーローの場合は色と座標が別のワードで来る
+    next_color: Option<Color>,
+}
+
+impl PolyLine {
+    fn new() -> PolyLine {
+        PolyLine {
+            shaded: false,
+            position: Position(0, 0),
+            color: Color(0, 0, 0),
+            next_color: None,
+        }
+    }
+}
+
+impl Savestate for PolyLine {
+    fn save_state(&self, w: &mut Writer) {
+        w.bool(self.shaded);
+        w.u16(self.position.0 as u16);
+        w.u16(self.position.1 as u16);
+        w.u32(self.color.to_gp0());
+        w.bool(self.next_color.is_some());
+        w.u32(self.next_color.map_or(0, Color::to_gp0));
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.shaded = r.bool()?;
+        self.position = Position(r.u16()? as i16, r.u16()? as i16);
+        self.color = Color::from_gp0(r.u32()?);
+        let has_next_color = r.bool()?;
+        let next_color = Color::from_gp0(r.u32()?);
+        self.next_color = has_next_color.then_some(next_color);
+
+        Ok(())
+    }
+}
+
+impl Savestate for Gpu {
+    fn save_state(&self, w: &mut Writer) {
+        w.u8(self.page_base_x);
+        w.u8(self.page_base_y);
+        w.u8(self.semi_transparency);
+        w.u8(self.texture_depth as u8);
+        w.bool(self.dithering);
+        w.bool(self.draw_to_display);
+        w.bool(self.force_set_mask_bit);
+        w.bool(self.preserve_masked_pixels);
+        w.u8(self.field as u8);
+        w.bool(self.texture_disable);
+        w.u8(self.hres.0);
+        w.u8(self.vres as u8);
+        w.u8(self.vmode as u8);
+        w.u8(self.display_depth as u8);
+        w.bool(self.interlaced);
+        w.bool(self.display_disabled);
+        w.bool(self.interrupt);
+        w.u8(self.dma_direction as u8);
+        w.bool(self.rectangle_texture_x_flip);
+        w.bool(self.rectangle_texture_y_flip);
+        w.u32(self.texture_window.pack());
+        w.u16(self.drawing_area_left);
+        w.u16(self.drawing_area_top);
+        w.u16(self.drawing_area_right);
+        w.u16(self.drawing_area_bottom);
+        w.u16(self.drawing_x_offset as u16);
+        w.u16(self.drawing_y_offset as u16);
+        w.u16(self.display_vram_x_start);
+        w.u16(self.display_vram_y_start);
+        w.u16(self.display_horiz_start);
+        w.u16(self.display_horiz_end);
+        w.u16(self.display_line_start);
+        w.u16(self.display_line_end);
+
+        self.timing.save_state(w);
+        self.irq_line.save_state(w);
+        self.vblank_line.save_state(w);
+
+        w.u8(self.gp0_mode as u8);
+        w.u32(self.gp0_fifo.len() as u32);
+        for val in &self.gp0_fifo {
+            w.u32(*val);
+        }
+        w.u32(self.busy_cycles);
+        w.u32(self.gp0_words_remaining);
+        self.gp0_command.save_state(w);
+        self.polyline.save_state(w);
+        self.image_load.save_state(w);
+        self.image_store.save_state(w);
+        w.u32(self.gpuread);
+
+        self.vram.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.page_base_x = r.u8()?;
+        self.page_base_y = r.u8()?;
+        self.semi_transparency = r.u8()?;
+        self.texture_depth = r.variant()?;
+        self.dithering = r.bool()?;
+        self.draw_to_display = r.bool()?;
+        self.force_set_mask_bit = r.bool()?;
+        self.preserve_masked_pixels = r.bool()?;
+        self.field = r.variant()?;
+        self.texture_disable = r.bool()?;
+        self.hres = HorizontalRes(r.u8()? & 7);
+        self.vres = r.variant()?;
+        self.vmode = r.variant()?;
+        self.display_depth = r.variant()?;
+        self.interlaced = r.bool()?;
+        self.display_disabled = r.bool()?;
+        self.interrupt = r.bool()?;
+        self.dma_direction = r.variant()?;
+        self.rectangle_texture_x_flip = r.bool()?;
+        self.rectangle_texture_y_flip = r.bool()?;
+        self.texture_window = TextureWindow::from_command(r.u32()?);
+        self.drawing_area_left = r.u16()?;
+        self.drawing_area_top = r.u16()?;
+        self.drawing_area_right = r.u16()?;
+        self.drawing_area_bottom = r.u16()?;
+        self.drawing_x_offset = r.u16()? as i16;
+        self.drawing_y_offset = r.u16()? as i16;
+        self.display_vram_x_start = r.u16()?;
+        self.display_vram_y_start = r.u16()?;
+        self.display_horiz_start = r.u16()?;
+        self.display_horiz_end = r.u16()?;
+        self.display_line_start = r.u16()?;
+        self.display_line_end = r.u16()?;
+
+        self.timing.load_state(r)?;
+        self.irq_line.load_state(r)?;
+        self.vblank_line.load_state(r)?;
+
+        self.gp0_mode = r.variant()?;
+        let len = r.u32()? as usize;
+        if len > GP0_FIFO_LEN {
+            bail!("Invalid GP0 FIFO length {}", len);
+        }
+        self.gp0_fifo.clear();
+        for _ in 0..len {
+            self.gp0_fifo.push_back(r.u32()?);
+        }
+        self.busy_cycles = r.u32()?;
+        self.gp0_words_remaining = r.u32()?;
+        self.gp0_command.load_state(r)?;
+        self.polyline.load_state(r)?;
+        self.image_load.load_state(r)?;
+        self.image_store.load_state(r)?;
+        self.gpuread = r.u32()?;
+
+        // 関数ポインタは保存できないので受信中のコマンドから復元する
+        self.gp0_command_method = if self.gp0_words_remaining > 0 {
+            Gpu::gp0_decode(self.gp0_command.val1()).1
+        } else {
+            |&mut _| {}
+        };
+
+        self.renderer
+            .set_draw_offset(self.drawing_x_offset, self.drawing_y_offset);
+        self.renderer.set_mask_check(self.preserve_masked_pixels);
+        self.renderer.set_force_set_mask(self.force_set_mask_bit);
+
+        self.vram.load_state(r)?;
+
+        // 描画先もVRAMの内容で置き換える
+        self.renderer.push_vram_copy(0, 0, VRAM_WIDTH, VRAM_HEIGHT);
+
+        Ok(())
+    }
+}
+
+impl Savestate for ImageTransfer {
+    fn save_state(&self, w: &mut Writer) {
+        w.u16(self.x);
+        w.u16(self.y);
+        w.u16(self.width);
+        w.u16(self.height);
+        w.u32(self.index);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.x = r.u16()?;
+        self.y = r.u16()?;
+        self.width = r.u16()?;
+        self.height = r.u16()?;
+        self.index = r.u32()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::{Interrupts, IrqEvent};
+
+    fn drain(gpu: &mut Gpu) {
+        while !gpu.gp0_fifo.is_empty() || gpu.busy_cycles > 0 {
+            gpu.tick(&mut IrqBus::new());
+        }
+    }
+
+    // GP0(0xA0) で1ワード2ピクセルずつ書き込む
+    fn image_load(gpu: &mut Gpu, position: u32, size: u32, words: &[u32]) {
+        gpu.gp0(0xA0000000);
+        gpu.gp0(position);
+        gpu.gp0(size);
+        for word in words {
+            gpu.gp0(*word);
+        }
+        drain(gpu);
+
+        assert!(!gpu.image_load.is_active());
+    }
+
+    // GP0(0xC0) で読み出してGPUREADのワードを集める
+    fn image_store_words(gpu: &mut Gpu, position: u32, size: u32) -> Vec<u32> {
+        gpu.gp0(0xC0000000);
+        gpu.gp0(position);
+        gpu.gp0(size);
+        drain(gpu);
+
+        let len = (ImageTransfer::new(position, size).len() + 1) / 2;
+        (0..len).map(|_| gpu.read()).collect()
+    }
+
+    fn pattern(len: usize) -> Vec<u32> {
+        (0..len as u32)
+            .map(|i| (i * 2 + 1) << 16 | (i * 2))
+            .collect()
+    }
+
+    #[test]
+    fn interrupt_request_raises_one_event() {
+        let mut gpu = Gpu::new(Renderer::null());
+        let mut irqs = IrqBus::new();
+        let mut interrupts = Interrupts::new();
+        let mut tick = |gpu: &mut Gpu| {
+            gpu.tick(&mut irqs);
+            interrupts.tick(&mut irqs);
+        };
+
+        tick(&mut gpu);
+        gpu.gp0(0x1F000000);
+        tick(&mut gpu);
+        tick(&mut gpu);
+
+        // 確認するまでは立ったままなのでイベントは1回だけ。確認した後はまた上がる
+        gpu.gp1(0x02000000);
+        tick(&mut gpu);
+        gpu.gp0(0x1F000000);
+        tick(&mut gpu);
+
+        let events: Vec<_> = interrupts.events().copied().collect();
+        assert_eq!(
+            events,
+            [
+                IrqEvent {
+                    irq: Irq::Gpu,
+                    cycle: 1
+                },
+                IrqEvent {
+                    irq: Irq::Gpu,
+                    cycle: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn image_load_round_trip() {
+        let mut gpu = Gpu::new(Renderer::null());
+        let words = pattern(6);
+
+        image_load(&mut gpu, 0x0008_0010, 0x0003_0004, &words);
+
+        assert_eq!(gpu.vram.read(16, 8), 0);
+        assert_eq!(gpu.vram.read(19, 8), 3);
+        assert_eq!(gpu.vram.read(16, 9), 4);
+        assert_eq!(gpu.vram.read(19, 10), 11);
+        assert_eq!(gpu.vram.read(20, 8), 0);
+
+        assert_eq!(image_store_words(&mut gpu, 0x0008_0010, 0x0003_0004), words);
+    }
+
+    #[test]
+    fn image_load_odd_pixel_count() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        // 3x3 = 9ピクセルなので最後のワードの上位は捨てられる
+        image_load(&mut gpu, 0x0000_0000, 0x0003_0003, &pattern(5));
+
+        assert_eq!(gpu.vram.read(2, 2), 8);
+        assert_eq!(gpu.vram.read(3, 2), 0);
+        assert_eq!(gpu.vram.read(0, 3), 0);
+
+        // 転送が終わった後のワードは次のコマンドとして扱われる
+        gpu.gp0(0xE6000001);
+        assert!(gpu.force_set_mask_bit);
+
+        let words = image_store_words(&mut gpu, 0x0000_0000, 0x0003_0003);
+        assert_eq!(words.len(), 5);
+        assert_eq!(words[4], 8);
+    }
+
+    #[test]
+    fn image_load_wraps_at_vram_edges() {
+        let mut gpu = Gpu::new(Renderer::null());
+        let words = pattern(8);
+
+        // (1022, 510) から 4x4
+        image_load(&mut gpu, 0x01FE_03FE, 0x0004_0004, &words);
+
+        assert_eq!(gpu.vram.read(1022, 510), 0);
+        assert_eq!(gpu.vram.read(1023, 510), 1);
+        assert_eq!(gpu.vram.read(0, 510), 2);
+        assert_eq!(gpu.vram.read(1, 510), 3);
+        assert_eq!(gpu.vram.read(1022, 0), 8);
+        assert_eq!(gpu.vram.read(1, 1), 15);
+
+        assert_eq!(image_store_words(&mut gpu, 0x01FE_03FE, 0x0004_0004), words);
+    }
+
+    #[test]
+    fn image_load_follows_mask_settings() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        // マスクビットを立てて書き、次はマスクされたピクセルを残す
+        gpu.gp0(0xE6000001);
+        image_load(&mut gpu, 0x0000_0000, 0x0001_0002, &[0x0002_0001]);
+        gpu.gp0(0xE6000002);
+        image_load(&mut gpu, 0x0000_0001, 0x0001_0002, &[0x0004_0003]);
+
+        assert_eq!(
+            image_store_words(&mut gpu, 0x0000_0000, 0x0001_0004),
+            [0x8002_8001, 0x0000_0004]
+        );
+    }
+
+    // 各ピクセルの中心で補間したテクスチャ座標
+    fn rect_texels(start: u8, len: i16, flip: bool) -> Vec<u8> {
+        let (t0, t1) = Gpu::rect_texcoords(start, len, flip);
+
+        (0..len)
+            .map(|i| {
+                let t = t0 + (t1 - t0) * (i as f32 + 0.5) / len as f32;
+                (t.floor() as i32 & 0xFF) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rect_texcoords_flip() {
+        assert_eq!(rect_texels(16, 4, false), vec![16, 17, 18, 19]);
+        assert_eq!(rect_texels(16, 4, true), vec![16, 15, 14, 13]);
+
+        // テクスチャページの端で折り返す
+        assert_eq!(rect_texels(254, 4, false), vec![254, 255, 0, 1]);
+        assert_eq!(rect_texels(1, 4, true), vec![1, 0, 255, 254]);
+    }
+
+    #[test]
+    fn get_info_returns_drawing_state() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        gpu.gp0(0xE20FA53C);
+        gpu.gp0(0xE3000000 | (20 << 10) | 10);
+        gpu.gp0(0xE4000000 | (239 << 10) | 319);
+        gpu.gp0(0xE5000000 | (7 << 11) | 0x7FB);
+
+        let mut info = |index: u32| {
+            gpu.gp1(0x10000000 | index);
+            gpu.read()
+        };
+
+        assert_eq!(info(0x2), 0x000FA53C);
+        assert_eq!(info(0x3), (20 << 10) | 10);
+        assert_eq!(info(0x4), (239 << 10) | 319);
+        assert_eq!(info(0x5), (7 << 11) | 0x7FB);
+        assert_eq!(info(0x7), 2);
+
+        // 対象外の番号では前の値が残る
+        assert_eq!(info(0x0), 2);
+        assert_eq!(info(0x6), 2);
+    }
+
+    #[test]
+    fn commands_wait_in_fifo_while_drawing() {
+        let mut gpu = Gpu::new(Renderer::null());
+        let ready = |gpu: &Gpu| (gpu.status() >> 26) & 1 != 0;
+        let dma_ready = |gpu: &Gpu| (gpu.status() >> 28) & 1 != 0;
+
+        assert!(ready(&gpu));
+
+        // 256x256 の塗りつぶし
+        gpu.gp0(0x02000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x01000100);
+        assert!(!ready(&gpu));
+
+        gpu.gp0(0xE3000000 | (20 << 10) | 10);
+        for _ in 0..14 {
+            gpu.gp0(0x00000000);
+        }
+        assert!(dma_ready(&gpu));
+
+        gpu.gp0(0x00000000);
+        assert!(!dma_ready(&gpu));
+
+        // 描画が終わるまではFIFOのコマンドは実行されない
+        assert_eq!(gpu.drawing_area_left, 0);
+
+        for _ in 0..256 * 256 / 8 {
+            gpu.tick(&mut IrqBus::new());
+        }
+
+        assert!(ready(&gpu));
+        assert!(dma_ready(&gpu));
+        assert_eq!((gpu.drawing_area_left, gpu.drawing_area_top), (10, 20));
+    }
+
+    #[test]
+    fn dma_request_follows_transfer_state() {
+        let mut gpu = Gpu::new(Renderer::null());
+        let dreq = |gpu: &Gpu| (gpu.status() >> 25) & 1 != 0;
+
+        assert!(!dreq(&gpu));
+
+        // FIFO: 空きがある間だけ
+        gpu.gp1(0x04000001);
+        assert!(dreq(&gpu));
+
+        gpu.gp0(0x02000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x01000100);
+        for _ in 0..GP0_FIFO_LEN {
+            gpu.gp0(0x00000000);
+        }
+        assert!(!dreq(&gpu));
+
+        for _ in 0..256 * 256 / 8 {
+            gpu.tick(&mut IrqBus::new());
+        }
+        assert!(dreq(&gpu));
+
+        // VRAM -> CPU: 読むものがある間だけ
+        gpu.gp1(0x04000003);
+        assert!(!dreq(&gpu));
+
+        gpu.gp0(0xC0000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x00010002);
+        assert!(dreq(&gpu));
+
+        gpu.read();
+        assert!(!dreq(&gpu));
+    }
+
+    #[test]
+    fn full_fifo_is_flushed_instead_of_dropped() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        gpu.gp0(0x02000000);
+        gpu.gp0(0x00000000);
+        gpu.gp0(0x01000100);
+
+        for _ in 0..16 {
+            gpu.gp0(0x00000000);
+        }
+        gpu.gp0(0xE3000000 | (20 << 10) | 10);
+
+        for _ in 0..256 * 256 / 8 {
+            gpu.tick(&mut IrqBus::new());
+        }
+
+        assert_eq!((gpu.drawing_area_left, gpu.drawing_area_top), (10, 20));
+    }
+
+    #[test]
+    fn primitive_counts_cover_the_last_frame() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        // 単色の三角形, ポリライン (2本), 1x1の矩形
+        let commands = [
+            0x20FFFFFF, 0x00000000, 0x00000010, 0x00100000, //
+            0x48FFFFFF, 0x00000000, 0x00100010, 0x00200000, 0x55555555, //
+            0x68FFFFFF, 0x00000000,
+        ];
+        for command in commands {
+            gpu.gp0(command);
+        }
+
+        let frames = gpu.frames();
+        while gpu.frames() == frames {
+            gpu.tick(&mut IrqBus::new());
+        }
+
+        let counts = PrimitiveCounts {
+            polygons: 1,
+            lines: 2,
+            rects: 1,
+            ..Default::default()
+        };
+        assert_eq!(gpu.primitive_counts(), counts);
+    }
+
+    #[test]
+    fn image_transfer_masks_position_and_size() {
+        let transfer = ImageTransfer::new(0xFE00_FC05, 0x0000_0000);
+
+        assert_eq!((transfer.x, transfer.y), (5, 0));
+        assert_eq!((transfer.width, transfer.height), (VRAM_WIDTH, VRAM_HEIGHT));
+
+        let transfer = ImageTransfer::new(0x0000_0000, 0x0201_0401);
+        assert_eq!((transfer.width, transfer.height), (1, 1));
+    }
+
+    #[test]
+    fn display_mode_sets_display_depth() {
+        let mut gpu = Gpu::new(Renderer::null());
+        let depth24 = |gpu: &Gpu| (gpu.status() >> 21) & 1 != 0;
+
+        gpu.gp1(0x08000010);
+        assert!(matches!(gpu.display_depth, DisplayDepth::D24Bits));
+        assert!(depth24(&gpu));
+
+        gpu.gp1(0x08000000);
+        assert!(matches!(gpu.display_depth, DisplayDepth::D15Bits));
+        assert!(!depth24(&gpu));
+    }
+
+    #[test]
+    fn texture_page_decodes_both_y_base_bits() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        // bit11 は page_base_y の bit1 になり、GPUSTAT の bit15 に出る
+        gpu.gp0(0xE1000800);
+        assert_eq!(gpu.page_base_y, 2);
+        assert_eq!(gpu.status() & (1 << 4), 0);
+        assert_ne!(gpu.status() & (1 << 15), 0);
+
+        gpu.gp0(0xE1000810);
+        assert_eq!(gpu.page_base_y, 3);
+        assert_ne!(gpu.status() & (1 << 4), 0);
+        assert_ne!(gpu.status() & (1 << 15), 0);
+
+        gpu.gp0(0xE1000010);
+        assert_eq!(gpu.page_base_y, 1);
+        assert_eq!(gpu.status() & (1 << 15), 0);
+    }
+
+    #[test]
+    fn reserved_texture_depth_is_15bit() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        gpu.gp0(0xE1000180);
+        assert!(matches!(gpu.texture_depth, TextureDepth::T15Bit));
+        assert_eq!((gpu.status() >> 7) & 3, 2);
+    }
+
+    // 描画先を読み戻すにはアダプタが要るので、描画のテストは --ignored を付けたときだけ動かす
+    fn headless_gpu() -> Gpu {
+        Gpu::new(Renderer::headless().expect("no wgpu adapter available"))
+    }
+
+    #[test]
+    #[ignore = "needs a wgpu adapter"]
+    fn drawn_primitives_are_read_back_by_image_store() {
+        let mut gpu = headless_gpu();
+
+        // (16, 8) に 4x2 の赤い四角形
+        gpu.gp0(0x600000FF);
+        gpu.gp0(0x00080010);
+        gpu.gp0(0x00020004);
+        while !gpu.gp0_fifo.is_empty() || gpu.busy_cycles > 0 {
+            gpu.tick(&mut IrqBus::new());
+        }
+
+        assert_eq!(
+            image_store_words(&mut gpu, 0x00080010, 0x00020004),
+            [0x001F001F; 4]
+        );
+        assert_eq!(gpu.vram.read(20, 8), 0);
+    }
+
+    #[test]
+    #[ignore = "needs a wgpu adapter"]
+    fn drawing_keeps_masked_pixels() {
+        let mut gpu = headless_gpu();
+        let rect = |gpu: &mut Gpu, mask: u32, color: u32, width: u32| {
+            gpu.gp0(0xE6000000 | mask);
+            gpu.gp0(0x60000000 | color);
+            gpu.gp0(0x00080010);
+            gpu.gp0(0x00010000 | width);
+            while !gpu.gp0_fifo.is_empty() || gpu.busy_cycles > 0 {
+                gpu.tick(&mut IrqBus::new());
+            }
+        };
+
+        // マスクビットを立てて赤を2ピクセル描き、マスクを確かめながら緑を4ピクセル描く
+        rect(&mut gpu, 1, 0x0000FF, 2);
+        rect(&mut gpu, 2, 0x00FF00, 4);
+        assert_eq!(
+            image_store_words(&mut gpu, 0x00080010, 0x00010004),
+            [0x801F801F, 0x03E003E0]
+        );
+
+        // 確かめなければ上書きされ、マスクビットも消える
+        rect(&mut gpu, 0, 0x00FF00, 4);
+        assert_eq!(
+            image_store_words(&mut gpu, 0x00080010, 0x00010004),
+            [0x03E003E0; 2]
+        );
+    }
+
+    #[test]
+    fn polygon_lengths() {
+        // bit0 (輝度変調) と bit1 (半透明) は語数を変えない
+        let lengths = [
+            (0x20, 4),
+            (0x24, 7),
+            (0x28, 5),
+            (0x2C, 9),
+            (0x30, 6),
+            (0x34, 9),
+            (0x38, 8),
+            (0x3C, 12),
+        ];
+
+        for opcode in 0x20..=0x3F {
+            let len = lengths
+                .iter()
+                .find(|(base, _)| *base == opcode & !0x03)
+                .unwrap()
+                .1;
+            assert_eq!(Gpu::polygon_len(opcode), len, "opcode {:02x}", opcode);
+
+            // ちょうどその語数で1つのポリゴンになり、次のワードはコマンドとして扱われる
+            let mut gpu = Gpu::new(Renderer::null());
+            gpu.gp0(opcode << 24);
+            for _ in 1..len {
+                gpu.gp0(0);
+            }
+            gpu.gp0(0xE6000001);
+            drain(&mut gpu);
+
+            assert_eq!(gpu.primitives.polygons, 1, "opcode {:02x}", opcode);
+            assert!(gpu.force_set_mask_bit, "opcode {:02x}", opcode);
+        }
+    }
+
+    // コマンドの後に GP0(0xE6) を送る。マスクの設定が効いていればコマンドの区切りが正しい
+    fn draw_then_mask(words: &[u32]) -> Gpu {
+        let mut gpu = Gpu::new(Renderer::null());
+        for word in words {
+            gpu.gp0(*word);
+        }
+        gpu.gp0(0xE6000001);
+        drain(&mut gpu);
+
+        gpu
+    }
+
+    #[test]
+    fn line_lengths() {
+        for opcode in 0x40..=0x5F {
+            let len = if opcode & 0x10 != 0 { 4 } else { 3 };
+            assert_eq!(Gpu::line_len(opcode), len, "opcode {:02x}", opcode);
+        }
+
+        // 単色: 色, 始点, 終点
+        let gpu = draw_then_mask(&[0x40000000, 0x00000000, 0x00100010]);
+        assert_eq!(gpu.primitives.lines, 1);
+        assert!(gpu.force_set_mask_bit);
+
+        // グーロー: 色, 始点, 色, 終点
+        let gpu = draw_then_mask(&[0x50FF0000, 0x00000000, 0x000000FF, 0x00100010]);
+        assert_eq!(gpu.primitives.lines, 1);
+        assert!(gpu.force_set_mask_bit);
+    }
+
+    #[test]
+    fn flat_polyline_ends_at_terminator() {
+        let gpu = draw_then_mask(&[
+            0x48000000, 0x00000000, 0x00100010, 0x00200000, 0x00000020, 0x55555555,
+        ]);
+
+        assert_eq!(gpu.primitives.lines, 3);
+        assert_eq!(gpu.polyline.position.0, 0x20);
+        assert!(matches!(gpu.gp0_mode, Gp0Mode::Command));
+        assert!(gpu.force_set_mask_bit);
+
+        // 0x5XXX5XXX ならどの値でも終わる
+        let gpu = draw_then_mask(&[0x48000000, 0x00000000, 0x00100010, 0x5FFF5000]);
+        assert_eq!(gpu.primitives.lines, 1);
+        assert!(gpu.force_set_mask_bit);
+    }
+
+    #[test]
+    fn shaded_polyline_ends_at_terminator() {
+        // 頂点ごとに色と座標が来て、終端は色の位置に来る
+        let gpu = draw_then_mask(&[
+            0x58000001, 0x00000000, 0x00000002, 0x00100010, 0x00000003, 0x00200000, 0x50005000,
+        ]);
+
+        assert_eq!(gpu.primitives.lines, 2);
+        assert_eq!(gpu.polyline.color.0, 3);
+        assert!(gpu.polyline.next_color.is_none());
+        assert!(matches!(gpu.gp0_mode, Gp0Mode::Command));
+        assert!(gpu.force_set_mask_bit);
+
+        // 色の後の座標の位置に来ても終わる
+        let gpu = draw_then_mask(&[
+            0x58000001, 0x00000000, 0x00000002, 0x00100010, 0x00000003, 0x55555555,
+        ]);
+
+        assert_eq!(gpu.primitives.lines, 1);
+        assert!(matches!(gpu.gp0_mode, Gp0Mode::Command));
+        assert!(gpu.force_set_mask_bit);
+    }
+
+    fn fill_rect(gpu: &mut Gpu, color: u32, position: u32, size: u32) {
+        gpu.gp0(0x02000000 | color);
+        gpu.gp0(position);
+        gpu.gp0(size);
+        drain(gpu);
+    }
+
+    #[test]
+    fn fill_rect_writes_color() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        // (16, 8) から 16x2 を赤で塗る
+        fill_rect(&mut gpu, 0x0000FF, 0x0008_0010, 0x0002_0010);
+
+        assert_eq!(gpu.vram.read(16, 8), 0x001F);
+        assert_eq!(gpu.vram.read(31, 9), 0x001F);
+        assert_eq!(gpu.vram.read(15, 8), 0);
+        assert_eq!(gpu.vram.read(32, 8), 0);
+        assert_eq!(gpu.vram.read(16, 10), 0);
+        assert_eq!(gpu.primitives.fills, 1);
+    }
+
+    #[test]
+    fn fill_rect_ignores_mask_settings() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        image_load(&mut gpu, 0x0000_0000, 0x0001_0002, &[0x80008000]);
+
+        // マスクビットを立てる設定もマスクされたピクセルを残す設定も効かない
+        gpu.gp0(0xE6000003);
+        fill_rect(&mut gpu, 0xFF0000, 0x0000_0000, 0x0001_0010);
+
+        assert_eq!(gpu.vram.read(0, 0), 0x7C00);
+        assert_eq!(gpu.vram.read(1, 0), 0x7C00);
+        assert_eq!(gpu.vram.read(15, 0), 0x7C00);
+    }
+
+    #[test]
+    fn fill_rect_rounds_position_and_size() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        // x は16ピクセル単位に切り捨て、幅は16ピクセル単位に切り上げる。y と高さはそのまま
+        fill_rect(&mut gpu, 0x00FF00, 0x0003_0013, 0x0003_0011);
+
+        assert_eq!(gpu.vram.read(15, 3), 0);
+        assert_eq!(gpu.vram.read(16, 3), 0x03E0);
+        assert_eq!(gpu.vram.read(47, 5), 0x03E0);
+        assert_eq!(gpu.vram.read(48, 3), 0);
+        assert_eq!(gpu.vram.read(16, 2), 0);
+        assert_eq!(gpu.vram.read(16, 6), 0);
+
+        // 幅0は塗らない
+        fill_rect(&mut gpu, 0x00FF00, 0x0000_0100, 0x0001_0000);
+        assert_eq!(gpu.vram.read(0x100, 0), 0);
+    }
+
+    #[test]
+    fn fill_rect_wraps_at_vram_edges() {
+        let mut gpu = Gpu::new(Renderer::null());
+
+        // (1008, 511) から 32x2
+        fill_rect(&mut gpu, 0xFFFFFF, 0x01FF_03F0, 0x0002_0020);
+
+        assert_eq!(gpu.vram.read(1008, 511), 0x7FFF);
+        assert_eq!(gpu.vram.read(1023, 511), 0x7FFF);
+        assert_eq!(gpu.vram.read(0, 511), 0x7FFF);
+        assert_eq!(gpu.vram.read(15, 0), 0x7FFF);
+        assert_eq!(gpu.vram.read(16, 511), 0);
+        assert_eq!(gpu.vram.read(1007, 511), 0);
+        assert_eq!(gpu.vram.read(0, 1), 0);
+    }
 }
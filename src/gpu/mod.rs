@@ -1,4 +1,12 @@
 mod command;
+pub mod command_log;
+mod flip;
 pub mod gpu;
+pub mod postprocess;
 mod primitive;
 pub mod renderer;
+pub mod screenshot;
+mod timing;
+pub mod vram;
+
+pub use timing::{VMode, CPU_CLOCK};
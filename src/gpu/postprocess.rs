@@ -0,0 +1,196 @@
+use wgpu::{include_wgsl, util::DeviceExt};
+
+use super::vram::{VRAM_HEIGHT, VRAM_WIDTH};
+
+// 表示範囲を画面に引き延ばすときのフィルタ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    // 整数倍までは最近傍で、端数だけ補間する
+    SharpBilinear,
+    Scanlines,
+    // 走査線、アパーチャグリル、画面の丸み
+    Crt,
+}
+
+impl Filter {
+    fn id(self) -> u32 {
+        match self {
+            Filter::Nearest => 0,
+            Filter::Bilinear => 1,
+            Filter::SharpBilinear => 2,
+            Filter::Scanlines => 3,
+            Filter::Crt => 4,
+        }
+    }
+}
+
+// シェーダーの Params と同じ並び (source, output, filter)。uniformは16byte単位なので詰め物をする
+const PARAMS_LEN: usize = 8;
+
+// 表示範囲を等倍で frame に描いてから、フィルタをかけて画面に出す
+pub struct PostProcess {
+    pipeline: wgpu::RenderPipeline,
+    bind_group: wgpu::BindGroup,
+    params_buffer: wgpu::Buffer,
+    // 表示範囲の描画先。左上だけを使う
+    frame: wgpu::TextureView,
+}
+
+impl PostProcess {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> PostProcess {
+        let shader = device.create_shader_module(&include_wgsl!("shader/postprocess.wgsl"));
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("postprocess params"),
+            contents: bytemuck::cast_slice(&[0u32; PARAMS_LEN]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // 画面と同じ形式にして、present のパイプラインをそのまま使う
+        let frame = device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("frame"),
+                size: wgpu::Extent3d {
+                    width: VRAM_WIDTH as u32,
+                    height: VRAM_HEIGHT as u32,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("frame sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("postprocess layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("postprocess"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&frame),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("postprocess pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("postprocess pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        PostProcess {
+            pipeline,
+            bind_group,
+            params_buffer,
+            frame,
+        }
+    }
+
+    pub fn frame(&self) -> &wgpu::TextureView {
+        &self.frame
+    }
+
+    // source は frame に描いた表示範囲の大きさ、output は出力先の矩形の大きさ
+    pub fn update(
+        &self,
+        queue: &wgpu::Queue,
+        filter: Filter,
+        source: (f32, f32),
+        output: (f32, f32),
+    ) {
+        let params: [u32; PARAMS_LEN] = [
+            source.0.to_bits(),
+            source.1.to_bits(),
+            output.0.to_bits(),
+            output.1.to_bits(),
+            filter.id(),
+            0,
+            0,
+            0,
+        ];
+
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&params));
+    }
+
+    // ビューポートを設定したパスに描く
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -7,6 +7,13 @@ use bytemuck::{Pod, Zeroable};
 pub struct Vertex {
     pub position: [f32; 2],
     pub color: [f32; 3],
+    pub texcoord: [f32; 2],
+    pub texpage: u32,
+    pub clut: u32,
+    pub flags: u32,
+    pub window: u32,
+    // 描画オフセット (積んだときの値)。VRAM_COPY では使わない
+    pub offset: [f32; 2],
 }
 
 impl Vertex {
@@ -18,6 +25,23 @@ impl Vertex {
                 col.1 as f32 / 256.0,
                 col.2 as f32 / 256.0,
             ],
+            ..Default::default()
+        }
+    }
+
+    pub fn textured(pos: Position, col: Color, texcoord: [f32; 2], texture: Texture) -> Self {
+        let mut flags = vertex_flags::TEXTURED;
+        if texture.raw {
+            flags |= vertex_flags::RAW_TEXTURE;
+        }
+
+        Self {
+            texcoord,
+            texpage: texture.page as u32,
+            clut: texture.clut as u32,
+            window: texture.window,
+            flags,
+            ..Vertex::new(pos, col)
         }
     }
 
@@ -36,13 +60,42 @@ impl Vertex {
                     offset: size_of::<[f32; 2]>() as wgpu::BufferAddress,
                     shader_location: 1,
                 },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x2,
+                    offset: size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: size_of::<[f32; 7]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: size_of::<[f32; 9]>() as wgpu::BufferAddress,
+                    shader_location: 5,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: size_of::<[f32; 10]>() as wgpu::BufferAddress,
+                    shader_location: 6,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x2,
+                    offset: size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 7,
+                },
             ],
         }
     }
 }
 
-#[derive(Clone, Copy, Default, Debug, Pod, Zeroable)]
-#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
 pub struct Offset {
     pub x: f32,
     pub y: f32,
@@ -55,6 +108,110 @@ impl Offset {
     }
 }
 
+// シェーダーと共通
+pub mod vertex_flags {
+    pub const TEXTURED: u32 = 1 << 0;
+    // テクスチャの色をそのまま使う (輝度変調しない)
+    pub const RAW_TEXTURE: u32 = 1 << 1;
+    // 15bitに落とす前に4x4のディザをかける
+    pub const DITHER: u32 = 1 << 2;
+    // 描いたピクセルのマスクビットを立てる
+    pub const SET_MASK: u32 = 1 << 3;
+    // 色の代わりにVRAMの内容をそのまま描画先に写す (描画オフセットも無視する)
+    pub const VRAM_COPY: u32 = 1 << 4;
+    // 15bitに落とさず、補間した色をそのまま出す (拡張カラー)
+    pub const TRUE_COLOR: u32 = 1 << 5;
+}
+
+// page: GP0(0xE1)と同じ形式のtexpage, clut: UVワードの上位16bit
+// window: GP0(0xE2)と同じ形式のテクスチャウィンドウ
+#[derive(Clone, Copy, Debug)]
+pub struct Texture {
+    pub page: u16,
+    pub clut: u16,
+    pub window: u32,
+    pub raw: bool,
+}
+
+// GP0(0xE2) のテクスチャウィンドウ (8ピクセル単位)
+// シェーダーでは (uv & !(mask * 8)) | ((offset & mask) * 8) で繰り返す
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextureWindow {
+    pub x_mask: u8,
+    pub y_mask: u8,
+    pub x_offset: u8,
+    pub y_offset: u8,
+}
+
+impl TextureWindow {
+    pub fn from_command(val: u32) -> TextureWindow {
+        TextureWindow {
+            x_mask: (val & 0x1F) as u8,
+            y_mask: ((val >> 5) & 0x1F) as u8,
+            x_offset: ((val >> 10) & 0x1F) as u8,
+            y_offset: ((val >> 15) & 0x1F) as u8,
+        }
+    }
+
+    // 頂点に載せる形式 (GP0(0xE2)と同じ)
+    pub fn pack(self) -> u32 {
+        let mut r = 0;
+
+        r |= self.x_mask as u32;
+        r |= (self.y_mask as u32) << 5;
+        r |= (self.x_offset as u32) << 10;
+        r |= (self.y_offset as u32) << 15;
+
+        r
+    }
+}
+
+// 画面に表示するVRAM上の矩形
+// uniformは16byte単位なので詰め物をする
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct DisplayArea {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub enabled: u32,
+    // 24bitの場合はVRAMから直接3byteずつ読む
+    pub depth24: u32,
+    _padding: [u32; 2],
+}
+
+impl Default for DisplayArea {
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            width: 1024.0,
+            height: 512.0,
+            enabled: 0,
+            depth24: 0,
+            _padding: [0; 2],
+        }
+    }
+}
+
+impl DisplayArea {
+    // VRAM全体を15bitとして表示する (VRAMビューア用)
+    pub fn whole_vram() -> DisplayArea {
+        DisplayArea {
+            enabled: 1,
+            ..DisplayArea::default()
+        }
+    }
+
+    pub fn set(&mut self, x: u16, y: u16, width: u16, height: u16) {
+        self.x = x as f32;
+        self.y = y as f32;
+        self.width = width.max(1) as f32;
+        self.height = height.max(1) as f32;
+    }
+}
+
 #[derive(Clone, Copy, Default, Debug)]
 pub struct Position(pub i16, pub i16);
 
@@ -77,6 +234,26 @@ impl Position {
     pub fn limit(self, x: i16, y: i16) -> Position {
         Position(self.0.min(x), self.1.min(y))
     }
+
+    // 三角形の面積 (ピクセル数の目安)
+    pub fn triangle_area(a: Position, b: Position, c: Position) -> u32 {
+        let (ax, ay) = (a.0 as i64, a.1 as i64);
+        let (bx, by) = (b.0 as i64, b.1 as i64);
+        let (cx, cy) = (c.0 as i64, c.1 as i64);
+
+        let cross = (bx - ax) * (cy - ay) - (cx - ax) * (by - ay);
+
+        // 実機では1024x512を超えるプリミティブは描かれない
+        (cross.unsigned_abs() / 2).min(1024 * 512) as u32
+    }
+
+    // 主軸方向のピクセル数
+    pub fn line_length(a: Position, b: Position) -> u32 {
+        let dx = (b.0 as i32 - a.0 as i32).unsigned_abs();
+        let dy = (b.1 as i32 - a.1 as i32).unsigned_abs();
+
+        dx.max(dy) + 1
+    }
 }
 
 #[derive(Clone, Copy, Default, Debug)]
@@ -90,4 +267,89 @@ impl Color {
 
         Color(r, g, b)
     }
+
+    pub fn to_gp0(self) -> u32 {
+        (self.0 as u32) | ((self.1 as u32) << 8) | ((self.2 as u32) << 16)
+    }
+
+    // 24bit -> 15bit (マスクビットは0)
+    pub fn to_vram(self) -> u16 {
+        let r = (self.0 >> 3) as u16;
+        let g = (self.1 >> 3) as u16;
+        let b = (self.2 >> 3) as u16;
+
+        r | (g << 5) | (b << 10)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // renderer.wgsl の fetch_texel と同じ手順で座標を求める
+    fn apply(window: u32, u: u32, v: u32) -> (u32, u32) {
+        let apply =
+            |coord: u32, mask: u32, offset: u32| (coord & !(mask * 8)) | ((offset & mask) * 8);
+
+        (
+            apply(u & 255, window & 31, (window >> 10) & 31),
+            apply(v & 255, (window >> 5) & 31, (window >> 15) & 31),
+        )
+    }
+
+    #[test]
+    fn pack_round_trips_command() {
+        let window = TextureWindow::from_command(0xE2_0F_A5_3C);
+        assert_eq!(
+            window,
+            TextureWindow {
+                x_mask: 0x1C,
+                y_mask: 0x09,
+                x_offset: 0x09,
+                y_offset: 0x1F,
+            }
+        );
+        assert_eq!(window.pack(), 0x000F_A53C);
+    }
+
+    #[test]
+    fn empty_window_keeps_coordinates() {
+        let window = TextureWindow::default().pack();
+
+        for u in 0..256 {
+            assert_eq!(apply(window, u, 255 - u), (u, 255 - u));
+        }
+    }
+
+    #[test]
+    fn window_repeats_tile() {
+        // 16x8 のタイルを (32, 8) から繰り返す
+        let window = TextureWindow {
+            x_mask: 0x1E,
+            y_mask: 0x1F,
+            x_offset: 0x04,
+            y_offset: 0x01,
+        }
+        .pack();
+
+        for u in 0..256 {
+            for v in 0..256 {
+                assert_eq!(apply(window, u, v), (32 + u % 16, 8 + v % 8));
+            }
+        }
+    }
+
+    #[test]
+    fn window_offset_outside_mask_is_ignored() {
+        let window = TextureWindow {
+            x_mask: 0x10,
+            y_mask: 0x00,
+            x_offset: 0x0F,
+            y_offset: 0x1F,
+        }
+        .pack();
+
+        // マスク外のオフセットは効かず、bit7だけ0になる
+        assert_eq!(apply(window, 0xC5, 0x42), (0x45, 0x42));
+    }
 }
@@ -5,21 +5,86 @@ use log::debug;
 use wgpu::{include_wgsl, util::DeviceExt};
 use winit::window::Window;
 
-use super::primitive::{Color, Offset, Position, Vertex};
+use super::{
+    postprocess::{Filter, PostProcess},
+    primitive::{vertex_flags, Color, DisplayArea, Offset, Position, Texture, Vertex},
+    screenshot::RgbaImage,
+    vram::{VRAM_HEIGHT, VRAM_WIDTH},
+};
 
 pub struct Renderer {
-    surface: wgpu::Surface,
+    // ウィンドウを持たない場合は None で、描画内容は捨てる
+    backend: Option<Backend>,
+    vertices: Vec<Vertex>,
+    nvertices: u32,
+    // 描く順の頂点番号。四角形は4頂点を2つの三角形で共有する
+    indices: Vec<u32>,
+    // 以降に積む頂点に載せる描画オフセット
+    offset: Offset,
+    dithering: bool,
+    mask_check: bool,
+    force_set_mask: bool,
+    // マスクビットを確かめるかどうかで描き方を分けた、indices の区切り
+    batches: Vec<Batch>,
+    // 実機の15bitの色ではなく8bitのまま描く
+    true_color: bool,
+    display_area: DisplayArea,
+    // 表示範囲ではなくVRAM全体を画面に出す
+    vram_view: bool,
+    scaling: Scaling,
+    filter: Filter,
+    // プリミティブごとに輪郭を色分けして重ねる
+    primitive_debug: bool,
+    // 次に画面に出すまでに積んだ輪郭と、その元になったプリミティブの数
+    outlines: Vec<Vertex>,
+    outlined: u32,
+}
+
+// indices の start 以降 (次の Batch まで) を同じパイプラインで描く
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Batch {
+    start: usize,
+    mask_check: bool,
+}
+
+// 表示範囲をウィンドウに合わせる方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    // ウィンドウ全体に引き伸ばす
+    Stretch,
+    // 4:3 を保って中央に出す
+    Aspect,
+    // 縦横とも整数倍にして、4:3 に近い倍率を選ぶ
+    Integer,
+}
+
+// wgpuのリソース
+struct Backend {
+    // ヘッドレスでは None で、画面には出さない
+    surface: Option<wgpu::Surface>,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
     render_pipeline: wgpu::RenderPipeline,
+    // 書き込み先のマスクビットが立っていたら描画先の色を残す
+    mask_pipeline: wgpu::RenderPipeline,
+    present_pipeline: wgpu::RenderPipeline,
+    outline_pipeline: wgpu::RenderPipeline,
+    outline_buffer: wgpu::Buffer,
+    // 輪郭の描画先 (VRAMと同じ大きさ)。画面に出すたびにクリアする
+    overlay: wgpu::TextureView,
     vertex_buffer: wgpu::Buffer,
-    vertices: Vec<Vertex>,
-    nvertices: u32,
-    offset: Offset,
-    offset_buffer: wgpu::Buffer,
-    offset_bind_group: wgpu::BindGroup,
+    index_buffer: wgpu::Buffer,
+    display_area_buffer: wgpu::Buffer,
+    vram_texture: wgpu::Texture,
+    vram_bind_group: wgpu::BindGroup,
+    // プリミティブの描画先 (VRAMと同じ大きさ)。フレームを跨いで内容を保持する
+    // アルファをマスクビットとして使う
+    draw_target_texture: wgpu::Texture,
+    draw_target: wgpu::TextureView,
+    present_bind_group: wgpu::BindGroup,
+    postprocess: PostProcess,
 }
 
 impl Renderer {
@@ -35,15 +100,7 @@ impl Renderer {
         }))
         .unwrap();
 
-        let (device, queue) = smol::block_on(adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
-                limits: wgpu::Limits::downlevel_defaults(),
-                label: None,
-            },
-            None,
-        ))
-        .unwrap();
+        let (device, queue) = request_device(&adapter);
 
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -55,6 +112,41 @@ impl Renderer {
 
         surface.configure(&device, &config);
 
+        Renderer::with_device(device, queue, Some(surface), config)
+    }
+
+    // ウィンドウを持たずに描画先だけを持つレンダラー (ヘッドレスのテスト用)
+    // 使えるアダプタがなければ None
+    #[cfg(any(test, feature = "testing"))]
+    pub fn headless() -> Option<Renderer> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let adapter = smol::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        }))?;
+
+        let (device, queue) = request_device(&adapter);
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: DRAW_TARGET_FORMAT,
+            width: VRAM_WIDTH as u32,
+            height: VRAM_HEIGHT as u32,
+            present_mode: wgpu::PresentMode::Fifo,
+        };
+
+        Some(Renderer::with_device(device, queue, None, config))
+    }
+
+    fn with_device(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        surface: Option<wgpu::Surface>,
+        config: wgpu::SurfaceConfiguration,
+    ) -> Renderer {
+        let size = winit::dpi::PhysicalSize::new(config.width, config.height);
+
         let shader = device.create_shader_module(&include_wgsl!("shader/renderer.wgsl"));
 
         let vertices = vec![Default::default(); VERTEX_BUFFER_LEN as usize];
@@ -65,189 +157,1378 @@ impl Renderer {
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
         });
 
-        let offset = Offset::default();
+        let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("index"),
+            size: (std::mem::size_of::<u32>() * INDEX_BUFFER_LEN) as u64,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let display_area = DisplayArea::default();
 
-        let offset_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("offset buffer"),
-            contents: bytemuck::cast_slice(&[offset]),
+        let display_area_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("display area buffer"),
+            contents: bytemuck::cast_slice(&[display_area]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let offset_bind_group_layout =
+        // テクスチャの参照用にVRAMの内容をそのまま置く (1ピクセル16bit)
+        let vram_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("vram"),
+            size: wgpu::Extent3d {
+                width: VRAM_WIDTH as u32,
+                height: VRAM_HEIGHT as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R16Uint,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+
+        let vram_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("offset layout"),
+                label: Some("vram layout"),
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Uint,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
                     },
                     count: None,
                 }],
             });
 
-        let offset_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("offset"),
-            layout: &offset_bind_group_layout,
+        let vram_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("vram"),
+            layout: &vram_bind_group_layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: offset_buffer.as_entire_binding(),
+                resource: wgpu::BindingResource::TextureView(
+                    &vram_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
             }],
         });
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("pipeline layout"),
-                bind_group_layouts: &[&offset_bind_group_layout],
+                bind_group_layouts: &[&vram_bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let create_render_pipeline = |label, blend| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[Vertex::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: DRAW_TARGET_FORMAT,
+                        blend: Some(blend),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: None,
+                    unclipped_depth: false,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    conservative: false,
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+
+        let render_pipeline = create_render_pipeline("pipeline", wgpu::BlendState::REPLACE);
+
+        // 描画先のアルファ (マスクビット) は 0 か 1 なので、立っていれば描画先の色がそのまま残る
+        let keep_masked = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::OneMinusDstAlpha,
+            dst_factor: wgpu::BlendFactor::DstAlpha,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let mask_pipeline = create_render_pipeline(
+            "mask pipeline",
+            wgpu::BlendState {
+                color: keep_masked,
+                alpha: keep_masked,
+            },
+        );
+
+        let draw_target_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("draw target"),
+            size: wgpu::Extent3d {
+                width: VRAM_WIDTH as u32,
+                height: VRAM_HEIGHT as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DRAW_TARGET_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+        });
+        let draw_target = draw_target_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let overlay = device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("overlay"),
+                size: wgpu::Extent3d {
+                    width: VRAM_WIDTH as u32,
+                    height: VRAM_HEIGHT as u32,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: DRAW_TARGET_FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let outline_shader = device.create_shader_module(&include_wgsl!("shader/outline.wgsl"));
+
+        let outline_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("outline vertex"),
+            size: (std::mem::size_of::<Vertex>() * OUTLINE_BUFFER_LEN) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let outline_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("outline pipeline layout"),
+                bind_group_layouts: &[],
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("pipeline"),
-            layout: Some(&render_pipeline_layout),
+        let outline_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("outline pipeline"),
+            layout: Some(&outline_pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &shader,
+                module: &outline_shader,
                 entry_point: "vs_main",
                 buffers: &[Vertex::desc()],
             },
             fragment: Some(wgpu::FragmentState {
-                module: &shader,
+                module: &outline_shader,
                 entry_point: "fs_main",
                 targets: &[wgpu::ColorTargetState {
-                    format: config.format,
+                    format: DRAW_TARGET_FORMAT,
                     blend: Some(wgpu::BlendState::REPLACE),
                     write_mask: wgpu::ColorWrites::ALL,
                 }],
             }),
             primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: None,
-                unclipped_depth: false,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                conservative: false,
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
             },
             depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        // 描画先から表示範囲を切り出してウィンドウに出す
+        let present_shader = device.create_shader_module(&include_wgsl!("shader/present.wgsl"));
+
+        let present_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("present layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Uint,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let present_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("present"),
+            layout: &present_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: display_area_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&draw_target),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(
+                        &vram_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&overlay),
+                },
+            ],
+        });
+
+        let present_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("present pipeline layout"),
+                bind_group_layouts: &[&present_bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let present_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("present pipeline"),
+            layout: Some(&present_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &present_shader,
+                entry_point: "vs_main",
+                buffers: &[],
             },
+            fragment: Some(wgpu::FragmentState {
+                module: &present_shader,
+                entry_point: "fs_main",
+                targets: &[wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
             multiview: None,
         });
 
-        Renderer {
+        let postprocess = PostProcess::new(&device, config.format);
+
+        let backend = Backend {
             surface,
             device,
             queue,
             config,
             size,
             render_pipeline,
+            mask_pipeline,
+            present_pipeline,
+            outline_pipeline,
+            outline_buffer,
+            overlay,
             vertex_buffer,
+            index_buffer,
+            display_area_buffer,
+            vram_texture,
+            vram_bind_group,
+            draw_target_texture,
+            draw_target,
+            present_bind_group,
+            postprocess,
+        };
+
+        Renderer {
+            backend: Some(backend),
             vertices,
             nvertices: 0,
-            offset,
-            offset_buffer,
-            offset_bind_group,
+            indices: Vec::with_capacity(INDEX_BUFFER_LEN),
+            offset: Offset::default(),
+            dithering: false,
+            mask_check: false,
+            force_set_mask: false,
+            batches: Vec::new(),
+            true_color: false,
+            display_area,
+            vram_view: false,
+            scaling: Scaling::Aspect,
+            filter: Filter::Nearest,
+            primitive_debug: false,
+            outlines: Vec::new(),
+            outlined: 0,
+        }
+    }
+
+    // 描画しないレンダラー (ヘッドレスのテスト用)
+    #[cfg(any(test, feature = "testing"))]
+    pub fn null() -> Renderer {
+        Renderer {
+            backend: None,
+            vertices: vec![Default::default(); VERTEX_BUFFER_LEN as usize],
+            nvertices: 0,
+            indices: Vec::with_capacity(INDEX_BUFFER_LEN),
+            offset: Offset::default(),
+            dithering: false,
+            mask_check: false,
+            force_set_mask: false,
+            batches: Vec::new(),
+            true_color: false,
+            display_area: DisplayArea::default(),
+            vram_view: false,
+            scaling: Scaling::Aspect,
+            filter: Filter::Nearest,
+            primitive_debug: false,
+            outlines: Vec::new(),
+            outlined: 0,
         }
     }
 
+    // 溜まったプリミティブを描画して画面に出す
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        self.submit(true)
+    }
 
-        let mut encoder = self
+    // 溜まったプリミティブを描画先に反映するだけで画面は更新しない
+    pub fn flush(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.submit(false)
+    }
+
+    fn submit(&mut self, present: bool) -> Result<(), wgpu::SurfaceError> {
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => {
+                self.reset_vertices();
+                if present {
+                    self.outlines.clear();
+                    self.outlined = 0;
+                }
+                return Ok(());
+            }
+        };
+
+        let output = match (present, &backend.surface) {
+            (true, Some(surface)) => match surface.get_current_texture() {
+                Ok(output) => Some(output),
+                // 大きさが変わった直後などは作り直し、このフレームは画面に出さない
+                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                    surface.configure(&backend.device, &backend.config);
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+            _ => None,
+        };
+
+        let mut encoder = backend
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("renderer"),
             });
 
-        self.queue
-            .write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&self.vertices));
-        self.queue
-            .write_buffer(&self.offset_buffer, 0, bytemuck::cast_slice(&[self.offset]));
+        if self.nvertices > 0 {
+            backend.queue.write_buffer(
+                &backend.vertex_buffer,
+                0,
+                bytemuck::cast_slice(&self.vertices[..self.nvertices as usize]),
+            );
+            backend.queue.write_buffer(
+                &backend.index_buffer,
+                0,
+                bytemuck::cast_slice(&self.indices),
+            );
+        }
+        backend.queue.write_buffer(
+            &backend.display_area_buffer,
+            0,
+            bytemuck::cast_slice(&[presented_area(self.display_area, self.vram_view)]),
+        );
 
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("renderer"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &backend.draw_target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            // 描画オフセットは頂点に載っているので、マスクの設定が変わるところでだけ区切る
+            if !self.indices.is_empty() {
+                render_pass.set_bind_group(0, &backend.vram_bind_group, &[]);
+                render_pass.set_vertex_buffer(0, backend.vertex_buffer.slice(..));
+                render_pass
+                    .set_index_buffer(backend.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+
+                for (i, batch) in self.batches.iter().enumerate() {
+                    let end = self
+                        .batches
+                        .get(i + 1)
+                        .map_or(self.indices.len(), |next| next.start);
+
+                    render_pass.set_pipeline(match batch.mask_check {
+                        true => &backend.mask_pipeline,
+                        false => &backend.render_pipeline,
+                    });
+                    render_pass.draw_indexed(batch.start as u32..end as u32, 0, 0..1);
+                }
+            }
+        }
+
+        if present {
+            // 輪郭がなくてもクリアだけはする
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("outline"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &backend.overlay,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            if !self.outlines.is_empty() {
+                backend.queue.write_buffer(
+                    &backend.outline_buffer,
+                    0,
+                    bytemuck::cast_slice(&self.outlines),
+                );
+
+                render_pass.set_pipeline(&backend.outline_pipeline);
+                render_pass.set_vertex_buffer(0, backend.outline_buffer.slice(..));
+                render_pass.draw(0..self.outlines.len() as u32, 0..1);
+            }
+        }
+
+        if let Some(output) = &output {
+            // 表示範囲を等倍で切り出す
+            let area = presented_area(self.display_area, self.vram_view);
+            {
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("present"),
+                    color_attachments: &[wgpu::RenderPassColorAttachment {
+                        view: backend.postprocess.frame(),
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                });
+
+                // 全画面を覆う三角形1枚
+                render_pass.set_viewport(0.0, 0.0, area.width, area.height, 0.0, 1.0);
+                render_pass.set_pipeline(&backend.present_pipeline);
+                render_pass.set_bind_group(0, &backend.present_bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+
+            // 表示範囲の解像度によらず4:3 (VRAMビューアでは1ピクセルを正方形にする)
+            let aspect = if self.vram_view {
+                VRAM_ASPECT
+            } else {
+                DISPLAY_ASPECT
+            };
+            let (window_width, window_height) = (backend.config.width, backend.config.height);
+            let (x, y, width, height) = match self.scaling {
+                Scaling::Stretch => (0.0, 0.0, window_width as f32, window_height as f32),
+                Scaling::Aspect => fit_aspect(window_width, window_height, aspect),
+                Scaling::Integer => fit_integer(
+                    window_width,
+                    window_height,
+                    (area.width as u32, area.height as u32),
+                    aspect,
+                ),
+            };
+
+            backend.postprocess.update(
+                &backend.queue,
+                self.filter,
+                (area.width, area.height),
+                (width, height),
+            );
+
+            let view = output
+                .texture
+                .create_view(&wgpu::TextureViewDescriptor::default());
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("postprocess"),
                 color_attachments: &[wgpu::RenderPassColorAttachment {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                         store: true,
                     },
                 }],
                 depth_stencil_attachment: None,
             });
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.offset_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            render_pass.draw(0..self.nvertices, 0..1);
+            render_pass.set_viewport(x, y, width, height, 0.0, 1.0);
+            backend.postprocess.draw(&mut render_pass);
         }
 
-        self.queue.submit(iter::once(encoder.finish()));
-        output.present();
+        backend.queue.submit(iter::once(encoder.finish()));
+        if let Some(output) = output {
+            output.present();
+        }
+
+        self.reset_vertices();
+        if present {
+            self.outlines.clear();
+            self.outlined = 0;
+        }
 
         Ok(())
     }
 
-    pub fn push_triangles(&mut self, positions: [Position; 3], colors: [Color; 3]) {
-        if self.nvertices + 3 > VERTEX_BUFFER_LEN {
+    // ウィンドウの物理ピクセルの大きさに合わせて出力先を作り直す
+    // HiDPIでもぼやけないよう、論理サイズではなくスケール後の大きさで描く
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        // 最小化中は0になる
+        if width == 0 || height == 0 {
             return;
         }
 
-        for i in 0..3 {
-            debug!("triangle vertex {}: {:?} {:?}", i, positions[i], colors[i]);
-            self.vertices[self.nvertices as usize] = Vertex::new(positions[i], colors[i]);
-            self.nvertices += 1;
+        backend.size = winit::dpi::PhysicalSize::new(width, height);
+        backend.config.width = width;
+        backend.config.height = height;
+        if let Some(surface) = &backend.surface {
+            surface.configure(&backend.device, &backend.config);
         }
     }
 
+    fn reset_vertices(&mut self) {
+        self.nvertices = 0;
+        self.indices.clear();
+        self.batches.clear();
+    }
+
+    // 描画先をVRAMと同じ形式 (アルファがマスクビット) で読み出す
+    // 積んだままの描画は含まない。ウィンドウを持たない場合は None
+    pub fn read_vram(&self) -> Option<Vec<u16>> {
+        let backend = self.backend.as_ref()?;
+
+        Some(backend.read_draw_target())
+    }
+
+    // 最後に描画した画面を表示範囲の解像度で読み出す。ウィンドウを持たない場合は None
+    pub fn capture_frame(&self) -> Option<RgbaImage> {
+        let backend = self.backend.as_ref()?;
+        let area = presented_area(self.display_area, self.vram_view);
+
+        Some(backend.capture(area.width as u32, area.height as u32))
+    }
+
+    // テクスチャの参照先を更新する
+    // FIXME: フレームの途中でVRAMが書き換えられても最後の内容で描画される
+    pub fn update_vram(&mut self, data: &[u16]) {
+        let backend = match &mut self.backend {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        backend.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &backend.vram_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(VRAM_WIDTH as u32 * 2),
+                rows_per_image: std::num::NonZeroU32::new(VRAM_HEIGHT as u32),
+            },
+            wgpu::Extent3d {
+                width: VRAM_WIDTH as u32,
+                height: VRAM_HEIGHT as u32,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    pub fn push_triangles(&mut self, positions: [Position; 3], colors: [Color; 3]) {
+        self.push_triangle_vertices([
+            Vertex::new(positions[0], colors[0]),
+            Vertex::new(positions[1], colors[1]),
+            Vertex::new(positions[2], colors[2]),
+        ]);
+    }
+
     pub fn push_quad(&mut self, positions: [Position; 4], colors: [Color; 4]) {
-        if self.nvertices + 6 > VERTEX_BUFFER_LEN {
+        self.push_quad_vertices([
+            Vertex::new(positions[0], colors[0]),
+            Vertex::new(positions[1], colors[1]),
+            Vertex::new(positions[2], colors[2]),
+            Vertex::new(positions[3], colors[3]),
+        ]);
+    }
+
+    // 両端を含む1ピクセル幅の四角形として描く
+    pub fn push_line(&mut self, positions: [Position; 2], colors: [Color; 2]) {
+        let [start, end] = positions;
+
+        let dx = end.0 - start.0;
+        let dy = end.1 - start.1;
+
+        // 主軸方向に終点のピクセルを含むよう伸ばし、副軸方向に1ピクセルの幅を持たせる
+        let (start_a, start_b, end_a, end_b) = if dx.abs() >= dy.abs() {
+            let (s, e) = if dx >= 0 { (0, 1) } else { (1, 0) };
+
+            (
+                start.inflate(s, 0),
+                start.inflate(s, 1),
+                end.inflate(e, 0),
+                end.inflate(e, 1),
+            )
+        } else {
+            let (s, e) = if dy >= 0 { (0, 1) } else { (1, 0) };
+
+            (
+                start.inflate(0, s),
+                start.inflate(1, s),
+                end.inflate(0, e),
+                end.inflate(1, e),
+            )
+        };
+
+        self.push_quad(
+            [start_a, start_b, end_a, end_b],
+            [colors[0], colors[0], colors[1], colors[1]],
+        );
+    }
+
+    pub fn push_textured_triangle(
+        &mut self,
+        positions: [Position; 3],
+        colors: [Color; 3],
+        texcoords: [[f32; 2]; 3],
+        texture: Texture,
+    ) {
+        self.push_triangle_vertices([
+            Vertex::textured(positions[0], colors[0], texcoords[0], texture),
+            Vertex::textured(positions[1], colors[1], texcoords[1], texture),
+            Vertex::textured(positions[2], colors[2], texcoords[2], texture),
+        ]);
+    }
+
+    pub fn push_textured_quad(
+        &mut self,
+        positions: [Position; 4],
+        colors: [Color; 4],
+        texcoords: [[f32; 2]; 4],
+        texture: Texture,
+    ) {
+        self.push_quad_vertices([
+            Vertex::textured(positions[0], colors[0], texcoords[0], texture),
+            Vertex::textured(positions[1], colors[1], texcoords[1], texture),
+            Vertex::textured(positions[2], colors[2], texcoords[2], texture),
+            Vertex::textured(positions[3], colors[3], texcoords[3], texture),
+        ]);
+    }
+
+    fn push_triangle_vertices(&mut self, vertices: [Vertex; 3]) {
+        self.reserve(3);
+
+        let base = self.nvertices;
+        for (i, vertex) in vertices.iter().enumerate() {
+            debug!("triangle vertex {}: {:?}", i, vertex);
+            self.push_vertex(*vertex);
+        }
+        self.begin_batch(self.mask_check);
+        self.indices.extend([0, 1, 2].map(|i| base + i));
+
+        self.push_outline(&vertices);
+    }
+
+    // 0,1,2 と 1,2,3 の2つの三角形に分割する
+    fn push_quad_vertices(&mut self, vertices: [Vertex; 4]) {
+        self.reserve(4);
+
+        let base = self.nvertices;
+        for vertex in vertices {
+            debug!("quad vertex: {:?}", vertex);
+            self.push_vertex(vertex);
+        }
+
+        // VRAMへの転送はプリミティブではない。マスクはCPU側で処理してある
+        let vram_copy = vertices[0].flags & vertex_flags::VRAM_COPY != 0;
+        self.begin_batch(self.mask_check && !vram_copy);
+        self.indices.extend([0, 1, 2, 1, 2, 3].map(|i| base + i));
+
+        if !vram_copy {
+            self.push_outline(&[vertices[0], vertices[1], vertices[3], vertices[2]]);
+        }
+    }
+
+    // 頂点を順につないだ輪郭を、プリミティブごとに違う色で積む
+    fn push_outline(&mut self, corners: &[Vertex]) {
+        if !self.primitive_debug || self.outlines.len() + corners.len() * 2 > OUTLINE_BUFFER_LEN {
             return;
         }
 
-        for i in (0..3).rev() {
-            debug!("quad vertex {}: {:?} {:?}", i, positions[i], colors[i]);
-            self.vertices[self.nvertices as usize] = Vertex::new(positions[i], colors[i]);
-            self.nvertices += 1;
+        let color = outline_color(self.outlined);
+        self.outlined += 1;
+
+        let offset = [self.offset.x, self.offset.y];
+        // 位置と色以外は使わない
+        let vertex = |corner: &Vertex| Vertex {
+            position: [
+                corner.position[0] + offset[0],
+                corner.position[1] + offset[1],
+            ],
+            color,
+            ..Default::default()
+        };
+
+        for (i, corner) in corners.iter().enumerate() {
+            let next = &corners[(i + 1) % corners.len()];
+
+            self.outlines.push(vertex(corner));
+            self.outlines.push(vertex(next));
         }
+    }
 
-        for i in 1..4 {
-            debug!("quad vertex {}: {:?} {:?}", i, positions[i], colors[i]);
-            self.vertices[self.nvertices as usize] = Vertex::new(positions[i], colors[i]);
-            self.nvertices += 1;
+    // マスクビットを確かめるかどうかが変わったら、そこから別のパイプラインで描く
+    fn begin_batch(&mut self, mask_check: bool) {
+        if self.batches.last().map(|batch| batch.mask_check) != Some(mask_check) {
+            self.batches.push(Batch {
+                start: self.indices.len(),
+                mask_check,
+            });
         }
     }
 
+    // 頂点バッファに入りきらなければそこまでを描画して空ける
+    fn reserve(&mut self, len: u32) {
+        if self.nvertices + len > VERTEX_BUFFER_LEN {
+            self.flush().unwrap();
+        }
+    }
+
+    fn push_vertex(&mut self, mut vertex: Vertex) {
+        if self.dithering {
+            vertex.flags |= vertex_flags::DITHER;
+        }
+        if self.force_set_mask {
+            vertex.flags |= vertex_flags::SET_MASK;
+        }
+        if self.true_color {
+            vertex.flags |= vertex_flags::TRUE_COLOR;
+        }
+        vertex.offset = [self.offset.x, self.offset.y];
+
+        self.vertices[self.nvertices as usize] = vertex;
+        self.nvertices += 1;
+    }
+
+    // GP0(0xE6) bit1
+    pub fn set_mask_check(&mut self, enabled: bool) {
+        self.mask_check = enabled;
+    }
+
+    // GP0(0xE6) bit0
+    pub fn set_force_set_mask(&mut self, enabled: bool) {
+        self.force_set_mask = enabled;
+    }
+
+    // 以降にpushするプリミティブを15bitに落とさずに描くか
+    pub fn set_true_color(&mut self, enabled: bool) {
+        self.true_color = enabled;
+    }
+
+    pub fn true_color(&self) -> bool {
+        self.true_color
+    }
+
+    // 以降にpushするプリミティブにディザをかけるか
+    pub fn set_dithering(&mut self, enabled: bool) {
+        self.dithering = enabled;
+    }
+
+    // 以降にpushするプリミティブの描画オフセット
+    // 頂点ごとに持つので、それまでに積んだ分は前のオフセットのまま同じ描画で描く
     pub fn set_draw_offset(&mut self, x: i16, y: i16) {
         self.offset.set(x, y);
     }
 
-    pub fn fill_rect(&mut self, color: Color, top_left: Position, size: Position) {
-        self.push_quad(
-            [
-                top_left,
-                top_left.inflate(size.0, 0),
-                top_left.inflate(0, size.1),
-                top_left.inflate(size.0, size.1),
-            ],
-            [color; 4],
+    pub fn set_display_area(&mut self, x: u16, y: u16, width: u16, height: u16) {
+        self.display_area.set(x, y, width, height);
+    }
+
+    // どのコマンドがおかしな形を描いているかの確認用
+    pub fn set_primitive_debug(&mut self, enabled: bool) {
+        self.primitive_debug = enabled;
+    }
+
+    pub fn primitive_debug(&self) -> bool {
+        self.primitive_debug
+    }
+
+    // テクスチャページやCLUTの確認用
+    pub fn set_vram_view(&mut self, enabled: bool) {
+        self.vram_view = enabled;
+    }
+
+    pub fn vram_view(&self) -> bool {
+        self.vram_view
+    }
+
+    pub fn set_scaling(&mut self, scaling: Scaling) {
+        self.scaling = scaling;
+    }
+
+    pub fn scaling(&self) -> Scaling {
+        self.scaling
+    }
+
+    // 画面に出すときのフィルタ。スクリーンショットにはかけない
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    // GP1(0x03)
+    pub fn set_display_enabled(&mut self, enabled: bool) {
+        self.display_area.enabled = enabled as u32;
+    }
+
+    // GP1(0x08) bit4
+    pub fn set_display_depth24(&mut self, depth24: bool) {
+        self.display_area.depth24 = depth24 as u32;
+    }
+
+    // CPUから書き込まれたVRAMの矩形を描画先に反映する
+    // 描画の順序を保つためにプリミティブと同じ頂点列に積む
+    // VRAMの端をまたぐ矩形は折り返した先の分も別にコピーする
+    pub fn push_vram_copy(&mut self, x: u16, y: u16, width: u16, height: u16) {
+        for (x, width) in wrap_span(x, width, VRAM_WIDTH) {
+            for (y, height) in wrap_span(y, height, VRAM_HEIGHT) {
+                self.push_vram_copy_rect(x, y, width, height);
+            }
+        }
+    }
+
+    fn push_vram_copy_rect(&mut self, x: u16, y: u16, width: u16, height: u16) {
+        let top_left = Position(x as i16, y as i16);
+        let (width, height) = (width as i16, height as i16);
+
+        let vertices = [
+            top_left,
+            top_left.inflate(width, 0),
+            top_left.inflate(0, height),
+            top_left.inflate(width, height),
+        ]
+        .map(|position| Vertex {
+            flags: vertex_flags::VRAM_COPY,
+            ..Vertex::new(position, Color(0, 0, 0))
+        });
+
+        self.push_quad_vertices(vertices);
+    }
+}
+
+impl Backend {
+    fn read_draw_target(&self) -> Vec<u16> {
+        let extent = wgpu::Extent3d {
+            width: VRAM_WIDTH as u32,
+            height: VRAM_HEIGHT as u32,
+            depth_or_array_layers: 1,
+        };
+
+        // 1ラインが 4096byte なのでアラインメントは合っている
+        let bytes_per_row = VRAM_WIDTH as u32 * 4;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback buffer"),
+            size: (bytes_per_row * VRAM_HEIGHT as u32) as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("readback"),
+            });
+
+        encoder.copy_texture_to_buffer(
+            self.draw_target_texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            extent,
         );
+
+        self.queue.submit(iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        smol::block_on(mapping).unwrap();
+
+        let data = slice.get_mapped_range().chunks(4).map(to_vram).collect();
+
+        data
+    }
+
+    // 画面に出すのと同じパスをオフスクリーンに描いて読み戻す
+    fn capture(&self, width: u32, height: u32) -> RgbaImage {
+        let extent = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("capture target"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // 行の長さはアラインメントが必要
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let bytes_per_row = (width * 4).div_ceil(align) * align;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("capture buffer"),
+            size: (bytes_per_row * height) as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("capture"),
+            });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("capture"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+
+            render_pass.set_pipeline(&self.present_pipeline);
+            render_pass.set_bind_group(0, &self.present_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            extent,
+        );
+
+        self.queue.submit(iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        smol::block_on(mapping).unwrap();
+
+        let bgra = matches!(
+            self.config.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        );
+
+        let data = slice
+            .get_mapped_range()
+            .chunks(bytes_per_row as usize)
+            .flat_map(|row| row[..width as usize * 4].chunks(4))
+            .flat_map(|pixel| match bgra {
+                true => [pixel[2], pixel[1], pixel[0], pixel[3]],
+                false => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            })
+            .collect();
+
+        RgbaImage {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+fn request_device(adapter: &wgpu::Adapter) -> (wgpu::Device, wgpu::Queue) {
+    smol::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits::downlevel_defaults(),
+            label: None,
+        },
+        None,
+    ))
+    .unwrap()
+}
+
+// 描画先の1ピクセル (RGBA 8bit) をVRAMの15bitの色とマスクビットに戻す
+fn to_vram(pixel: &[u8]) -> u16 {
+    let channel = |c: u8| (c as u16 * 31 + 127) / 255;
+    let mask = (pixel[3] >= 0x80) as u16;
+
+    channel(pixel[0]) | channel(pixel[1]) << 5 | channel(pixel[2]) << 10 | mask << 15
+}
+
+// 隣り合うプリミティブが見分けられるよう、番号から色相を散らす
+fn outline_color(index: u32) -> [f32; 3] {
+    let hash = index.wrapping_mul(0x9E3779B9);
+
+    [hash >> 24, hash >> 16, hash >> 8].map(|c| ((c & 0xFF) | 0x40) as f32 / 255.0)
+}
+
+// 画面に出す範囲。VRAMビューアではVRAM全体
+fn presented_area(display_area: DisplayArea, vram_view: bool) -> DisplayArea {
+    match vram_view {
+        true => DisplayArea::whole_vram(),
+        false => display_area,
     }
 }
 
+// 縦横比を保ったまま width x height に収まる中央の矩形 (x, y, width, height)
+// 端数でぼやけないようにピクセル単位に揃える
+fn fit_aspect(width: u32, height: u32, aspect: f32) -> (f32, f32, f32, f32) {
+    let (width, height) = (width as f32, height as f32);
+
+    let (fit_width, fit_height) = if width / height > aspect {
+        ((height * aspect).floor(), height)
+    } else {
+        (width, (width / aspect).floor())
+    };
+
+    (
+        ((width - fit_width) / 2.0).floor(),
+        ((height - fit_height) / 2.0).floor(),
+        fit_width,
+        fit_height,
+    )
+}
+
+// 表示範囲 (source) を縦横それぞれ整数倍して width x height に収まる中央の矩形
+// 縦はできるだけ大きく、横は縦の倍率で aspect に最も近くなる倍率にする
+// ウィンドウが表示範囲より小さければ fit_aspect と同じ
+fn fit_integer(width: u32, height: u32, source: (u32, u32), aspect: f32) -> (f32, f32, f32, f32) {
+    let (source_width, source_height) = source;
+
+    for scale_y in (1..=height / source_height).rev() {
+        let target_width = (source_height * scale_y) as f32 * aspect;
+        let scale_x = ((target_width / source_width as f32).round() as u32).max(1);
+
+        let (fit_width, fit_height) = (source_width * scale_x, source_height * scale_y);
+        if fit_width <= width {
+            return (
+                ((width - fit_width) / 2) as f32,
+                ((height - fit_height) / 2) as f32,
+                fit_width as f32,
+                fit_height as f32,
+            );
+        }
+    }
+
+    fit_aspect(width, height, aspect)
+}
+
+// [start, start + len) を [0, size) に収まる区間に分ける
+fn wrap_span(start: u16, len: u16, size: u16) -> impl Iterator<Item = (u16, u16)> {
+    let start = start % size;
+    let len = len.min(size);
+    let first = len.min(size - start);
+
+    [(start, first), (0, len - first)]
+        .into_iter()
+        .filter(|&(_, len)| len > 0)
+}
+
 const VERTEX_BUFFER_LEN: u32 = 64 * 1024;
+// 頂点が尽きるより先に溢れない大きさ (四角形は4頂点に6つ)
+const INDEX_BUFFER_LEN: usize = VERTEX_BUFFER_LEN as usize / 2 * 3;
+
+// テレビに映したときの縦横比
+const DISPLAY_ASPECT: f32 = 4.0 / 3.0;
+
+// VRAMビューアは 1024x512 をそのまま出す
+const VRAM_ASPECT: f32 = VRAM_WIDTH as f32 / VRAM_HEIGHT as f32;
+
+// 1フレームに積める輪郭の頂点数。超えた分は描かない
+const OUTLINE_BUFFER_LEN: usize = 64 * 1024;
+
+const DRAW_TARGET_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_triangle(renderer: &mut Renderer) {
+        let positions = [Position(0, 0), Position(1, 0), Position(0, 1)];
+        renderer.push_triangles(positions, [Color(0, 0, 0); 3]);
+    }
+
+    #[test]
+    fn draw_offset_is_stored_per_vertex() {
+        let mut renderer = Renderer::null();
+
+        push_triangle(&mut renderer);
+        renderer.set_draw_offset(10, 20);
+        renderer.set_draw_offset(30, 40);
+        push_triangle(&mut renderer);
+
+        let offsets: Vec<_> = renderer.vertices[..6].iter().map(|v| v.offset).collect();
+        let mut expected = vec![[0.0, 0.0]; 3];
+        expected.extend([[30.0, 40.0]; 3]);
+        assert_eq!(offsets, expected);
+    }
+
+    #[test]
+    fn outlines_follow_draw_offset() {
+        let mut renderer = Renderer::null();
+        renderer.set_primitive_debug(true);
+        renderer.set_draw_offset(100, 50);
+
+        push_triangle(&mut renderer);
+        renderer.push_vram_copy(0, 0, 16, 16);
+
+        let positions: Vec<_> = renderer.outlines.iter().map(|v| v.position).collect();
+        assert_eq!(
+            positions,
+            vec![
+                [100.0, 50.0],
+                [101.0, 50.0],
+                [101.0, 50.0],
+                [100.0, 51.0],
+                [100.0, 51.0],
+                [100.0, 50.0],
+            ]
+        );
+
+        renderer.render().unwrap();
+        assert!(renderer.outlines.is_empty());
+    }
+
+    #[test]
+    fn draw_offset_changes_do_not_flush() {
+        let mut renderer = Renderer::null();
+
+        for i in 0..1000 {
+            push_triangle(&mut renderer);
+            renderer.set_draw_offset(i, 0);
+        }
+
+        assert_eq!(renderer.nvertices, 3000);
+        assert_eq!(renderer.vertices[2999].offset, [998.0, 0.0]);
+    }
+
+    #[test]
+    fn quads_share_vertices() {
+        let mut renderer = Renderer::null();
+
+        push_triangle(&mut renderer);
+        let positions = [
+            Position(0, 0),
+            Position(1, 0),
+            Position(0, 1),
+            Position(1, 1),
+        ];
+        renderer.push_quad(positions, [Color(0, 0, 0); 4]);
+
+        assert_eq!(renderer.nvertices, 7);
+        assert_eq!(renderer.indices, vec![0, 1, 2, 3, 4, 5, 4, 5, 6]);
+    }
+
+    #[test]
+    fn true_color_marks_vertices() {
+        let mut renderer = Renderer::null();
+
+        push_triangle(&mut renderer);
+        renderer.set_true_color(true);
+        push_triangle(&mut renderer);
+
+        let flags: Vec<_> = renderer.vertices[..6]
+            .iter()
+            .map(|v| v.flags & vertex_flags::TRUE_COLOR != 0)
+            .collect();
+        assert_eq!(flags, vec![false, false, false, true, true, true]);
+    }
+
+    #[test]
+    fn fit_aspect_letterboxes() {
+        assert_eq!(
+            fit_aspect(1920, 1080, DISPLAY_ASPECT),
+            (240.0, 0.0, 1440.0, 1080.0)
+        );
+        assert_eq!(fit_aspect(1024, 1024, 2.0), (0.0, 256.0, 1024.0, 512.0));
+    }
+
+    #[test]
+    fn fit_integer_keeps_display_aspect() {
+        // 横の画素が細長い解像度でも 4:3 に近づける
+        for source in [(320, 240), (256, 240), (640, 480), (640, 240)] {
+            assert_eq!(
+                fit_integer(1920, 1080, source, DISPLAY_ASPECT),
+                (320.0, 60.0, 1280.0, 960.0),
+                "{:?}",
+                source
+            );
+        }
+
+        // 368 は4:3にちょうどならないので近い倍率
+        assert_eq!(
+            fit_integer(1920, 1080, (368, 240), DISPLAY_ASPECT),
+            (408.0, 60.0, 1104.0, 960.0)
+        );
+
+        // 収まらなければ縦横比だけ保つ
+        assert_eq!(
+            fit_integer(300, 200, (320, 240), DISPLAY_ASPECT),
+            fit_aspect(300, 200, DISPLAY_ASPECT)
+        );
+    }
+
+    #[test]
+    fn full_vertex_buffer_flushes() {
+        let mut renderer = Renderer::null();
+        renderer.set_draw_offset(10, 0);
+
+        for _ in 0..VERTEX_BUFFER_LEN / 3 + 1 {
+            push_triangle(&mut renderer);
+        }
+
+        // 溢れた三角形は捨てずに次の描画の先頭に積む
+        assert_eq!(renderer.nvertices, 3);
+        assert_eq!(renderer.vertices[0].offset, [10.0, 0.0]);
+    }
+
+    #[test]
+    fn draw_target_pixels_convert_to_vram() {
+        // 5bit の各値は Rgba8Unorm に丸めて書かれる
+        for c in 0..32u16 {
+            let c8 = ((c as u32 * 255 + 15) / 31) as u8;
+            assert_eq!(to_vram(&[c8, 0, 0, 0]), c);
+            assert_eq!(to_vram(&[0, c8, 0, 0]), c << 5);
+            assert_eq!(to_vram(&[0, 0, c8, 0]), c << 10);
+        }
+
+        // アルファはマスクビット
+        assert_eq!(to_vram(&[0, 0, 0, 255]), 0x8000);
+        assert_eq!(to_vram(&[255, 255, 255, 0]), 0x7FFF);
+    }
+
+    #[test]
+    fn mask_check_splits_batches() {
+        let mut renderer = Renderer::null();
+
+        push_triangle(&mut renderer);
+        renderer.set_mask_check(true);
+        push_triangle(&mut renderer);
+        push_triangle(&mut renderer);
+        // VRAMへの転送はCPU側でマスクを処理してあるので確かめない
+        renderer.push_vram_copy(0, 0, 16, 16);
+        renderer.set_force_set_mask(true);
+        push_triangle(&mut renderer);
+
+        let batch = |start, mask_check| Batch { start, mask_check };
+        assert_eq!(
+            renderer.batches,
+            [
+                batch(0, false),
+                batch(3, true),
+                batch(9, false),
+                batch(15, true)
+            ]
+        );
+
+        let flags: Vec<_> = renderer.vertices[..renderer.nvertices as usize]
+            .iter()
+            .map(|v| v.flags & vertex_flags::SET_MASK != 0)
+            .collect();
+        assert_eq!(flags[..13], [false; 13]);
+        assert_eq!(flags[13..], [true; 3]);
+
+        renderer.flush().unwrap();
+        assert!(renderer.batches.is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use anyhow::Result;
+
+use super::gpu::Frame;
+
+// 8bit RGBA、行優先
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl RgbaImage {
+    // VRAMの15bitの画素をそのまま変換する (ウィンドウを持たない場合用)
+    pub fn from_frame(frame: &Frame) -> RgbaImage {
+        let data = frame
+            .pixels
+            .iter()
+            .flat_map(|&pixel| {
+                let channel = |shift: u16| {
+                    let c = ((pixel >> shift) & 0x1F) as u8;
+                    (c << 3) | (c >> 2)
+                };
+
+                [channel(0), channel(5), channel(10), 0xFF]
+            })
+            .collect();
+
+        RgbaImage {
+            width: frame.width as u32,
+            height: frame.height as u32,
+            data,
+        }
+    }
+
+    pub fn write_png(&self, path: &Path) -> Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_15bit_pixels() {
+        let frame = Frame {
+            width: 2,
+            height: 1,
+            pixels: vec![0x001F, 0x7FE0],
+        };
+
+        let image = RgbaImage::from_frame(&frame);
+
+        assert_eq!(
+            image.data,
+            vec![0xFF, 0x00, 0x00, 0xFF, 0x00, 0xFF, 0xFF, 0xFF]
+        );
+    }
+}
@@ -0,0 +1,136 @@
+struct Params {
+  // 表示範囲の大きさ (frame の左上に描かれている)
+  source: vec2<f32>;
+  // 出力先の矩形の大きさ
+  output: vec2<f32>;
+  filter: u32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> params: Params;
+
+// 表示範囲を1ピクセルずつ描いたもの (VRAMと同じ大きさ)
+[[group(0), binding(1)]]
+var frame: texture_2d<f32>;
+
+[[group(0), binding(2)]]
+var frame_sampler: sampler;
+
+let FILTER_NEAREST: u32 = 0u;
+let FILTER_BILINEAR: u32 = 1u;
+let FILTER_SHARP_BILINEAR: u32 = 2u;
+let FILTER_SCANLINES: u32 = 3u;
+let FILTER_CRT: u32 = 4u;
+
+let FRAME_SIZE: vec2<f32> = vec2<f32>(1024.0, 512.0);
+
+// ライン間の暗さ
+let SCANLINE_DEPTH: f32 = 0.45;
+// 走査線とマスクで暗くなった分を持ち上げる
+let CRT_BRIGHTNESS: f32 = 1.25;
+let CRT_MASK: f32 = 0.8;
+let CRT_CURVATURE: f32 = 0.06;
+
+struct VertexOutput {
+  [[builtin(position)]] position: vec4<f32>;
+  [[location(0)]] uv: vec2<f32>;
+};
+
+// 画面全体を覆う三角形
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+  var out: VertexOutput;
+
+  let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+
+  out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+  out.uv = uv;
+
+  return out;
+}
+
+// 表示範囲の座標 (ピクセル単位) で補間して読む
+// 表示範囲の外にある古い内容が混ざらないよう、端のピクセルの中心までに制限する
+fn sample(texel: vec2<f32>) -> vec4<f32> {
+  let clamped = clamp(texel, vec2<f32>(0.5), params.source - 0.5);
+
+  return textureSampleLevel(frame, frame_sampler, clamped / FRAME_SIZE, 0.0);
+}
+
+fn nearest(texel: vec2<f32>) -> vec4<f32> {
+  return sample(floor(texel) + 0.5);
+}
+
+// 整数倍までは最近傍で拡大し、残りの端数だけ補間する
+fn sharp_bilinear(texel: vec2<f32>) -> vec4<f32> {
+  let scale = max(floor(params.output / params.source), vec2<f32>(1.0));
+  let region = 0.5 - 0.5 / scale;
+
+  let center = fract(texel) - 0.5;
+  let f = (center - clamp(center, -region, region)) * scale + 0.5;
+
+  return sample(floor(texel) + f);
+}
+
+// ラインの中心から離れるほど暗くする
+fn scanline(texel: vec2<f32>) -> f32 {
+  let d = abs(fract(texel.y) - 0.5) * 2.0;
+
+  return 1.0 - SCANLINE_DEPTH * d * d;
+}
+
+// 出力先のピクセルの列ごとにRGBのどれかを強調する (アパーチャグリル)
+fn mask(position: vec4<f32>) -> vec3<f32> {
+  let column = u32(position.x) % 3u;
+
+  var weights = vec3<f32>(CRT_MASK);
+  if (column == 0u) {
+    weights.r = 1.0;
+  } else if (column == 1u) {
+    weights.g = 1.0;
+  } else {
+    weights.b = 1.0;
+  }
+
+  return weights;
+}
+
+// ブラウン管の丸みに合わせて外側を引き延ばす
+fn curve(uv: vec2<f32>) -> vec2<f32> {
+  let centered = uv * 2.0 - 1.0;
+  let offset = centered.yx * centered.yx * CRT_CURVATURE;
+
+  return (centered * (1.0 + offset)) * 0.5 + 0.5;
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+  let texel = in.uv * params.source;
+
+  if (params.filter == FILTER_BILINEAR) {
+    return sample(texel);
+  }
+
+  if (params.filter == FILTER_SHARP_BILINEAR) {
+    return sharp_bilinear(texel);
+  }
+
+  if (params.filter == FILTER_SCANLINES) {
+    let color = nearest(texel);
+    return vec4<f32>(color.rgb * scanline(texel), 1.0);
+  }
+
+  if (params.filter == FILTER_CRT) {
+    let uv = curve(in.uv);
+    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0))) {
+      return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+
+    let curved = uv * params.source;
+    let color = sharp_bilinear(curved).rgb * scanline(curved) * mask(in.position);
+
+    return vec4<f32>(min(color * CRT_BRIGHTNESS, vec3<f32>(1.0)), 1.0);
+  }
+
+  return nearest(texel);
+}
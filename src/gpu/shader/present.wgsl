@@ -0,0 +1,84 @@
+struct DisplayArea {
+  x: f32;
+  y: f32;
+  width: f32;
+  height: f32;
+  enabled: u32;
+  depth24: u32;
+};
+
+[[group(0), binding(0)]]
+var<uniform> display_area: DisplayArea;
+
+[[group(0), binding(1)]]
+var draw_target: texture_2d<f32>;
+
+[[group(0), binding(2)]]
+var vram: texture_2d<u32>;
+
+// プリミティブの輪郭 (デバッグ表示)。描かれていない所は透明
+[[group(0), binding(3)]]
+var overlay: texture_2d<f32>;
+
+struct VertexOutput {
+  [[builtin(position)]] position: vec4<f32>;
+  [[location(0)]] uv: vec2<f32>;
+};
+
+// 画面全体を覆う三角形
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+  var out: VertexOutput;
+
+  let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+
+  out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+  out.uv = uv;
+
+  return out;
+}
+
+// VRAMの1ラインを2048byteの列として読む
+fn vram_byte(offset: u32, y: u32) -> u32 {
+  let offset = offset & 2047u;
+  let word = textureLoad(vram, vec2<i32>(i32(offset >> 1u), i32(y & 511u)), 0).r;
+
+  return (word >> ((offset & 1u) * 8u)) & 255u;
+}
+
+// 24bitモードでは表示開始位置から1ピクセル3byteで並んでいる
+fn fetch_rgb24(x: u32, y: u32) -> vec4<f32> {
+  let offset = u32(display_area.x) * 2u + x * 3u;
+
+  return vec4<f32>(
+    f32(vram_byte(offset, y)),
+    f32(vram_byte(offset + 1u, y)),
+    f32(vram_byte(offset + 2u, y)),
+    255.0,
+  ) / 255.0;
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+  if (display_area.enabled == 0u) {
+    return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+  }
+
+  // 表示範囲をウィンドウ全体に合わせる
+  let dx = floor(in.uv.x * display_area.width);
+  let y = display_area.y + floor(in.uv.y * display_area.height);
+
+  let texel = vec2<i32>(i32(display_area.x + dx) & 1023, i32(y) & 511);
+
+  var color: vec4<f32>;
+  if (display_area.depth24 != 0u) {
+    color = fetch_rgb24(u32(dx), u32(y));
+  } else {
+    // アルファはマスクビットなので画面には出さない
+    color = vec4<f32>(textureLoad(draw_target, texel, 0).rgb, 1.0);
+  }
+
+  let outline = textureLoad(overlay, texel, 0);
+
+  return mix(color, vec4<f32>(outline.rgb, 1.0), outline.a);
+}
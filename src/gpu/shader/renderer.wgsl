@@ -1,20 +1,35 @@
 struct VertexInput {
   [[location(0)]] position: vec2<f32>;
   [[location(1)]] color: vec3<f32>;
+  [[location(2)]] texcoord: vec2<f32>;
+  [[location(3)]] texpage: u32;
+  [[location(4)]] clut: u32;
+  [[location(5)]] flags: u32;
+  [[location(6)]] window: u32;
+  [[location(7)]] offset: vec2<f32>;
 };
 
 struct VertexOutput {
   [[builtin(position)]] position: vec4<f32>;
   [[location(0)]] color: vec3<f32>;
-};
-
-struct Offset {
-  x: f32;
-  y: f32;
+  [[location(1)]] texcoord: vec2<f32>;
+  [[location(2), interpolate(flat)]] texpage: u32;
+  [[location(3), interpolate(flat)]] clut: u32;
+  [[location(4), interpolate(flat)]] flags: u32;
+  [[location(5)]] vram_position: vec2<f32>;
+  [[location(6), interpolate(flat)]] window: u32;
 };
 
 [[group(0), binding(0)]]
-var<uniform> offset: Offset;
+var vram: texture_2d<u32>;
+
+// primitive::vertex_flags と同じ
+let FLAG_TEXTURED: u32 = 1u;
+let FLAG_RAW_TEXTURE: u32 = 2u;
+let FLAG_DITHER: u32 = 4u;
+let FLAG_SET_MASK: u32 = 8u;
+let FLAG_VRAM_COPY: u32 = 16u;
+let FLAG_TRUE_COLOR: u32 = 32u;
 
 [[stage(vertex)]]
 fn vs_main(
@@ -22,21 +37,164 @@ fn vs_main(
 ) -> VertexOutput {
   var out: VertexOutput;
 
-  let pos = vec2<f32>(
-    model.position.x + offset.x,
-    model.position.y + offset.y,
-  );
+  var pos = model.position;
+  if ((model.flags & FLAG_VRAM_COPY) == 0u) {
+    pos = pos + model.offset;
+  }
 
-  let x = (pos.x / 512.0) - 1.0;
-  let y = 1.0 - (pos.y / 256.0);
+  // 描画先はVRAMと同じ 1024x512
+  let x = pos.x / 512.0 - 1.0;
+  let y = 1.0 - pos.y / 256.0;
 
   out.position = vec4<f32>(x, y, 0.0, 1.0);
   out.color = model.color;
+  out.texcoord = model.texcoord;
+  out.texpage = model.texpage;
+  out.clut = model.clut;
+  out.flags = model.flags;
+  out.vram_position = pos;
+  out.window = model.window;
 
   return out;
 }
 
+fn vram_load(x: u32, y: u32) -> u32 {
+  return textureLoad(vram, vec2<i32>(i32(x & 1023u), i32(y & 511u)), 0).r;
+}
+
+// テクスチャウィンドウ: (uv & !(mask * 8)) | ((offset & mask) * 8)
+fn apply_window(coord: u32, mask: u32, offset: u32) -> u32 {
+  return (coord & ~(mask * 8u)) | ((offset & mask) * 8u);
+}
+
+// texpage/clutの形式で16bitのテクセルを取り出す
+fn fetch_texel(texcoord: vec2<f32>, texpage: u32, clut: u32, window: u32) -> u32 {
+  let u = apply_window(u32(i32(floor(texcoord.x)) & 255), window & 31u, (window >> 10u) & 31u);
+  let v = apply_window(u32(i32(floor(texcoord.y)) & 255), (window >> 5u) & 31u, (window >> 15u) & 31u);
+
+  let page_x = (texpage & 15u) * 64u;
+  let page_y = ((texpage >> 4u) & 1u) * 256u;
+  let depth = (texpage >> 7u) & 3u;
+
+  let clut_x = (clut & 63u) * 16u;
+  let clut_y = (clut >> 6u) & 511u;
+
+  if (depth == 0u) {
+    let word = vram_load(page_x + u / 4u, page_y + v);
+    let index = (word >> ((u & 3u) * 4u)) & 15u;
+    return vram_load(clut_x + index, clut_y);
+  }
+
+  if (depth == 1u) {
+    let word = vram_load(page_x + u / 2u, page_y + v);
+    let index = (word >> ((u & 1u) * 8u)) & 255u;
+    return vram_load(clut_x + index, clut_y);
+  }
+
+  return vram_load(page_x + u, page_y + v);
+}
+
+fn rgb15(texel: u32) -> vec3<f32> {
+  return vec3<f32>(
+    f32(texel & 31u),
+    f32((texel >> 5u) & 31u),
+    f32((texel >> 10u) & 31u),
+  ) / 31.0;
+}
+
+// 4x4のディザ行列 (8bitの色に加算する)
+fn dither_offset(position: vec2<f32>) -> f32 {
+  let x = u32(i32(floor(position.x)) & 3);
+  let y = u32(i32(floor(position.y)) & 3);
+
+  var row: vec4<f32>;
+  switch (y) {
+    case 0: { row = vec4<f32>(-4.0, 0.0, -3.0, 1.0); }
+    case 1: { row = vec4<f32>(2.0, -2.0, 3.0, -1.0); }
+    case 2: { row = vec4<f32>(-3.0, 1.0, -4.0, 0.0); }
+    default: { row = vec4<f32>(3.0, -1.0, 2.0, -2.0); }
+  }
+
+  return row[x];
+}
+
+// 頂点色 (256で割ってある) を実機と同じく8bitの整数に切り捨てる
+// 補間の誤差で整数を下回らないよう少し足す
+fn color8(color: vec3<f32>) -> vec3<f32> {
+  return floor(color * 256.0 + 0.001);
+}
+
+// 5bitの各チャンネルを8bitに広げる
+fn texel8(texel: u32) -> vec3<f32> {
+  return vec3<f32>(
+    f32((texel & 31u) << 3u),
+    f32(((texel >> 5u) & 31u) << 3u),
+    f32(((texel >> 10u) & 31u) << 3u),
+  );
+}
+
+// テクセルと頂点色 (8bit) の積。0x80で等倍
+fn modulate(texel: u32, color: vec3<f32>) -> vec3<f32> {
+  return min(floor(texel8(texel) * color8(color) / 128.0), vec3<f32>(255.0));
+}
+
+// 8bitの色を15bitに落とす。ディザがなければ下位ビットを捨てる
+fn to_rgb15(color: vec3<f32>, flags: u32, position: vec2<f32>) -> vec3<f32> {
+  var c = color;
+
+  if ((flags & FLAG_DITHER) != 0u) {
+    c = c + vec3<f32>(dither_offset(position));
+  }
+
+  return floor(clamp(c, vec3<f32>(0.0), vec3<f32>(255.0)) / 8.0) / 31.0;
+}
+
+// アルファにマスクビットを入れる。書き込み先のマスクビットはパイプラインのブレンドで確かめる
+fn with_mask(color: vec3<f32>, texel: u32, flags: u32) -> vec4<f32> {
+  if ((texel & 0x8000u) != 0u || (flags & FLAG_SET_MASK) != 0u) {
+    return vec4<f32>(color, 1.0);
+  }
+
+  return vec4<f32>(color, 0.0);
+}
+
 [[stage(fragment)]]
 fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-  return vec4<f32>(in.color, 1.0);
+  if ((in.flags & FLAG_VRAM_COPY) != 0u) {
+    let texel = vram_load(u32(i32(floor(in.vram_position.x))), u32(i32(floor(in.vram_position.y))));
+    return with_mask(rgb15(texel), texel, 0u);
+  }
+
+  let true_color = (in.flags & FLAG_TRUE_COLOR) != 0u;
+
+  if ((in.flags & FLAG_TEXTURED) == 0u) {
+    // 補間したままの色を出す
+    if (true_color) {
+      return with_mask(min(in.color * 256.0 / 255.0, vec3<f32>(1.0)), 0u, in.flags);
+    }
+
+    return with_mask(to_rgb15(color8(in.color), in.flags, in.vram_position), 0u, in.flags);
+  }
+
+  let texel = fetch_texel(in.texcoord, in.texpage, in.clut, in.window);
+
+  // 0x0000は透明
+  if (texel == 0u) {
+    discard;
+  }
+
+  // テクセルのマスクビットはそのまま描画先に書かれる
+  if ((in.flags & FLAG_RAW_TEXTURE) != 0u) {
+    if (true_color) {
+      return with_mask(rgb15(texel), texel, in.flags);
+    }
+
+    return with_mask(to_rgb15(texel8(texel), in.flags, in.vram_position), texel, in.flags);
+  }
+
+  if (true_color) {
+    return with_mask(min(rgb15(texel) * in.color * 2.0, vec3<f32>(1.0)), texel, in.flags);
+  }
+
+  return with_mask(to_rgb15(modulate(texel, in.color), in.flags, in.vram_position), texel, in.flags);
 }
@@ -0,0 +1,240 @@
+use anyhow::Result;
+use num_derive::FromPrimitive;
+
+use crate::savestate::{Reader, Savestate, Writer};
+
+#[derive(Clone, Copy, FromPrimitive)]
+pub enum VMode {
+    Ntsc = 0,
+    Pal = 1,
+}
+
+pub const CPU_CLOCK: u64 = 33_868_800;
+
+impl VMode {
+    // GPUの水晶はビデオ方式ごとに異なる
+    fn gpu_clock(self) -> u64 {
+        match self {
+            VMode::Ntsc => 53_693_175,
+            VMode::Pal => 53_203_425,
+        }
+    }
+
+    // 1 CPUサイクルあたりのGPUクロック数 (16.16固定小数点)
+    fn clocks_per_cpu_cycle(self) -> u32 {
+        ((self.gpu_clock() << 16) / CPU_CLOCK) as u32
+    }
+
+    fn cycles_per_line(self) -> u16 {
+        match self {
+            VMode::Ntsc => 3413,
+            VMode::Pal => 3406,
+        }
+    }
+
+    fn lines_per_frame(self) -> u16 {
+        match self {
+            VMode::Ntsc => 263,
+            VMode::Pal => 314,
+        }
+    }
+
+    pub fn visible_lines(self) -> u16 {
+        match self {
+            VMode::Ntsc => 240,
+            VMode::Pal => 288,
+        }
+    }
+
+    // 1秒あたりのフレーム数
+    pub fn refresh_rate(self) -> f64 {
+        self.gpu_clock() as f64 / (self.cycles_per_line() as f64 * self.lines_per_frame() as f64)
+    }
+
+    // 1フレームで実行されるはずのCPUサイクル数 (NTSCで約564k)
+    pub fn cycles_per_frame(self) -> f64 {
+        CPU_CLOCK as f64 / self.refresh_rate()
+    }
+}
+
+// CPUサイクルで駆動するビデオタイミング
+// タイマーと割り込みに hblank / vblank / dotclock を供給する
+pub struct Timing {
+    // ライン内のGPUクロック
+    cycles: u16,
+    scanlines: u16,
+    // GPUクロックの端数 (16.16固定小数点)
+    clock_fraction: u32,
+    // 奇数フレーム (インターレース時のフィールド)
+    odd_frame: bool,
+
+    hblank: bool,
+    vblank: bool,
+    dotclock: bool,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing {
+            cycles: 0,
+            scanlines: 0,
+            clock_fraction: 0,
+            odd_frame: false,
+            hblank: false,
+            vblank: false,
+            dotclock: false,
+        }
+    }
+
+    // 1 CPUサイクル進める。新しいフレームが始まったら true
+    pub fn tick(&mut self, vmode: VMode, width: u16, dotclock_divider: u16) -> bool {
+        self.clock_fraction += vmode.clocks_per_cpu_cycle();
+        let clocks = (self.clock_fraction >> 16) as u16;
+        self.clock_fraction &= 0xFFFF;
+
+        let prev_dots = self.cycles / dotclock_divider;
+
+        self.cycles += clocks;
+
+        let mut new_frame = false;
+
+        if self.cycles >= vmode.cycles_per_line() {
+            self.cycles -= vmode.cycles_per_line();
+            self.scanlines += 1;
+
+            if self.scanlines >= vmode.lines_per_frame() {
+                self.scanlines = 0;
+                self.odd_frame = !self.odd_frame;
+                new_frame = true;
+            }
+        }
+
+        // 1 CPUサイクルで複数のGPUクロックが進むので、ドットの境界をまたいだかで判定する
+        self.dotclock = self.cycles / dotclock_divider != prev_dots || self.cycles < clocks;
+
+        // 表示幅をGPUクロックに換算して水平ブランクを判定する
+        self.hblank = self.cycles >= width * dotclock_divider;
+
+        // TODO: インターレース時の解像度を確認する
+        self.vblank = self.scanlines >= vmode.visible_lines();
+
+        new_frame
+    }
+
+    pub fn hblank(&self) -> bool {
+        self.hblank
+    }
+
+    pub fn vblank(&self) -> bool {
+        self.vblank
+    }
+
+    pub fn dotclock(&self) -> bool {
+        self.dotclock
+    }
+
+    // GPUSTAT bit31: 480ラインのインターレースではフィールド、それ以外はラインの偶奇
+    // vblank中は常に0
+    pub fn odd_line(&self, interlaced480: bool) -> bool {
+        if self.vblank {
+            return false;
+        }
+
+        if interlaced480 {
+            self.odd_frame
+        } else {
+            self.scanlines & 1 != 0
+        }
+    }
+}
+
+impl Savestate for Timing {
+    fn save_state(&self, w: &mut Writer) {
+        w.u16(self.cycles);
+        w.u16(self.scanlines);
+        w.u32(self.clock_fraction);
+        w.bool(self.odd_frame);
+        w.bool(self.hblank);
+        w.bool(self.vblank);
+        w.bool(self.dotclock);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.cycles = r.u16()?;
+        self.scanlines = r.u16()?;
+        self.clock_fraction = r.u32()?;
+        self.odd_frame = r.bool()?;
+        self.hblank = r.bool()?;
+        self.vblank = r.bool()?;
+        self.dotclock = r.bool()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 320x240 のドットクロック
+    const WIDTH: u16 = 320;
+    const DIVIDER: u16 = 8;
+
+    // 1フレーム分進めてCPUサイクル数と各信号の立ち上がり回数を数える
+    fn run_frame(timing: &mut Timing, vmode: VMode) -> (u32, u32, u32, u32) {
+        let (mut cycles, mut hblanks, mut vblanks, mut dots) = (0, 0, 0, 0);
+
+        loop {
+            let (hblank, vblank) = (timing.hblank(), timing.vblank());
+            let new_frame = timing.tick(vmode, WIDTH, DIVIDER);
+            cycles += 1;
+
+            hblanks += (timing.hblank() && !hblank) as u32;
+            vblanks += (timing.vblank() && !vblank) as u32;
+            dots += timing.dotclock() as u32;
+
+            if new_frame {
+                return (cycles, hblanks, vblanks, dots);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_timing_matches_video_mode() {
+        for vmode in [VMode::Ntsc, VMode::Pal] {
+            let mut timing = Timing::new();
+            run_frame(&mut timing, vmode);
+
+            let (cycles, hblanks, vblanks, dots) = run_frame(&mut timing, vmode);
+
+            let expected = vmode.cycles_per_frame();
+            // 固定小数点の端数の分だけずれる
+            assert!((cycles as f64 / expected - 1.0).abs() < 1e-4, "{}", cycles);
+
+            assert_eq!(hblanks, vmode.lines_per_frame() as u32);
+            assert_eq!(vblanks, 1);
+
+            let clocks = vmode.cycles_per_line() as u32 * vmode.lines_per_frame() as u32;
+            let expected_dots = clocks / DIVIDER as u32;
+            assert!(dots.abs_diff(expected_dots) <= vmode.lines_per_frame() as u32);
+        }
+    }
+
+    #[test]
+    fn refresh_rates() {
+        assert!((VMode::Ntsc.refresh_rate() - 59.82).abs() < 0.01);
+        assert!((VMode::Pal.refresh_rate() - 49.75).abs() < 0.01);
+    }
+
+    #[test]
+    fn odd_line_is_cleared_during_vblank() {
+        let mut timing = Timing::new();
+
+        while !timing.vblank() {
+            timing.tick(VMode::Ntsc, WIDTH, DIVIDER);
+        }
+
+        assert!(!timing.odd_line(false));
+        assert!(!timing.odd_line(true));
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::Result;
+
+use crate::savestate::{Reader, Savestate, Writer};
+
+pub const VRAM_WIDTH: u16 = 1024;
+pub const VRAM_HEIGHT: u16 = 512;
+
+// 1024x512 16bit
+pub struct Vram {
+    data: Vec<u16>,
+}
+
+impl Vram {
+    pub fn new() -> Vram {
+        let data = vec![0; VRAM_WIDTH as usize * VRAM_HEIGHT as usize];
+
+        Vram { data }
+    }
+
+    // 座標は VRAM の端で折り返す
+    fn index(x: u16, y: u16) -> usize {
+        let x = (x % VRAM_WIDTH) as usize;
+        let y = (y % VRAM_HEIGHT) as usize;
+
+        y * VRAM_WIDTH as usize + x
+    }
+
+    pub fn read(&self, x: u16, y: u16) -> u16 {
+        self.data[Vram::index(x, y)]
+    }
+
+    pub fn write(&mut self, x: u16, y: u16, val: u16) {
+        self.data[Vram::index(x, y)] = val;
+    }
+
+    pub fn data(&self) -> &[u16] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u16] {
+        &mut self.data
+    }
+}
+
+impl Default for Vram {
+    fn default() -> Self {
+        Vram::new()
+    }
+}
+
+impl Savestate for Vram {
+    fn save_state(&self, w: &mut Writer) {
+        for pixel in &self.data {
+            w.u16(*pixel);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        for pixel in &mut self.data {
+            *pixel = r.u16()?;
+        }
+
+        Ok(())
+    }
+}
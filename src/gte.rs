@@ -1,12 +1,18 @@
-use std::collections::VecDeque;
+use std::fmt;
 
-use log::warn;
+use anyhow::Result;
+use log::debug;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use vectrix::{Matrix, Vector};
 
-use crate::{addressible::Addressible, cpu::RegisterIndex};
+use crate::{
+    addressible::Addressible,
+    cpu::RegisterIndex,
+    savestate::{Reader, Savestate, Writer},
+};
 
+#[derive(Clone, Copy)]
 struct GteInstruction(u32);
 
 #[derive(Debug, FromPrimitive)]
@@ -71,6 +77,60 @@ impl GteInstruction {
     }
 }
 
+// FLAG register bits
+mod flag {
+    pub const MAC1_POSITIVE: u32 = 1 << 30;
+    pub const MAC2_POSITIVE: u32 = 1 << 29;
+    pub const MAC3_POSITIVE: u32 = 1 << 28;
+    pub const MAC1_NEGATIVE: u32 = 1 << 27;
+    pub const MAC2_NEGATIVE: u32 = 1 << 26;
+    pub const MAC3_NEGATIVE: u32 = 1 << 25;
+    pub const IR1_SATURATED: u32 = 1 << 24;
+    pub const IR2_SATURATED: u32 = 1 << 23;
+    pub const IR3_SATURATED: u32 = 1 << 22;
+    pub const SZ3_OTZ_SATURATED: u32 = 1 << 18;
+    pub const DIVIDE_OVERFLOW: u32 = 1 << 17;
+    pub const MAC0_POSITIVE: u32 = 1 << 16;
+    pub const MAC0_NEGATIVE: u32 = 1 << 15;
+    pub const SX2_SATURATED: u32 = 1 << 14;
+    pub const SY2_SATURATED: u32 = 1 << 13;
+    pub const IR0_SATURATED: u32 = 1 << 12;
+
+    pub const WRITABLE: u32 = 0x7FFFF000;
+    pub const ERROR: u32 = 0x7F87E000;
+    pub const CHECKSUM: u32 = 1 << 31;
+}
+
+pub const DATA_REGISTER_NAMES: [&str; 32] = [
+    "vxy0", "vz0", "vxy1", "vz1", "vxy2", "vz2", "rgbc", "otz", //
+    "ir0", "ir1", "ir2", "ir3", "sxy0", "sxy1", "sxy2", "sxyp", //
+    "sz0", "sz1", "sz2", "sz3", "rgb0", "rgb1", "rgb2", "res1", //
+    "mac0", "mac1", "mac2", "mac3", "irgb", "orgb", "lzcs", "lzcr",
+];
+
+pub const CONTROL_REGISTER_NAMES: [&str; 32] = [
+    "rt11rt12", "rt13rt21", "rt22rt23", "rt31rt32", "rt33", "trx", "try", "trz", //
+    "l11l12", "l13l21", "l22l23", "l31l32", "l33", "rbk", "gbk", "bbk", //
+    "lr1lr2", "lr3lg1", "lg2lg3", "lb1lb2", "lb3", "rfc", "gfc", "bfc", //
+    "ofx", "ofy", "h", "dqa", "dqb", "zsf3", "zsf4", "flag",
+];
+
+// 割り算テーブル (UNR)
+const UNR_TABLE: [u8; 0x101] = unr_table();
+
+const fn unr_table() -> [u8; 0x101] {
+    let mut table = [0; 0x101];
+    let mut i = 0;
+
+    while i < table.len() {
+        let v = (0x40000 / (i as i32 + 0x100) + 1) / 2 - 0x101;
+        table[i] = if v > 0 { v as u8 } else { 0 };
+        i += 1;
+    }
+
+    table
+}
+
 pub struct Gte {
     v0: Vector<i16, 3>,
     v1: Vector<i16, 3>,
@@ -84,30 +144,29 @@ pub struct Gte {
     ir2: i16,
     ir3: i16,
 
-    sxy: VecDeque<(i16, i16)>,
-    sz: VecDeque<(i16, i16)>,
-    rgb: VecDeque<(u8, u8, u8, u8)>,
+    sxy: [(i16, i16); 3],
+    sz: [u16; 4],
+    rgb: [(u8, u8, u8, u8); 3],
+    res1: u32,
 
     mac0: i32,
     mac1: i32,
     mac2: i32,
     mac3: i32,
 
-    irgb: u16,
-    orgb: u16,
-    lzcs: i32,
-    lzcr: i32,
+    lzcs: u32,
+    lzcr: u32,
 
     rotation: Matrix<i16, 3, 3>,
     translation: Vector<i32, 3>,
     light_source: Matrix<i16, 3, 3>,
-    background_color: (u32, u32, u32),
-    light_color_source: Matrix<i32, 3, 3>,
-    far_color: (u32, u32, u32),
+    background_color: Vector<i32, 3>,
+    light_color_source: Matrix<i16, 3, 3>,
+    far_color: Vector<i32, 3>,
     offset: (i32, i32),
     projection_distance: u16,
     depth_coeff: i16,
-    depth_offset: u32,
+    depth_offset: i32,
     average_z_scale_3: i16,
     average_z_scale_4: i16,
     flag: u32,
@@ -125,23 +184,22 @@ impl Gte {
             ir1: 0,
             ir2: 0,
             ir3: 0,
-            sxy: VecDeque::new(),
-            sz: VecDeque::new(),
-            rgb: VecDeque::new(),
+            sxy: [(0, 0); 3],
+            sz: [0; 4],
+            rgb: [(0, 0, 0, 0); 3],
+            res1: 0,
             mac0: 0,
             mac1: 0,
             mac2: 0,
             mac3: 0,
-            irgb: 0,
-            orgb: 0,
             lzcs: 0,
-            lzcr: 0,
+            lzcr: 32,
             rotation: Matrix::identity(),
             translation: Vector::zero(),
             light_source: Matrix::identity(),
-            background_color: (0, 0, 0),
+            background_color: Vector::zero(),
             light_color_source: Matrix::identity(),
-            far_color: (0, 0, 0),
+            far_color: Vector::zero(),
             offset: (0, 0),
             projection_distance: 0,
             depth_coeff: 0,
@@ -153,63 +211,736 @@ impl Gte {
     }
 
     pub fn load_data<T: Addressible>(&self, offset: RegisterIndex) -> T {
+        let v = match offset.0 {
+            0 => pack_i16(self.v0[0], self.v0[1]),
+            1 => self.v0[2] as u32,
+            2 => pack_i16(self.v1[0], self.v1[1]),
+            3 => self.v1[2] as u32,
+            4 => pack_i16(self.v2[0], self.v2[1]),
+            5 => self.v2[2] as u32,
+            6 => pack_rgbc(self.color),
+            7 => self.otz as u32,
+            8 => self.ir0 as u32,
+            9 => self.ir1 as u32,
+            10 => self.ir2 as u32,
+            11 => self.ir3 as u32,
+            12 => pack_i16(self.sxy[0].0, self.sxy[0].1),
+            13 => pack_i16(self.sxy[1].0, self.sxy[1].1),
+            14 | 15 => pack_i16(self.sxy[2].0, self.sxy[2].1),
+            16 => self.sz[0] as u32,
+            17 => self.sz[1] as u32,
+            18 => self.sz[2] as u32,
+            19 => self.sz[3] as u32,
+            20 => pack_rgbc(self.rgb[0]),
+            21 => pack_rgbc(self.rgb[1]),
+            22 => pack_rgbc(self.rgb[2]),
+            23 => self.res1,
+            24 => self.mac0 as u32,
+            25 => self.mac1 as u32,
+            26 => self.mac2 as u32,
+            27 => self.mac3 as u32,
+            28 | 29 => self.orgb(),
+            30 => self.lzcs,
+            31 => self.lzcr,
+            _ => unreachable!(),
+        };
+
+        Addressible::from_u32(v)
+    }
+
+    pub fn store_data<T: Addressible>(&mut self, offset: RegisterIndex, val: T) {
+        let val = val.as_u32();
+
         match offset.0 {
-            24 => Addressible::from_u32(self.mac0 as u32),
-            25 => Addressible::from_u32(self.mac1 as u32),
-            26 => Addressible::from_u32(self.mac2 as u32),
-            27 => Addressible::from_u32(self.mac3 as u32),
-            28 => Addressible::from_u32(self.irgb as u32),
-            29 => Addressible::from_u32(self.orgb as u32),
-            30 => Addressible::from_u32(self.lzcs as u32),
-            31 => Addressible::from_u32(self.lzcr as u32),
-            _ => panic!("unhandled GTE DATA load offset: {:04x}", offset.0,),
+            0 => (self.v0[0], self.v0[1]) = unpack_i16(val),
+            1 => self.v0[2] = val as i16,
+            2 => (self.v1[0], self.v1[1]) = unpack_i16(val),
+            3 => self.v1[2] = val as i16,
+            4 => (self.v2[0], self.v2[1]) = unpack_i16(val),
+            5 => self.v2[2] = val as i16,
+            6 => self.color = unpack_rgbc(val),
+            7 => self.otz = val as u16,
+            8 => self.ir0 = val as i16,
+            9 => self.ir1 = val as i16,
+            10 => self.ir2 = val as i16,
+            11 => self.ir3 = val as i16,
+            12 => self.sxy[0] = unpack_i16(val),
+            13 => self.sxy[1] = unpack_i16(val),
+            14 => self.sxy[2] = unpack_i16(val),
+            15 => {
+                let (x, y) = unpack_i16(val);
+                self.push_sxy(x, y);
+            }
+            16 => self.sz[0] = val as u16,
+            17 => self.sz[1] = val as u16,
+            18 => self.sz[2] = val as u16,
+            19 => self.sz[3] = val as u16,
+            20 => self.rgb[0] = unpack_rgbc(val),
+            21 => self.rgb[1] = unpack_rgbc(val),
+            22 => self.rgb[2] = unpack_rgbc(val),
+            23 => self.res1 = val,
+            24 => self.mac0 = val as i32,
+            25 => self.mac1 = val as i32,
+            26 => self.mac2 = val as i32,
+            27 => self.mac3 = val as i32,
+            28 => {
+                self.ir1 = ((val & 0x1F) << 7) as i16;
+                self.ir2 = (((val >> 5) & 0x1F) << 7) as i16;
+                self.ir3 = (((val >> 10) & 0x1F) << 7) as i16;
+            }
+            29 => {}
+            30 => {
+                self.lzcs = val;
+                self.lzcr = if (val as i32) < 0 {
+                    val.leading_ones()
+                } else {
+                    val.leading_zeros()
+                };
+            }
+            31 => {}
+            _ => unreachable!(),
         }
     }
 
-    pub fn store_data<T: Addressible>(&mut self, offset: RegisterIndex, val: T) {
+    pub fn load_control<T: Addressible>(&self, offset: RegisterIndex) -> T {
+        let v = match offset.0 {
+            0..=4 => load_matrix(&self.rotation, offset.0),
+            5..=7 => self.translation[offset.0 as usize - 5] as u32,
+            8..=12 => load_matrix(&self.light_source, offset.0 - 8),
+            13..=15 => self.background_color[offset.0 as usize - 13] as u32,
+            16..=20 => load_matrix(&self.light_color_source, offset.0 - 16),
+            21..=23 => self.far_color[offset.0 as usize - 21] as u32,
+            24 => self.offset.0 as u32,
+            25 => self.offset.1 as u32,
+            // ハードウェアのバグで符号拡張される
+            26 => self.projection_distance as i16 as u32,
+            27 => self.depth_coeff as u32,
+            28 => self.depth_offset as u32,
+            29 => self.average_z_scale_3 as u32,
+            30 => self.average_z_scale_4 as u32,
+            31 => self.flag,
+            _ => unreachable!(),
+        };
+
+        Addressible::from_u32(v)
+    }
+
+    pub fn store_control<T: Addressible>(&mut self, offset: RegisterIndex, val: T) {
+        let val = val.as_u32();
+
         match offset.0 {
-            24 => {
-                self.mac0 = val.as_u32() as i32;
+            0..=4 => store_matrix(&mut self.rotation, offset.0, val),
+            5..=7 => self.translation[offset.0 as usize - 5] = val as i32,
+            8..=12 => store_matrix(&mut self.light_source, offset.0 - 8, val),
+            13..=15 => self.background_color[offset.0 as usize - 13] = val as i32,
+            16..=20 => store_matrix(&mut self.light_color_source, offset.0 - 16, val),
+            21..=23 => self.far_color[offset.0 as usize - 21] = val as i32,
+            24 => self.offset.0 = val as i32,
+            25 => self.offset.1 = val as i32,
+            26 => self.projection_distance = val as u16,
+            27 => self.depth_coeff = val as i16,
+            28 => self.depth_offset = val as i32,
+            29 => self.average_z_scale_3 = val as i16,
+            30 => self.average_z_scale_4 = val as i16,
+            31 => {
+                self.flag = val & flag::WRITABLE;
+                self.update_flag_checksum();
             }
-            25 => {
-                self.mac1 = val.as_u32() as i32;
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn command(&mut self, command: u32) {
+        let instruction = GteInstruction(command);
+
+        self.flag = 0;
+
+        // sf: MACの結果を12bit右シフトする, lm: IRを0未満に飽和させない
+        let sf = instruction.op_sf();
+        let lm = instruction.op_saturate();
+
+        match instruction.op_command() {
+            0x01 => self.op_rtps(sf, lm),
+            0x30 => self.op_rtpt(sf, lm),
+            _ => panic!("unhandled GTE instruction {:04x}", command),
+        }
+
+        self.update_flag_checksum();
+    }
+
+    // RTPS perspective transformation (single)
+    fn op_rtps(&mut self, sf: bool, lm: bool) {
+        debug!("GTE RTPS");
+
+        self.rtp(self.v0, sf, lm, true);
+    }
+
+    // RTPT perspective transformation (triple)
+    fn op_rtpt(&mut self, sf: bool, lm: bool) {
+        debug!("GTE RTPT");
+
+        self.rtp(self.v0, sf, lm, false);
+        self.rtp(self.v1, sf, lm, false);
+        self.rtp(self.v2, sf, lm, true);
+    }
+
+    // デプスキュー (MAC0/IR0) は最後のベクトルでしか計算しない
+    fn rtp(&mut self, v: Vector<i16, 3>, sf: bool, lm: bool, depth_cue: bool) {
+        let shift = if sf { 12 } else { 0 };
+
+        for i in 0..3 {
+            let mut sum = self.check_mac(i + 1, (self.translation[i] as i64) << 12);
+
+            for j in 0..3 {
+                let product = self.rotation[(i, j)] as i64 * v[j] as i64;
+                sum = self.check_mac(i + 1, sum + product);
             }
-            26 => {
-                self.mac2 = val.as_u32() as i32;
+
+            self.set_mac(i + 1, sum >> shift);
+        }
+
+        self.ir1 = self.saturate_ir(1, self.mac1, lm);
+        self.ir2 = self.saturate_ir(2, self.mac2, lm);
+
+        // IR3のフラグはsfに関係なくMAC3 >> 12で判定される
+        let ir3_flag_source = (self.mac3 as i64) >> (12 - shift);
+        if !(-0x8000..=0x7FFF).contains(&ir3_flag_source) {
+            self.flag |= flag::IR3_SATURATED;
+        }
+        self.ir3 = self.mac3.clamp(if lm { 0 } else { -0x8000 }, 0x7FFF) as i16;
+
+        let z = (self.mac3 as i64) >> (12 - shift);
+        let sz = self.saturate_sz(z);
+        self.push_sz(sz);
+
+        let n = self.divide(self.projection_distance, self.sz[3]) as i64;
+
+        let x = self.check_mac0(n * self.ir1 as i64 + self.offset.0 as i64);
+        let y = self.check_mac0(n * self.ir2 as i64 + self.offset.1 as i64);
+
+        let sx = self.saturate_sxy(x >> 16, flag::SX2_SATURATED);
+        let sy = self.saturate_sxy(y >> 16, flag::SY2_SATURATED);
+        self.push_sxy(sx, sy);
+
+        if !depth_cue {
+            return;
+        }
+
+        let depth = self.check_mac0(n * self.depth_coeff as i64 + self.depth_offset as i64);
+        self.mac0 = depth as i32;
+        self.ir0 = self.saturate_ir0(depth >> 12);
+    }
+
+    // H / SZ3 をハードウェアと同じニュートン法で計算する
+    fn divide(&mut self, numerator: u16, denominator: u16) -> u32 {
+        if numerator as u32 >= denominator as u32 * 2 {
+            self.flag |= flag::DIVIDE_OVERFLOW;
+            return 0x1FFFF;
+        }
+
+        let shift = denominator.leading_zeros();
+
+        let n = (numerator as u64) << shift;
+        let d = (denominator as u64) << shift;
+
+        let u = UNR_TABLE[((d - 0x7FC0) >> 7) as usize] as u64 + 0x101;
+        let d = (0x2000080 - d * u) >> 8;
+        let d = (0x0000080 + d * u) >> 8;
+
+        (((n * d) + 0x8000) >> 16).min(0x1FFFF) as u32
+    }
+
+    fn check_mac(&mut self, index: usize, val: i64) -> i64 {
+        let (positive, negative) = match index {
+            1 => (flag::MAC1_POSITIVE, flag::MAC1_NEGATIVE),
+            2 => (flag::MAC2_POSITIVE, flag::MAC2_NEGATIVE),
+            3 => (flag::MAC3_POSITIVE, flag::MAC3_NEGATIVE),
+            _ => unreachable!(),
+        };
+
+        if val >= 1 << 43 {
+            self.flag |= positive;
+        } else if val < -(1 << 43) {
+            self.flag |= negative;
+        }
+
+        // 44bitで符号拡張
+        (val << 20) >> 20
+    }
+
+    fn set_mac(&mut self, index: usize, val: i64) {
+        match index {
+            1 => self.mac1 = val as i32,
+            2 => self.mac2 = val as i32,
+            3 => self.mac3 = val as i32,
+            _ => unreachable!(),
+        }
+    }
+
+    fn check_mac0(&mut self, val: i64) -> i64 {
+        if val > i32::MAX as i64 {
+            self.flag |= flag::MAC0_POSITIVE;
+        } else if val < i32::MIN as i64 {
+            self.flag |= flag::MAC0_NEGATIVE;
+        }
+
+        val
+    }
+
+    fn saturate_ir(&mut self, index: usize, val: i32, lm: bool) -> i16 {
+        let min = if lm { 0 } else { -0x8000 };
+
+        if val < min || val > 0x7FFF {
+            self.flag |= match index {
+                1 => flag::IR1_SATURATED,
+                2 => flag::IR2_SATURATED,
+                3 => flag::IR3_SATURATED,
+                _ => unreachable!(),
+            };
+        }
+
+        val.clamp(min, 0x7FFF) as i16
+    }
+
+    fn saturate_ir0(&mut self, val: i64) -> i16 {
+        if !(0..=0x1000).contains(&val) {
+            self.flag |= flag::IR0_SATURATED;
+        }
+
+        val.clamp(0, 0x1000) as i16
+    }
+
+    fn saturate_sz(&mut self, val: i64) -> u16 {
+        if !(0..=0xFFFF).contains(&val) {
+            self.flag |= flag::SZ3_OTZ_SATURATED;
+        }
+
+        val.clamp(0, 0xFFFF) as u16
+    }
+
+    fn saturate_sxy(&mut self, val: i64, bit: u32) -> i16 {
+        if !(-0x400..=0x3FF).contains(&val) {
+            self.flag |= bit;
+        }
+
+        val.clamp(-0x400, 0x3FF) as i16
+    }
+
+    fn push_sxy(&mut self, x: i16, y: i16) {
+        self.sxy[0] = self.sxy[1];
+        self.sxy[1] = self.sxy[2];
+        self.sxy[2] = (x, y);
+    }
+
+    fn push_sz(&mut self, z: u16) {
+        self.sz[0] = self.sz[1];
+        self.sz[1] = self.sz[2];
+        self.sz[2] = self.sz[3];
+        self.sz[3] = z;
+    }
+
+    fn orgb(&self) -> u32 {
+        let component = |ir: i16| ((ir >> 7).clamp(0, 0x1F)) as u32;
+
+        component(self.ir1) | (component(self.ir2) << 5) | (component(self.ir3) << 10)
+    }
+
+    fn update_flag_checksum(&mut self) {
+        self.flag &= !flag::CHECKSUM;
+
+        if self.flag & flag::ERROR != 0 {
+            self.flag |= flag::CHECKSUM;
+        }
+    }
+}
+
+impl Savestate for Gte {
+    fn save_state(&self, w: &mut Writer) {
+        for v in [&self.v0, &self.v1, &self.v2] {
+            for i in 0..3 {
+                w.u16(v[i] as u16);
             }
-            27 => {
-                self.mac3 = val.as_u32() as i32;
+        }
+        w.u32(pack_rgbc(self.color));
+        w.u16(self.otz);
+        for ir in [self.ir0, self.ir1, self.ir2, self.ir3] {
+            w.u16(ir as u16);
+        }
+        for sxy in &self.sxy {
+            w.u32(pack_i16(sxy.0, sxy.1));
+        }
+        for sz in &self.sz {
+            w.u16(*sz);
+        }
+        for rgb in &self.rgb {
+            w.u32(pack_rgbc(*rgb));
+        }
+        w.u32(self.res1);
+        for mac in [self.mac0, self.mac1, self.mac2, self.mac3] {
+            w.u32(mac as u32);
+        }
+        w.u32(self.lzcs);
+        w.u32(self.lzcr);
+
+        for m in [&self.rotation, &self.light_source, &self.light_color_source] {
+            for i in 0..5 {
+                w.u32(load_matrix(m, i));
             }
-            28 => {
-                self.irgb = val.as_u32() as u16;
+        }
+        for v in [&self.translation, &self.background_color, &self.far_color] {
+            for i in 0..3 {
+                w.u32(v[i] as u32);
             }
-            29 => {
-                self.orgb = val.as_u32() as u16;
+        }
+        w.u32(self.offset.0 as u32);
+        w.u32(self.offset.1 as u32);
+        w.u16(self.projection_distance);
+        w.u16(self.depth_coeff as u16);
+        w.u32(self.depth_offset as u32);
+        w.u16(self.average_z_scale_3 as u16);
+        w.u16(self.average_z_scale_4 as u16);
+        w.u32(self.flag);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        for v in [&mut self.v0, &mut self.v1, &mut self.v2] {
+            for i in 0..3 {
+                v[i] = r.u16()? as i16;
             }
-            30 => {
-                self.lzcs = val.as_u32() as i32;
+        }
+        self.color = unpack_rgbc(r.u32()?);
+        self.otz = r.u16()?;
+        for ir in [&mut self.ir0, &mut self.ir1, &mut self.ir2, &mut self.ir3] {
+            *ir = r.u16()? as i16;
+        }
+        for sxy in &mut self.sxy {
+            *sxy = unpack_i16(r.u32()?);
+        }
+        for sz in &mut self.sz {
+            *sz = r.u16()?;
+        }
+        for rgb in &mut self.rgb {
+            *rgb = unpack_rgbc(r.u32()?);
+        }
+        self.res1 = r.u32()?;
+        for mac in [
+            &mut self.mac0,
+            &mut self.mac1,
+            &mut self.mac2,
+            &mut self.mac3,
+        ] {
+            *mac = r.u32()? as i32;
+        }
+        self.lzcs = r.u32()?;
+        self.lzcr = r.u32()?;
+
+        for m in [
+            &mut self.rotation,
+            &mut self.light_source,
+            &mut self.light_color_source,
+        ] {
+            for i in 0..5 {
+                store_matrix(m, i, r.u32()?);
             }
-            31 => {
-                self.lzcr = val.as_u32() as i32;
+        }
+        for v in [
+            &mut self.translation,
+            &mut self.background_color,
+            &mut self.far_color,
+        ] {
+            for i in 0..3 {
+                v[i] = r.u32()? as i32;
             }
-            _ => panic!(
-                "unhandled GTE DATA store offset: {:04x} val: {:04x}",
-                offset.0,
-                val.as_u32()
-            ),
         }
+        self.offset = (r.u32()? as i32, r.u32()? as i32);
+        self.projection_distance = r.u16()?;
+        self.depth_coeff = r.u16()? as i16;
+        self.depth_offset = r.u32()? as i32;
+        self.average_z_scale_3 = r.u16()? as i16;
+        self.average_z_scale_4 = r.u16()? as i16;
+        self.flag = r.u32()?;
+
+        Ok(())
     }
+}
 
-    pub fn load_control<T: Addressible>(&self, offset: RegisterIndex) -> T {
-        warn!("unhandled GTE CONTROL load offset: {:04x}", offset.0);
-        Addressible::from_u32(0)
+// デバッガ向けの表示 (行列は1.3.12固定小数点)
+impl fmt::Display for Gte {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fixed = |val: i16| val as f32 / 4096.0;
+
+        for (name, m) in [
+            ("RT ", &self.rotation),
+            ("LLM", &self.light_source),
+            ("LCM", &self.light_color_source),
+        ] {
+            for i in 0..3 {
+                let label = if i == 0 { name } else { "   " };
+                writeln!(
+                    f,
+                    "{} [{:9.4} {:9.4} {:9.4}]  ({:04x} {:04x} {:04x})",
+                    label,
+                    fixed(m[(i, 0)]),
+                    fixed(m[(i, 1)]),
+                    fixed(m[(i, 2)]),
+                    m[(i, 0)] as u16,
+                    m[(i, 1)] as u16,
+                    m[(i, 2)] as u16,
+                )?;
+            }
+        }
+
+        for (name, v) in [
+            ("TR ", &self.translation),
+            ("BK ", &self.background_color),
+            ("FC ", &self.far_color),
+        ] {
+            writeln!(f, "{} ({}, {}, {})", name, v[0], v[1], v[2])?;
+        }
+
+        for (i, v) in [&self.v0, &self.v1, &self.v2].iter().enumerate() {
+            writeln!(f, "V{}  ({}, {}, {})", i, v[0], v[1], v[2])?;
+        }
+
+        writeln!(
+            f,
+            "OF ({}, {}) H {} DQA {} DQB {} ZSF3 {} ZSF4 {}",
+            self.offset.0 as f32 / 65536.0,
+            self.offset.1 as f32 / 65536.0,
+            self.projection_distance,
+            self.depth_coeff,
+            self.depth_offset,
+            self.average_z_scale_3,
+            self.average_z_scale_4,
+        )?;
+
+        // FIFO は古い順
+        writeln!(
+            f,
+            "SXY [({}, {}) ({}, {}) ({}, {})]",
+            self.sxy[0].0,
+            self.sxy[0].1,
+            self.sxy[1].0,
+            self.sxy[1].1,
+            self.sxy[2].0,
+            self.sxy[2].1,
+        )?;
+        writeln!(
+            f,
+            "SZ  [{} {} {} {}]",
+            self.sz[0], self.sz[1], self.sz[2], self.sz[3]
+        )?;
+        writeln!(
+            f,
+            "RGB [{:08x} {:08x} {:08x}] RGBC {:08x}",
+            pack_rgbc(self.rgb[0]),
+            pack_rgbc(self.rgb[1]),
+            pack_rgbc(self.rgb[2]),
+            pack_rgbc(self.color),
+        )?;
+
+        writeln!(
+            f,
+            "MAC [{} {} {} {}]",
+            self.mac0, self.mac1, self.mac2, self.mac3
+        )?;
+        writeln!(
+            f,
+            "IR  [{} {} {} {}] OTZ {}",
+            self.ir0, self.ir1, self.ir2, self.ir3, self.otz
+        )?;
+        write!(f, "FLAG {:08x}", self.flag)
     }
+}
 
-    pub fn store_control<T: Addressible>(&mut self, offset: RegisterIndex, val: T) {}
+fn pack_i16(lo: i16, hi: i16) -> u32 {
+    (lo as u16 as u32) | ((hi as u16 as u32) << 16)
+}
 
-    pub fn command(&mut self, command: u32) {
-        match command {
-            _ => panic!("unhandled GTE instruction {:04x}", command),
+fn unpack_i16(val: u32) -> (i16, i16) {
+    (val as i16, (val >> 16) as i16)
+}
+
+fn pack_rgbc(c: (u8, u8, u8, u8)) -> u32 {
+    (c.0 as u32) | ((c.1 as u32) << 8) | ((c.2 as u32) << 16) | ((c.3 as u32) << 24)
+}
+
+fn unpack_rgbc(val: u32) -> (u8, u8, u8, u8) {
+    (
+        val as u8,
+        (val >> 8) as u8,
+        (val >> 16) as u8,
+        (val >> 24) as u8,
+    )
+}
+
+// 3x3行列は5ワードに詰め込まれている (11,12 / 13,21 / 22,23 / 31,32 / 33)
+fn load_matrix(m: &Matrix<i16, 3, 3>, index: u32) -> u32 {
+    match index {
+        0 => pack_i16(m[(0, 0)], m[(0, 1)]),
+        1 => pack_i16(m[(0, 2)], m[(1, 0)]),
+        2 => pack_i16(m[(1, 1)], m[(1, 2)]),
+        3 => pack_i16(m[(2, 0)], m[(2, 1)]),
+        4 => m[(2, 2)] as u32,
+        _ => unreachable!(),
+    }
+}
+
+fn store_matrix(m: &mut Matrix<i16, 3, 3>, index: u32, val: u32) {
+    let (lo, hi) = unpack_i16(val);
+
+    match index {
+        0 => (m[(0, 0)], m[(0, 1)]) = (lo, hi),
+        1 => (m[(0, 2)], m[(1, 0)]) = (lo, hi),
+        2 => (m[(1, 1)], m[(1, 2)]) = (lo, hi),
+        3 => (m[(2, 0)], m[(2, 1)]) = (lo, hi),
+        4 => m[(2, 2)] = lo,
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtps(sf: bool, lm: bool) -> u32 {
+        0x01 | (sf as u32) << 19 | (lm as u32) << 10
+    }
+
+    fn rtpt(sf: bool, lm: bool) -> u32 {
+        0x30 | (sf as u32) << 19 | (lm as u32) << 10
+    }
+
+    // 回転行列を1.0 (0x1000) の単位行列にする
+    fn gte_with_vector(x: i16, y: i16, z: i16) -> Gte {
+        let mut gte = Gte::new();
+
+        gte.rotation = Matrix::zero();
+        for i in 0..3 {
+            gte.rotation[(i, i)] = 0x1000;
+        }
+        gte.v0 = Vector::from([x, y, z]);
+        gte.projection_distance = 0x100;
+
+        gte
+    }
+
+    #[test]
+    fn sf_shifts_mac() {
+        let mut gte = gte_with_vector(0x100, 0x200, 0x300);
+        gte.command(rtps(true, false));
+
+        assert_eq!((gte.mac1, gte.mac2, gte.mac3), (0x100, 0x200, 0x300));
+        assert_eq!((gte.ir1, gte.ir2, gte.ir3), (0x100, 0x200, 0x300));
+        assert_eq!(gte.flag & flag::ERROR, 0);
+
+        let mut gte = gte_with_vector(0x100, 0x200, 0x300);
+        gte.command(rtps(false, false));
+
+        assert_eq!(
+            (gte.mac1, gte.mac2, gte.mac3),
+            (0x100000, 0x200000, 0x300000)
+        );
+        assert_eq!((gte.ir1, gte.ir2, gte.ir3), (0x7FFF, 0x7FFF, 0x7FFF));
+        assert_ne!(gte.flag & flag::IR1_SATURATED, 0);
+        assert_ne!(gte.flag & flag::IR2_SATURATED, 0);
+    }
+
+    #[test]
+    fn sz3_does_not_depend_on_sf() {
+        for sf in [false, true] {
+            let mut gte = gte_with_vector(0, 0, 0x300);
+            gte.command(rtps(sf, false));
+
+            assert_eq!(gte.sz[3], 0x300);
+        }
+    }
+
+    #[test]
+    fn ir3_flag_uses_unshifted_mac3() {
+        // sf=0ではIR3は飽和するが、フラグはMAC3 >> 12 で判定されるので立たない
+        let mut gte = gte_with_vector(0, 0, 0x300);
+        gte.command(rtps(false, false));
+
+        assert_eq!(gte.ir3, 0x7FFF);
+        assert_eq!(gte.flag & flag::IR3_SATURATED, 0);
+    }
+
+    #[test]
+    fn lm_clamps_ir_to_zero() {
+        let mut gte = gte_with_vector(-0x100, -0x200, 0x300);
+        gte.command(rtps(true, false));
+
+        assert_eq!((gte.ir1, gte.ir2), (-0x100, -0x200));
+        assert_eq!(gte.flag & flag::ERROR, 0);
+
+        let mut gte = gte_with_vector(-0x100, -0x200, 0x300);
+        gte.command(rtps(true, true));
+
+        assert_eq!((gte.mac1, gte.mac2), (-0x100, -0x200));
+        assert_eq!((gte.ir1, gte.ir2), (0, 0));
+        assert_ne!(gte.flag & flag::IR1_SATURATED, 0);
+        assert_ne!(gte.flag & flag::IR2_SATURATED, 0);
+    }
+
+    #[test]
+    fn rtpt_applies_sf_and_lm_to_every_vector() {
+        let mut gte = gte_with_vector(-0x100, 0x100, 0x100);
+        gte.v1 = Vector::from([-0x200, 0x200, 0x200]);
+        gte.v2 = Vector::from([-0x300, 0x300, 0x300]);
+        gte.command(rtpt(true, true));
+
+        assert_eq!((gte.mac1, gte.ir1), (-0x300, 0));
+        assert_eq!(gte.sz[1..], [0x100, 0x200, 0x300]);
+        assert_ne!(gte.flag & flag::IR1_SATURATED, 0);
+    }
+
+    #[test]
+    fn rtpt_depth_cues_only_the_last_vector() {
+        // v0 は H / SZ3 が大きく MAC0 と IR0 があふれるが、v2 はあふれない
+        let mut gte = gte_with_vector(0, 0, 0x81);
+        gte.depth_coeff = 0x7FFF;
+        gte.command(rtps(true, false));
+
+        assert_ne!(gte.flag & flag::MAC0_POSITIVE, 0);
+        assert_ne!(gte.flag & flag::IR0_SATURATED, 0);
+
+        let mut gte = gte_with_vector(0, 0, 0x81);
+        gte.v1 = Vector::from([0, 0, 0x7FFF]);
+        gte.v2 = Vector::from([0, 0, 0x7FFF]);
+        gte.depth_coeff = 0x7FFF;
+        gte.command(rtpt(true, false));
+
+        let mask = flag::MAC0_POSITIVE | flag::MAC0_NEGATIVE | flag::IR0_SATURATED;
+        assert_eq!(gte.flag & mask, 0);
+        assert_eq!(gte.ir0, 0xFFF);
+    }
+
+    #[test]
+    fn divide_follows_unr_results() {
+        let mut gte = Gte::new();
+
+        // 結果は 1.16 の固定小数点
+        assert_eq!(gte.divide(1, 1), 0x10000);
+        assert_eq!(gte.divide(0x4000, 0x8000), 0x8000);
+        assert_eq!(gte.divide(0x100, 0x300), 0x5555);
+        assert_eq!(gte.divide(0x7FFF, 0xFFFF), 0x7FFF);
+        // 商が2に届かなければあふれない
+        assert_eq!(gte.divide(0x1FF, 0x100), 0x1FF00);
+        assert_eq!(gte.divide(0xFFFF, 0x8000), 0x1FFFE);
+        // UNR の近似は真の値 (0xE104.2B..) より1大きくなる
+        assert_eq!(gte.divide(0x1000, 0x1234), 0xE105);
+        assert_eq!(gte.flag & flag::DIVIDE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn divide_overflow_saturates() {
+        // H >= SZ3 * 2 ならフラグを立てて 0x1FFFF
+        for (h, sz3) in [(0x200, 0x100), (0xFFFF, 0x7FFF), (0, 0), (0x100, 0)] {
+            let mut gte = Gte::new();
+
+            assert_eq!(gte.divide(h, sz3), 0x1FFFF, "{:x} / {:x}", h, sz3);
+            assert_ne!(gte.flag & flag::DIVIDE_OVERFLOW, 0);
         }
     }
 }
@@ -1,43 +1,116 @@
+use anyhow::Result;
 use log::{debug, trace, warn};
 
 use crate::{
     addressible::{AccessWidth, Addressible},
     bios::Bios,
     cdrom::CdRom,
+    config::{Accuracy, MachineConfig},
     dma::{Direction, Dma, Port, Step, Sync},
     gpu::gpu::Gpu,
-    interrupts::{Interrupts, Irq},
+    interrupts::{Interrupts, IrqBus},
     joypad::Joypad,
     ram::Ram,
+    savestate::{Reader, Savestate, Writer},
     scratchpad::ScratchPad,
+    spu::Spu,
     timer::Timer,
 };
 
 pub struct Interconnect {
+    pub accuracy: Accuracy,
     pub bios: Bios,
     scratchpad: ScratchPad,
     ram: Ram,
     dma: Dma,
     gpu: Gpu,
     cdrom: CdRom,
+    spu: Spu,
     joypad: Joypad,
     timers: [Timer; 3],
     pub interrupts: Interrupts,
+    // 各デバイスが割り込みを上げる先
+    irqs: IrqBus,
 }
 
 impl Interconnect {
-    pub fn new(bios: Bios, gpu: Gpu, rom: Option<Vec<u8>>) -> Interconnect {
+    pub fn new(config: MachineConfig, mut gpu: Gpu) -> Interconnect {
+        gpu.set_vmode(config.region.video_mode());
+
+        let mut cdrom = CdRom::new(config.disc);
+        cdrom.set_verify_sectors(config.verify_sectors);
+
         Interconnect {
-            bios,
-            scratchpad: ScratchPad::new(),
-            ram: Ram::new(),
+            accuracy: config.accuracy,
+            bios: config.bios,
+            scratchpad: ScratchPad::new(config.power_on),
+            ram: Ram::new(config.ram_size, config.power_on),
             dma: Dma::new(),
             gpu,
-            cdrom: CdRom::new(rom),
-            joypad: Joypad::new(),
+            cdrom,
+            spu: Spu::new(),
+            joypad: Joypad::new(config.devices, config.memory_cards),
             timers: [Timer::new(0), Timer::new(1), Timer::new(2)],
             interrupts: Interrupts::new(),
+            irqs: IrqBus::new(),
+        }
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    pub fn gpu_mut(&mut self) -> &mut Gpu {
+        &mut self.gpu
+    }
+
+    // 起動してから実行したCPUサイクル数
+    pub fn cycles(&self) -> u64 {
+        self.irqs.cycle()
+    }
+
+    pub fn ram_size(&self) -> u32 {
+        self.ram.size()
+    }
+
+    pub fn ram_checksum(&self) -> u32 {
+        self.ram.checksum()
+    }
+
+    pub fn cdrom(&self) -> &CdRom {
+        &self.cdrom
+    }
+
+    pub fn cdrom_mut(&mut self) -> &mut CdRom {
+        &mut self.cdrom
+    }
+
+    // SPU が出力したサンプル (44.1kHz のステレオ)。CD の音声も SPU で混ぜてある
+    pub fn drain_audio(&mut self) -> impl Iterator<Item = [i16; 2]> + '_ {
+        self.spu.drain_audio()
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    // 副作用なしに読めるメモリ (RAMとスクラッチパッド) だけを読む
+    pub fn peek<T: Addressible>(&self, abs_addr: u32) -> Option<T> {
+        let addr = map::mask_region(abs_addr);
+
+        if let Some(offset) = map::RAM.contains(addr) {
+            return Some(self.ram.load(offset));
+        }
+
+        if let Some(offset) = map::SCRATCHPAD.contains(addr) {
+            return Some(self.scratchpad.load(offset));
         }
+
+        None
     }
 
     pub fn load<T: Addressible>(&mut self, abs_addr: u32) -> T {
@@ -103,6 +176,10 @@ impl Interconnect {
             return self.cdrom.load(offset);
         }
 
+        if let Some(offset) = map::SPU.contains(addr) {
+            return self.spu.load(offset);
+        }
+
         if let Some(offset) = map::TIMER_0.contains(addr) {
             return self.timers[0].load(offset);
         }
@@ -134,7 +211,12 @@ impl Interconnect {
             return Addressible::from_u32(0);
         }
 
-        warn!("unhandled load{:?} at address {:08x}", T::width(), abs_addr);
+        warn!(
+            "unhandled load{:?} at address {:08x} ({})",
+            T::width(),
+            abs_addr,
+            map::describe(abs_addr)
+        );
         return Addressible::from_u32(0);
     }
 
@@ -206,8 +288,7 @@ impl Interconnect {
         }
 
         if let Some(offset) = map::SPU.contains(addr) {
-            warn!("SPU write {} {}", offset, val.as_u32());
-            return;
+            return self.spu.store(offset, val);
         }
 
         if let Some(offset) = map::JOYPAD.contains(addr) {
@@ -252,30 +333,31 @@ impl Interconnect {
         }
 
         warn!(
-            "unhandled store{:?} into address {:08x}",
+            "unhandled store{:?} into address {:08x} ({})",
             T::width(),
-            abs_addr
+            abs_addr,
+            map::describe(abs_addr)
         );
     }
 
     pub fn tick(&mut self) {
-        self.cdrom.tick();
-        self.gpu.tick();
+        self.cdrom.tick(&mut self.irqs);
+        let cdrom = &mut self.cdrom;
+        self.spu.tick(|| cdrom.pop_audio(), &mut self.irqs);
+        self.gpu.tick(&mut self.irqs);
         self.joypad.tick();
+        self.step_dma_linked_list();
+        self.step_dma_request();
+        self.step_dma_cdrom();
+        self.dma.tick(&mut self.irqs);
+
+        let (hblank, vblank, dotclock) =
+            (self.gpu.hblank(), self.gpu.vblank(), self.gpu.dotclock());
+        for timer in &mut self.timers {
+            timer.tick(hblank, vblank, dotclock, &mut self.irqs);
+        }
 
-        self.timers[0].tick(self.gpu.hblank, self.gpu.vblank, self.gpu.dotclock);
-        self.timers[1].tick(self.gpu.hblank, self.gpu.vblank, self.gpu.dotclock);
-        self.timers[2].tick(self.gpu.hblank, self.gpu.vblank, self.gpu.dotclock);
-
-        self.interrupts.set(Irq::VBlank, self.gpu.vblank);
-        self.interrupts.set(Irq::Gpu, self.gpu.interrupt);
-        self.interrupts.set(Irq::CdRom, self.cdrom.check_irq());
-        self.interrupts.set(Irq::Dma, self.dma.check_irq());
-        self.interrupts.set(Irq::Tmr0, !self.timers[0].n_irq);
-        self.interrupts.set(Irq::Tmr1, !self.timers[1].n_irq);
-        self.interrupts.set(Irq::Tmr2, !self.timers[2].n_irq);
-
-        self.interrupts.tick();
+        self.interrupts.tick(&mut self.irqs);
     }
 
     fn dma_reg<T: Addressible>(&self, offset: u32) -> T {
@@ -354,6 +436,10 @@ impl Interconnect {
     fn do_dma(&mut self, port: Port) {
         match self.dma.channel(port).sync() {
             Sync::LinkedList => self.do_dma_linked_list(port),
+            // GPUはDREQが立っている間だけ送るので tick で進める
+            Sync::Request if port == Port::Gpu => self.dma.channel_mut(port).start_request(),
+            // CD-ROMはデータFIFOにセクタがある間だけ送るので tick で進める
+            _ if port == Port::CdRom => self.dma.channel_mut(port).start_request(),
             _ => self.do_dma_block(port),
         }
     }
@@ -382,6 +468,7 @@ impl Interconnect {
 
                     match port {
                         Port::Gpu => self.gpu.gp0(src_word),
+                        Port::Spu => self.spu.dma_write(src_word),
                         _ => panic!("Unhandled DMA destination port {}", port as u8),
                     }
                 }
@@ -391,11 +478,8 @@ impl Interconnect {
                             1 => 0xFFFFFF,
                             _ => addr.wrapping_sub(4) & 0x1FFFFF,
                         },
-                        Port::Gpu => {
-                            //warn!("Unhandled DMA source port GPU");
-                            0
-                        }
-                        Port::CdRom => self.cdrom.load(2),
+                        Port::Gpu => self.gpu.read(),
+                        Port::Spu => self.spu.dma_read(),
                         _ => panic!("Unhandled DMA source port {}", port as u8),
                     };
 
@@ -407,14 +491,65 @@ impl Interconnect {
             remsz -= 1;
         }
 
-        channel.done();
+        self.dma.complete(port);
     }
 
+    // GPUのDREQ (GPUSTAT bit25) が立っているときに1サイクル1ワード送る
+    // FIFOが埋まっている間やVRAMからの読み出しが無い間は待たされる
+    fn step_dma_request(&mut self) {
+        let channel = self.dma.channel_mut(Port::Gpu);
+
+        if !channel.active() || !matches!(channel.sync(), Sync::Request) {
+            return;
+        }
+
+        if channel.request_remaining() == 0 {
+            self.dma.complete(Port::Gpu);
+            return;
+        }
+
+        if !self.gpu.dma_request() {
+            return;
+        }
+
+        let addr = channel.next_request_word();
+
+        match channel.direction() {
+            Direction::FromRam => self.gpu.gp0(self.ram.load(addr)),
+            Direction::ToRam => self.ram.store(addr, self.gpu.read()),
+        }
+    }
+
+    // CD-ROMのデータFIFOにセクタがあるときに1サイクル1ワード送る
+    // ブロックの始めだけDREQ (FIFOが空でない) を待ち、途中で空になったら残りは0になる
+    fn step_dma_cdrom(&mut self) {
+        let channel = self.dma.channel_mut(Port::CdRom);
+
+        if !channel.active() {
+            return;
+        }
+
+        if channel.request_remaining() == 0 {
+            self.dma.complete(Port::CdRom);
+            return;
+        }
+
+        if channel.request_block_start() && !self.cdrom.dma_request() {
+            return;
+        }
+
+        let addr = channel.next_request_word();
+
+        match channel.direction() {
+            Direction::ToRam => self.ram.store(addr, self.cdrom.dma_read()),
+            Direction::FromRam => warn!("CD-ROM DMA from RAM {:08x}", addr),
+        }
+    }
+
+    // 先頭のノードを読むだけで、転送は tick で進める
     fn do_dma_linked_list(&mut self, port: Port) {
         let channel = self.dma.channel_mut(port);
 
-        let mut addr = channel.base() & 0x1FFFFC;
-
         if channel.direction() == Direction::ToRam {
             panic!("Invalid DMA direction for linked list mode");
         }
@@ -425,43 +560,109 @@ impl Interconnect {
 
         // 8bit     | 24bit
         // commands | next header addr
-        loop {
-            let header: u32 = self.ram.load(addr);
+        let header: u32 = self.ram.load(channel.base() & 0x1FFFFC);
+        channel.start_node(header >> 24);
+    }
 
-            let mut remsz = header >> 24;
+    // GPUのFIFOに空きがあるときに1サイクル1ワード送る
+    // 描画に時間がかかるとFIFOが埋まるので、DMAの完了もGPUの処理に合わせて遅れる
+    fn step_dma_linked_list(&mut self) {
+        let channel = self.dma.channel_mut(Port::Gpu);
+
+        if !channel.active() || !matches!(channel.sync(), Sync::LinkedList) {
+            return;
+        }
+
+        if channel.node_remaining() > 0 {
+            if self.gpu.fifo_ready() {
+                let addr = channel.next_word();
+                self.gpu.gp0(self.ram.load(addr));
+            }
 
-            while remsz > 0 {
-                addr = (addr + 4) & 0x1FFFFC;
+            return;
+        }
 
-                let command = self.ram.load(addr);
+        // ノードを送り終えたので次のノードへ進む
+        let header: u32 = self.ram.load(channel.base() & 0x1FFFFC);
 
-                self.gpu.gp0(command);
+        if header & 0x800000 != 0 {
+            self.dma.complete(Port::Gpu);
+            return;
+        }
 
-                remsz -= 1;
-            }
+        channel.set_base(header & 0x1FFFFC);
 
-            if header & 0x800000 != 0 {
-                break;
-            }
+        let header: u32 = self.ram.load(channel.base());
+        channel.start_node(header >> 24);
+    }
+}
 
-            addr = header & 0x1FFFFC;
+impl Savestate for Interconnect {
+    fn save_state(&self, w: &mut Writer) {
+        self.scratchpad.save_state(w);
+        self.ram.save_state(w);
+        self.dma.save_state(w);
+        self.gpu.save_state(w);
+        self.cdrom.save_state(w);
+        self.spu.save_state(w);
+        self.joypad.save_state(w);
+        for timer in &self.timers {
+            timer.save_state(w);
         }
+        self.interrupts.save_state(w);
+        self.irqs.save_state(w);
+    }
 
-        channel.done();
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.scratchpad.load_state(r)?;
+        self.ram.load_state(r)?;
+        self.dma.load_state(r)?;
+        self.gpu.load_state(r)?;
+        self.cdrom.load_state(r)?;
+        self.spu.load_state(r)?;
+        self.joypad.load_state(r)?;
+        for timer in &mut self.timers {
+            timer.load_state(r)?;
+        }
+        self.interrupts.load_state(r)?;
+        self.irqs.load_state(r)
     }
 }
 
-mod map {
-    pub struct Range(u32, u32); // (start, length)
+// メモリマップの表。アドレスの振り分け、GDBのメモリマップ、ログでの名前、READMEの表はここから作る
+pub mod map {
+    use std::fmt::Write;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Kind {
+        Ram,
+        Rom,
+        Io,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct Range {
+        pub name: &'static str,
+        pub start: u32,
+        pub length: u32,
+        pub kind: Kind,
+    }
 
     impl Range {
+        const fn new(name: &'static str, start: u32, length: u32, kind: Kind) -> Range {
+            Range {
+                name,
+                start,
+                length,
+                kind,
+            }
+        }
+
         // addr: 絶対アドレス
         // 戻り値: 相対アドレス
         pub fn contains(self, addr: u32) -> Option<u32> {
-            let Range(start, length) = self;
-
-            if start <= addr && addr < start + length {
-                Some(addr - start)
+            if self.start <= addr && addr - self.start < self.length {
+                Some(addr - self.start)
             } else {
                 None
             }
@@ -479,29 +680,364 @@ mod map {
         0xFFFFFFFF, 0xFFFFFFFF,
     ];
 
+    // 物理アドレスの見える KUSEG / KSEG0 / KSEG1 の先頭
+    const SEGMENTS: [u32; 3] = [0x00000000, 0x80000000, 0xA0000000];
+
     pub fn mask_region(addr: u32) -> u32 {
         let index = (addr >> 29) as usize;
 
         addr & REGION_MASK[index]
     }
 
-    pub const RAM: Range = Range(0x00000000, 2 * 1024 * 1024);
-    pub const EXPANSION_1: Range = Range(0x1F000000, 256);
-    pub const SCRATCHPAD: Range = Range(0x1F800000, 0x400);
-    pub const MEM_CONTROL: Range = Range(0x1F801000, 36);
-    pub const JOYPAD: Range = Range(0x1F801040, 16);
-    pub const SIO: Range = Range(0x1F801050, 16);
-    pub const RAM_SIZE: Range = Range(0x1F801060, 4);
-    pub const IRQ_CONTROL: Range = Range(0x1F801070, 8);
-    pub const DMA: Range = Range(0x1F801080, 0x80);
-    pub const TIMER_0: Range = Range(0x1F801100, 12);
-    pub const TIMER_1: Range = Range(0x1F801110, 12);
-    pub const TIMER_2: Range = Range(0x1F801120, 12);
-    pub const CDROM: Range = Range(0x1F801800, 4);
-    pub const GPU: Range = Range(0x1F801810, 16);
-    pub const SPU: Range = Range(0x1F801C00, 640);
-    pub const EXPANSION_2: Range = Range(0x1F802000, 66);
-    pub const EXPANSION_3: Range = Range(0x1FA00000, 2048 * 1024);
-    pub const BIOS: Range = Range(0x1FC00000, 512 * 1024);
-    pub const CACHE_SIZE: Range = Range(0xFFFE0130, 4);
+    // 8MB 全体が RAM のミラー (2MB なら4回繰り返す)。実際の大きさは ram() で
+    pub const RAM: Range = Range::new("RAM", 0x00000000, 8 * 1024 * 1024, Kind::Ram);
+    pub const EXPANSION_1: Range = Range::new("EXPANSION_1", 0x1F000000, 256, Kind::Io);
+    pub const SCRATCHPAD: Range = Range::new("SCRATCHPAD", 0x1F800000, 0x400, Kind::Ram);
+    pub const MEM_CONTROL: Range = Range::new("MEM_CONTROL", 0x1F801000, 36, Kind::Io);
+    pub const JOYPAD: Range = Range::new("JOYPAD", 0x1F801040, 16, Kind::Io);
+    pub const SIO: Range = Range::new("SIO", 0x1F801050, 16, Kind::Io);
+    pub const RAM_SIZE: Range = Range::new("RAM_SIZE", 0x1F801060, 4, Kind::Io);
+    pub const IRQ_CONTROL: Range = Range::new("IRQ_CONTROL", 0x1F801070, 8, Kind::Io);
+    pub const DMA: Range = Range::new("DMA", 0x1F801080, 0x80, Kind::Io);
+    pub const TIMER_0: Range = Range::new("TIMER_0", 0x1F801100, 12, Kind::Io);
+    pub const TIMER_1: Range = Range::new("TIMER_1", 0x1F801110, 12, Kind::Io);
+    pub const TIMER_2: Range = Range::new("TIMER_2", 0x1F801120, 12, Kind::Io);
+    pub const CDROM: Range = Range::new("CDROM", 0x1F801800, 4, Kind::Io);
+    pub const GPU: Range = Range::new("GPU", 0x1F801810, 16, Kind::Io);
+    pub const SPU: Range = Range::new("SPU", 0x1F801C00, 640, Kind::Io);
+    pub const EXPANSION_2: Range = Range::new("EXPANSION_2", 0x1F802000, 66, Kind::Io);
+    pub const EXPANSION_3: Range = Range::new("EXPANSION_3", 0x1FA00000, 2048 * 1024, Kind::Io);
+    pub const BIOS: Range = Range::new("BIOS", 0x1FC00000, 512 * 1024, Kind::Rom);
+    // KSEG2 にしかない
+    pub const CACHE_SIZE: Range = Range::new("CACHE_SIZE", 0xFFFE0130, 4, Kind::Io);
+
+    // アドレス順。新しいデバイスはここにも足す
+    pub const RANGES: [Range; 19] = [
+        RAM,
+        EXPANSION_1,
+        SCRATCHPAD,
+        MEM_CONTROL,
+        JOYPAD,
+        SIO,
+        RAM_SIZE,
+        IRQ_CONTROL,
+        DMA,
+        TIMER_0,
+        TIMER_1,
+        TIMER_2,
+        CDROM,
+        GPU,
+        SPU,
+        EXPANSION_2,
+        EXPANSION_3,
+        BIOS,
+        CACHE_SIZE,
+    ];
+
+    pub fn ram(size: u32) -> Range {
+        Range {
+            length: size,
+            ..RAM
+        }
+    }
+
+    // 絶対アドレスを含む範囲と相対アドレス
+    pub fn find(abs_addr: u32) -> Option<(Range, u32)> {
+        let addr = mask_region(abs_addr);
+
+        RANGES
+            .iter()
+            .find_map(|&range| range.contains(addr).map(|offset| (range, offset)))
+    }
+
+    // ログ用の "CDROM+0x1" のような名前
+    pub fn describe(abs_addr: u32) -> String {
+        match find(abs_addr) {
+            Some((range, offset)) => format!("{}+0x{:x}", range.name, offset),
+            None => format!("{:08x}", abs_addr),
+        }
+    }
+
+    // GDB の qXfer:memory-map:read に返すXML
+    pub fn gdb_memory_map(ram_size: u32) -> String {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<!DOCTYPE memory-map\n",
+            "    PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\"\n",
+            "            \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n",
+            "<memory-map>\n",
+        ));
+
+        for range in RANGES {
+            let range = match range.name {
+                "RAM" => ram(ram_size),
+                _ => range,
+            };
+            let kind = match range.kind {
+                Kind::Rom => "rom",
+                Kind::Ram | Kind::Io => "ram",
+            };
+
+            // KSEG2 の範囲はそのまま、他は3つのセグメントに見える
+            let bases: &[u32] = match range.start < 0x20000000 {
+                true => &SEGMENTS,
+                false => &[0],
+            };
+
+            for base in bases {
+                let _ = writeln!(
+                    xml,
+                    "    <memory type=\"{}\" start=\"0x{:x}\" length=\"0x{:x}\"/>",
+                    kind,
+                    base + range.start,
+                    range.length
+                );
+            }
+        }
+
+        xml.push_str("</memory-map>");
+        xml
+    }
+
+    // README の表
+    pub fn markdown_table() -> String {
+        let mut table = String::from("| Name | Start | Length | Kind |\n|---|---|---|---|\n");
+
+        for range in RANGES {
+            let _ = writeln!(
+                table,
+                "| {} | 0x{:08X} | 0x{:X} | {:?} |",
+                range.name, range.start, range.length, range.kind
+            );
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::map;
+    use crate::{
+        cdrom::image::{Image, DATA_SIZE},
+        cpu::cpu::Cpu,
+        disc::Disc,
+        testing::TestMachineBuilder,
+    };
+
+    const GPU_DMA_BASE: u32 = 0x1F8010A0;
+    const GPU_DMA_CONTROL: u32 = 0x1F8010A8;
+    const GPU_DMA_BLOCK: u32 = 0x1F8010A4;
+    // RAMから, リンクリスト, 開始
+    const LINKED_LIST_START: u32 = 0x01000401;
+    // RAMから, DREQ同期, 開始
+    const REQUEST_START: u32 = 0x01000201;
+    const GP1: u32 = 0x1F801814;
+
+    // 256x256の塗りつぶしをするノードを count 個つなげたリスト
+    fn fill_list(count: u32) -> Vec<u8> {
+        let mut words = vec![];
+
+        for i in 0..count {
+            let next = match i + 1 == count {
+                true => 0xFFFFFF,
+                false => (i + 1) * 16,
+            };
+
+            words.extend([3 << 24 | next, 0x02FFFFFF, 0x00000000, 0x01000100]);
+        }
+
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn linked_list_dma_is_paced_by_gpu() {
+        let mut cpu = TestMachineBuilder::new().ram(0, &fill_list(8)).build();
+
+        cpu.inter.store::<u32>(GPU_DMA_BASE, 0);
+        cpu.inter.store::<u32>(GPU_DMA_CONTROL, LINKED_LIST_START);
+
+        let mut cycles = 0;
+        while cpu.inter.load::<u32>(GPU_DMA_CONTROL) & (1 << 24) != 0 {
+            cpu.inter.tick();
+            cycles += 1;
+        }
+
+        // 16ワードのFIFOに全ノードは入らないので、少なくとも1回は塗りつぶしを待つ
+        assert!(cycles > 256 * 256 / 8, "{}", cycles);
+    }
+
+    #[test]
+    fn linked_list_dma_sends_one_word_per_cycle() {
+        let mut cpu = TestMachineBuilder::new().ram(0, &fill_list(1)).build();
+
+        cpu.inter.store::<u32>(GPU_DMA_BASE, 0);
+        cpu.inter.store::<u32>(GPU_DMA_CONTROL, LINKED_LIST_START);
+
+        // 開始直後はまだ終わっていない
+        assert_ne!(cpu.inter.load::<u32>(GPU_DMA_CONTROL) & (1 << 24), 0);
+
+        for _ in 0..4 {
+            cpu.inter.tick();
+        }
+
+        assert_eq!(cpu.inter.load::<u32>(GPU_DMA_CONTROL) & (1 << 24), 0);
+    }
+
+    fn dma_running(cpu: &mut Cpu) -> bool {
+        cpu.inter.load::<u32>(GPU_DMA_CONTROL) & (1 << 24) != 0
+    }
+
+    #[test]
+    fn request_dma_waits_for_gpu_dreq() {
+        // 256x256の塗りつぶしの後に2x16ワードのNOP
+        let mut words: Vec<u32> = vec![0x02FFFFFF, 0x00000000, 0x01000100];
+        words.extend([0; 32]);
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        let mut cpu = TestMachineBuilder::new().ram(0, &bytes).build();
+
+        cpu.inter.store::<u32>(GPU_DMA_BASE, 0);
+        cpu.inter.store::<u32>(GPU_DMA_BLOCK, 0x00050007);
+        cpu.inter.store::<u32>(GPU_DMA_CONTROL, REQUEST_START);
+
+        // DMA方向がOffの間はDREQが立たない
+        for _ in 0..100 {
+            cpu.inter.tick();
+        }
+        assert!(dma_running(&mut cpu));
+
+        cpu.inter.store::<u32>(GP1, 0x04000002);
+
+        let mut cycles = 0;
+        while dma_running(&mut cpu) {
+            cpu.inter.tick();
+            cycles += 1;
+        }
+
+        // FIFOが埋まると塗りつぶしが終わるまで待たされる
+        assert!(cycles > 256 * 256 / 8, "{}", cycles);
+    }
+
+    const CDROM_INDEX: u32 = 0x1F801800;
+    const CDROM_COMMAND: u32 = 0x1F801801;
+    const CDROM_REQUEST: u32 = 0x1F801803;
+    const CDROM_DMA_BASE: u32 = 0x1F8010B0;
+    const CDROM_DMA_BLOCK: u32 = 0x1F8010B4;
+    const CDROM_DMA_CONTROL: u32 = 0x1F8010B8;
+    const DMA_INTERRUPT: u32 = 0x1F8010F4;
+    // RAMへ, 即時, 開始
+    const MANUAL_START: u32 = 0x11000000;
+
+    // CD-ROMの割り込みを待って ack する
+    fn wait_cdrom_irq(cpu: &mut Cpu) -> u8 {
+        cpu.inter.store::<u8>(CDROM_INDEX, 1);
+
+        loop {
+            let irq = cpu.inter.load::<u8>(CDROM_REQUEST) & 0x7;
+            if irq != 0 {
+                cpu.inter.store::<u8>(CDROM_REQUEST, 0x1F);
+                cpu.inter.store::<u8>(CDROM_INDEX, 0);
+                return irq;
+            }
+
+            cpu.inter.tick();
+        }
+    }
+
+    #[test]
+    fn cdrom_dma_drains_the_sector_and_raises_irq() {
+        let data: Vec<u8> = (0..DATA_SIZE * 16).map(|i| (i / 4) as u8).collect();
+        let mut cpu = TestMachineBuilder::new()
+            .disc(Image::from_disc(Disc::from_bytes(data.clone())))
+            .build();
+
+        cpu.inter
+            .store::<u32>(DMA_INTERRUPT, 1 << 23 | 1 << (16 + 3));
+        cpu.inter.store::<u32>(CDROM_DMA_BASE, 0x1000);
+        cpu.inter.store::<u32>(CDROM_DMA_BLOCK, 0x200);
+        cpu.inter.store::<u32>(CDROM_DMA_CONTROL, MANUAL_START);
+
+        // セクタが届くまでは待つ
+        for _ in 0..100 {
+            cpu.inter.tick();
+        }
+        assert_ne!(cpu.inter.load::<u32>(CDROM_DMA_CONTROL) & (1 << 24), 0);
+
+        cpu.inter.store::<u8>(CDROM_INDEX, 0);
+        cpu.inter.store::<u8>(CDROM_COMMAND, 0x06);
+        assert_eq!(wait_cdrom_irq(&mut cpu), 3);
+        assert_eq!(wait_cdrom_irq(&mut cpu), 1);
+
+        cpu.inter.store::<u8>(CDROM_REQUEST, 0x80);
+        while cpu.inter.load::<u32>(CDROM_DMA_CONTROL) & (1 << 24) != 0 {
+            cpu.inter.tick();
+        }
+
+        let ram: Vec<u8> = (0..DATA_SIZE as u32)
+            .map(|i| cpu.inter.load::<u8>(0x1000 + i))
+            .collect();
+        assert_eq!(ram, data[..DATA_SIZE]);
+
+        assert_ne!(cpu.inter.load::<u32>(DMA_INTERRUPT) & (1 << (24 + 3)), 0);
+    }
+
+    #[test]
+    fn ranges_are_sorted_and_disjoint() {
+        for pair in map::RANGES.windows(2) {
+            assert!(
+                pair[0].start + pair[0].length <= pair[1].start,
+                "{} overlaps {}",
+                pair[0].name,
+                pair[1].name
+            );
+        }
+    }
+
+    #[test]
+    fn describes_addresses_in_every_segment() {
+        assert_eq!(map::describe(0x1F801801), "CDROM+0x1");
+        assert_eq!(map::describe(0xBF801C02), "SPU+0x2");
+        assert_eq!(map::describe(0x80001000), "RAM+0x1000");
+        assert_eq!(map::describe(0x1F801820), "1f801820");
+    }
+
+    #[test]
+    fn ram_is_mirrored_up_to_8mb() {
+        let mut cpu = TestMachineBuilder::new().build();
+        assert_eq!(cpu.inter.ram_size(), 2 * 1024 * 1024);
+
+        // 2MB の RAM は 0x00200000, 0x00400000, 0x00600000 にも見える
+        cpu.inter.store::<u32>(0x80601000, 0x12345678);
+        assert_eq!(cpu.inter.load::<u32>(0xA0001000), 0x12345678);
+        assert_eq!(cpu.inter.load::<u32>(0x00201000), 0x12345678);
+        assert_eq!(
+            cpu.inter.peek::<u32>(0x807FFFFC),
+            cpu.inter.peek(0x801FFFFC)
+        );
+
+        // 8MB より先は RAM ではない
+        assert_eq!(cpu.inter.peek::<u32>(0x80801000), None);
+    }
+
+    #[test]
+    fn gdb_memory_map_mirrors_segments() {
+        let xml = map::gdb_memory_map(8 * 1024 * 1024);
+
+        assert!(xml.contains(r#"<memory type="ram" start="0x80000000" length="0x800000"/>"#));
+        assert!(xml.contains(r#"<memory type="rom" start="0xbfc00000" length="0x80000"/>"#));
+        assert!(xml.contains(r#"<memory type="ram" start="0xfffe0130" length="0x4"/>"#));
+        assert!(!xml.contains(r#"start="0x7ffe0130""#));
+    }
+
+    #[test]
+    fn readme_lists_memory_map() {
+        let readme = include_str!("../README.md");
+
+        assert!(
+            readme.contains(&map::markdown_table()),
+            "README memory map is out of date:\n{}",
+            map::markdown_table()
+        );
+    }
 }
@@ -1,8 +1,15 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
 use log::debug;
 
-use crate::addressible::Addressible;
+use crate::{
+    addressible::Addressible,
+    savestate::{Reader, Savestate, Writer},
+};
 
-#[derive(Debug, Clone, Copy)]
+// I_STAT のビット番号
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Irq {
     VBlank = 0,
     Gpu = 1,
@@ -17,11 +24,102 @@ pub enum Irq {
     LightPen = 10,
 }
 
+// I_STAT のビットが立ったサイクル
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrqEvent {
+    pub irq: Irq,
+    pub cycle: u64,
+}
+
+// デバイスが割り込みを上げる先。上げたサイクルを付けて Interrupts::tick まで溜めておく
+pub struct IrqBus {
+    // 起動してからのサイクル数
+    cycle: u64,
+    pending: Vec<IrqEvent>,
+}
+
+impl IrqBus {
+    pub fn new() -> IrqBus {
+        IrqBus {
+            cycle: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    pub fn raise(&mut self, irq: Irq) {
+        self.pending.push(IrqEvent {
+            irq,
+            cycle: self.cycle,
+        });
+    }
+}
+
+impl Default for IrqBus {
+    fn default() -> Self {
+        IrqBus::new()
+    }
+}
+
+impl Savestate for IrqBus {
+    // イベントは毎サイクル Interrupts に渡すので、保存するときには残っていない
+    fn save_state(&self, w: &mut Writer) {
+        w.u64(self.cycle);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.cycle = r.u64()?;
+        self.pending.clear();
+
+        Ok(())
+    }
+}
+
+// レベルで出ているデバイスの割り込み線。立ち上がりでバスにイベントを上げる
+#[derive(Clone, Copy)]
+pub struct IrqLine {
+    irq: Irq,
+    level: bool,
+}
+
+impl IrqLine {
+    pub fn new(irq: Irq) -> IrqLine {
+        IrqLine { irq, level: false }
+    }
+
+    pub fn set(&mut self, irqs: &mut IrqBus, level: bool) {
+        if level && !self.level {
+            irqs.raise(self.irq);
+        }
+
+        self.level = level;
+    }
+}
+
+impl Savestate for IrqLine {
+    fn save_state(&self, w: &mut Writer) {
+        w.bool(self.level);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.level = r.bool()?;
+
+        Ok(())
+    }
+}
+
+// 調査用に残す直近のイベント数
+const EVENT_LOG_LEN: usize = 64;
+
 pub struct Interrupts {
     stat: u32,
     mask: u32,
 
-    prev_pulse: u32,
+    // 直近のイベント (ステートには含めない)
+    events: VecDeque<IrqEvent>,
 }
 
 impl Interrupts {
@@ -29,7 +127,7 @@ impl Interrupts {
         Self {
             stat: 0,
             mask: 0,
-            prev_pulse: 0,
+            events: VecDeque::with_capacity(EVENT_LOG_LEN),
         }
     }
 
@@ -56,7 +154,28 @@ impl Interrupts {
         }
     }
 
-    pub fn tick(&mut self) {}
+    // デバイスがこのサイクルに上げた割り込みで I_STAT のビットを立ててバスを1サイクル進める
+    // 同じサイクルのイベントはデバイスを進めた順ではなくIRQ番号の小さい順に記録する
+    pub fn tick(&mut self, irqs: &mut IrqBus) {
+        irqs.pending.sort_by_key(|event| event.irq as u32);
+
+        for event in irqs.pending.drain(..) {
+            debug!("irq raised {:?}", event.irq);
+
+            self.stat |= 1 << event.irq as u32;
+
+            if self.events.len() == EVENT_LOG_LEN {
+                self.events.pop_front();
+            }
+            self.events.push_back(event);
+        }
+
+        irqs.cycle += 1;
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &IrqEvent> {
+        self.events.iter()
+    }
 
     pub fn check(&mut self) -> bool {
         let irq = self.stat & self.mask;
@@ -68,18 +187,80 @@ impl Interrupts {
         debug!("irq ack {:08x}", val);
         self.stat &= val;
     }
+}
 
-    pub fn set(&mut self, irq: Irq, val: bool) {
-        let mask = 1 << (irq as u32);
+impl Savestate for Interrupts {
+    fn save_state(&self, w: &mut Writer) {
+        w.u32(self.stat);
+        w.u32(self.mask);
+    }
 
-        if val && (self.prev_pulse & mask == 0) {
-            debug!("irq raised {:?}", irq);
-            self.stat |= mask;
-        }
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.stat = r.u32()?;
+        self.mask = r.u32()?;
+        self.events.clear();
 
-        self.prev_pulse &= !mask;
-        if val {
-            self.prev_pulse |= mask;
-        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_cycle_events_are_ordered_by_irq_number() {
+        let mut interrupts = Interrupts::new();
+        let mut irqs = IrqBus::new();
+
+        interrupts.tick(&mut irqs);
+        irqs.raise(Irq::Tmr2);
+        irqs.raise(Irq::VBlank);
+        irqs.raise(Irq::CdRom);
+        interrupts.tick(&mut irqs);
+
+        let events: Vec<_> = interrupts.events().copied().collect();
+        assert_eq!(
+            events,
+            vec![
+                IrqEvent {
+                    irq: Irq::VBlank,
+                    cycle: 1
+                },
+                IrqEvent {
+                    irq: Irq::CdRom,
+                    cycle: 1
+                },
+                IrqEvent {
+                    irq: Irq::Tmr2,
+                    cycle: 1
+                },
+            ]
+        );
+        assert_eq!(irqs.cycle(), 2);
+    }
+
+    #[test]
+    fn held_lines_raise_once() {
+        let mut interrupts = Interrupts::new();
+        let mut irqs = IrqBus::new();
+        let mut line = IrqLine::new(Irq::Dma);
+
+        line.set(&mut irqs, true);
+        interrupts.tick(&mut irqs);
+        interrupts.store::<u32>(0, 0);
+        line.set(&mut irqs, true);
+        interrupts.tick(&mut irqs);
+
+        assert_eq!(interrupts.load::<u32>(0), 0);
+        assert_eq!(interrupts.events().count(), 1);
+
+        line.set(&mut irqs, false);
+        interrupts.tick(&mut irqs);
+        line.set(&mut irqs, true);
+        interrupts.tick(&mut irqs);
+
+        assert_eq!(interrupts.load::<u32>(0), 1 << Irq::Dma as u32);
+        assert_eq!(interrupts.events().last().unwrap().cycle, 3);
     }
 }
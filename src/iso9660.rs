@@ -0,0 +1,286 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::cdrom::{
+    edc::{self, Damage},
+    image::{Image, DATA_SIZE as SECTOR_SIZE, SYNC},
+};
+
+// ユーザーデータ部分 (2048byte) を返す
+fn sector(disc: &Image, lba: usize) -> Result<Vec<u8>> {
+    let raw = disc
+        .read_sector(lba as u32)
+        .ok_or_else(|| anyhow!("Sector {} is out of range", lba))?;
+
+    let offset = match raw[15] {
+        1 => 16,
+        2 => 24,
+        mode => bail!("Unsupported sector mode {} at {}", mode, lba),
+    };
+
+    Ok(raw[offset..offset + SECTOR_SIZE].to_vec())
+}
+
+fn le32(data: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ]) as usize
+}
+
+struct Entry {
+    lba: usize,
+    size: usize,
+    directory: bool,
+}
+
+fn read_extent(disc: &Image, entry: &Entry) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(entry.size);
+    let mut lba = entry.lba;
+
+    while data.len() < entry.size {
+        let remaining = entry.size - data.len();
+        let sector = sector(disc, lba)?;
+        data.extend_from_slice(&sector[..remaining.min(SECTOR_SIZE)]);
+        lba += 1;
+    }
+
+    Ok(data)
+}
+
+fn root(disc: &Image) -> Result<Entry> {
+    let pvd = sector(disc, 16)?;
+
+    if pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+        bail!("Primary volume descriptor not found");
+    }
+
+    Ok(Entry {
+        lba: le32(&pvd, 156 + 2),
+        size: le32(&pvd, 156 + 10),
+        directory: true,
+    })
+}
+
+fn find(disc: &Image, directory: &Entry, name: &str) -> Result<Entry> {
+    let data = read_extent(disc, directory)?;
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let len = data[offset] as usize;
+
+        // レコードはセクタを跨がない
+        if len == 0 {
+            offset = (offset / SECTOR_SIZE + 1) * SECTOR_SIZE;
+            continue;
+        }
+
+        // 壊れたイメージでも範囲外を読まないように、固定部分 (33byte) と名前が収まるか確かめる
+        if len < 33 || offset + len > data.len() {
+            bail!("Invalid directory record at {} (length {})", offset, len);
+        }
+
+        let record = &data[offset..offset + len];
+        let name_len = record[32] as usize;
+        if 33 + name_len > len {
+            bail!("Directory record name at {} overflows the record", offset);
+        }
+
+        let record_name = String::from_utf8_lossy(&record[33..33 + name_len]);
+        let record_name = record_name.split(';').next().unwrap_or("");
+
+        if record_name.eq_ignore_ascii_case(name) {
+            return Ok(Entry {
+                lba: le32(record, 2),
+                size: le32(record, 10),
+                directory: record[25] & 2 != 0,
+            });
+        }
+
+        offset += len;
+    }
+
+    bail!("{} not found", name)
+}
+
+pub fn read_file(disc: &Image, path: &str) -> Result<Vec<u8>> {
+    let mut entry = root(disc)?;
+
+    for name in path.split(['\\', '/']).filter(|name| !name.is_empty()) {
+        if !entry.directory {
+            bail!("{} is not a directory", path);
+        }
+        entry = find(disc, &entry, name)?;
+    }
+
+    read_extent(disc, &entry)
+}
+
+// SYSTEM.CNF の BOOT 行からEXEのパスを探す
+pub fn boot_path(disc: &Image) -> Result<String> {
+    let cnf = match read_file(disc, "SYSTEM.CNF") {
+        Ok(cnf) => cnf,
+        Err(_) => return Ok("PSX.EXE".to_string()),
+    };
+
+    let cnf = String::from_utf8_lossy(&cnf);
+
+    for line in cnf.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+
+        if key.trim().eq_ignore_ascii_case("BOOT") {
+            let value = value.trim();
+            let path = value.split_once(':').map(|(_, path)| path).unwrap_or(value);
+            let path = path.split(';').next().unwrap_or(path);
+
+            return Ok(path.to_string());
+        }
+    }
+
+    bail!("BOOT entry not found in SYSTEM.CNF")
+}
+
+// データトラックのうち、EDC か ECC が合わないセクタのLBAと壊れている所を返す
+// ISOイメージでは読むときに付けるので必ず合う
+pub fn damaged_sectors(disc: &Image) -> Vec<(usize, Damage)> {
+    disc.tracks()
+        .iter()
+        .filter(|track| track.kind.is_data())
+        .flat_map(|track| track.start..track.start + track.length)
+        .filter_map(|lba| {
+            let damage = edc::verify(&disc.read_sector(lba)?).err()?;
+            Some((lba as usize, damage))
+        })
+        .collect()
+}
+
+// データトラックのうち、同期パターンが壊れているか読めないセクタのLBAを返す
+// ISOイメージでは同期パターンを付けて読むので見つからない
+pub fn broken_sectors(disc: &Image) -> Vec<usize> {
+    disc.tracks()
+        .iter()
+        .filter(|track| track.kind.is_data())
+        .flat_map(|track| track.start..track.start + track.length)
+        .filter(|&lba| {
+            disc.read_sector(lba)
+                .is_none_or(|sector| sector[..SYNC.len()] != SYNC)
+        })
+        .map(|lba| lba as usize)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{disc::Disc, exe::Exe};
+
+    const ROOT_LBA: usize = 20;
+
+    fn record(lba: usize, size: usize, directory: bool, name: &[u8]) -> Vec<u8> {
+        let len = 33 + name.len() + (name.len() + 1) % 2;
+        let mut record = vec![0; len];
+
+        record[0] = len as u8;
+        record[2..6].copy_from_slice(&(lba as u32).to_le_bytes());
+        record[10..14].copy_from_slice(&(size as u32).to_le_bytes());
+        record[25] = if directory { 2 } else { 0 };
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name);
+
+        record
+    }
+
+    // ISO 形式 (2048byte/セクタ) のイメージ。ルートディレクトリに files を並べる
+    fn image(files: &[(&[u8], &[u8])], root: Option<Vec<u8>>) -> Image {
+        let mut data = vec![0; SECTOR_SIZE * (ROOT_LBA + 1 + files.len() * 2)];
+
+        let pvd = &mut data[SECTOR_SIZE * 16..];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[156..156 + 34].copy_from_slice(&record(ROOT_LBA, SECTOR_SIZE, true, &[0]));
+
+        let root = root.unwrap_or_else(|| {
+            let mut root = record(ROOT_LBA, SECTOR_SIZE, true, &[0]);
+            root.extend(record(ROOT_LBA, SECTOR_SIZE, true, &[1]));
+            for (i, (name, contents)) in files.iter().enumerate() {
+                root.extend(record(ROOT_LBA + 1 + i * 2, contents.len(), false, name));
+            }
+            root
+        });
+        data[SECTOR_SIZE * ROOT_LBA..][..root.len()].copy_from_slice(&root);
+
+        for (i, (_, contents)) in files.iter().enumerate() {
+            data[SECTOR_SIZE * (ROOT_LBA + 1 + i * 2)..][..contents.len()]
+                .copy_from_slice(contents);
+        }
+
+        Image::from_disc(Disc::from_bytes(data))
+    }
+
+    fn exe(pc: u32) -> Vec<u8> {
+        let mut exe = vec![0; 0x800 + 0x800];
+        exe[..8].copy_from_slice(b"PS-X EXE");
+        exe[0x10..0x14].copy_from_slice(&pc.to_le_bytes());
+        exe[0x18..0x1C].copy_from_slice(&0x8001_0000u32.to_le_bytes());
+        exe[0x1C..0x20].copy_from_slice(&0x800u32.to_le_bytes());
+        exe
+    }
+
+    #[test]
+    fn boots_the_exe_named_in_system_cnf() {
+        let disc = image(
+            &[
+                (b"SYSTEM.CNF;1", b"BOOT = cdrom:\\MAIN.EXE;1\r\nTCB = 4\r\n"),
+                (b"MAIN.EXE;1", &exe(0x8001_2345)),
+            ],
+            None,
+        );
+
+        let path = boot_path(&disc).unwrap();
+        assert_eq!(path, "\\MAIN.EXE");
+
+        let exe = Exe::parse(&read_file(&disc, &path).unwrap()).unwrap();
+        assert_eq!(exe.pc, 0x8001_2345);
+        assert_eq!(exe.load_addr, 0x8001_0000);
+        assert_eq!(exe.data.len(), 0x800);
+
+        assert!(read_file(&disc, "MISSING.EXE").is_err());
+        assert!(read_file(&disc, "MAIN.EXE/FILE").is_err());
+    }
+
+    #[test]
+    fn falls_back_to_psx_exe_without_system_cnf() {
+        let disc = image(&[(b"PSX.EXE;1", &exe(0x8001_0000))], None);
+
+        assert_eq!(boot_path(&disc).unwrap(), "PSX.EXE");
+        assert!(Exe::parse(&read_file(&disc, "PSX.EXE").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_directory_records() {
+        let error = |root: Vec<u8>| {
+            read_file(&image(&[], Some(root)), "PSX.EXE")
+                .err()
+                .unwrap()
+                .to_string()
+        };
+
+        // 固定部分より短いレコード
+        assert!(error(vec![10; 10]).starts_with("Invalid directory record"));
+
+        // 名前がレコードからはみ出す
+        let mut root = record(ROOT_LBA, SECTOR_SIZE, true, b"PSX.EXE;1");
+        root[32] = 0xFF;
+        assert!(error(root).contains("overflows the record"));
+
+        // 最後のレコードがディレクトリの終わりを越える
+        let mut root = record(ROOT_LBA, SECTOR_SIZE, true, &[0]).repeat(59);
+        root.push(0xFF);
+        assert!(error(root).starts_with("Invalid directory record at 2006"));
+    }
+}
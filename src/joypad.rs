@@ -1,10 +1,190 @@
 use std::collections::VecDeque;
 
+use anyhow::Result;
 use log::debug;
 
-use crate::addressible::Addressible;
+use crate::{
+    addressible::Addressible,
+    config::Device,
+    memcard::{MemoryCard, Session},
+    savestate::{Reader, Savestate, Writer},
+};
+
+// デジタルパッドのボタン (Joypad::set_buttons に押されているものをORして渡す)
+pub mod button {
+    pub const SELECT: u16 = 1 << 0;
+    pub const START: u16 = 1 << 3;
+    pub const UP: u16 = 1 << 4;
+    pub const RIGHT: u16 = 1 << 5;
+    pub const DOWN: u16 = 1 << 6;
+    pub const LEFT: u16 = 1 << 7;
+    pub const L2: u16 = 1 << 8;
+    pub const R2: u16 = 1 << 9;
+    pub const L1: u16 = 1 << 10;
+    pub const R1: u16 = 1 << 11;
+    pub const TRIANGLE: u16 = 1 << 12;
+    pub const CIRCLE: u16 = 1 << 13;
+    pub const CROSS: u16 = 1 << 14;
+    pub const SQUARE: u16 = 1 << 15;
+}
+
+// DualShock の振動モーターの状態
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Motors {
+    pub small: bool,
+    pub large: u8,
+}
+
+// DualShock の状態
+// 設定モード (0x43) でアナログモードの切り替え (0x44) とモーターの割り当て (0x4D) を行う
+#[derive(Clone, Copy)]
+struct DualShock {
+    analog: bool,
+    config: bool,
+    // 通信中のコマンドと最初の引数
+    command: u8,
+    param: u8,
+    // 通信中のコマンドの引数のバイト数 (IDと0x5Aの後)
+    len: u8,
+    // ポーリングの各バイトをどのモーターに使うか (0x00: 小, 0x01: 大, それ以外: 使わない)
+    rumble_map: [u8; 6],
+    motors: Motors,
+}
+
+impl DualShock {
+    fn new() -> DualShock {
+        DualShock {
+            analog: false,
+            config: false,
+            command: 0,
+            param: 0,
+            len: 0,
+            rumble_map: [0xFF; 6],
+            motors: Motors::default(),
+        }
+    }
+
+    // コマンドを受け取ってIDを返す。下位4bitは引数のハーフワード数
+    fn start(&mut self, command: u8) -> u8 {
+        let id = match (self.config, self.analog) {
+            (true, _) => 0xF3,
+            (false, true) => 0x73,
+            (false, false) => 0x41,
+        };
+
+        self.command = command;
+        self.len = (id & 0x0F) * 2;
+
+        id
+    }
+
+    // index 番目の引数を受け取って応答を返す。最後のバイトなら true
+    fn payload(&mut self, index: u8, val: u8, buttons: u16) -> (u8, bool) {
+        if index == 0 {
+            self.param = val;
+        }
+
+        let i = index as usize;
+        let res = match (self.config, self.command) {
+            (_, 0x42) | (false, 0x43) => {
+                if self.command == 0x42 {
+                    self.set_motor(i, val);
+                }
+
+                match index {
+                    0 => buttons as u8,
+                    1 => (buttons >> 8) as u8,
+                    // スティックは中央
+                    _ => 0x80,
+                }
+            }
+            (true, 0x43) | (true, 0x44) => 0x00,
+            (true, 0x45) => [0x01, 0x02, self.analog as u8, 0x02, 0x01, 0x00][i],
+            (true, 0x46) => match self.param {
+                0x00 => [0x00, 0x00, 0x01, 0x02, 0x00, 0x0A][i],
+                _ => [0x00, 0x00, 0x01, 0x01, 0x01, 0x14][i],
+            },
+            (true, 0x47) => [0x00, 0x00, 0x02, 0x00, 0x01, 0x00][i],
+            (true, 0x4C) => match (i, self.param) {
+                (3, 0x00) => 0x04,
+                (3, _) => 0x07,
+                _ => 0x00,
+            },
+            // 前の割り当てを返す
+            (true, 0x4D) => std::mem::replace(&mut self.rumble_map[i], val),
+            _ => {
+                debug!("DUALSHOCK unhandled COMMAND {:02x}", self.command);
+                0x00
+            }
+        };
+
+        let last = index + 1 >= self.len;
+        if last {
+            self.finish();
+        }
+
+        (res, last)
+    }
+
+    // モードの切り替えはコマンドの最後で反映する
+    fn finish(&mut self) {
+        match (self.config, self.command) {
+            (_, 0x43) => self.config = self.param == 0x01,
+            (true, 0x44) => self.analog = self.param == 0x01,
+            _ => {}
+        }
+    }
+
+    fn set_motor(&mut self, index: usize, val: u8) {
+        match self.rumble_map[index] {
+            // 小さいモーターはオンオフだけ (bit0)
+            0x00 => self.motors.small = val & 1 != 0,
+            0x01 => self.motors.large = val,
+            _ => {}
+        }
+    }
+}
+
+impl Savestate for DualShock {
+    fn save_state(&self, w: &mut Writer) {
+        w.bool(self.analog);
+        w.bool(self.config);
+        w.u8(self.command);
+        w.u8(self.param);
+        w.u8(self.len);
+        for map in self.rumble_map {
+            w.u8(map);
+        }
+        w.bool(self.motors.small);
+        w.u8(self.motors.large);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.analog = r.bool()?;
+        self.config = r.bool()?;
+        self.command = r.u8()?;
+        self.param = r.u8()?;
+        self.len = r.u8()?;
+        for map in &mut self.rumble_map {
+            *map = r.u8()?;
+        }
+        self.motors.small = r.bool()?;
+        self.motors.large = r.u8()?;
+
+        Ok(())
+    }
+}
 
 pub struct Joypad {
+    devices: [Device; 2],
+    dualshock: [DualShock; 2],
+    memory_cards: [Option<MemoryCard>; 2],
+    // 押されているボタン (Ps が vblank の開始で取り込んだ値)
+    buttons: [u16; 2],
+    // 選択中のデバイスとの通信で何バイト目か
+    transfer: u8,
+    // 通信の相手がパッドではなくメモリーカード (最初のバイトが 0x81)
+    card_selected: bool,
     select: bool,
     target: bool,
     tx_enabled: bool,
@@ -20,8 +200,14 @@ pub struct Joypad {
 }
 
 impl Joypad {
-    pub fn new() -> Self {
+    pub fn new(devices: [Device; 2], memory_cards: [Option<MemoryCard>; 2]) -> Self {
         Joypad {
+            devices,
+            dualshock: [DualShock::new(); 2],
+            memory_cards,
+            buttons: [0; 2],
+            transfer: 0,
+            card_selected: false,
             select: false,
             target: false,
             tx_enabled: true,
@@ -37,6 +223,31 @@ impl Joypad {
         }
     }
 
+    pub fn set_buttons(&mut self, port: usize, buttons: u16) {
+        self.buttons[port] = buttons;
+    }
+
+    // DualShock 以外では常に止まっている
+    pub fn motors(&self, port: usize) -> Motors {
+        match self.devices[port] {
+            Device::DualShock => self.dualshock[port].motors,
+            _ => Motors::default(),
+        }
+    }
+
+    pub fn memory_card(&self, slot: usize) -> Option<&MemoryCard> {
+        self.memory_cards[slot].as_ref()
+    }
+
+    // 書き込まれたメモリーカードをファイルに書き戻す
+    pub fn flush_memory_cards(&mut self) -> Result<()> {
+        for card in self.memory_cards.iter_mut().flatten() {
+            card.flush()?;
+        }
+
+        Ok(())
+    }
+
     pub fn tick(&mut self) {
         if self.tx_enabled && !self.tx.is_empty() {
             let cmd = self.tx.pop_front().unwrap();
@@ -88,14 +299,90 @@ impl Joypad {
     }
 
     fn command(&mut self, command: u8) {
-        match command {
-            0x01 => self.command_access(),
-            _ => debug!("JOYPAD unhandled COMMAND {:02x}", command),
+        let port = self.target as usize;
+        let device = self.devices[port];
+        // ボタンは押されていると0
+        let buttons = !self.buttons[port];
+
+        match (device, self.transfer, command) {
+            (_, 0, 0x01) => self.command_access(),
+            (_, 0, 0x81) => self.memory_card_access(),
+            _ if self.card_selected => self.memory_card_command(command),
+            // ID (デジタルパッド)
+            (Device::DigitalPad, 1, 0x42) => self.respond(0x41),
+            (Device::DigitalPad, 2, _) => self.respond(0x5A),
+            (Device::DigitalPad, 3, _) => self.respond(buttons as u8),
+            (Device::DigitalPad, 4, _) => {
+                self.respond((buttons >> 8) as u8);
+                self.finish_transfer();
+            }
+            (Device::DualShock, 1, _) => {
+                let id = self.dualshock[port].start(command);
+                self.respond(id);
+            }
+            (Device::DualShock, 2, _) => self.respond(0x5A),
+            (Device::DualShock, _, _) => {
+                let index = self.transfer - 3;
+                let (res, last) = self.dualshock[port].payload(index, command, buttons);
+
+                self.respond(res);
+                if last {
+                    self.finish_transfer();
+                }
+            }
+            _ => {
+                debug!("JOYPAD unhandled COMMAND {:02x}", command);
+                self.finish_transfer();
+            }
         }
     }
 
+    fn memory_card_access(&mut self) {
+        match self.memory_cards[self.target as usize] {
+            Some(_) => {
+                self.card_selected = true;
+                self.respond(0xFF);
+            }
+            None => self.rx.push_back(0xFF),
+        }
+    }
+
+    fn memory_card_command(&mut self, command: u8) {
+        let card = match &mut self.memory_cards[self.target as usize] {
+            Some(card) => card,
+            None => return self.finish_transfer(),
+        };
+
+        if self.transfer == 1 {
+            let flag = card.start(command);
+            self.respond(flag);
+            return;
+        }
+
+        let (res, last) = card.payload(self.transfer - 2, command);
+
+        self.respond(res);
+        if last {
+            self.finish_transfer();
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        self.transfer = 0;
+        self.card_selected = false;
+    }
+
     fn command_access(&mut self) {
-        self.rx.push_back(0);
+        match self.devices[self.target as usize] {
+            Device::DigitalPad | Device::DualShock => self.respond(0),
+            // 何も繋がっていなければバスはHighのまま
+            Device::None => self.rx.push_back(0xFF),
+        }
+    }
+
+    fn respond(&mut self, val: u8) {
+        self.rx.push_back(val);
+        self.transfer += 1;
     }
 
     fn stat(&self) -> u32 {
@@ -155,7 +442,233 @@ impl Joypad {
         }
 
         if self.select {
-            self.target = (val << 13) & 1 > 0;
+            self.target = (val >> 13) & 1 > 0;
+        } else {
+            self.finish_transfer();
         }
     }
 }
+
+impl Savestate for Joypad {
+    fn save_state(&self, w: &mut Writer) {
+        w.bool(self.select);
+        w.bool(self.target);
+        w.bool(self.tx_enabled);
+        w.fifo(&self.tx);
+        w.bool(self.rx_enabled);
+        w.fifo(&self.rx);
+        w.bool(self.ack);
+        w.bool(self.acked);
+        w.bool(self.irq);
+        w.u16(self.baud_timer);
+        w.u16(self.baud_rate);
+        w.u16(self.mode);
+        w.u8(self.transfer);
+        w.u16(self.buttons[0]);
+        w.u16(self.buttons[1]);
+        self.dualshock[0].save_state(w);
+        self.dualshock[1].save_state(w);
+        w.bool(self.card_selected);
+        for card in &self.memory_cards {
+            card.as_ref()
+                .map_or_else(Session::new, MemoryCard::session)
+                .save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.select = r.bool()?;
+        self.target = r.bool()?;
+        self.tx_enabled = r.bool()?;
+        self.tx = r.fifo()?;
+        self.rx_enabled = r.bool()?;
+        self.rx = r.fifo()?;
+        self.ack = r.bool()?;
+        self.acked = r.bool()?;
+        self.irq = r.bool()?;
+        self.baud_timer = r.u16()?;
+        self.baud_rate = r.u16()?;
+        self.mode = r.u16()?;
+        self.transfer = r.u8()?;
+        self.buttons = [r.u16()?, r.u16()?];
+        self.dualshock[0].load_state(r)?;
+        self.dualshock[1].load_state(r)?;
+        self.card_selected = r.bool()?;
+        // 挿さっていないスロットの状態は読み捨てる
+        for card in &mut self.memory_cards {
+            let mut session = Session::new();
+            session.load_state(r)?;
+
+            if let Some(card) = card {
+                card.set_session(session);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(joypad: &mut Joypad, bytes: &[u8]) -> Vec<u8> {
+        // select, TX enable
+        joypad.store::<u16>(10, 0x0003);
+
+        let res = bytes
+            .iter()
+            .map(|byte| {
+                joypad.store::<u8>(0, *byte);
+                joypad.tick();
+                joypad.load::<u8>(0)
+            })
+            .collect();
+
+        joypad.store::<u16>(10, 0x0000);
+
+        res
+    }
+
+    #[test]
+    fn digital_pad_reports_buttons() {
+        let mut joypad = Joypad::new([Device::DigitalPad, Device::None], [None, None]);
+        joypad.set_buttons(0, button::START | button::CROSS);
+
+        let res = transfer(&mut joypad, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+
+        assert_eq!(res, vec![0x00, 0x41, 0x5A, 0xF7, 0xBF]);
+
+        // 次の通信は最初から
+        joypad.set_buttons(0, 0);
+        let res = transfer(&mut joypad, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+
+        assert_eq!(res, vec![0x00, 0x41, 0x5A, 0xFF, 0xFF]);
+    }
+
+    // 設定モードに入る / 抜ける
+    const ENTER_CONFIG: [u8; 5] = [0x01, 0x43, 0x00, 0x01, 0x00];
+    const EXIT_CONFIG: [u8; 9] = [0x01, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn dualshock_drives_mapped_motors() {
+        let mut joypad = Joypad::new([Device::DualShock, Device::None], [None, None]);
+
+        transfer(&mut joypad, &ENTER_CONFIG);
+        // 1バイト目を小、2バイト目を大のモーターに割り当てる
+        let res = transfer(
+            &mut joypad,
+            &[0x01, 0x4D, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFF],
+        );
+        assert_eq!(res[1], 0xF3);
+        transfer(&mut joypad, &EXIT_CONFIG);
+
+        let res = transfer(&mut joypad, &[0x01, 0x42, 0x00, 0x01, 0xC0]);
+        assert_eq!(res, vec![0x00, 0x41, 0x5A, 0xFF, 0xFF]);
+        assert_eq!(
+            joypad.motors(0),
+            Motors {
+                small: true,
+                large: 0xC0
+            }
+        );
+
+        transfer(&mut joypad, &[0x01, 0x42, 0x00, 0x00, 0x00]);
+        assert_eq!(joypad.motors(0), Motors::default());
+    }
+
+    #[test]
+    fn dualshock_analog_mode_reports_sticks() {
+        let mut joypad = Joypad::new([Device::DualShock, Device::None], [None, None]);
+
+        transfer(&mut joypad, &ENTER_CONFIG);
+        transfer(
+            &mut joypad,
+            &[0x01, 0x44, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00],
+        );
+        transfer(&mut joypad, &EXIT_CONFIG);
+
+        let res = transfer(
+            &mut joypad,
+            &[0x01, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(
+            res,
+            vec![0x00, 0x73, 0x5A, 0xFF, 0xFF, 0x80, 0x80, 0x80, 0x80]
+        );
+    }
+
+    fn joypad_with_card(name: &str) -> (Joypad, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("rps-{}-{}.mcd", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let card = MemoryCard::open(&path).unwrap();
+
+        (
+            Joypad::new([Device::DigitalPad, Device::None], [Some(card), None]),
+            path,
+        )
+    }
+
+    #[test]
+    fn memory_card_reports_id() {
+        let (mut joypad, path) = joypad_with_card("id");
+
+        let res = transfer(&mut joypad, &[0x81, 0x53, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(
+            res,
+            vec![0xFF, 0x08, 0x5A, 0x5D, 0x5C, 0x5D, 0x04, 0x00, 0x00, 0x80]
+        );
+
+        // 空きスロットは応答しない
+        joypad.store::<u16>(10, 0x2003);
+        joypad.store::<u8>(0, 0x81);
+        joypad.tick();
+        assert_eq!(joypad.load::<u8>(0), 0xFF);
+        assert_eq!(joypad.transfer, 0);
+
+        // 書き込まれていないカードはファイルを作らない
+        assert!(!joypad.memory_card(0).unwrap().is_dirty());
+        joypad.flush_memory_cards().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn memory_card_write_read_and_flush() {
+        let (mut joypad, path) = joypad_with_card("rw");
+
+        let data: Vec<u8> = (0..128).map(|i| i as u8).collect();
+        let checksum = data.iter().fold(0x01 ^ 0x23, |acc, b| acc ^ b);
+
+        let mut write = vec![0x81, 0x57, 0x00, 0x00, 0x01, 0x23];
+        write.extend(&data);
+        write.extend([checksum, 0x00, 0x00, 0x00]);
+        let res = transfer(&mut joypad, &write);
+
+        assert_eq!(res[1], 0x08);
+        assert_eq!(&res[res.len() - 3..], [0x5C, 0x5D, 0x47]);
+        assert!(joypad.memory_card(0).unwrap().is_dirty());
+
+        // 書き込んだ後は FLAG の bit3 が落ちる
+        let mut read = vec![0x81, 0x52, 0x00, 0x00, 0x01, 0x23];
+        read.extend([0; 4 + 128 + 2]);
+        let res = transfer(&mut joypad, &read);
+
+        assert_eq!(res[1], 0x00);
+        assert_eq!(&res[6..10], [0x5C, 0x5D, 0x01, 0x23]);
+        assert_eq!(&res[10..138], &data[..]);
+        assert_eq!(&res[138..], [checksum, 0x47]);
+
+        // 壊れたチェックサムは書き込まれない
+        write[6] ^= 0xFF;
+        let res = transfer(&mut joypad, &write);
+        assert_eq!(res[res.len() - 1], 0x4E);
+
+        joypad.flush_memory_cards().unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!joypad.memory_card(0).unwrap().is_dirty());
+        assert_eq!(&saved[0x123 * 128..0x124 * 128], &data[..]);
+    }
+}
@@ -1,14 +1,36 @@
+#[cfg(feature = "achievements")]
+pub mod achievements;
 mod addressible;
+mod adpcm;
+pub mod affinity;
+pub mod autosave;
+pub mod autosplit;
 pub mod bios;
-mod cdrom;
+pub mod cdrom;
+pub mod config;
 pub mod cpu;
+pub mod crash;
+pub mod disc;
 mod dma;
+pub mod exe;
 pub mod gpu;
 mod gte;
 pub mod interconnect;
 mod interrupts;
-mod joypad;
+pub mod iso9660;
+pub mod joypad;
+pub mod memcard;
+pub mod ps;
 mod ram;
+pub mod rcheevos;
+pub mod replay;
+pub mod rumble;
+mod savestate;
 mod scratchpad;
+mod spu;
+pub mod test_roms;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod timer;
+pub mod trigger;
 mod utils;
@@ -1,41 +1,188 @@
 use std::{
-    fs::File,
-    io::{self, BufReader, Read},
+    io::{self, Write},
     net::{TcpListener, TcpStream},
-    path::Path,
-    string,
-    sync::mpsc,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
     thread,
+    time::{Duration, Instant},
 };
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
 use gdbstub::{
     common::Signal,
     conn::{Connection, ConnectionExt},
     stub::{run_blocking, DisconnectReason, GdbStub, GdbStubError, SingleThreadStopReason},
     target::Target,
 };
+use native_dialog::{FileDialog, MessageDialog, MessageType};
+#[cfg(feature = "achievements")]
+use rps::achievements::{self, Credentials, Runtime};
 use rps::{
+    affinity::{self, Priority, ThreadConfig},
+    autosave::Autosave,
+    autosplit::{self, LiveSplit},
     bios::Bios,
+    cdrom::image::Image,
+    config::{BootMode, Device, MachineConfig, PowerOnState, Region},
     cpu::{cpu, cpu::Cpu},
-    gpu::{gpu::Gpu, renderer::Renderer},
-    interconnect::Interconnect,
+    crash,
+    exe::Exe,
+    gpu::{
+        command_log::{CommandLog, Replayer},
+        gpu::Gpu,
+        postprocess::Filter,
+        renderer::{Renderer, Scaling},
+        CPU_CLOCK,
+    },
+    iso9660,
+    memcard::{self, BlockState, MemoryCard},
+    ps::{ExitConditions, Ps, SharedPs},
+    rumble::{Rumble, RumbleOutput, RumbleScale, Strength},
+    test_roms::{self, Registry, Score},
 };
 use winit::{
     dpi::LogicalSize,
-    event::{Event, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, ModifiersState, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Fullscreen, WindowBuilder},
 };
 
 type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
 
-enum PsThreadEvent {}
+// エミュレーションスレッドからUIスレッドへの通知
+enum UiEvent {
+    CoreCrashed(String),
+}
 
-enum UiThreadEvent {}
+// 1: エラー, 2以降: ExitReason::code
+const EXIT_ERROR: i32 = 1;
 
 fn main() {
-    run().unwrap();
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(EXIT_ERROR);
+    }
+}
+
+// run と bench で共通のマシン設定
+fn machine_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("rom")
+            .short('r')
+            .long("rom")
+            .help("disc image (.bin, .iso or .cue)")
+            .takes_value(true),
+        Arg::new("bios")
+            .short('b')
+            .long("bios")
+            .help("bios file")
+            .takes_value(true)
+            .default_value("roms/bios.rom"),
+        Arg::new("exe")
+            .short('e')
+            .long("exe")
+            .help("PS-X EXE file to boot after the BIOS initialization")
+            .takes_value(true)
+            .conflicts_with("fast-boot"),
+        Arg::new("fast-boot")
+            .long("fast-boot")
+            .help("skip the BIOS shell and boot the disc EXE directly")
+            .requires("rom"),
+        Arg::new("precache")
+            .long("precache")
+            .help("load the whole disc image into memory instead of streaming it"),
+        Arg::new("controller")
+            .long("controller")
+            .help("controller connected to port 1")
+            .takes_value(true)
+            .possible_values(["digital", "dualshock"])
+            .default_value("digital"),
+        Arg::new("memcard1")
+            .long("memcard1")
+            .help("memory card image in slot 1 (created on the first write if missing)")
+            .takes_value(true),
+        Arg::new("memcard2")
+            .long("memcard2")
+            .help("memory card image in slot 2 (created on the first write if missing)")
+            .takes_value(true),
+        Arg::new("power-on")
+            .long("power-on")
+            .help("initial contents of RAM and CPU registers")
+            .takes_value(true)
+            .possible_values(["pattern", "zeros", "garbage"])
+            .default_value("pattern"),
+        Arg::new("verify-sectors")
+            .long("verify-sectors")
+            .help("check EDC/ECC of data sectors as they are read and log corrupted ones"),
+        Arg::new("region")
+            .long("region")
+            .help("console region (auto detects it from the disc or the BIOS)")
+            .takes_value(true)
+            .possible_values(["auto", "japan", "north-america", "europe"])
+            .default_value("auto"),
+    ]
+}
+
+// ホストのスレッドの割り当て。既定ではOSに任せる
+fn thread_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("emu-cores")
+            .long("emu-cores")
+            .help("pin the emulation thread to cores (e.g. 2 or 2-3)")
+            .takes_value(true),
+        Arg::new("emu-priority")
+            .long("emu-priority")
+            .help("priority of the emulation thread (high may need privileges)")
+            .takes_value(true)
+            .possible_values(["normal", "high"])
+            .default_value("normal"),
+        Arg::new("ui-cores")
+            .long("ui-cores")
+            .help("pin the window and rendering thread to cores")
+            .takes_value(true),
+        Arg::new("ui-priority")
+            .long("ui-priority")
+            .help("priority of the window and rendering thread")
+            .takes_value(true)
+            .possible_values(["normal", "high"])
+            .default_value("normal"),
+    ]
+}
+
+fn thread_config(matches: &ArgMatches, prefix: &str) -> DynResult<ThreadConfig> {
+    let cores = match matches.value_of(format!("{}-cores", prefix)) {
+        Some(cores) => Some(affinity::parse_cores(cores)?),
+        None => None,
+    };
+    let priority = match matches.value_of(format!("{}-priority", prefix)).unwrap() {
+        "high" => Priority::High,
+        _ => Priority::Normal,
+    };
+
+    Ok(ThreadConfig { cores, priority })
+}
+
+// 実績は achievements フィーチャーを有効にしたときだけ使える
+#[cfg(feature = "achievements")]
+fn achievement_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("achievements")
+            .long("achievements")
+            .help("RetroAchievements set to evaluate (XXX-User.txt format)")
+            .takes_value(true),
+        Arg::new("ra-login")
+            .long("ra-login")
+            .help("file with the RetroAchievements username and token")
+            .takes_value(true)
+            .requires("achievements"),
+    ]
+}
+
+#[cfg(not(feature = "achievements"))]
+fn achievement_args() -> Vec<Arg<'static>> {
+    vec![]
 }
 
 fn run() -> DynResult<()> {
@@ -45,116 +192,1119 @@ fn run() -> DynResult<()> {
         .about("PlayStation Emulator")
         .version("0.1.0")
         .author("mjhd <mjhd.devlion@gmail.com>")
-        .arg(
-            Arg::new("debug")
-                .short('d')
-                .long("debug")
-                .help("enable gdb remote debugging"),
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("run")
+                .about("run the emulator")
+                .args(machine_args())
+                .args(achievement_args())
+                .args(thread_args())
+                .arg(
+                    Arg::new("debug")
+                        .short('d')
+                        .long("debug")
+                        .help("enable gdb remote debugging"),
+                )
+                .arg(
+                    Arg::new("state")
+                        .short('s')
+                        .long("state")
+                        .help("savestate file to resume from")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("autosave")
+                        .long("autosave")
+                        .help("write back memory cards and save the state every N seconds")
+                        .takes_value(true)
+                        .conflicts_with("debug"),
+                )
+                .arg(
+                    Arg::new("autosave-keep")
+                        .long("autosave-keep")
+                        .help("number of autosaves to keep")
+                        .takes_value(true)
+                        .default_value("3")
+                        .requires("autosave"),
+                )
+                .arg(
+                    Arg::new("autosave-dir")
+                        .long("autosave-dir")
+                        .help("directory to write autosaves to")
+                        .takes_value(true)
+                        .default_value("saves")
+                        .requires("autosave"),
+                )
+                .arg(
+                    Arg::new("splits")
+                        .long("splits")
+                        .help("auto-splitter rules to send to LiveSplit Server")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("livesplit")
+                        .long("livesplit")
+                        .help("LiveSplit Server address")
+                        .takes_value(true)
+                        .default_value(autosplit::DEFAULT_SERVER)
+                        .requires("splits"),
+                )
+                .arg(
+                    Arg::new("protect")
+                        .long("protect")
+                        .help("drop and report CPU writes to ADDR:LEN")
+                        .takes_value(true)
+                        .multiple_occurrences(true),
+                )
+                .arg(
+                    Arg::new("exit-on-halt")
+                        .long("exit-on-halt")
+                        .help("exit when the CPU branches to itself")
+                        .conflicts_with("debug"),
+                )
+                .arg(
+                    Arg::new("exit-on-pc")
+                        .long("exit-on-pc")
+                        .help("exit when the PC reaches the address")
+                        .takes_value(true)
+                        .conflicts_with("debug"),
+                )
+                .arg(
+                    Arg::new("exit-after-frames")
+                        .long("exit-after-frames")
+                        .help("exit after N frames")
+                        .takes_value(true)
+                        .conflicts_with("debug"),
+                )
+                .arg(
+                    Arg::new("screenshot-dir")
+                        .long("screenshot-dir")
+                        .help("directory to write screenshots (F10) to")
+                        .takes_value(true)
+                        .default_value("screenshots"),
+                )
+                .arg(
+                    Arg::new("screenshot-on-exit")
+                        .long("screenshot-on-exit")
+                        .help("write the last frame as PNG when an exit condition is met")
+                        .takes_value(true)
+                        .conflicts_with("debug"),
+                )
+                .arg(
+                    Arg::new("fullscreen")
+                        .long("fullscreen")
+                        .help("start in borderless fullscreen (toggle with Alt+Enter)"),
+                )
+                .arg(
+                    Arg::new("monitor")
+                        .long("monitor")
+                        .help("index of the monitor to use for fullscreen")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("enhanced-color")
+                        .long("enhanced-color")
+                        .help("draw without 15-bit truncation and dithering (toggle with F7)"),
+                )
+                .arg(
+                    Arg::new("scaling")
+                        .long("scaling")
+                        .help("how to fit the display to the window")
+                        .takes_value(true)
+                        .possible_values(["stretch", "aspect", "integer"])
+                        .default_value("aspect"),
+                )
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .help("filter used to scale the display to the window")
+                        .takes_value(true)
+                        .possible_values([
+                            "nearest",
+                            "bilinear",
+                            "sharp-bilinear",
+                            "scanlines",
+                            "crt",
+                        ])
+                        .default_value("nearest"),
+                )
+                .arg(
+                    Arg::new("rumble-small")
+                        .long("rumble-small")
+                        .help("intensity of the small rumble motor (0.0-1.0, test with F6)")
+                        .takes_value(true)
+                        .default_value("1.0"),
+                )
+                .arg(
+                    Arg::new("rumble-large")
+                        .long("rumble-large")
+                        .help("scale of the large rumble motor")
+                        .takes_value(true)
+                        .default_value("1.0"),
+                )
+                .arg(
+                    Arg::new("open")
+                        .long("open")
+                        .help("choose the disc image with a file dialog (F8 swaps discs while running)")
+                        .conflicts_with("rom"),
+                )
+                .arg(
+                    Arg::new("show-stats")
+                        .long("show-stats")
+                        .help("show the emulated CPU clock and cycles per frame in the title"),
+                )
+                .arg(
+                    Arg::new("record-gpu")
+                        .long("record-gpu")
+                        .help("record GP0/GP1 commands to FILE for gpu-replay")
+                        .takes_value(true),
+                )
+                .after_help(
+                    "EXIT CODES:\n    0    window closed\n    1    error\n    \
+                     2    halted (--exit-on-halt)\n    3    PC reached (--exit-on-pc)\n    \
+                     4    frame limit reached (--exit-after-frames)",
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("measure the emulation speed")
+                .args(machine_args())
+                .args(thread_args())
+                .arg(
+                    Arg::new("seconds")
+                        .long("seconds")
+                        .help("how long to run")
+                        .takes_value(true)
+                        .default_value("10"),
+                ),
+        )
+        .subcommand(
+            Command::new("test-roms")
+                .about("run the known test ROMs found in a directory and report a score")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .help("directory with the test ROMs (see test-roms.toml)")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("registry")
+                        .long("registry")
+                        .help("test ROM list to use instead of the bundled one")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("bios")
+                        .short('b')
+                        .long("bios")
+                        .help("bios file")
+                        .takes_value(true)
+                        .default_value("roms/bios.rom"),
+                ),
+        )
+        .subcommand(
+            Command::new("gpu-replay")
+                .about("feed recorded GPU commands to the renderer without the CPU")
+                .arg(Arg::new("file").required(true)),
         )
-        .arg(
-            Arg::new("rom")
-                .short('r')
-                .long("rom")
-                .help("rom file")
-                .takes_value(true),
+        .subcommand(
+            Command::new("mcd")
+                .about("inspect and create memory card images")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("create a formatted memory card image")
+                        .arg(Arg::new("file").required(true)),
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("list the saves on a memory card image")
+                        .arg(Arg::new("file").required(true)),
+                ),
         )
-        .arg(
-            Arg::new("bios")
-                .short('b')
-                .long("bios")
-                .help("bios file")
-                .takes_value(true),
+        .subcommand(
+            Command::new("verify-disc")
+                .about("check that a disc image is intact and bootable")
+                .arg(Arg::new("rom").required(true)),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("extract a file from a disc image")
+                .arg(Arg::new("rom").required(true))
+                .arg(
+                    Arg::new("path")
+                        .help("path on the disc (defaults to the boot EXE)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("output file")
+                        .takes_value(true),
+                ),
         )
         .get_matches();
 
-    let event_loop = EventLoop::new();
-    let size = LogicalSize::<u32>::new(1024, 512);
-    let window = WindowBuilder::new()
-        .with_title("rps")
-        .with_inner_size(size)
-        .with_min_inner_size(size)
-        .build(&event_loop)
-        .unwrap();
+    match matches.subcommand() {
+        Some(("run", matches)) => run_emulator(matches),
+        Some(("bench", matches)) => bench(matches),
+        Some(("test-roms", matches)) => test_roms(matches),
+        Some(("gpu-replay", matches)) => gpu_replay(matches),
+        Some(("mcd", matches)) => mcd(matches),
+        Some(("verify-disc", matches)) => verify_disc(matches),
+        Some(("dump", matches)) => dump(matches),
+        _ => unreachable!(),
+    }
+}
 
-    let bios = if matches.is_present("bios") {
-        Bios::new(Path::new(
-            matches.value_of::<String>("bios".to_string()).unwrap(),
-        ))
-        .unwrap()
-    } else {
-        Bios::new(Path::new("roms/bios.rom")).unwrap()
-    };
+// interactive ならBIOSが見つからないときやディスクの指定にファイル選択ダイアログを使う
+fn machine_config(matches: &ArgMatches, interactive: bool) -> DynResult<MachineConfig> {
+    let mut bios_path = PathBuf::from(matches.value_of("bios").unwrap());
 
-    let rom = if matches.is_present("rom") {
-        let rom = BufReader::new(
-            File::open(Path::new(
-                matches.value_of::<String>("rom".to_string()).unwrap(),
+    if interactive && !bios_path.exists() {
+        MessageDialog::new()
+            .set_title("rps")
+            .set_type(MessageType::Warning)
+            .set_text(&format!(
+                "BIOS not found at {}.\nPlease select a BIOS image.",
+                bios_path.display()
             ))
-            .unwrap(),
-        );
+            .show_alert()?;
 
-        Some(rom.bytes().collect::<io::Result<Vec<u8>>>().unwrap())
+        bios_path = pick_file("Select BIOS", "BIOS image", &["bin", "rom", "BIN", "ROM"])?
+            .ok_or_else(|| format!("BIOS not found: {}", bios_path.display()))?;
+    }
+
+    let bios = Bios::new(&bios_path)?;
+
+    let rom = match matches.value_of("rom") {
+        Some(rom) => Some(PathBuf::from(rom)),
+        None if interactive && matches.is_present("open") => Some(
+            pick_file(
+                "Open disc image",
+                "Disc image",
+                &["cue", "bin", "iso", "CUE", "BIN", "ISO"],
+            )?
+            .ok_or("No disc image selected")?,
+        ),
+        None => None,
+    };
+
+    let mut config = MachineConfig::new(bios);
+    config.disc = match rom {
+        Some(rom) => Some(Image::open(&rom, matches.is_present("precache"))?),
+        None => None,
+    };
+    config.boot = if let Some(exe) = matches.value_of("exe") {
+        BootMode::Sideload(std::fs::read(exe)?)
+    } else if matches.is_present("fast-boot") {
+        BootMode::FastBoot
     } else {
-        None
+        BootMode::Bios
+    };
+    config.devices[0] = match matches.value_of("controller").unwrap() {
+        "dualshock" => Device::DualShock,
+        _ => Device::DigitalPad,
+    };
+    for (slot, name) in ["memcard1", "memcard2"].iter().enumerate() {
+        if let Some(path) = matches.value_of(name) {
+            config.memory_cards[slot] = Some(MemoryCard::open(Path::new(path))?);
+        }
+    }
+    config.power_on = match matches.value_of("power-on").unwrap() {
+        "zeros" => PowerOnState::Zeros,
+        "garbage" => PowerOnState::Garbage,
+        _ => PowerOnState::Pattern,
+    };
+    config.verify_sectors = matches.is_present("verify-sectors");
+    match matches.value_of("region").unwrap() {
+        "japan" => config.region = Region::Japan,
+        "north-america" => config.region = Region::NorthAmerica,
+        "europe" => config.region = Region::Europe,
+        _ => config.detect_region(),
+    }
+    config.validate()?;
+
+    Ok(config)
+}
+
+// キャンセルされたら None
+fn pick_file(title: &str, description: &str, extensions: &[&str]) -> DynResult<Option<PathBuf>> {
+    Ok(FileDialog::new()
+        .set_title(title)
+        .add_filter(description, extensions)
+        .show_open_single_file()?)
+}
+
+fn run_emulator(matches: &ArgMatches) -> DynResult<()> {
+    crash::install_hook();
+
+    let event_loop = EventLoop::<UiEvent>::with_user_event();
+
+    // 指定がなければウィンドウのあるモニタでフルスクリーンにする
+    let monitor = match matches.value_of("monitor") {
+        Some(index) => {
+            let index: usize = index.parse()?;
+            let monitors: Vec<_> = event_loop.available_monitors().collect();
+
+            let monitor = monitors.get(index).cloned().ok_or_else(|| {
+                let names: Vec<_> = monitors
+                    .iter()
+                    .enumerate()
+                    .map(|(i, m)| format!("{}: {}", i, m.name().unwrap_or_default()))
+                    .collect();
+
+                format!("monitor {} not found ({})", index, names.join(", "))
+            })?;
+
+            Some(monitor)
+        }
+        None => None,
     };
+    let fullscreen = matches.is_present("fullscreen");
+
+    // 表示は4:3で中央に出すので、ウィンドウの大きさは自由に変えられる
+    let window = WindowBuilder::new()
+        .with_title("rps")
+        .with_inner_size(LogicalSize::<u32>::new(960, 720))
+        .with_min_inner_size(LogicalSize::<u32>::new(320, 240))
+        .with_fullscreen(fullscreen.then(|| Fullscreen::Borderless(monitor.clone())))
+        .build(&event_loop)
+        .unwrap();
+
+    let config = machine_config(matches, true)?;
 
     let renderer = Renderer::new(&window);
     let gpu = Gpu::new(renderer);
 
-    let (ps_sender, ps_receiver) = mpsc::sync_channel::<PsThreadEvent>(1);
-    let (ui_sender, ui_receiver) = mpsc::sync_channel::<UiThreadEvent>(1);
+    let debug = matches.is_present("debug");
+    let mut ps = Ps::new(config, gpu)?;
+    ps.set_scaling(match matches.value_of("scaling").unwrap() {
+        "stretch" => Scaling::Stretch,
+        "integer" => Scaling::Integer,
+        _ => Scaling::Aspect,
+    });
+    ps.set_true_color(matches.is_present("enhanced-color"));
+    ps.set_filter(match matches.value_of("filter").unwrap() {
+        "bilinear" => Filter::Bilinear,
+        "sharp-bilinear" => Filter::SharpBilinear,
+        "scanlines" => Filter::Scanlines,
+        "crt" => Filter::Crt,
+        _ => Filter::Nearest,
+    });
+
+    if let Some(state) = matches.value_of("state") {
+        ps.load_state(&std::fs::read(state)?)?;
+    }
+
+    if let Some(path) = matches.value_of("record-gpu") {
+        ps.start_gpu_recording(Path::new(path))?;
+    }
+
+    for range in matches.values_of("protect").into_iter().flatten() {
+        let (addr, len) = range
+            .split_once(':')
+            .ok_or_else(|| format!("invalid range {}, expected ADDR:LEN", range))?;
+
+        ps.cpu_mut()
+            .protect(parse_address(addr)?, parse_address(len)?);
+    }
+
+    if let Some(splits) = matches.value_of("splits") {
+        let rules = autosplit::parse_rules(&std::fs::read_to_string(splits)?)?;
+        let livesplit = LiveSplit::connect(matches.value_of("livesplit").unwrap())?;
+
+        livesplit.install(&rules, ps.triggers_mut());
+    }
+
+    #[cfg(feature = "achievements")]
+    install_achievements(matches, &mut ps)?;
+
+    let exit = ExitConditions {
+        on_halt: matches.is_present("exit-on-halt"),
+        pc: match matches.value_of("exit-on-pc") {
+            Some(pc) => Some(parse_address(pc)?),
+            None => None,
+        },
+        frames: match matches.value_of("exit-after-frames") {
+            Some(frames) => Some(frames.parse()?),
+            None => None,
+        },
+    };
+
+    let mut rumble = Rumble::new(
+        LogRumble,
+        RumbleScale {
+            small: matches.value_of("rumble-small").unwrap().parse()?,
+            large: matches.value_of("rumble-large").unwrap().parse()?,
+        },
+    );
+
+    let emu_thread = thread_config(matches, "emu")?;
+    thread_config(matches, "ui")?.apply("UI");
+
+    let screenshot_dir = PathBuf::from(matches.value_of("screenshot-dir").unwrap());
+    let precache = matches.is_present("precache");
+    let screenshot_on_exit = matches.value_of("screenshot-on-exit").map(PathBuf::from);
+
+    let shared = Arc::new(SharedPs::new(ps));
+
+    if let Some(interval) = matches.value_of("autosave") {
+        let autosave = Autosave::new(
+            PathBuf::from(matches.value_of("autosave-dir").unwrap()),
+            Duration::from_secs(interval.parse()?),
+            matches.value_of("autosave-keep").unwrap().parse()?,
+        )?;
+
+        autosave.spawn(Arc::clone(&shared));
+    }
 
     {
-        thread::spawn(move || {
-            smol::block_on(async {
-                let inter = Interconnect::new(bios, gpu, rom);
-                let mut cpu = Cpu::new(inter);
+        let shared = Arc::clone(&shared);
+        let proxy = event_loop.create_proxy();
 
-                if !matches.is_present("debug") {
-                    while cpu.step() != Some(cpu::Event::Halted) {}
+        thread::spawn(move || {
+            emu_thread.apply("emulation");
 
-                    return;
-                }
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                smol::block_on(async {
+                    if !debug {
+                        let reason = shared.run(&exit);
+                        println!("Exit: {:?}", reason);
 
-                let connection: Box<dyn ConnectionExt<Error = std::io::Error>> =
-                    Box::new(wait_for_tcp(9001).unwrap());
-                let gdb = GdbStub::new(connection);
-                match gdb.run_blocking::<EmuGdbEventLoop>(&mut cpu) {
-                    Ok(disconnect_reason) => match disconnect_reason {
-                        DisconnectReason::Disconnect => {
-                            println!("GDB client has disconnected. Running to completion...");
-                            while cpu.step() != Some(cpu::Event::Halted) {}
+                        if let Err(e) = shared.lock().stop_gpu_recording() {
+                            eprintln!("Failed to finish GPU recording: {}", e);
                         }
-                        DisconnectReason::TargetExited(code) => {
-                            println!("Target exited with code {}!", code)
+
+                        if let Err(e) = shared.lock().flush_memory_cards() {
+                            eprintln!("Failed to write memory cards: {}", e);
                         }
-                        DisconnectReason::TargetTerminated(sig) => {
-                            println!("Target terminated with signal {}!", sig)
+
+                        if let Some(path) = &screenshot_on_exit {
+                            if let Err(e) = shared.lock().capture_frame().write_png(path) {
+                                eprintln!("Failed to write screenshot: {}", e);
+                            }
                         }
-                        DisconnectReason::Kill => println!("GDB sent a kill command!"),
-                    },
-                    Err(GdbStubError::TargetError(e)) => {
-                        println!("target encountered a fatal error: {}", e)
-                    }
-                    Err(e) => {
-                        println!("gdbstub encountered a fatal error: {}", e)
+                        process::exit(reason.code());
                     }
-                };
-            });
+
+                    let mut ps = shared.lock();
+                    let cpu = ps.cpu_mut();
+
+                    let connection: Box<dyn ConnectionExt<Error = std::io::Error>> =
+                        Box::new(wait_for_tcp(9001).unwrap());
+                    let gdb = GdbStub::new(connection);
+                    match gdb.run_blocking::<EmuGdbEventLoop>(cpu) {
+                        Ok(disconnect_reason) => match disconnect_reason {
+                            DisconnectReason::Disconnect => {
+                                println!("GDB client has disconnected. Running to completion...");
+                                while cpu.step() != Some(cpu::Event::Halted) {}
+                            }
+                            DisconnectReason::TargetExited(code) => {
+                                println!("Target exited with code {}!", code)
+                            }
+                            DisconnectReason::TargetTerminated(sig) => {
+                                println!("Target terminated with signal {}!", sig)
+                            }
+                            DisconnectReason::Kill => println!("GDB sent a kill command!"),
+                        },
+                        Err(GdbStubError::TargetError(e)) => {
+                            println!("target encountered a fatal error: {}", e)
+                        }
+                        Err(e) => {
+                            println!("gdbstub encountered a fatal error: {}", e)
+                        }
+                    };
+                })
+            }));
+
+            // コアがパニックしたら調査用に状態を書き出してUIスレッドに知らせる
+            if result.is_err() {
+                let message = crash::panic_message();
+                let mut ps = shared.recover();
+
+                // カードには書き込みの終わったセクタしか反映されないので、パニック後でも書き戻せる
+                if let Err(e) = ps.flush_memory_cards() {
+                    eprintln!("Failed to write memory cards: {}", e);
+                }
+
+                match crash::write_report(Path::new(crash::DEFAULT_DIR), &mut ps, &message) {
+                    Ok(path) => eprintln!("Crash report written to {}", path.display()),
+                    Err(e) => eprintln!("Failed to write crash report: {}", e),
+                }
+
+                let _ = proxy.send_event(UiEvent::CoreCrashed(message));
+            }
         });
     }
 
+    let mut crashed = false;
+    let mut modifiers = ModifiersState::empty();
+    // プリミティブのデバッグ表示中はタイトルにコマンド数を出す
+    let mut primitive_debug = false;
+    // エミュレートしているCPUのクロックと、フレームあたりのサイクル数をタイトルに出す
+    let show_stats = matches.is_present("show-stats");
+    let mut title_cycles = shared.pause().cycles();
+    let mut title_updated = Instant::now();
+    let mut rumble_updated = Instant::now();
+
     event_loop.run(move |event, _, control_flow| match event {
+        Event::UserEvent(UiEvent::CoreCrashed(message)) => {
+            crashed = true;
+            window.set_title(&format!("rps - crashed: {}", message));
+            *control_flow = ControlFlow::Wait;
+        }
         Event::WindowEvent {
             event: WindowEvent::CloseRequested,
             ..
-        } => *control_flow = ControlFlow::Exit,
+        } => {
+            // GDBのセッション中はコアを止められない
+            if !debug && !crashed {
+                if let Err(e) = shared.pause().flush_memory_cards() {
+                    eprintln!("Failed to write memory cards: {}", e);
+                }
+            }
+            *control_flow = ControlFlow::Exit;
+        }
+        Event::WindowEvent {
+            event: WindowEvent::ModifiersChanged(state),
+            ..
+        } => modifiers = state,
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::Return),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if modifiers.alt() && !debug && !crashed => {
+            // 大きさの変更は Resized で反映される
+            let fullscreen = window.fullscreen().is_none();
+            window.set_fullscreen(fullscreen.then(|| Fullscreen::Borderless(monitor.clone())));
+        }
+        Event::WindowEvent {
+            event: WindowEvent::Resized(size),
+            ..
+        } if !debug && !crashed => shared.pause().resize(size.width, size.height),
+        // 別のDPIのモニタに移ったとき
+        Event::WindowEvent {
+            event: WindowEvent::ScaleFactorChanged { new_inner_size, .. },
+            ..
+        } if !debug && !crashed => shared
+            .pause()
+            .resize(new_inner_size.width, new_inner_size.height),
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F12),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if !debug && !crashed => {
+            // 命令の境界で止めてレジスタを表示する
+            let ps = shared.pause();
+            let cpu = ps.cpu();
+
+            println!("pc: {:08x} hi: {:08x} lo: {:08x}", cpu.pc(), cpu.hi, cpu.lo);
+            for (i, reg) in cpu.regs.iter().enumerate() {
+                println!("r{:02}: {:08x}", i, reg);
+            }
+        }
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F11),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if !debug && !crashed => {
+            // 表示範囲とVRAM全体の表示を切り替える
+            let mut ps = shared.pause();
+            let enabled = !ps.vram_view();
+            ps.set_vram_view(enabled);
+        }
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F10),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if !debug && !crashed => {
+            let mut ps = shared.pause();
+
+            match save_screenshot(&screenshot_dir, &mut ps) {
+                Ok(path) => println!("Screenshot written to {}", path.display()),
+                Err(e) => eprintln!("Failed to write screenshot: {}", e),
+            }
+        }
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F9),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if !debug && !crashed => {
+            primitive_debug = !primitive_debug;
+            shared.pause().set_primitive_debug(primitive_debug);
+
+            if !primitive_debug {
+                window.set_title("rps");
+            }
+        }
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F7),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if !debug && !crashed => {
+            // 実機の色と拡張カラーを切り替える
+            let mut ps = shared.pause();
+            let enabled = !ps.true_color();
+            ps.set_true_color(enabled);
+        }
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F8),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if !debug && !crashed => {
+            // 1回目でふたを開け、2回目で選んだディスクを入れて閉じる
+            let shell_open = shared.pause().shell_open();
+
+            if !shell_open {
+                shared.pause().eject_disc();
+                println!("Disc ejected, press F8 again to insert a disc");
+                return;
+            }
+
+            let path = match pick_file(
+                "Insert disc image",
+                "Disc image",
+                &["cue", "bin", "iso", "CUE", "BIN", "ISO"],
+            ) {
+                Ok(Some(path)) => path,
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Failed to open file dialog: {}", e);
+                    return;
+                }
+            };
+
+            match Image::open(&path, precache) {
+                Ok(disc) => shared.pause().insert_disc(disc),
+                Err(e) => eprintln!("Failed to open {}: {}", path.display(), e),
+            }
+        }
+        Event::MainEventsCleared
+            if (primitive_debug || show_stats)
+                && !crashed
+                && title_updated.elapsed() >= Duration::from_millis(500) =>
+        {
+            let (counts, cycles, budget) = {
+                let ps = shared.pause();
+                (ps.primitive_counts(), ps.cycles(), ps.cycle_budget())
+            };
+
+            let mut title = String::from("rps");
+            if show_stats {
+                // ステートを読み込むとサイクル数が戻ることがある
+                let elapsed = title_updated.elapsed().as_secs_f64();
+                let mhz = cycles.saturating_sub(title_cycles) as f64 / elapsed / 1_000_000.0;
+                title += &format!(" - {:.2} MHz, {}", mhz, budget);
+            }
+            if primitive_debug {
+                title += &format!(" - {}", counts);
+            }
+            window.set_title(&title);
+
+            title_cycles = cycles;
+            title_updated = Instant::now();
+        }
+        Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F6),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } if !debug && !crashed => rumble.test(Instant::now()),
+        Event::MainEventsCleared
+            if !debug && !crashed && rumble_updated.elapsed() >= RUMBLE_INTERVAL =>
+        {
+            let motors = {
+                let ps = shared.pause();
+                [ps.motors(0), ps.motors(1)]
+            };
+
+            rumble_updated = Instant::now();
+            rumble.update(motors, rumble_updated);
+        }
+        _ if crashed => *control_flow = ControlFlow::Wait,
         _ => {
             *control_flow = ControlFlow::Poll;
         }
     });
 }
 
+#[cfg(feature = "achievements")]
+fn install_achievements(matches: &ArgMatches, ps: &mut Ps) -> DynResult<()> {
+    let path = match matches.value_of("achievements") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let achievements = achievements::parse_achievements(&std::fs::read_to_string(path)?)?;
+    println!("Loaded {} achievements", achievements.len());
+
+    let mut runtime = Runtime::new(
+        achievements,
+        Box::new(|unlock| {
+            let achievement = unlock.achievement;
+            println!(
+                "Achievement unlocked at frame {}: {} ({} points) - {}",
+                unlock.frame, achievement.title, achievement.points, achievement.description
+            );
+        }),
+    );
+
+    if let Some(login) = matches.value_of("ra-login") {
+        runtime.login(Credentials::load(Path::new(login))?);
+    }
+
+    ps.set_achievements(Some(runtime));
+
+    Ok(())
+}
+
+// 振動の状態を確認する間隔
+const RUMBLE_INTERVAL: Duration = Duration::from_millis(50);
+
+// ホストのパッドを扱う仕組みがまだないので、振動の変化をログに出す
+struct LogRumble;
+
+impl RumbleOutput for LogRumble {
+    fn set(&mut self, port: usize, strength: Strength) {
+        println!(
+            "Rumble port {}: weak {:.2} strong {:.2}",
+            port + 1,
+            strength.weak,
+            strength.strong
+        );
+    }
+}
+
+// フレーム数をファイル名にして書き出す
+fn save_screenshot(dir: &Path, ps: &mut Ps) -> DynResult<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("rps-{:08}.png", ps.frames()));
+    ps.capture_frame().write_png(&path)?;
+
+    Ok(path)
+}
+
+// 画面には何も出さずにCPUを回して1秒あたりの命令数を測る
+fn bench(matches: &ArgMatches) -> DynResult<()> {
+    let seconds: u64 = matches.value_of("seconds").unwrap().parse()?;
+
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title("rps")
+        .with_visible(false)
+        .build(&event_loop)?;
+
+    let config = machine_config(matches, false)?;
+    let gpu = Gpu::new(Renderer::new(&window));
+    let mut ps = Ps::new(config, gpu)?;
+
+    // エミュレーションはこのスレッドで回す
+    thread_config(matches, "emu")?.apply("emulation");
+
+    let duration = Duration::from_secs(seconds);
+    let start = Instant::now();
+    let mut instructions: u64 = 0;
+    let start_frame = ps.frames();
+    let start_cycles = ps.cycles();
+
+    'bench: while start.elapsed() < duration {
+        // 時刻の取得は重いので一定数ごとに確認する
+        for _ in 0..100_000 {
+            instructions += 1;
+            if ps.step() == Some(cpu::Event::Halted) {
+                break 'bench;
+            }
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let per_second = instructions as f64 / elapsed;
+
+    println!("instructions: {}", instructions);
+    println!("elapsed:      {:.3}s", elapsed);
+    println!("speed:        {:.2} MIPS", per_second / 1_000_000.0);
+
+    // 実機のリフレッシュレートに対する速度
+    let fps = ps.frames().wrapping_sub(start_frame) as f64 / elapsed;
+    let refresh_rate = ps.refresh_rate();
+    println!(
+        "frames:       {:.2} fps ({:.1}% of {:.2} Hz)",
+        fps,
+        fps / refresh_rate * 100.0,
+        refresh_rate
+    );
+
+    // 1フレームあたりのサイクル数が本来の数からずれていないか
+    let cycles = ps.cycles() - start_cycles;
+    let clock = cycles as f64 / elapsed;
+    println!(
+        "clock:        {:.2} MHz ({:.1}% of {:.2} MHz)",
+        clock / 1_000_000.0,
+        clock / CPU_CLOCK as f64 * 100.0,
+        CPU_CLOCK as f64 / 1_000_000.0
+    );
+
+    let frames = ps.frames().wrapping_sub(start_frame);
+    if frames > 0 {
+        println!(
+            "cycles/frame: {:.0} average, last {}",
+            cycles as f64 / frames as f64,
+            ps.cycle_budget()
+        );
+    }
+
+    Ok(())
+}
+
+// 記録したGPUコマンドを最後まで流して描画の速度を測る
+fn gpu_replay(matches: &ArgMatches) -> DynResult<()> {
+    let log = CommandLog::read(Path::new(matches.value_of("file").unwrap()))?;
+
+    let event_loop = EventLoop::new();
+    let size = LogicalSize::<u32>::new(1024, 512);
+    let window = WindowBuilder::new()
+        .with_title("rps - gpu replay")
+        .with_inner_size(size)
+        .build(&event_loop)?;
+
+    let mut gpu = Gpu::new(Renderer::new(&window));
+    let mut replayer = Replayer::new(log, &mut gpu)?;
+
+    let start = Instant::now();
+    let mut frames: u32 = 0;
+
+    while replayer.run_frame(&mut gpu) {
+        frames += 1;
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!("frames:       {}", frames);
+    println!("elapsed:      {:.3}s", elapsed);
+    println!("speed:        {:.2} fps", frames as f64 / elapsed);
+
+    Ok(())
+}
+
+fn mcd(matches: &ArgMatches) -> DynResult<()> {
+    match matches.subcommand() {
+        Some(("create", matches)) => {
+            let file = Path::new(matches.value_of("file").unwrap());
+            if file.exists() {
+                return Err(format!("{} already exists", file.display()).into());
+            }
+
+            std::fs::write(file, memcard::format())?;
+        }
+        Some(("list", matches)) => {
+            let data = std::fs::read(matches.value_of("file").unwrap())?;
+
+            let mut used = 0;
+            for entry in memcard::directory(&data)? {
+                if entry.state != BlockState::First {
+                    continue;
+                }
+
+                let blocks = (entry.size as usize + memcard::BLOCK_SIZE - 1) / memcard::BLOCK_SIZE;
+                used += blocks;
+
+                println!("{:2}: {:<21} {} block(s)", entry.block, entry.name, blocks);
+            }
+
+            println!("{} of {} blocks used", used, memcard::BLOCKS - 1);
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+// 見つかったテストROMをBIOSから起動し、TTY の出力で判定する
+fn test_roms(matches: &ArgMatches) -> DynResult<()> {
+    let registry = match matches.value_of("registry") {
+        Some(path) => Registry::load(Path::new(path))?,
+        None => Registry::parse(test_roms::REGISTRY)?,
+    };
+    let dir = PathBuf::from(matches.value_of("dir").unwrap());
+    let bios = Path::new(matches.value_of("bios").unwrap());
+
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title("rps")
+        .with_visible(false)
+        .build(&event_loop)?;
+
+    let mut results = vec![];
+
+    for rom in &registry.roms {
+        let path = dir.join(&rom.file);
+        if !path.exists() {
+            println!("SKIP     {} ({})", rom.name, rom.category);
+            results.push(None);
+            continue;
+        }
+
+        let mut config = MachineConfig::new(Bios::new(bios)?);
+        config.boot = BootMode::Sideload(std::fs::read(&path)?);
+
+        let mut ps = Ps::new(config, Gpu::new(Renderer::new(&window)))?;
+        let outcome = rom.run(&mut ps);
+
+        println!(
+            "{:<8} {} ({})",
+            format!("{:?}", outcome).to_uppercase(),
+            rom.name,
+            rom.category
+        );
+        results.push(Some(outcome));
+    }
+
+    let score = Score::new(&results);
+    println!(
+        "score: {}/{} ({:.1}%), {} not found",
+        score.passed,
+        score.run,
+        score.percent(),
+        score.skipped
+    );
+
+    if score.passed < score.run {
+        return Err(format!("{} test ROM(s) did not pass", score.run - score.passed).into());
+    }
+
+    Ok(())
+}
+
+fn verify_disc(matches: &ArgMatches) -> DynResult<()> {
+    let disc = Image::open(Path::new(matches.value_of("rom").unwrap()), true)?;
+
+    let broken = iso9660::broken_sectors(&disc);
+    if !broken.is_empty() {
+        return Err(format!(
+            "{} sector(s) without sync pattern, first at {}",
+            broken.len(),
+            broken[0]
+        )
+        .into());
+    }
+
+    let damaged = iso9660::damaged_sectors(&disc);
+    if !damaged.is_empty() {
+        let (lba, damage) = damaged[0];
+        return Err(format!(
+            "{} sector(s) with bad EDC/ECC, first at {} ({:?})",
+            damaged.len(),
+            lba,
+            damage
+        )
+        .into());
+    }
+
+    let path = iso9660::boot_path(&disc)?;
+    let exe = Exe::parse(&iso9660::read_file(&disc, &path)?)?;
+
+    println!("boot:  {}", path);
+    println!("pc:    {:08x}", exe.pc);
+    println!("load:  {:08x} ({} bytes)", exe.load_addr, exe.data.len());
+    println!("OK");
+
+    Ok(())
+}
+
+fn dump(matches: &ArgMatches) -> DynResult<()> {
+    let disc = Image::open(Path::new(matches.value_of("rom").unwrap()), true)?;
+
+    let path = match matches.value_of("path") {
+        Some(path) => path.to_string(),
+        None => iso9660::boot_path(&disc)?,
+    };
+    let data = iso9660::read_file(&disc, &path)?;
+
+    match matches.value_of("output") {
+        Some(output) => std::fs::write(output, data)?,
+        None => io::stdout().write_all(&data)?,
+    }
+
+    Ok(())
+}
+
+fn parse_address(val: &str) -> DynResult<u32> {
+    let hex = val
+        .strip_prefix("0x")
+        .or_else(|| val.strip_prefix("0X"))
+        .unwrap_or(val);
+
+    Ok(u32::from_str_radix(hex, 16)?)
+}
+
 fn wait_for_tcp(port: u16) -> DynResult<TcpStream> {
     let sockaddr = format!("127.0.0.1:{}", port);
     eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
@@ -210,6 +1360,12 @@ impl run_blocking::BlockingEventLoop for EmuGdbEventLoop {
                             kind: WatchKind::Read,
                             addr,
                         },
+                        // 書き込み保護はウォッチポイントと同じように止める
+                        cpu::Event::WriteProtected(addr) => SingleThreadStopReason::Watch {
+                            tid: (),
+                            kind: WatchKind::Write,
+                            addr,
+                        },
                     };
 
                     Ok(run_blocking::Event::TargetStopped(stop_reason))
@@ -0,0 +1,408 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use log::debug;
+
+use crate::savestate::{Reader, Savestate, Writer};
+
+pub const FRAME_SIZE: usize = 128;
+pub const BLOCK_SIZE: usize = 64 * FRAME_SIZE;
+pub const BLOCKS: usize = 16;
+pub const CARD_SIZE: usize = BLOCKS * BLOCK_SIZE;
+
+// 管理ブロック (ブロック0) のフレーム配置
+const DIRECTORY_FRAMES: usize = 15;
+const BROKEN_FRAMES: usize = 20;
+const WRITE_TEST_FRAME: usize = 63;
+
+const FREE: u32 = 0xA0;
+
+// FLAG のbit3: 電源投入後にまだ書き込まれていない
+const FLAG_NOT_WRITTEN: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    Free,
+    First,
+    Middle,
+    Last,
+    Deleted,
+}
+
+impl BlockState {
+    fn from_u32(val: u32) -> Option<BlockState> {
+        match val {
+            0xA0 => Some(BlockState::Free),
+            0x51 => Some(BlockState::First),
+            0x52 => Some(BlockState::Middle),
+            0x53 => Some(BlockState::Last),
+            0xA1..=0xA3 => Some(BlockState::Deleted),
+            _ => None,
+        }
+    }
+}
+
+pub struct DirectoryEntry {
+    // データブロックの番号 (1-15)
+    pub block: usize,
+    pub state: BlockState,
+    pub size: u32,
+    pub next: Option<usize>,
+    pub name: String,
+}
+
+fn frame(data: &[u8], n: usize) -> &[u8] {
+    &data[n * FRAME_SIZE..(n + 1) * FRAME_SIZE]
+}
+
+fn checksum(frame: &[u8]) -> u8 {
+    frame[..FRAME_SIZE - 1].iter().fold(0, |acc, b| acc ^ b)
+}
+
+fn write_frame(data: &mut [u8], n: usize, frame: &[u8; FRAME_SIZE]) {
+    let mut frame = *frame;
+    frame[FRAME_SIZE - 1] = checksum(&frame);
+
+    data[n * FRAME_SIZE..(n + 1) * FRAME_SIZE].copy_from_slice(&frame);
+}
+
+// 初期化済みの空のメモリーカードイメージを作る
+pub fn format() -> Vec<u8> {
+    let mut data = vec![0; CARD_SIZE];
+
+    let mut header = [0; FRAME_SIZE];
+    header[..2].copy_from_slice(b"MC");
+    write_frame(&mut data, 0, &header);
+
+    let mut free = [0; FRAME_SIZE];
+    free[..4].copy_from_slice(&FREE.to_le_bytes());
+    free[8..10].copy_from_slice(&0xFFFFu16.to_le_bytes());
+    for n in 1..=DIRECTORY_FRAMES {
+        write_frame(&mut data, n, &free);
+    }
+
+    let mut broken = [0; FRAME_SIZE];
+    broken[..4].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+    broken[8..10].copy_from_slice(&0xFFFFu16.to_le_bytes());
+    for n in DIRECTORY_FRAMES + 1..=DIRECTORY_FRAMES + BROKEN_FRAMES {
+        write_frame(&mut data, n, &broken);
+    }
+
+    write_frame(&mut data, WRITE_TEST_FRAME, &header);
+
+    data
+}
+
+pub fn directory(data: &[u8]) -> Result<Vec<DirectoryEntry>> {
+    if data.len() != CARD_SIZE {
+        bail!("Invalid memory card size: {} bytes", data.len());
+    }
+
+    if &frame(data, 0)[..2] != b"MC" {
+        bail!("Memory card header not found");
+    }
+
+    let mut entries = vec![];
+
+    for n in 1..=DIRECTORY_FRAMES {
+        let frame = frame(data, n);
+
+        if checksum(frame) != frame[FRAME_SIZE - 1] {
+            bail!("Checksum mismatch in directory frame {}", n);
+        }
+
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                frame[offset],
+                frame[offset + 1],
+                frame[offset + 2],
+                frame[offset + 3],
+            ])
+        };
+
+        let raw_state = word(0);
+        let state = match BlockState::from_u32(raw_state) {
+            Some(state) => state,
+            None => bail!("Unknown block state {:08x} in frame {}", raw_state, n),
+        };
+
+        let next = match u16::from_le_bytes([frame[8], frame[9]]) {
+            0xFFFF => None,
+            next => Some(next as usize + 1),
+        };
+
+        let name = &frame[0x0A..0x0A + 21];
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+
+        entries.push(DirectoryEntry {
+            block: n,
+            state,
+            size: word(4),
+            next,
+            name: String::from_utf8_lossy(&name[..len]).to_string(),
+        });
+    }
+
+    Ok(entries)
+}
+
+// コントローラーポートに挿すメモリーカード
+// 中身はファイルと対応させ、書き込まれたら flush で書き戻す
+// セーブステートには通信の状態だけを入れ、カードの中身は入れない
+pub struct MemoryCard {
+    path: PathBuf,
+    data: Vec<u8>,
+    // ファイルに書き戻していない書き込みがある
+    dirty: bool,
+    session: Session,
+}
+
+// 通信の状態。カードが挿さっていなくてもセーブステートの形を変えないように分けておく
+#[derive(Clone, Copy)]
+pub struct Session {
+    flag: u8,
+    // 通信中のコマンドとセクタ (フレーム) 番号
+    command: u8,
+    sector: u16,
+    checksum: u8,
+    // 直前に受け取ったバイト。応答が1バイト遅れて返る
+    prev: u8,
+    buffer: [u8; FRAME_SIZE],
+}
+
+impl MemoryCard {
+    // ファイルがなければフォーマット済みの空のカードにする (最初に書き込まれた後の flush で作られる)
+    pub fn open(path: &Path) -> Result<MemoryCard> {
+        let data = if path.exists() {
+            let data = fs::read(path)?;
+            if data.len() != CARD_SIZE {
+                bail!(
+                    "Invalid memory card size: {} bytes in {}",
+                    data.len(),
+                    path.display()
+                );
+            }
+
+            data
+        } else {
+            format()
+        };
+
+        Ok(MemoryCard {
+            path: path.to_path_buf(),
+            data,
+            dirty: false,
+            session: Session::new(),
+        })
+    }
+
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    pub fn set_session(&mut self, session: Session) {
+        self.session = session;
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // 書き込み中に落ちても古いイメージが壊れないように一時ファイルから移す
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, &self.data)?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    // コマンドを受け取ってFLAGを返す
+    pub fn start(&mut self, command: u8) -> u8 {
+        self.session.command = command;
+
+        self.session.flag
+    }
+
+    // コマンドの後の index 番目のバイトを受け取って応答を返す。最後のバイトなら true
+    pub fn payload(&mut self, index: u8, val: u8) -> (u8, bool) {
+        let prev = std::mem::replace(&mut self.session.prev, val);
+
+        match self.session.command {
+            // 'R' セクタの読み込み
+            0x52 => self.read(index, val, prev),
+            // 'W' セクタの書き込み
+            0x57 => self.write(index, val, prev),
+            // 'S' ID
+            0x53 => {
+                let res = [0x5A, 0x5D, 0x5C, 0x5D, 0x04, 0x00, 0x00, 0x80][index as usize];
+
+                (res, index == 7)
+            }
+            _ => {
+                debug!("MEMCARD unhandled COMMAND {:02x}", self.session.command);
+                (0xFF, true)
+            }
+        }
+    }
+
+    fn valid_sector(&self) -> bool {
+        (self.session.sector as usize) < CARD_SIZE / FRAME_SIZE
+    }
+
+    fn set_sector_byte(&mut self, index: u8, val: u8) {
+        match index {
+            2 => self.session.sector = (val as u16) << 8,
+            3 => self.session.sector |= val as u16,
+            _ => unreachable!(),
+        }
+        self.session.checksum ^= val;
+    }
+
+    fn read(&mut self, index: u8, val: u8, prev: u8) -> (u8, bool) {
+        let data_start = 8;
+        let data_end = data_start + FRAME_SIZE as u8;
+
+        let res = match index {
+            0 => {
+                self.session.checksum = 0;
+                0x5A
+            }
+            1 => 0x5D,
+            2 => {
+                self.set_sector_byte(index, val);
+                0x00
+            }
+            3 => {
+                self.set_sector_byte(index, val);
+                prev
+            }
+            4 => 0x5C,
+            5 => 0x5D,
+            // 範囲外のセクタならアドレスの代わりに 0xFFFF を返して終わる
+            6 | 7 if !self.valid_sector() => return (0xFF, index == 7),
+            6 => (self.session.sector >> 8) as u8,
+            7 => self.session.sector as u8,
+            i if i < data_end => {
+                let offset = self.session.sector as usize * FRAME_SIZE + (i - data_start) as usize;
+                let byte = self.data[offset];
+                self.session.checksum ^= byte;
+
+                byte
+            }
+            i if i == data_end => self.session.checksum,
+            _ => return (0x47, true),
+        };
+
+        (res, false)
+    }
+
+    fn write(&mut self, index: u8, val: u8, prev: u8) -> (u8, bool) {
+        let data_start = 4;
+        let data_end = data_start + FRAME_SIZE as u8;
+
+        let res = match index {
+            0 => {
+                self.session.checksum = 0;
+                0x5A
+            }
+            1 => 0x5D,
+            2 => {
+                self.set_sector_byte(index, val);
+                0x00
+            }
+            3 => {
+                self.set_sector_byte(index, val);
+                prev
+            }
+            i if i < data_end => {
+                self.session.buffer[(i - data_start) as usize] = val;
+                self.session.checksum ^= val;
+
+                prev
+            }
+            // 送られてきたチェックサムと比べる
+            i if i == data_end => {
+                self.session.checksum ^= val;
+
+                prev
+            }
+            i if i == data_end + 1 => 0x5C,
+            i if i == data_end + 2 => 0x5D,
+            _ => return (self.finish_write(), true),
+        };
+
+        (res, false)
+    }
+
+    fn finish_write(&mut self) -> u8 {
+        if !self.valid_sector() {
+            return 0xFF;
+        }
+
+        if self.session.checksum != 0 {
+            return 0x4E;
+        }
+
+        let offset = self.session.sector as usize * FRAME_SIZE;
+        self.data[offset..offset + FRAME_SIZE].copy_from_slice(&self.session.buffer);
+        self.dirty = true;
+        self.session.flag &= !FLAG_NOT_WRITTEN;
+
+        0x47
+    }
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            flag: FLAG_NOT_WRITTEN,
+            command: 0,
+            sector: 0,
+            checksum: 0,
+            prev: 0,
+            buffer: [0; FRAME_SIZE],
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+impl Savestate for Session {
+    fn save_state(&self, w: &mut Writer) {
+        w.u8(self.flag);
+        w.u8(self.command);
+        w.u16(self.sector);
+        w.u8(self.checksum);
+        w.u8(self.prev);
+        w.bytes(&self.buffer);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.flag = r.u8()?;
+        self.command = r.u8()?;
+        self.sector = r.u16()?;
+        self.checksum = r.u8()?;
+        self.prev = r.u8()?;
+        r.bytes_into(&mut self.buffer)?;
+
+        Ok(())
+    }
+}
@@ -1,16 +1,712 @@
-use crate::{cpu::cpu::Cpu, interconnect::Interconnect};
+//! エミュレータ本体のAPI
+//!
+//! ウィンドウを持たずに動かす例 (testing feature のダミーBIOSを使う)
+//!
+//! ```
+//! use rps::testing::TestMachineBuilder;
+//!
+//! let mut ps = TestMachineBuilder::new().build_ps();
+//!
+//! // 1フレーム分進めて表示中の画面を取り出す
+//! assert_eq!(ps.run_frame(), None);
+//! assert_eq!(ps.frames(), 1);
+//!
+//! let frame = ps.frame();
+//! assert_eq!(frame.pixels.len(), frame.width as usize * frame.height as usize);
+//! ```
+//!
+//! パッドの入力はフレームの前に渡す (vblank の開始で取り込まれる)
+//!
+//! ```
+//! use rps::{joypad::button, testing::TestMachineBuilder};
+//!
+//! let mut ps = TestMachineBuilder::new().build_ps();
+//!
+//! ps.set_buttons(0, button::START | button::CROSS);
+//! ps.run_frame();
+//! ps.set_buttons(0, 0);
+//! ps.run_frame();
+//! ```
+//!
+//! セーブステートから戻すと同じ状態から続けられる (フレーム数は戻らない)
+//!
+//! ```
+//! use rps::testing::TestMachineBuilder;
+//!
+//! let mut ps = TestMachineBuilder::new().build_ps();
+//! ps.run_frame();
+//!
+//! let state = ps.save_state();
+//! ps.run_frame();
+//! assert_ne!(ps.save_state(), state);
+//!
+//! ps.load_state(&state).unwrap();
+//! assert_eq!(ps.save_state(), state);
+//! ```
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use anyhow::{bail, Result};
+use log::info;
+
+#[cfg(feature = "achievements")]
+use crate::achievements::Runtime;
+use crate::{
+    cdrom::image::Image,
+    config::{BootMode, MachineConfig},
+    cpu::cpu::{Cpu, Event},
+    exe::Exe,
+    gpu::{
+        gpu::{Frame, Gpu, PrimitiveCounts},
+        postprocess::Filter,
+        renderer::Scaling,
+        screenshot::RgbaImage,
+    },
+    interconnect::Interconnect,
+    iso9660,
+    joypad::Motors,
+    rcheevos,
+    replay::{Playback, Replay, ReplayFrame},
+    savestate::{self, Reader, Savestate, Writer},
+    trigger::Triggers,
+};
 
 pub struct Ps {
     cpu: Cpu,
-    interconnect: Interconnect,
+    triggers: Triggers,
+    last_frame: u32,
+    // ホストから渡された入力。vblank の開始でパッドに取り込む
+    input: [u16; 2],
+    vblank: bool,
+    recording: Option<Replay>,
+    playback: Option<Playback>,
+    // 今のフレームが始まったときのサイクル数と、直前のフレームのサイクル数
+    frame_start: u64,
+    last_frame_cycles: u64,
+    #[cfg(feature = "achievements")]
+    achievements: Option<Runtime>,
+}
+
+// 直前のフレームで実行したCPUサイクル数と、ビデオ方式から決まる本来のサイクル数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CycleBudget {
+    pub cycles: u64,
+    pub budget: f64,
+}
+
+impl CycleBudget {
+    // 1.0 より大きければ予定より多く実行している
+    pub fn ratio(&self) -> f64 {
+        self.cycles as f64 / self.budget
+    }
+}
+
+impl fmt::Display for CycleBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} / {:.0} cycles ({:+.2}%)",
+            self.cycles,
+            self.budget,
+            (self.ratio() - 1.0) * 100.0
+        )
+    }
 }
 
 impl Ps {
-    pub fn new(cpu: Cpu, interconnect: Interconnect) -> Self {
-        Self { cpu, interconnect }
+    pub fn new(config: MachineConfig, gpu: Gpu) -> Result<Self> {
+        config.validate()?;
+
+        let exe = match &config.boot {
+            BootMode::Bios => None,
+            BootMode::FastBoot => {
+                let disc = config.disc.as_ref().unwrap();
+                let path = iso9660::boot_path(disc)?;
+                info!("fast boot: {}", path);
+
+                Some(Exe::parse(&iso9660::read_file(disc, &path)?)?)
+            }
+            BootMode::Sideload(data) => Some(Exe::parse(data)?),
+        };
+
+        let power_on = config.power_on;
+        let interconnect = Interconnect::new(config, gpu);
+
+        let mut cpu = Cpu::new(interconnect, power_on);
+        cpu.boot_exe = exe;
+
+        Ok(Self {
+            cpu,
+            triggers: Triggers::new(),
+            last_frame: 0,
+            input: [0; 2],
+            vblank: false,
+            recording: None,
+            playback: None,
+            frame_start: 0,
+            last_frame_cycles: 0,
+            #[cfg(feature = "achievements")]
+            achievements: None,
+        })
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.cpu.inter.gpu().frames()
+    }
+
+    pub fn refresh_rate(&self) -> f64 {
+        self.cpu.inter.gpu().refresh_rate()
+    }
+
+    // 起動してから実行したCPUサイクル数
+    pub fn cycles(&self) -> u64 {
+        self.cpu.inter.cycles()
+    }
+
+    pub fn cycle_budget(&self) -> CycleBudget {
+        CycleBudget {
+            cycles: self.last_frame_cycles,
+            budget: self.cpu.inter.gpu().cycles_per_frame(),
+        }
+    }
+
+    pub fn triggers_mut(&mut self) -> &mut Triggers {
+        &mut self.triggers
+    }
+
+    // rcheevos のアドレスでメモリを読む。読めたバイト数を返す
+    pub fn read_achievement_memory(&self, address: u32, buf: &mut [u8]) -> usize {
+        rcheevos::read_memory(&self.cpu.inter, address, buf)
+    }
+
+    // フレームごとに実績を評価する
+    #[cfg(feature = "achievements")]
+    pub fn set_achievements(&mut self, runtime: Option<Runtime>) {
+        self.achievements = runtime;
+    }
+
+    #[cfg(feature = "achievements")]
+    pub fn achievements(&self) -> Option<&Runtime> {
+        self.achievements.as_ref()
+    }
+
+    // 1ステップ進める。フレームが切り替わったらトリガーを評価する
+    pub fn step(&mut self) -> Option<Event> {
+        let event = self.cpu.step();
+
+        let vblank = self.cpu.inter.gpu().vblank();
+        if vblank && !self.vblank {
+            self.latch_input();
+        }
+        self.vblank = vblank;
+
+        let frame = self.frames();
+        if frame != self.last_frame {
+            self.last_frame = frame;
+
+            let cycles = self.cycles();
+            self.last_frame_cycles = cycles - self.frame_start;
+            self.frame_start = cycles;
+
+            self.triggers.evaluate(&self.cpu.inter, frame);
+
+            #[cfg(feature = "achievements")]
+            if let Some(runtime) = &mut self.achievements {
+                let inter = &self.cpu.inter;
+                runtime.do_frame(&|address| rcheevos::peek(inter, address), frame);
+            }
+        }
+
+        event
+    }
+
+    // 次のフレームが始まるまで進める
+    // ブレークポイントなどで途中で止まった場合はそのイベントを返す
+    pub fn run_frame(&mut self) -> Option<Event> {
+        let frame = self.frames();
+
+        while self.frames() == frame {
+            match self.step() {
+                None | Some(Event::DoneStep) => {}
+                event => return event,
+            }
+        }
+
+        None
+    }
+
+    // 表示中の画面 (VRAMの表示範囲)
+    pub fn frame(&mut self) -> Frame {
+        self.cpu.inter.gpu_mut().frame()
+    }
+
+    pub fn capture_frame(&mut self) -> RgbaImage {
+        self.cpu.inter.gpu_mut().capture_frame()
+    }
+
+    // デバッグ用にプリミティブの輪郭を表示する
+    pub fn set_primitive_debug(&mut self, enabled: bool) {
+        self.cpu.inter.gpu_mut().set_primitive_debug(enabled);
+    }
+
+    pub fn primitive_debug(&self) -> bool {
+        self.cpu.inter.gpu().primitive_debug()
+    }
+
+    pub fn primitive_counts(&self) -> PrimitiveCounts {
+        self.cpu.inter.gpu().primitive_counts()
+    }
+
+    // GP0/GP1 のコマンドを記録する (gpu-replay で再生できる)
+    pub fn start_gpu_recording(&mut self, path: &Path) -> Result<()> {
+        self.cpu.inter.gpu_mut().start_recording(path)
+    }
+
+    pub fn stop_gpu_recording(&mut self) -> Result<()> {
+        self.cpu.inter.gpu_mut().stop_recording()
+    }
+
+    // ウィンドウの物理ピクセルの大きさ
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.cpu.inter.gpu_mut().resize(width, height);
+    }
+
+    // 拡張カラー (15bitに落とさない)
+    pub fn set_true_color(&mut self, enabled: bool) {
+        self.cpu.inter.gpu_mut().set_true_color(enabled);
+    }
+
+    pub fn true_color(&self) -> bool {
+        self.cpu.inter.gpu().true_color()
+    }
+
+    pub fn set_scaling(&mut self, scaling: Scaling) {
+        self.cpu.inter.gpu_mut().set_scaling(scaling);
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.cpu.inter.gpu_mut().set_filter(filter);
+    }
+
+    // デバッグ用にVRAM全体を表示する
+    pub fn set_vram_view(&mut self, enabled: bool) {
+        self.cpu.inter.gpu_mut().set_vram_view(enabled);
+    }
+
+    pub fn vram_view(&self) -> bool {
+        self.cpu.inter.gpu().vram_view()
+    }
+
+    // port 0 / 1 のパッドで押されているボタン (joypad::button の組み合わせ)
+    // 次の vblank の開始で取り込まれる。再生中は無視される
+    pub fn set_buttons(&mut self, port: usize, buttons: u16) {
+        self.input[port] = buttons;
+    }
+
+    // DualShock の振動モーターの状態
+    pub fn motors(&self, port: usize) -> Motors {
+        self.cpu.inter.joypad().motors(port)
+    }
+
+    // 書き込まれたメモリーカードをファイルに書き戻す
+    pub fn flush_memory_cards(&mut self) -> Result<()> {
+        self.cpu.inter.joypad_mut().flush_memory_cards()
+    }
+
+    // ふたを開けてディスクを取り出す。ゲームは GetStat でそれを知る
+    pub fn eject_disc(&mut self) {
+        info!("Disc ejected");
+        self.cpu.inter.cdrom_mut().eject();
+    }
+
+    // ディスクを入れてふたを閉じる (2枚目への入れ替えなど)
+    pub fn insert_disc(&mut self, disc: Image) {
+        info!("Disc inserted");
+        self.cpu.inter.cdrom_mut().insert(disc);
+    }
+
+    pub fn shell_open(&self) -> bool {
+        self.cpu.inter.cdrom().shell_open()
+    }
+
+    // 入力を取り込むタイミングをエミュレーション側で決めておくことで、
+    // ホストのスレッドの都合によらずリプレイで同じ入力を再現できる
+    fn latch_input(&mut self) {
+        if self.recording.is_none() && self.playback.is_none() {
+            return self.apply_input();
+        }
+
+        let checksum = self.cpu.inter.ram_checksum();
+
+        if let Some(buttons) = self.playback.as_mut().and_then(|p| p.next(checksum)) {
+            self.input = buttons;
+        }
+
+        if let Some(replay) = &mut self.recording {
+            replay.frames.push(ReplayFrame {
+                buttons: self.input,
+                checksum,
+            });
+        }
+
+        self.apply_input();
+    }
+
+    fn apply_input(&mut self) {
+        for (port, &buttons) in self.input.iter().enumerate() {
+            self.cpu.inter.joypad_mut().set_buttons(port, buttons);
+        }
+    }
+
+    // 現在の状態からリプレイを記録する
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Replay {
+            state: self.save_state(),
+            frames: vec![],
+        });
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Replay> {
+        self.recording.take()
+    }
+
+    // リプレイの開始時の状態に戻して再生する
+    pub fn start_playback(&mut self, replay: Replay) -> Result<()> {
+        self.load_state(&replay.state)?;
+        self.recording = None;
+        self.playback = Some(Playback::new(replay));
+
+        Ok(())
+    }
+
+    pub fn playback(&self) -> Option<&Playback> {
+        self.playback.as_ref()
     }
 
-    pub async fn run() {
+    // 描いた内容をVRAMに含めるため、先に描画先を読み戻す
+    pub fn save_state(&mut self) -> Vec<u8> {
+        self.cpu.inter.gpu_mut().sync_vram();
+
+        let mut w = Writer::new();
+
+        w.u32(u32::from_le_bytes(*savestate::MAGIC));
+        w.u32(savestate::VERSION);
+        self.cpu.save_state(&mut w);
+
+        w.into_inner()
+    }
+
+    // 途中で失敗した場合はマシンの状態が壊れている
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let mut r = Reader::new(data);
+
+        if r.u32()? != u32::from_le_bytes(*savestate::MAGIC) {
+            bail!("Not a savestate");
+        }
+
+        let version = r.u32()?;
+        if version != savestate::VERSION {
+            bail!("Unsupported savestate version {}", version);
+        }
+
+        self.cpu.load_state(&mut r)?;
+
+        if !r.is_empty() {
+            bail!("Trailing data in savestate");
+        }
+
+        self.vblank = self.cpu.inter.gpu().vblank();
+        self.frame_start = self.cycles();
+        self.last_frame_cycles = 0;
+
+        Ok(())
+    }
+}
+
+// 自動実行用の終了条件
+#[derive(Debug, Clone, Default)]
+pub struct ExitConditions {
+    pub on_halt: bool,
+    pub pc: Option<u32>,
+    pub frames: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Halted,
+    Pc(u32),
+    Frames(u32),
+}
+
+impl ExitReason {
+    // スクリプトから区別できるようにプロセスの終了コードを分ける
+    pub fn code(self) -> i32 {
+        match self {
+            ExitReason::Halted => 2,
+            ExitReason::Pc(_) => 3,
+            ExitReason::Frames(_) => 4,
+        }
+    }
+}
+
+impl ExitConditions {
+    fn check(&self, ps: &Ps, start_frame: u32) -> Option<ExitReason> {
+        if self.on_halt && ps.cpu.is_spinning() {
+            return Some(ExitReason::Halted);
+        }
+
+        if self.pc == Some(ps.cpu.pc()) {
+            return Some(ExitReason::Pc(ps.cpu.pc()));
+        }
+
+        if let Some(frames) = self.frames {
+            if ps.frames().wrapping_sub(start_frame) >= frames {
+                return Some(ExitReason::Frames(frames));
+            }
+        }
+
+        None
+    }
+}
+
+// エミュレーションスレッドとUIスレッドで共有するマシン
+// UIスレッドは pause() で命令の境界まで待ってから参照できる
+pub struct SharedPs {
+    ps: Mutex<Ps>,
+    // UIスレッドとオートセーブのように複数のスレッドが同時に止められるので数える
+    pauses: AtomicUsize,
+    pause_lock: Mutex<()>,
+    resume: Condvar,
+}
+
+impl SharedPs {
+    pub fn new(ps: Ps) -> Self {
+        Self {
+            ps: Mutex::new(ps),
+            pauses: AtomicUsize::new(0),
+            pause_lock: Mutex::new(()),
+            resume: Condvar::new(),
+        }
+    }
+
+    // エミュレーションスレッドから呼ぶ。終了条件を満たすまで戻らない
+    pub fn run(&self, exit: &ExitConditions) -> ExitReason {
+        let start_frame = self.ps.lock().unwrap().frames();
+
+        loop {
+            {
+                let mut ps = self.ps.lock().unwrap();
+
+                while self.pauses.load(Ordering::Acquire) == 0 {
+                    if ps.step() == Some(Event::Halted) {
+                        return ExitReason::Halted;
+                    }
+
+                    if let Some(reason) = exit.check(&ps, start_frame) {
+                        return reason;
+                    }
+                }
+            }
+
+            self.wait_for_resume();
+        }
+    }
+
+    fn wait_for_resume(&self) {
+        let mut guard = self.pause_lock.lock().unwrap();
+
+        while self.pauses.load(Ordering::Acquire) != 0 {
+            guard = self.resume.wait(guard).unwrap();
+        }
+    }
+
+    // 排他的に使う (GDBセッションなど)
+    pub fn lock(&self) -> MutexGuard<'_, Ps> {
+        self.ps.lock().unwrap()
+    }
+
+    // エミュレーションスレッドがパニックした後でも中身を取り出す
+    pub fn recover(&self) -> MutexGuard<'_, Ps> {
+        self.ps.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.ps.is_poisoned()
+    }
+
+    // UIスレッドから呼ぶ。すべての PauseGuard が drop されると再開する
+    pub fn pause(&self) -> PauseGuard<'_> {
+        self.pauses.fetch_add(1, Ordering::AcqRel);
+
+        PauseGuard {
+            shared: self,
+            ps: Some(self.ps.lock().unwrap()),
+        }
+    }
+}
+
+pub struct PauseGuard<'a> {
+    shared: &'a SharedPs,
+    ps: Option<MutexGuard<'a, Ps>>,
+}
+
+impl Deref for PauseGuard<'_> {
+    type Target = Ps;
+
+    fn deref(&self) -> &Ps {
+        self.ps.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PauseGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Ps {
+        self.ps.as_mut().unwrap()
+    }
+}
+
+impl Drop for PauseGuard<'_> {
+    fn drop(&mut self) {
+        self.ps = None;
+
+        let _guard = self.shared.pause_lock.lock().unwrap();
+        self.shared.pauses.fetch_sub(1, Ordering::AcqRel);
+        self.shared.resume.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::testing::TestMachineBuilder;
+
+    #[test]
+    fn frames_run_for_their_cycle_budget() {
+        // 無限ループ
+        let mut ps = TestMachineBuilder::new()
+            .program(0x80010000, &[0x08004000, 0x00000000])
+            .build_ps();
+
+        ps.run_frame();
+        let start = ps.cycles();
+        ps.run_frame();
+
+        let budget = ps.cycle_budget();
+        assert_eq!(budget.cycles, ps.cycles() - start);
+        // 命令の途中でフレームが切り替わる分だけずれる
+        assert!((budget.ratio() - 1.0).abs() < 1e-3, "{}", budget);
+    }
+
+    #[test]
+    fn exit_conditions_stop_the_run() {
+        // j . の無限ループ
+        let run = |exit: ExitConditions| {
+            let ps = TestMachineBuilder::new()
+                .program(0x80010000, &[0x08004000, 0x00000000])
+                .build_ps();
+            let shared = SharedPs::new(ps);
+            let reason = shared.run(&exit);
+            let frames = shared.lock().frames();
+
+            (reason, frames)
+        };
+
+        let (reason, _) = run(ExitConditions {
+            on_halt: true,
+            ..Default::default()
+        });
+        assert_eq!(reason, ExitReason::Halted);
+        assert_eq!(reason.code(), 2);
+
+        // 遅延スロットに来たところで止まる
+        let (reason, _) = run(ExitConditions {
+            pc: Some(0x80010004),
+            ..Default::default()
+        });
+        assert_eq!(reason, ExitReason::Pc(0x80010004));
+        assert_eq!(reason.code(), 3);
+
+        let (reason, frames) = run(ExitConditions {
+            frames: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(reason, ExitReason::Frames(2));
+        assert_eq!(reason.code(), 4);
+        assert_eq!(frames, 2);
+    }
+
+    #[test]
+    fn concurrent_pauses_keep_the_machine_stopped() {
+        // 無限ループ。最後に止めたときに抜け先へ飛ばす
+        let ps = TestMachineBuilder::new()
+            .program(0x80010000, &[0x08004000, 0x00000000])
+            .build_ps();
+        let shared = Arc::new(SharedPs::new(ps));
+
+        let runner = thread::spawn({
+            let shared = shared.clone();
+            move || {
+                shared.run(&ExitConditions {
+                    on_halt: false,
+                    pc: Some(0x80020004),
+                    frames: None,
+                })
+            }
+        });
+
+        let first = shared.pause();
+        let cycles = first.cycles();
+
+        // 2つ目は1つ目が drop されるまで待つ
+        let (acquired, wait_acquired) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let second = thread::spawn({
+            let shared = shared.clone();
+            move || {
+                let ps = shared.pause();
+                acquired.send(ps.cycles()).unwrap();
+                wait_release.recv().unwrap();
+            }
+        });
+
+        while shared.pauses.load(Ordering::Acquire) != 2 {
+            thread::yield_now();
+        }
+        drop(first);
+
+        // 1つ目が drop されてもまだ止めたまま
+        let second_cycles = wait_acquired
+            .recv_timeout(Duration::from_secs(10))
+            .expect("second pause() never returned");
+        assert_eq!(second_cycles, cycles);
+        assert_eq!(shared.pauses.load(Ordering::Acquire), 1);
+
+        release.send(()).unwrap();
+        second.join().unwrap();
+        assert_eq!(shared.pauses.load(Ordering::Acquire), 0);
 
+        shared.pause().cpu_mut().set_pc(0x80020000);
+        assert_eq!(runner.join().unwrap(), ExitReason::Pc(0x80020004));
     }
 }
@@ -1,34 +1,77 @@
+use anyhow::Result;
 use log::trace;
 
-use crate::addressible::Addressible;
+use crate::{
+    addressible::{read_le, write_le, Addressible},
+    config::PowerOnState,
+    savestate::{Reader, Savestate, Writer},
+};
 
 pub struct Ram {
     data: Vec<u8>,
 }
 
 impl Ram {
-    pub fn new() -> Ram {
-        let data = [0xCA; 2 * 1024 * 1024].to_vec();
+    pub fn new(size: usize, power_on: PowerOnState) -> Ram {
+        debug_assert!(size.is_power_of_two(), "RAM size must be a power of two");
+
+        let mut data = vec![0; size];
+        power_on.fill_bytes(&mut data);
 
         Ram { data }
     }
 
+    // RAMの大きさで折り返す
+    // ミラーやバグで範囲外を指してもホストごと落とさない
+    fn mask(&self, offset: u32) -> usize {
+        let offset = (offset & (self.size() - 1)) as usize;
+        debug_assert!(offset < self.data.len());
+
+        offset
+    }
+
+    // 末尾を跨ぐ (アラインされていない) アクセスは先頭に折り返す
+    fn straddles<T: Addressible>(&self, offset: usize) -> bool {
+        offset + T::width() as usize > self.data.len()
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    // 内容の比較用 (FNV-1a)
+    pub fn checksum(&self) -> u32 {
+        self.data.iter().fold(0x811C9DC5, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x01000193)
+        })
+    }
+
     pub fn load<T: Addressible>(&self, offset: u32) -> T {
-        let offset = offset as usize;
+        let offset = self.mask(offset);
 
-        let mut v = 0;
+        let v = if self.straddles::<T>(offset) {
+            let mut v = 0;
+            for i in 0..T::width() as usize {
+                v |= (self.data[(offset + i) % self.data.len()] as u32) << (i * 8);
+            }
 
-        for i in 0..T::width() as usize {
-            v |= (self.data[offset + i] as u32) << (i * 8);
-        }
+            Addressible::from_u32(v)
+        } else {
+            read_le::<T>(&self.data, offset)
+        };
 
-        trace!("RAM{:?} load {:08x} => {:08x}", T::width(), offset, v);
+        trace!(
+            "RAM{:?} load {:08x} => {:08x}",
+            T::width(),
+            offset,
+            v.as_u32()
+        );
 
-        Addressible::from_u32(v)
+        v
     }
 
     pub fn store<T: Addressible>(&mut self, offset: u32, val: T) {
-        let offset = offset as usize;
+        let offset = self.mask(offset);
 
         trace!(
             "RAM{:?} store {:08x} => {:08x}",
@@ -37,10 +80,44 @@ impl Ram {
             val.as_u32()
         );
 
-        let val = val.as_u32();
-
-        for i in 0..T::width() as usize {
-            self.data[offset + i] = (val >> (i * 8)) as u8;
+        if self.straddles::<T>(offset) {
+            let len = self.data.len();
+            let val = val.as_u32();
+            for i in 0..T::width() as usize {
+                self.data[(offset + i) % len] = (val >> (i * 8)) as u8;
+            }
+        } else {
+            write_le(&mut self.data, offset, val);
         }
     }
 }
+
+impl Savestate for Ram {
+    fn save_state(&self, w: &mut Writer) {
+        w.bytes(&self.data);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.bytes_into(&mut self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_wrap_at_ram_size() {
+        let mut ram = Ram::new(2 * 1024 * 1024, PowerOnState::Zeros);
+
+        ram.store::<u32>(0x200000 + 0x10, 0x12345678);
+        assert_eq!(ram.load::<u32>(0x10), 0x12345678);
+        assert_eq!(ram.load::<u32>(0x600010), 0x12345678);
+
+        // 末尾を跨ぐアクセスも落ちない
+        ram.store::<u32>(0x1FFFFE, 0xAABBCCDD);
+        assert_eq!(ram.load::<u16>(0x1FFFFE), 0xCCDD);
+        assert_eq!(ram.load::<u16>(0), 0xAABB);
+        assert_eq!(ram.load::<u32>(0x1FFFFE), 0xAABBCCDD);
+    }
+}
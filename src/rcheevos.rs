@@ -0,0 +1,73 @@
+use crate::interconnect::Interconnect;
+
+// rcheevos (RetroAchievements) から見たPS1のメモリ
+// 0x000000-0x1FFFFF がメインRAM (2MB)、0x200000-0x2003FF がスクラッチパッド
+pub const RAM_SIZE: u32 = 0x200000;
+pub const SCRATCHPAD_START: u32 = 0x200000;
+pub const SCRATCHPAD_SIZE: u32 = 0x400;
+pub const MEMORY_SIZE: u32 = SCRATCHPAD_START + SCRATCHPAD_SIZE;
+
+const SCRATCHPAD_BASE: u32 = 0x1F800000;
+
+// RetroAchievements のアドレスをCPUのアドレスにする
+pub fn cpu_address(address: u32) -> Option<u32> {
+    if address < RAM_SIZE {
+        Some(address)
+    } else if address < MEMORY_SIZE {
+        Some(SCRATCHPAD_BASE + address - SCRATCHPAD_START)
+    } else {
+        None
+    }
+}
+
+pub fn peek(inter: &Interconnect, address: u32) -> Option<u8> {
+    inter.peek::<u8>(cpu_address(address)?)
+}
+
+// rc_client の read_memory と同じく、先頭から読めたバイト数を返す
+pub fn read_memory(inter: &Interconnect, address: u32, buf: &mut [u8]) -> usize {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        match peek(inter, address.wrapping_add(i as u32)) {
+            Some(val) => *byte = val,
+            None => return i,
+        }
+    }
+
+    buf.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestMachineBuilder;
+
+    #[test]
+    fn maps_ram_and_scratchpad() {
+        // スクラッチパッドの先頭に 0x5A を書いて止まる
+        let mut ps = TestMachineBuilder::new()
+            .ram(0x1234, &[0x12, 0x34])
+            .program(
+                0x80010000,
+                &[
+                    0x3C081F80, // lui $t0, 0x1F80
+                    0x3409005A, // ori $t1, $zero, 0x5A
+                    0xA1090000, // sb $t1, 0($t0)
+                    0x08004003, // j 0x8001000C
+                    0x00000000, // nop
+                ],
+            )
+            .build_ps();
+
+        ps.run_frame();
+
+        let inter = &ps.cpu().inter;
+        let mut buf = [0; 2];
+        assert_eq!(read_memory(inter, 0x1234, &mut buf), 2);
+        assert_eq!(buf, [0x12, 0x34]);
+        assert_eq!(peek(inter, SCRATCHPAD_START), Some(0x5A));
+
+        // 範囲の外は読めたところまで
+        let mut buf = [0; 4];
+        assert_eq!(read_memory(inter, MEMORY_SIZE - 2, &mut buf), 2);
+    }
+}
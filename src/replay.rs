@@ -0,0 +1,240 @@
+use anyhow::{bail, Result};
+
+use crate::savestate::{Reader, Writer};
+
+const MAGIC: &[u8; 4] = b"RPSR";
+const VERSION: u32 = 1;
+
+// 入力を取り込むタイミング
+// 今は vblank の開始だけだが、再生側が違うタイミングで解釈しないように形式に含める
+const LATCH_VBLANK_START: u8 = 0;
+
+// vblank の開始で取り込んだ入力と、その直前のRAMのチェックサム
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayFrame {
+    pub buttons: [u16; 2],
+    pub checksum: u32,
+}
+
+// 開始時のセーブステートとフレームごとの入力
+// セーブステートの形式が変わると再生できなくなる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    pub state: Vec<u8>,
+    pub frames: Vec<ReplayFrame>,
+}
+
+impl Replay {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+
+        w.u32(u32::from_le_bytes(*MAGIC));
+        w.u32(VERSION);
+        w.u8(LATCH_VBLANK_START);
+        w.bytes(&self.state);
+
+        w.u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            w.u16(frame.buttons[0]);
+            w.u16(frame.buttons[1]);
+            w.u32(frame.checksum);
+        }
+
+        w.into_inner()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Replay> {
+        let mut r = Reader::new(data);
+
+        if r.u32()? != u32::from_le_bytes(*MAGIC) {
+            bail!("Not a replay");
+        }
+
+        let version = r.u32()?;
+        if version != VERSION {
+            bail!("Unsupported replay version {}", version);
+        }
+
+        let latch = r.u8()?;
+        if latch != LATCH_VBLANK_START {
+            bail!("Unsupported input latch {}", latch);
+        }
+
+        let state = r.bytes()?;
+
+        let len = r.u32()?;
+        let frames = (0..len)
+            .map(|_| {
+                Ok(ReplayFrame {
+                    buttons: [r.u16()?, r.u16()?],
+                    checksum: r.u32()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        if !r.is_empty() {
+            bail!("Trailing data in replay");
+        }
+
+        Ok(Replay { state, frames })
+    }
+}
+
+// 再生中の位置と、記録時とRAMが食い違った最初のフレーム
+pub struct Playback {
+    replay: Replay,
+    pos: usize,
+    desync: Option<usize>,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Playback {
+        Playback {
+            replay,
+            pos: 0,
+            desync: None,
+        }
+    }
+
+    // vblank の開始で呼ぶ。再生し終わっていたら None
+    pub fn next(&mut self, checksum: u32) -> Option<[u16; 2]> {
+        let frame = self.replay.frames.get(self.pos)?;
+
+        if frame.checksum != checksum && self.desync.is_none() {
+            self.desync = Some(self.pos);
+        }
+
+        self.pos += 1;
+
+        Some(frame.buttons)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.pos == self.replay.frames.len()
+    }
+
+    pub fn desync(&self) -> Option<usize> {
+        self.desync
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{joypad::button, ps::Ps, testing::TestMachineBuilder};
+
+    // パッドを読んでボタンの2バイトを 0x80000200 に書き続ける
+    const READ_PAD: [u32; 28] = [
+        0x3C081F80, // lui $t0, 0x1F80
+        0x34090003, // ori $t1, $zero, 3
+        0xA509104A, // sh $t1, 0x104A($t0) (TX有効、port 0 を選択)
+        0x34090001, // ori $t1, $zero, 0x01
+        0xA1091040, // sb $t1, 0x1040($t0)
+        0x34090042, // ori $t1, $zero, 0x42
+        0xA1091040, // sb $t1, 0x1040($t0)
+        0xA1001040, // sb $zero, 0x1040($t0)
+        0xA1001040, // sb $zero, 0x1040($t0)
+        0xA1001040, // sb $zero, 0x1040($t0)
+        0x00000000, // nop
+        0x00000000, // nop
+        0x910A1040, // lbu $t2, 0x1040($t0)
+        0x910A1040, // lbu $t2, 0x1040($t0)
+        0x910A1040, // lbu $t2, 0x1040($t0)
+        0x910A1040, // lbu $t2, 0x1040($t0)
+        0x910B1040, // lbu $t3, 0x1040($t0)
+        0x3C0C8000, // lui $t4, 0x8000
+        0xA18A0200, // sb $t2, 0x200($t4)
+        0xA18B0201, // sb $t3, 0x201($t4)
+        0xA500104A, // sh $zero, 0x104A($t0)
+        0x00000000, // nop
+        0x00000000, // nop
+        0x00000000, // nop
+        0x00000000, // nop
+        0x00000000, // nop
+        0x08004000, // j 0x80010000
+        0x00000000, // nop
+    ];
+
+    const INPUTS: [u16; 4] = [button::START, 0, button::CROSS | button::UP, button::CIRCLE];
+
+    fn machine() -> Ps {
+        TestMachineBuilder::new()
+            .program(0x80010000, &READ_PAD)
+            .build_ps()
+    }
+
+    // ホストの入力を毎フレーム変えながら記録する
+    fn record(ps: &mut Ps) -> Replay {
+        ps.run_frame();
+        ps.start_recording();
+
+        for buttons in INPUTS {
+            ps.set_buttons(0, buttons);
+            ps.run_frame();
+        }
+
+        ps.stop_recording().unwrap()
+    }
+
+    // 記録と同じフレーム数だけ進める
+    fn play(replay: Replay) -> Ps {
+        let frames = replay.frames.len();
+
+        let mut ps = machine();
+        ps.start_playback(replay).unwrap();
+
+        for _ in 0..frames {
+            ps.run_frame();
+        }
+
+        assert!(ps.playback().unwrap().finished());
+
+        ps
+    }
+
+    #[test]
+    fn pad_input_reaches_ram() {
+        let mut ps = machine();
+        record(&mut ps);
+
+        // 最後に取り込んだのは CIRCLE (アクティブロー)
+        let pressed = !ps.cpu().inter.peek::<u16>(0x80000200).unwrap();
+        assert_eq!(pressed, button::CIRCLE);
+    }
+
+    #[test]
+    fn playback_matches_live_run() {
+        let mut live = machine();
+        let replay = record(&mut live);
+        assert_eq!(replay.frames.len(), INPUTS.len());
+
+        let mut played = play(Replay::decode(&replay.encode()).unwrap());
+
+        assert_eq!(played.playback().unwrap().desync(), None);
+        assert_eq!(played.save_state(), live.save_state());
+    }
+
+    #[test]
+    fn detects_desync() {
+        let mut replay = record(&mut machine());
+        replay.frames[1].buttons[0] = button::SQUARE;
+
+        let played = play(replay);
+
+        // 入力が変わったフレームの次の vblank で食い違う
+        assert_eq!(played.playback().unwrap().desync(), Some(2));
+    }
+
+    #[test]
+    fn rejects_other_latch_timing() {
+        let replay = Replay {
+            state: vec![],
+            frames: vec![],
+        };
+
+        let mut data = replay.encode();
+        data[8] = 1;
+
+        assert!(Replay::decode(&data).is_err());
+    }
+}
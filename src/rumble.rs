@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+use crate::joypad::Motors;
+
+// 振動の確認 (テストモード) の長さ
+pub const TEST_DURATION: Duration = Duration::from_secs(2);
+
+// ホストの振動の強さ (0.0..=1.0)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Strength {
+    // 小さいモーター (高周波)
+    pub weak: f32,
+    // 大きいモーター (低周波)
+    pub strong: f32,
+}
+
+// モーターごとの強さの倍率。ホストのパッドによって感じ方が違うので調整できるようにする
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RumbleScale {
+    pub small: f32,
+    pub large: f32,
+}
+
+impl Default for RumbleScale {
+    fn default() -> Self {
+        RumbleScale {
+            small: 1.0,
+            large: 1.0,
+        }
+    }
+}
+
+impl RumbleScale {
+    // 小さいモーターはオンオフだけ、大きいモーターは 0-255
+    pub fn strength(&self, motors: Motors) -> Strength {
+        Strength {
+            weak: match motors.small {
+                true => self.small.clamp(0.0, 1.0),
+                false => 0.0,
+            },
+            strong: (motors.large as f32 / 255.0 * self.large).clamp(0.0, 1.0),
+        }
+    }
+}
+
+// ホスト側の振動の出力先
+pub trait RumbleOutput {
+    fn set(&mut self, port: usize, strength: Strength);
+}
+
+// エミュレータのモーターの値を倍率をかけて出力先に伝える
+// 出力先には変化があったときだけ伝える
+pub struct Rumble<O> {
+    output: O,
+    scale: RumbleScale,
+    // テストモードの終了時刻
+    test_until: Option<Instant>,
+    last: [Strength; 2],
+}
+
+impl<O: RumbleOutput> Rumble<O> {
+    pub fn new(output: O, scale: RumbleScale) -> Rumble<O> {
+        Rumble {
+            output,
+            scale,
+            test_until: None,
+            last: [Strength::default(); 2],
+        }
+    }
+
+    // しばらく両方のモーターを最大 (倍率はかける) で回し、割り当てを確認できるようにする
+    pub fn test(&mut self, now: Instant) {
+        self.test_until = Some(now + TEST_DURATION);
+    }
+
+    pub fn update(&mut self, motors: [Motors; 2], now: Instant) {
+        if self.test_until.is_some_and(|until| now >= until) {
+            self.test_until = None;
+        }
+
+        for (port, motors) in motors.into_iter().enumerate() {
+            let motors = match self.test_until {
+                Some(_) => Motors {
+                    small: true,
+                    large: 0xFF,
+                },
+                None => motors,
+            };
+
+            let strength = self.scale.strength(motors);
+            if strength != self.last[port] {
+                self.output.set(port, strength);
+                self.last[port] = strength;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Vec<(usize, Strength)>);
+
+    impl RumbleOutput for &mut Recorder {
+        fn set(&mut self, port: usize, strength: Strength) {
+            self.0.push((port, strength));
+        }
+    }
+
+    #[test]
+    fn scales_motor_values() {
+        let scale = RumbleScale {
+            small: 0.5,
+            large: 2.0,
+        };
+
+        let strength = scale.strength(Motors {
+            small: true,
+            large: 0x40,
+        });
+
+        assert_eq!(strength.weak, 0.5);
+        assert!((strength.strong - 0x80 as f32 / 255.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mode_runs_for_a_while() {
+        let mut recorder = Recorder::default();
+        let mut rumble = Rumble::new(&mut recorder, RumbleScale::default());
+        let start = Instant::now();
+        let stopped = [Motors::default(); 2];
+
+        rumble.update(stopped, start);
+        rumble.test(start);
+        rumble.update(stopped, start);
+        // 変化がなければ伝えない
+        rumble.update(stopped, start + TEST_DURATION / 2);
+        rumble.update(stopped, start + TEST_DURATION);
+
+        let full = Strength {
+            weak: 1.0,
+            strong: 1.0,
+        };
+        assert_eq!(
+            recorder.0,
+            vec![
+                (0, full),
+                (1, full),
+                (0, Strength::default()),
+                (1, Strength::default())
+            ]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Context, Result};
+use num_traits::FromPrimitive;
+
+pub const MAGIC: &[u8; 4] = b"RPSS";
+pub const VERSION: u32 = 32;
+
+// 各コンポーネントの状態をバイト列に書き出す
+pub trait Savestate {
+    fn save_state(&self, w: &mut Writer);
+    fn load_state(&mut self, r: &mut Reader) -> Result<()>;
+}
+
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { data: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.data.extend_from_slice(val);
+    }
+
+    pub fn fifo(&mut self, val: &VecDeque<u8>) {
+        self.u32(val.len() as u32);
+        self.data.extend(val.iter());
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            bail!("Unexpected end of savestate at {}", self.pos);
+        }
+
+        let r = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(r)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    // 固定長のバッファに読み込む。長さが違う場合はエラー
+    pub fn bytes_into(&mut self, buf: &mut [u8]) -> Result<()> {
+        let len = self.u32()? as usize;
+        if len != buf.len() {
+            bail!("Savestate size mismatch: {} != {}", len, buf.len());
+        }
+
+        buf.copy_from_slice(self.take(len)?);
+
+        Ok(())
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn fifo(&mut self) -> Result<VecDeque<u8>> {
+        let len = self.u32()? as usize;
+
+        Ok(self.take(len)?.iter().copied().collect())
+    }
+
+    pub fn variant<T: FromPrimitive>(&mut self) -> Result<T> {
+        let val = self.u8()?;
+
+        T::from_u8(val).with_context(|| format!("Invalid savestate variant {}", val))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{cpu::cpu::Cpu, testing::TestMachineBuilder};
+
+    const ENTRY: u32 = 0x80010000;
+
+    // $s0 を作業領域 (0x80100000) の先頭にする
+    const PROLOGUE: u32 = 0x3C108010; // lui $s0, 0x8010
+
+    // 作業領域のアドレスは$s0、それ以外は$t0-$t7だけを使う命令
+    fn instruction() -> impl Strategy<Value = u32> {
+        let reg = || 8u32..16;
+
+        prop_oneof![
+            (reg(), reg(), any::<u16>())
+                .prop_map(|(s, t, imm)| 0x24000000 | s << 21 | t << 16 | imm as u32), // addiu
+            (reg(), any::<u16>()).prop_map(|(t, imm)| 0x3C000000 | t << 16 | imm as u32), // lui
+            (reg(), reg(), reg()).prop_map(|(s, t, d)| s << 21 | t << 16 | d << 11 | 0x21), // addu
+            (reg(), reg(), reg()).prop_map(|(s, t, d)| s << 21 | t << 16 | d << 11 | 0x26), // xor
+            (reg(), reg(), 0u32..32).prop_map(|(t, d, sa)| t << 16 | d << 11 | sa << 6),  // sll
+            (reg(), 0u32..64).prop_map(|(t, off)| 0xAE000000 | t << 16 | off * 4),        // sw
+            (reg(), 0u32..64).prop_map(|(t, off)| 0x8E000000 | t << 16 | off * 4),        // lw
+            (reg(), 0u32..256).prop_map(|(t, off)| 0xA2000000 | t << 16 | off),           // sb
+            (reg(), 0u32..128).prop_map(|(t, off)| 0x86000000 | t << 16 | off * 2),       // lh
+        ]
+    }
+
+    // 命令列を無限に繰り返すプログラム
+    fn machine(body: &[u32]) -> Cpu {
+        let mut program = vec![PROLOGUE];
+        program.extend_from_slice(body);
+        program.push(0x08000000 | ((ENTRY + 4) >> 2) & 0x3FFFFFF); // j body
+        program.push(0x00000000);
+
+        TestMachineBuilder::new().program(ENTRY, &program).build()
+    }
+
+    fn save(cpu: &Cpu) -> Vec<u8> {
+        let mut w = Writer::new();
+        cpu.save_state(&mut w);
+
+        w.into_inner()
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn round_trip_runs_in_lockstep(
+            body in prop::collection::vec(instruction(), 1..32),
+            before in 0usize..2000,
+            after in 1usize..500,
+        ) {
+            let mut original = machine(&body);
+            for _ in 0..before {
+                original.step();
+            }
+
+            let state = save(&original);
+
+            let mut restored = machine(&[]);
+            let mut r = Reader::new(&state);
+            restored.load_state(&mut r).unwrap();
+            prop_assert!(r.is_empty());
+            prop_assert_eq!(&save(&restored), &state);
+
+            for _ in 0..after {
+                prop_assert_eq!(original.step(), restored.step());
+                prop_assert_eq!(original.pc(), restored.pc());
+                prop_assert_eq!(original.regs, restored.regs);
+            }
+
+            prop_assert!(save(&original) == save(&restored));
+        }
+    }
+}
@@ -1,14 +1,21 @@
+use anyhow::Result;
 use log::trace;
 
-use crate::addressible::Addressible;
+use crate::{
+    addressible::{read_le, write_le, Addressible},
+    config::PowerOnState,
+    interconnect::map,
+    savestate::{Reader, Savestate, Writer},
+};
 
 pub struct ScratchPad {
     data: Vec<u8>,
 }
 
 impl ScratchPad {
-    pub fn new() -> ScratchPad {
-        let data = [0xCA; 2 * 1024 * 1024].to_vec();
+    pub fn new(power_on: PowerOnState) -> ScratchPad {
+        let mut data = vec![0; map::SCRATCHPAD.length as usize];
+        power_on.fill_bytes(&mut data);
 
         ScratchPad { data }
     }
@@ -16,20 +23,16 @@ impl ScratchPad {
     pub fn load<T: Addressible>(&self, offset: u32) -> T {
         let offset = offset as usize;
 
-        let mut v = 0;
-
-        for i in 0..T::width() as usize {
-            v |= (self.data[offset + i] as u32) << (i * 8);
-        }
+        let v = read_le::<T>(&self.data, offset);
 
         trace!(
             "SCRATCHPAD{:?} load {:08x} => {:08x}",
             T::width(),
             offset,
-            v
+            v.as_u32()
         );
 
-        Addressible::from_u32(v)
+        v
     }
 
     pub fn store<T: Addressible>(&mut self, offset: u32, val: T) {
@@ -42,10 +45,16 @@ impl ScratchPad {
             val.as_u32()
         );
 
-        let val = val.as_u32();
+        write_le(&mut self.data, offset, val);
+    }
+}
+
+impl Savestate for ScratchPad {
+    fn save_state(&self, w: &mut Writer) {
+        w.bytes(&self.data);
+    }
 
-        for i in 0..T::width() as usize {
-            self.data[offset + i] = (val >> (i * 8)) as u8;
-        }
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.bytes_into(&mut self.data)
     }
 }
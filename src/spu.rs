@@ -0,0 +1,883 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use log::{debug, warn};
+
+use crate::{
+    addressible::{AccessWidth, Addressible},
+    gpu::CPU_CLOCK,
+    interrupts::{Irq, IrqBus, IrqLine},
+    savestate::{Reader, Savestate, Writer},
+};
+
+use self::{reverb::Reverb, voice::Voice};
+
+mod adsr;
+mod reverb;
+mod voice;
+
+// SPU RAM の大きさ。転送のアドレスはこの大きさで折り返す
+pub const RAM_SIZE: usize = 512 * 1024;
+
+// 手動転送のFIFOの深さ (ハーフワード)
+const FIFO_LEN: usize = 32;
+// 手動転送で1ハーフワード書き込むのにかかるサイクル
+const TRANSFER_CYCLES: u32 = 16;
+
+pub const VOICE_COUNT: usize = 24;
+// ボイスのレジスタは 0x000-0x17F に16byteずつ並ぶ
+const VOICE_REGS_END: u32 = VOICE_COUNT as u32 * 16;
+const VOICE_MASK: u32 = (1 << VOICE_COUNT) - 1;
+
+// 出力は 44.1kHz
+const SAMPLE_CYCLES: u32 = (CPU_CLOCK / 44100) as u32;
+// 取り出されないまま溜まるサンプルの上限 (約1秒)。超えたら古いものから捨てる。CD-ROM の再生待ちも同じ
+pub(crate) const AUDIO_BUFFER_LEN: usize = 44100;
+
+// SPUCNT bit4-5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransferMode {
+    Stop,
+    ManualWrite,
+    DmaWrite,
+    DmaRead,
+}
+
+// bit15 が0なら固定の音量 (bit0-14 を2倍した符号付き)。1ならスイープで、まだ対応していない
+fn fixed_volume(val: u16) -> Option<i16> {
+    match val & 0x8000 {
+        0 => Some((val << 1) as i16),
+        _ => {
+            debug!("SPU volume sweep {:04x}", val);
+            None
+        }
+    }
+}
+
+// ボイスごとのビットを2つのレジスタに分けたもの (下位16bit と上位8bit)
+fn voice_bits(val: u32, offset: u32) -> u16 {
+    (val >> ((offset & 2) * 8)) as u16
+}
+
+// 左右で対になったレジスタのどちらか
+fn channel(offset: u32) -> usize {
+    (offset as usize >> 1) & 1
+}
+
+fn set_voice_bits(reg: &mut u32, offset: u32, val: u16) {
+    let shift = (offset & 2) * 8;
+    *reg = (*reg & !(0xFFFF << shift)) | (((val as u32) << shift) & VOICE_MASK);
+}
+
+// SPU RAMと転送、24ボイスの再生とリバーブ
+pub struct Spu {
+    ram: Vec<u8>,
+
+    voices: [Voice; VOICE_COUNT],
+    // 最後に書かれた KON / KOFF (bit0-23 がボイス)
+    key_on: u32,
+    key_off: u32,
+    // ピッチモジュレーション, ノイズ, リバーブを使うボイス
+    pitch_modulation: u32,
+    noise: u32,
+    reverb_enable: u32,
+
+    // メインの音量 (左, 右) と、その今の値
+    main_volume: [u16; 2],
+    current_main_volume: [i16; 2],
+    reverb_volume: [u16; 2],
+    cd_volume: [u16; 2],
+    // 拡張ポートからの入力はないので、レジスタとして持つだけ
+    external_volume: [u16; 2],
+    // リバーブの作業領域の先頭 (8byte単位) と設定 (0x1C0-0x1FF)
+    reverb_base: u16,
+    reverb_regs: [u16; 32],
+    reverb: Reverb,
+
+    // 次の出力サンプルまでのサイクル
+    sample_cycles: u32,
+    // 出力したサンプル (左, 右)。音声の出力先が drain_audio で取り出す
+    audio: VecDeque<[i16; 2]>,
+
+    // SPUCNT
+    control: u16,
+    // 8byte単位
+    irq_address: u16,
+    transfer_start: u16,
+    transfer_control: u16,
+    // 次に転送するSPU RAMのアドレス (byte)
+    transfer_address: u32,
+
+    fifo: VecDeque<u16>,
+    // 手動転送でFIFOの先頭を書き込むまでのサイクル
+    transfer_cycles: u32,
+
+    // SPUSTAT bit6
+    irq: bool,
+    irq_line: IrqLine,
+}
+
+impl Spu {
+    pub fn new() -> Spu {
+        Spu {
+            ram: vec![0; RAM_SIZE],
+            voices: std::array::from_fn(|_| Voice::new()),
+            key_on: 0,
+            key_off: 0,
+            pitch_modulation: 0,
+            noise: 0,
+            reverb_enable: 0,
+            main_volume: [0; 2],
+            current_main_volume: [0; 2],
+            reverb_volume: [0; 2],
+            cd_volume: [0; 2],
+            external_volume: [0; 2],
+            reverb_base: 0,
+            reverb_regs: [0; 32],
+            reverb: Reverb::new(),
+            sample_cycles: SAMPLE_CYCLES,
+            audio: VecDeque::with_capacity(AUDIO_BUFFER_LEN),
+            control: 0,
+            irq_address: 0,
+            transfer_start: 0,
+            transfer_control: 0,
+            transfer_address: 0,
+            fifo: VecDeque::with_capacity(FIFO_LEN),
+            transfer_cycles: 0,
+            irq: false,
+            irq_line: IrqLine::new(Irq::Spu),
+        }
+    }
+
+    // レジスタは16bit。32bitのアクセスは2つに分ける
+    pub fn load<T: Addressible>(&self, offset: u32) -> T {
+        let val = match T::width() {
+            AccessWidth::Word => {
+                self.load16(offset) as u32 | (self.load16(offset + 2) as u32) << 16
+            }
+            AccessWidth::Halfword => self.load16(offset) as u32,
+            // 奇数番地は上位の byte
+            AccessWidth::Byte => (self.load16(offset & !1) >> ((offset & 1) * 8)) as u32,
+        };
+
+        Addressible::from_u32(val)
+    }
+
+    pub fn store<T: Addressible>(&mut self, offset: u32, val: T) {
+        let val = val.as_u32();
+
+        match T::width() {
+            AccessWidth::Word => {
+                self.store16(offset, val as u16);
+                self.store16(offset + 2, (val >> 16) as u16);
+            }
+            AccessWidth::Halfword => self.store16(offset, val as u16),
+            AccessWidth::Byte => warn!("SPU byte write {:x} {:02x}", offset, val),
+        }
+    }
+
+    fn load16(&self, offset: u32) -> u16 {
+        match offset {
+            0x000..VOICE_REGS_END => self.voices[offset as usize / 16].load(offset % 16),
+            0x180 | 0x182 => self.main_volume[channel(offset)],
+            0x184 | 0x186 => self.reverb_volume[channel(offset)],
+            0x188 | 0x18A => voice_bits(self.key_on, offset),
+            0x18C | 0x18E => voice_bits(self.key_off, offset),
+            0x190 | 0x192 => voice_bits(self.pitch_modulation, offset),
+            0x194 | 0x196 => voice_bits(self.noise, offset),
+            0x198 | 0x19A => voice_bits(self.reverb_enable, offset),
+            0x19C | 0x19E => voice_bits(self.end(), offset),
+            0x1A2 => self.reverb_base,
+            0x1A4 => self.irq_address,
+            0x1A6 => self.transfer_start,
+            // FIFO は書き込み専用
+            0x1A8 => 0,
+            0x1AA => self.control,
+            0x1AC => self.transfer_control,
+            0x1AE => self.status(),
+            0x1B0 | 0x1B2 => self.cd_volume[channel(offset)],
+            0x1B4 | 0x1B6 => self.external_volume[channel(offset)],
+            0x1B8 | 0x1BA => self.current_main_volume[channel(offset)] as u16,
+            0x1C0..0x200 => self.reverb_regs[(offset as usize - 0x1C0) / 2],
+            // ボイスごとの今の音量 (左, 右)
+            0x200..0x260 => {
+                let voice = (offset as usize - 0x200) / 4;
+                self.voices[voice].current_volume()[channel(offset)] as u16
+            }
+            // 用途のわからないレジスタ
+            0x1A0 | 0x1BC | 0x1BE | 0x260..0x280 => 0,
+            _ => {
+                warn!("SPU read {:x}", offset);
+                0
+            }
+        }
+    }
+
+    fn store16(&mut self, offset: u32, val: u16) {
+        match offset {
+            0x000..VOICE_REGS_END => self.voices[offset as usize / 16].store(offset % 16, val),
+            0x180 | 0x182 => {
+                self.main_volume[channel(offset)] = val;
+                if let Some(volume) = fixed_volume(val) {
+                    self.current_main_volume[channel(offset)] = volume;
+                }
+            }
+            0x184 | 0x186 => self.reverb_volume[channel(offset)] = val,
+            0x188 | 0x18A => self.set_key_on(offset, val),
+            0x18C | 0x18E => self.set_key_off(offset, val),
+            0x190 | 0x192 => set_voice_bits(&mut self.pitch_modulation, offset, val),
+            0x194 | 0x196 => set_voice_bits(&mut self.noise, offset, val),
+            0x198 | 0x19A => set_voice_bits(&mut self.reverb_enable, offset, val),
+            // ENDX は読み出し専用
+            0x19C | 0x19E => {}
+            0x1A2 => {
+                self.reverb_base = val;
+                self.reverb.set_base(val);
+            }
+            0x1A4 => self.irq_address = val,
+            0x1A6 => {
+                self.transfer_start = val;
+                self.transfer_address = val as u32 * 8;
+            }
+            0x1A8 => self.push_fifo(val),
+            0x1AA => self.set_control(val),
+            0x1AC => self.transfer_control = val,
+            // SPUSTAT は読み出し専用
+            0x1AE => {}
+            0x1B0 | 0x1B2 => self.cd_volume[channel(offset)] = val,
+            0x1B4 | 0x1B6 => self.external_volume[channel(offset)] = val,
+            // 今のメインの音量は読み出し専用
+            0x1B8 | 0x1BA => {}
+            0x1C0..0x200 => self.reverb_regs[(offset as usize - 0x1C0) / 2] = val,
+            0x200..0x260 => {}
+            0x1A0 | 0x1BC | 0x1BE | 0x260..0x280 => {
+                debug!("SPU write to unknown register {:x} {:04x}", offset, val)
+            }
+            _ => warn!("SPU write {:x} {:04x}", offset, val),
+        }
+    }
+
+    // 書いた半分のうち、1のビットのボイスだけ鳴らし始める (0 は何もしない)
+    fn set_key_on(&mut self, offset: u32, val: u16) {
+        let voices = ((val as u32) << ((offset & 2) * 8)) & VOICE_MASK;
+        set_voice_bits(&mut self.key_on, offset, val);
+
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            if voices & (1 << i) != 0 {
+                voice.key_on(&self.ram);
+            }
+        }
+    }
+
+    // ループの終わりを過ぎたボイス (ENDX)
+    fn end(&self) -> u32 {
+        self.voices
+            .iter()
+            .enumerate()
+            .fold(0, |end, (i, voice)| end | (voice.ended() as u32) << i)
+    }
+
+    fn set_key_off(&mut self, offset: u32, val: u16) {
+        let voices = ((val as u32) << ((offset & 2) * 8)) & VOICE_MASK;
+        set_voice_bits(&mut self.key_off, offset, val);
+
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            if voices & (1 << i) != 0 {
+                voice.key_off();
+            }
+        }
+    }
+
+    fn transfer_mode(&self) -> TransferMode {
+        match (self.control >> 4) & 3 {
+            0 => TransferMode::Stop,
+            1 => TransferMode::ManualWrite,
+            2 => TransferMode::DmaWrite,
+            _ => TransferMode::DmaRead,
+        }
+    }
+
+    // SPUCNT bit15 が0ならボイスは止まる。bit14 が0なら出力は無音
+    fn enabled(&self) -> bool {
+        self.control & (1 << 15) != 0
+    }
+
+    fn unmuted(&self) -> bool {
+        self.control & (1 << 14) != 0
+    }
+
+    // SPUCNT bit0 が CD の音声、bit2 がそのリバーブへの入力を有効にする
+    fn cd_audio_enabled(&self) -> bool {
+        self.control & 1 != 0
+    }
+
+    fn cd_reverb_enabled(&self) -> bool {
+        self.control & (1 << 2) != 0
+    }
+
+    // SPUCNT bit7 が0ならリバーブの作業領域に書き込まない
+    fn reverb_write_enabled(&self) -> bool {
+        self.control & (1 << 7) != 0
+    }
+
+    fn irq_enabled(&self) -> bool {
+        self.control & (1 << 6) != 0
+    }
+
+    fn set_control(&mut self, val: u16) {
+        debug!("SPU control {:04x}", val);
+
+        self.control = val;
+
+        // IRQ9 は有効ビットを落とすと解除される
+        if !self.irq_enabled() {
+            self.irq = false;
+        }
+
+        if self.transfer_mode() == TransferMode::ManualWrite && self.transfer_cycles == 0 {
+            self.transfer_cycles = TRANSFER_CYCLES;
+        }
+    }
+
+    fn push_fifo(&mut self, val: u16) {
+        if self.fifo.len() == FIFO_LEN {
+            warn!("SPU transfer FIFO overflow");
+            return;
+        }
+
+        self.fifo.push_back(val);
+
+        if self.transfer_cycles == 0 {
+            self.transfer_cycles = TRANSFER_CYCLES;
+        }
+    }
+
+    // 手動転送でFIFOの中身を書き込み終えるまで立つ
+    fn busy(&self) -> bool {
+        self.transfer_mode() == TransferMode::ManualWrite && !self.fifo.is_empty()
+    }
+
+    fn status(&self) -> u16 {
+        let mode = self.transfer_mode();
+        let write_request = mode == TransferMode::DmaWrite;
+        let read_request = mode == TransferMode::DmaRead;
+
+        let mut r = 0;
+
+        r |= self.control & 0x3F;
+        r |= (self.irq as u16) << 6;
+        r |= ((write_request || read_request) as u16) << 7;
+        r |= (write_request as u16) << 8;
+        r |= (read_request as u16) << 9;
+        r |= (self.busy() as u16) << 10;
+
+        r
+    }
+
+    // cd_audio は出力サンプルごとに CD-ROM から1サンプル取り込む。鳴らしていなければ None
+    // IRQアドレスへのアクセスで SPUSTAT bit6 が立ったら irqs に上げる
+    pub fn tick(&mut self, cd_audio: impl FnOnce() -> Option<[i16; 2]>, irqs: &mut IrqBus) {
+        self.step_transfer();
+
+        self.sample_cycles -= 1;
+        if self.sample_cycles == 0 {
+            self.sample_cycles = SAMPLE_CYCLES;
+            self.mix(cd_audio().unwrap_or_default());
+        }
+
+        self.irq_line.set(irqs, self.irq);
+    }
+
+    // 出力されたサンプルを古い順に取り出す
+    pub fn drain_audio(&mut self) -> impl Iterator<Item = [i16; 2]> + '_ {
+        self.audio.drain(..)
+    }
+
+    // 全ボイスを1サンプル進めて CD の音声と足し合わせる。EON のボイスはリバーブにも送る
+    fn mix(&mut self, cd: [i16; 2]) {
+        let mut out = [0i32; 2];
+        let mut reverb_in = [0i32; 2];
+
+        // CD の音声は SPUCNT bit15 に関係なく鳴る
+        if self.cd_audio_enabled() {
+            let reverb = self.cd_reverb_enabled();
+            for ch in 0..2 {
+                let sample = (cd[ch] as i32 * self.cd_volume[ch] as i16 as i32) >> 15;
+                out[ch] += sample;
+                if reverb {
+                    reverb_in[ch] += sample;
+                }
+            }
+        }
+
+        if self.enabled() {
+            for (i, voice) in self.voices.iter_mut().enumerate() {
+                let sample = voice.next_sample(&self.ram);
+                let reverb = self.reverb_enable & (1 << i) != 0;
+
+                for ch in 0..2 {
+                    out[ch] += sample[ch];
+                    if reverb {
+                        reverb_in[ch] += sample[ch];
+                    }
+                }
+            }
+        }
+
+        let write = self.reverb_write_enabled();
+        let reverb_out = self.reverb.step(
+            &self.reverb_regs,
+            self.reverb_base,
+            &mut self.ram,
+            reverb_in,
+            write,
+        );
+        for ch in 0..2 {
+            out[ch] += (reverb_out[ch] * self.reverb_volume[ch] as i16 as i32) >> 15;
+        }
+
+        let clamp = |sample: i32| sample.clamp(i16::MIN as i32, i16::MAX as i32);
+        let out = match self.unmuted() {
+            true => [0, 1]
+                .map(|i| clamp((clamp(out[i]) * self.current_main_volume[i] as i32) >> 15) as i16),
+            false => [0; 2],
+        };
+
+        if self.audio.len() == AUDIO_BUFFER_LEN {
+            self.audio.pop_front();
+        }
+        self.audio.push_back(out);
+    }
+
+    fn step_transfer(&mut self) {
+        if !self.busy() {
+            return;
+        }
+
+        self.transfer_cycles = self.transfer_cycles.saturating_sub(1);
+        if self.transfer_cycles > 0 {
+            return;
+        }
+
+        let val = self.fifo.pop_front().unwrap();
+        self.write_transfer(val);
+
+        self.transfer_cycles = match self.fifo.is_empty() {
+            true => 0,
+            false => TRANSFER_CYCLES,
+        };
+    }
+
+    // DMA (チャンネル4) から1ワード書き込む
+    pub fn dma_write(&mut self, val: u32) {
+        if self.transfer_mode() != TransferMode::DmaWrite {
+            warn!("SPU DMA write in {:?} mode", self.transfer_mode());
+        }
+
+        self.write_transfer(val as u16);
+        self.write_transfer((val >> 16) as u16);
+    }
+
+    pub fn dma_read(&mut self) -> u32 {
+        if self.transfer_mode() != TransferMode::DmaRead {
+            warn!("SPU DMA read in {:?} mode", self.transfer_mode());
+        }
+
+        let lo = self.read_transfer() as u32;
+        let hi = self.read_transfer() as u32;
+
+        lo | (hi << 16)
+    }
+
+    fn write_transfer(&mut self, val: u16) {
+        let addr = self.next_transfer_address();
+        self.ram[addr..addr + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn read_transfer(&mut self) -> u16 {
+        let addr = self.next_transfer_address();
+        u16::from_le_bytes([self.ram[addr], self.ram[addr + 1]])
+    }
+
+    // 転送先を1ハーフワード進める。IRQアドレスの8byteに触れたらIRQ9を立てる
+    fn next_transfer_address(&mut self) -> usize {
+        let addr = self.transfer_address as usize & (RAM_SIZE - 2);
+        self.transfer_address = ((addr + 2) % RAM_SIZE) as u32;
+
+        if self.irq_enabled() && addr / 8 == self.irq_address as usize {
+            self.irq = true;
+        }
+
+        addr
+    }
+}
+
+// 出力待ちのサンプルは保存しない
+impl Savestate for Spu {
+    fn save_state(&self, w: &mut Writer) {
+        w.bytes(&self.ram);
+        for voice in &self.voices {
+            voice.save_state(w);
+        }
+        w.u32(self.key_on);
+        w.u32(self.key_off);
+        w.u32(self.pitch_modulation);
+        w.u32(self.noise);
+        w.u32(self.reverb_enable);
+        for volume in [
+            self.main_volume,
+            self.reverb_volume,
+            self.cd_volume,
+            self.external_volume,
+        ] {
+            w.u16(volume[0]);
+            w.u16(volume[1]);
+        }
+        w.u16(self.current_main_volume[0] as u16);
+        w.u16(self.current_main_volume[1] as u16);
+        w.u16(self.reverb_base);
+        for reg in self.reverb_regs {
+            w.u16(reg);
+        }
+        self.reverb.save_state(w);
+        w.u32(self.sample_cycles);
+        w.u16(self.control);
+        w.u16(self.irq_address);
+        w.u16(self.transfer_start);
+        w.u16(self.transfer_control);
+        w.u32(self.transfer_address);
+        w.u32(self.fifo.len() as u32);
+        for &val in &self.fifo {
+            w.u16(val);
+        }
+        w.u32(self.transfer_cycles);
+        w.bool(self.irq);
+        self.irq_line.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        r.bytes_into(&mut self.ram)?;
+        for voice in &mut self.voices {
+            voice.load_state(r)?;
+        }
+        self.key_on = r.u32()? & VOICE_MASK;
+        self.key_off = r.u32()? & VOICE_MASK;
+        self.pitch_modulation = r.u32()? & VOICE_MASK;
+        self.noise = r.u32()? & VOICE_MASK;
+        self.reverb_enable = r.u32()? & VOICE_MASK;
+        for volume in [
+            &mut self.main_volume,
+            &mut self.reverb_volume,
+            &mut self.cd_volume,
+            &mut self.external_volume,
+        ] {
+            *volume = [r.u16()?, r.u16()?];
+        }
+        self.current_main_volume = [r.u16()? as i16, r.u16()? as i16];
+        self.reverb_base = r.u16()?;
+        for reg in &mut self.reverb_regs {
+            *reg = r.u16()?;
+        }
+        self.reverb.load_state(r)?;
+        self.sample_cycles = r.u32()?.clamp(1, SAMPLE_CYCLES);
+        self.control = r.u16()?;
+        self.irq_address = r.u16()?;
+        self.transfer_start = r.u16()?;
+        self.transfer_control = r.u16()?;
+        self.transfer_address = r.u32()? % RAM_SIZE as u32;
+
+        let len = r.u32()? as usize;
+        self.fifo.clear();
+        for _ in 0..len {
+            self.fifo.push_back(r.u16()?);
+        }
+
+        self.transfer_cycles = r.u32()?;
+        self.irq = r.bool()?;
+        self.irq_line.load_state(r)?;
+
+        self.audio.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IRQ_ADDRESS: u32 = 0x1A4;
+    const TRANSFER_ADDRESS: u32 = 0x1A6;
+    const FIFO: u32 = 0x1A8;
+    const CONTROL: u32 = 0x1AA;
+    const STATUS: u32 = 0x1AE;
+
+    const MANUAL_WRITE: u16 = 1 << 4;
+    const IRQ_ENABLE: u16 = 1 << 6;
+    const BUSY: u16 = 1 << 10;
+    const ENABLE: u16 = 1 << 15;
+    const UNMUTE: u16 = 1 << 14;
+    const CD_AUDIO: u16 = 1 << 0;
+
+    const KEY_ON: u32 = 0x188;
+    const KEY_OFF: u32 = 0x18C;
+    const MAIN_VOLUME: u32 = 0x180;
+    const CD_VOLUME: u32 = 0x1B0;
+
+    fn status(spu: &Spu) -> u16 {
+        spu.load::<u16>(STATUS)
+    }
+
+    // FIFOに積んでから手動転送を始め、busy が落ちるまで回す
+    fn manual_write(spu: &mut Spu, control: u16, address: u16, data: &[u16]) -> u32 {
+        spu.store::<u16>(TRANSFER_ADDRESS, address);
+        for &val in data {
+            spu.store::<u16>(FIFO, val);
+        }
+        spu.store::<u16>(CONTROL, control | MANUAL_WRITE);
+
+        let mut cycles = 0;
+        while status(spu) & BUSY != 0 {
+            spu.tick(|| None, &mut IrqBus::new());
+            cycles += 1;
+        }
+
+        spu.store::<u16>(CONTROL, control);
+
+        cycles
+    }
+
+    fn halfword(spu: &Spu, addr: usize) -> u16 {
+        u16::from_le_bytes([spu.ram[addr], spu.ram[addr + 1]])
+    }
+
+    // ボイスのレジスタ
+    fn voice(spu: &mut Spu, n: u32, reg: u32, val: u16) {
+        spu.store::<u16>(n * 16 + reg, val);
+    }
+
+    // サンプルがどれも同じニブルの ADPCM ブロック (シフト0)
+    fn block(spu: &mut Spu, addr: usize, nibble: u8) {
+        spu.ram[addr..addr + 2].fill(0);
+        spu.ram[addr + 2..addr + 16].fill(nibble << 4 | nibble);
+    }
+
+    // アタックも持続も動かない ADSR
+    const HOLD_ADSR: u32 = 0x1FC0_7FFF;
+
+    // キーオンしてからエンベロープを最大にする
+    fn key_on_at_full_level(spu: &mut Spu, voices: u16) {
+        spu.store::<u16>(KEY_ON, voices);
+
+        for n in 0..16 {
+            if voices & (1 << n) != 0 {
+                voice(spu, n, 0xC, 0x7FFF);
+            }
+        }
+    }
+
+    fn run_samples(spu: &mut Spu, samples: usize) -> Vec<[i16; 2]> {
+        for _ in 0..samples as u32 * SAMPLE_CYCLES {
+            spu.tick(|| None, &mut IrqBus::new());
+        }
+
+        spu.drain_audio().collect()
+    }
+
+    #[test]
+    fn voices_play_samples_at_their_pitch() {
+        let mut spu = Spu::new();
+        spu.store::<u16>(CONTROL, ENABLE | UNMUTE);
+        spu.store::<u32>(MAIN_VOLUME, 0x3FFF_3FFF);
+
+        block(&mut spu, 0x1000, 1);
+        block(&mut spu, 0x1010, 2);
+
+        // 左は最大、右は半分。2倍速
+        voice(&mut spu, 3, 0x0, 0x3FFF);
+        voice(&mut spu, 3, 0x2, 0x2000);
+        voice(&mut spu, 3, 0x4, 0x2000);
+        voice(&mut spu, 3, 0x6, 0x1000 / 8);
+        spu.store::<u32>(3 * 16 + 0x8, HOLD_ADSR);
+
+        // キーオンまでは無音
+        assert_eq!(run_samples(&mut spu, 4), vec![[0, 0]; 4]);
+
+        key_on_at_full_level(&mut spu, 1 << 3);
+        let samples = run_samples(&mut spu, 16);
+
+        // 4096 * 0x7FFF >> 15 = 4095 にボイスとメインの音量をかける
+        assert_eq!(samples[0], [4093, 2046]);
+        // 28サンプルのブロックを2つずつ進むので、15サンプル目から次のブロック
+        assert_eq!(samples[13], [4093, 2046]);
+        assert_eq!(samples[14], [8189, 4094]);
+
+        // 2つのボイスは足し合わされる
+        voice(&mut spu, 5, 0x0, 0x3FFF);
+        voice(&mut spu, 5, 0x4, 0x1000);
+        voice(&mut spu, 5, 0x6, 0x1000 / 8);
+        spu.store::<u32>(5 * 16 + 0x8, HOLD_ADSR);
+        key_on_at_full_level(&mut spu, 1 << 5);
+        let samples = run_samples(&mut spu, 1);
+        assert_eq!(samples[0][1], 4094);
+        assert!(samples[0][0] > 8189);
+
+        // 一番速いリリースで2サンプルのうちに消える
+        spu.store::<u16>(KEY_OFF, 1 << 3 | 1 << 5);
+        let samples = run_samples(&mut spu, 3);
+        assert!(samples[1][0] < samples[0][0]);
+        assert_eq!(samples[2], [0, 0]);
+
+        // ミュート中はボイスが進んでも出力しない
+        key_on_at_full_level(&mut spu, 1 << 3);
+        spu.store::<u16>(CONTROL, ENABLE);
+        assert_eq!(run_samples(&mut spu, 2), vec![[0, 0]; 2]);
+    }
+
+    #[test]
+    fn registers_read_back() {
+        let mut spu = Spu::new();
+
+        voice(&mut spu, 23, 0x4, 0x1234);
+        voice(&mut spu, 23, 0xE, 0x0200);
+        assert_eq!(spu.load::<u16>(23 * 16 + 0x4), 0x1234);
+        assert_eq!(spu.load::<u32>(23 * 16 + 0xC), 0x0200_0000);
+
+        // 今の音量はボイスごとに4byte
+        voice(&mut spu, 2, 0x2, 0x1000);
+        assert_eq!(spu.load::<u16>(0x200 + 2 * 4 + 2), 0x2000);
+
+        // 24ボイス分のビットは下位16bit と上位8bit に分かれる
+        spu.store::<u32>(0x198, 0xFFFF_FFFF);
+        assert_eq!(spu.load::<u32>(0x198), 0x00FF_FFFF);
+
+        spu.store::<u16>(MAIN_VOLUME + 2, 0x4000);
+        assert_eq!(spu.load::<u16>(MAIN_VOLUME + 2), 0x4000);
+        assert_eq!(spu.load::<u16>(0x1BA), 0x8000);
+
+        spu.store::<u16>(0x1C0 + 0x3E, 0xBEEF);
+        assert_eq!(spu.load::<u16>(0x1FE), 0xBEEF);
+
+        // byte で読むと奇数番地は上位の byte
+        assert_eq!(spu.load::<u8>(0x1FE), 0xEF);
+        assert_eq!(spu.load::<u8>(0x1FF), 0xBE);
+        spu.store::<u16>(IRQ_ADDRESS, 0x1234);
+        assert_eq!(spu.load::<u8>(IRQ_ADDRESS + 1), 0x12);
+        assert_eq!(
+            spu.load::<u8>(STATUS + 1),
+            (spu.load::<u16>(STATUS) >> 8) as u8
+        );
+
+        // ループの終わりを過ぎると ENDX が立ち、キーオンで消える。書き込みは無視される
+        spu.store::<u16>(CONTROL, ENABLE);
+        block(&mut spu, 0x100, 1);
+        spu.ram[0x101] = 0x03;
+        for n in [1, 3] {
+            voice(&mut spu, n, 0x4, 0x4000);
+            voice(&mut spu, n, 0x6, 0x100 / 8);
+        }
+        spu.store::<u16>(KEY_ON, 0b1010);
+        run_samples(&mut spu, 7);
+        assert_eq!(spu.load::<u16>(0x19C), 0b1010);
+
+        spu.store::<u16>(0x19C, 0);
+        spu.store::<u16>(KEY_ON, 0b10);
+        assert_eq!(spu.load::<u16>(0x19C), 0b1000);
+    }
+
+    #[test]
+    fn manual_transfer_is_busy_until_fifo_drains() {
+        let mut spu = Spu::new();
+        let data: Vec<u16> = (0..FIFO_LEN as u16).map(|i| 0x1000 + i).collect();
+
+        let cycles = manual_write(&mut spu, 0, 0x200, &data);
+
+        assert_eq!(cycles, TRANSFER_CYCLES * FIFO_LEN as u32);
+        for (i, &val) in data.iter().enumerate() {
+            assert_eq!(halfword(&spu, 0x1000 + i * 2), val);
+        }
+
+        // 状態のビットはSPUCNTの下位6bitを写す
+        assert_eq!(status(&spu) & 0x3F, 0);
+    }
+
+    #[test]
+    fn transfer_wraps_at_end_of_ram() {
+        let mut spu = Spu::new();
+
+        // 最後の8byteから16byte書く
+        manual_write(&mut spu, 0, 0xFFFF, &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(halfword(&spu, RAM_SIZE - 8), 1);
+        assert_eq!(halfword(&spu, RAM_SIZE - 2), 4);
+        assert_eq!(halfword(&spu, 0), 5);
+        assert_eq!(halfword(&spu, 6), 8);
+    }
+
+    #[test]
+    fn transfer_to_irq_address_raises_irq() {
+        let mut spu = Spu::new();
+        let irq = |spu: &Spu| status(spu) & (1 << 6) != 0;
+        spu.store::<u16>(IRQ_ADDRESS, 0x102);
+
+        // IRQが無効なら立たない
+        manual_write(&mut spu, 0, 0x100, &[0; 32]);
+        assert!(!irq(&spu));
+
+        manual_write(&mut spu, IRQ_ENABLE, 0x100, &[0; 8]);
+        assert!(!irq(&spu));
+
+        manual_write(&mut spu, IRQ_ENABLE, 0x100, &[0; 12]);
+        assert!(irq(&spu));
+
+        // 有効ビットを落とすと解除
+        spu.store::<u16>(CONTROL, 0);
+        assert!(!irq(&spu));
+    }
+
+    #[test]
+    fn dma_round_trips_through_ram() {
+        let mut spu = Spu::new();
+
+        spu.store::<u16>(TRANSFER_ADDRESS, 0x10);
+        spu.store::<u16>(CONTROL, 2 << 4);
+        assert_eq!(status(&spu) & 0x180, 0x180);
+        spu.dma_write(0x5678_1234);
+
+        spu.store::<u16>(TRANSFER_ADDRESS, 0x10);
+        spu.store::<u16>(CONTROL, 3 << 4);
+        assert_eq!(status(&spu) & 0x280, 0x280);
+        assert_eq!(spu.dma_read(), 0x5678_1234);
+    }
+
+    #[test]
+    fn cd_audio_is_mixed_at_cd_volume() {
+        let mut spu = Spu::new();
+        spu.store::<u32>(MAIN_VOLUME, 0x3FFF_3FFF);
+        spu.store::<u32>(CD_VOLUME, 0x2000_4000);
+
+        let run = |spu: &mut Spu, control: u16| {
+            spu.store::<u16>(CONTROL, control);
+            let mut taken = 0;
+            for _ in 0..SAMPLE_CYCLES {
+                spu.tick(
+                    || {
+                        taken += 1;
+                        Some([8000, -8000])
+                    },
+                    &mut IrqBus::new(),
+                );
+            }
+            assert_eq!(taken, 1);
+
+            spu.drain_audio().next().unwrap()
+        };
+
+        // SPUCNT bit15 が0でも鳴る。左は半分、右は4分の1
+        assert_eq!(run(&mut spu, UNMUTE | CD_AUDIO), [3999, -2000]);
+
+        // bit0 が0なら取り込んでも混ぜない
+        assert_eq!(run(&mut spu, UNMUTE), [0, 0]);
+        assert_eq!(run(&mut spu, CD_AUDIO), [0, 0]);
+    }
+}
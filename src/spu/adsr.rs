@@ -0,0 +1,238 @@
+// ボイスの ADSR エンベロープ。44.1kHz の1サンプルごとに進む
+
+use anyhow::{bail, Result};
+
+use crate::savestate::{Reader, Savestate, Writer};
+
+const MAX_LEVEL: i16 = 0x7FFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    // リリースし終わった
+    Off,
+}
+
+// フェーズごとの進み方
+// rate はシフト量 (上位5bit) と増減の大きさ (下位2bit)
+struct Rate {
+    rate: u32,
+    decreasing: bool,
+    exponential: bool,
+}
+
+#[derive(Clone)]
+pub struct Envelope {
+    phase: Phase,
+    level: i16,
+    // bit15 が立つごとに level が1段進む
+    counter: u32,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            phase: Phase::Off,
+            level: 0,
+            counter: 0,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn level(&self) -> i16 {
+        self.level
+    }
+
+    // 今の音量のレジスタにも書き込める
+    pub fn set_level(&mut self, level: u16) {
+        self.level = level as i16;
+    }
+
+    pub fn key_on(&mut self) {
+        self.enter(Phase::Attack);
+        self.level = 0;
+    }
+
+    pub fn key_off(&mut self) {
+        self.enter(Phase::Release);
+    }
+
+    // 繰り返さないループの終わり。音量0のままリリースする
+    pub fn silence(&mut self) {
+        self.enter(Phase::Release);
+        self.level = 0;
+    }
+
+    fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.counter = 0;
+    }
+
+    // adsr はボイスの ADSR レジスタ (32bit)
+    pub fn tick(&mut self, adsr: u32) {
+        let rate = match self.phase {
+            Phase::Attack => Rate {
+                rate: (adsr >> 8) & 0x7F,
+                decreasing: false,
+                exponential: adsr & (1 << 15) != 0,
+            },
+            // 減衰とリリースは下位2bit が0
+            Phase::Decay => Rate {
+                rate: ((adsr >> 4) & 0x0F) << 2,
+                decreasing: true,
+                exponential: true,
+            },
+            Phase::Sustain => Rate {
+                rate: (adsr >> 22) & 0x7F,
+                decreasing: adsr & (1 << 30) != 0,
+                exponential: adsr & (1 << 31) != 0,
+            },
+            Phase::Release => Rate {
+                rate: ((adsr >> 16) & 0x1F) << 2,
+                decreasing: true,
+                exponential: adsr & (1 << 21) != 0,
+            },
+            Phase::Off => return,
+        };
+
+        self.step(rate);
+
+        let sustain_level = ((((adsr & 0x0F) + 1) * 0x800) as i32).min(MAX_LEVEL as i32);
+        match self.phase {
+            Phase::Attack if self.level == MAX_LEVEL => self.enter(Phase::Decay),
+            Phase::Decay if self.level as i32 <= sustain_level => self.enter(Phase::Sustain),
+            Phase::Release if self.level == 0 => self.enter(Phase::Off),
+            _ => {}
+        }
+    }
+
+    // シフト量が11 より小さければ1段で大きく動き、大きければ何サンプルかに1段だけ動く
+    // 指数モードでは、増えるときは 0x6000 を超えると4倍遅くなり、減るときは今の音量に比例する
+    fn step(
+        &mut self,
+        Rate {
+            rate,
+            decreasing,
+            exponential,
+        }: Rate,
+    ) {
+        let shift = rate >> 2;
+        let base = 7 - (rate & 3) as i32;
+
+        let mut step = match decreasing {
+            true => !base,
+            false => base,
+        } << 11u32.saturating_sub(shift);
+        let mut increment = 0x8000 >> shift.saturating_sub(11);
+
+        if exponential {
+            if decreasing {
+                step = (step * self.level as i32) >> 15;
+            } else if self.level >= 0x6000 {
+                match rate {
+                    0..40 => step >>= 2,
+                    40..44 => {
+                        step >>= 1;
+                        increment >>= 1;
+                    }
+                    _ => increment >>= 2,
+                }
+            }
+        }
+
+        self.counter += increment;
+        if self.counter & 0x8000 == 0 {
+            return;
+        }
+        self.counter = 0;
+
+        self.level = (self.level as i32 + step).clamp(0, MAX_LEVEL as i32) as i16;
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, w: &mut Writer) {
+        w.u8(self.phase as u8);
+        w.u16(self.level as u16);
+        w.u32(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.phase = match r.u8()? {
+            0 => Phase::Attack,
+            1 => Phase::Decay,
+            2 => Phase::Sustain,
+            3 => Phase::Release,
+            4 => Phase::Off,
+            n => bail!("Invalid ADSR phase {}", n),
+        };
+        self.level = r.u16()? as i16;
+        self.counter = r.u32()? & 0x7FFF;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // [減衰のシフト量, 持続レベル, アタック, 持続, リリース] を並べた ADSR レジスタ
+    fn adsr(attack: u32, decay_shift: u32, sustain_level: u32, sustain: u32, release: u32) -> u32 {
+        attack << 8 | decay_shift << 4 | sustain_level | sustain << 22 | release << 16
+    }
+
+    fn levels(envelope: &mut Envelope, adsr: u32, ticks: usize) -> Vec<i16> {
+        (0..ticks)
+            .map(|_| {
+                envelope.tick(adsr);
+                envelope.level()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_through_the_phases() {
+        // 一番速いアタックと減衰。持続レベル 0x4000、持続中は動かない
+        let adsr = adsr(0x00, 0, 7, 0x7F, 0);
+        let mut envelope = Envelope::new();
+
+        envelope.key_on();
+        assert_eq!(levels(&mut envelope, adsr, 3), [0x3800, 0x7000, 0x7FFF]);
+        assert_eq!(envelope.phase(), Phase::Decay);
+
+        // 今の音量に比例して減る
+        assert_eq!(levels(&mut envelope, adsr, 1), [0x7FFF - 0x4000]);
+        assert_eq!(envelope.phase(), Phase::Sustain);
+        assert_eq!(levels(&mut envelope, adsr, 100), [0x3FFF; 100]);
+
+        envelope.key_off();
+        assert_eq!(levels(&mut envelope, adsr, 1), [0]);
+        assert_eq!(envelope.phase(), Phase::Off);
+    }
+
+    #[test]
+    fn slow_rates_step_every_few_samples() {
+        // シフト13 は4サンプルに1段
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+        assert_eq!(
+            levels(&mut envelope, adsr(13 << 2, 0, 0, 0, 0), 8),
+            [0, 0, 0, 7, 7, 7, 7, 14]
+        );
+
+        // 指数モードのアタックは 0x6000 を超えると遅くなる
+        let exponential = adsr(9 << 2, 0, 0, 0, 0) | 1 << 15;
+        envelope.set_level(0x5FFF);
+        assert_eq!(
+            levels(&mut envelope, exponential, 2),
+            [0x5FFF + 28, 0x5FFF + 28 + 7]
+        );
+    }
+}
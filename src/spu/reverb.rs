@@ -0,0 +1,262 @@
+// リバーブ。SPU RAM の作業領域 (mBASE から最後まで) をリングバッファにして 22.05kHz で回す
+
+use anyhow::Result;
+
+use crate::savestate::{Reader, Savestate, Writer};
+
+use super::RAM_SIZE;
+
+// 0x1C0-0x1FF のレジスタの並び。アドレスは8byte単位、音量は符号付き
+const D_APF1: usize = 0;
+const D_APF2: usize = 1;
+const V_IIR: usize = 2;
+const V_COMB1: usize = 3;
+const V_WALL: usize = 7;
+const V_APF1: usize = 8;
+const V_APF2: usize = 9;
+const M_SAME: usize = 10;
+const M_COMB1: usize = 12;
+const M_COMB2: usize = 14;
+const D_SAME: usize = 16;
+const M_DIFF: usize = 18;
+const M_COMB3: usize = 20;
+const M_COMB4: usize = 22;
+const D_DIFF: usize = 24;
+const M_APF1: usize = 26;
+const M_APF2: usize = 28;
+const V_IN: usize = 30;
+
+fn clamp(val: i32) -> i32 {
+    val.clamp(i16::MIN as i32, i16::MAX as i32)
+}
+
+// 音量をかける (1.15 の固定小数点)
+fn mul(val: i32, volume: u16) -> i32 {
+    (val * volume as i16 as i32) >> 15
+}
+
+#[derive(Clone)]
+pub struct Reverb {
+    // 作業領域の今の位置 (byte)
+    address: u32,
+    // 44.1kHz の偶数サンプルで計算して、奇数サンプルでは前の出力を使う
+    odd: bool,
+    output: [i32; 2],
+}
+
+impl Reverb {
+    pub fn new() -> Reverb {
+        Reverb {
+            address: 0,
+            odd: false,
+            output: [0; 2],
+        }
+    }
+
+    // mBASE を書くと作業領域の先頭から回り直す
+    pub fn set_base(&mut self, base: u16) {
+        self.address = base as u32 * 8;
+    }
+
+    // input はリバーブを使うボイスの和。write が偽なら作業領域には書き込まない
+    pub fn step(
+        &mut self,
+        regs: &[u16; 32],
+        base: u16,
+        ram: &mut [u8],
+        input: [i32; 2],
+        write: bool,
+    ) -> [i32; 2] {
+        self.odd = !self.odd;
+        if !self.odd {
+            return self.output;
+        }
+
+        let mut area = Area {
+            ram,
+            base: base as u32 * 8,
+            address: self.address,
+            write,
+        };
+
+        let reg = |i: usize| regs[i] as u32 * 8;
+        let input = [0, 1].map(|ch| mul(clamp(input[ch]), regs[V_IN + ch]));
+
+        // 同じ側と反対側の壁からの反射
+        for (ch, input) in input.into_iter().enumerate() {
+            let same = reg(M_SAME + ch);
+            let diff = reg(M_DIFF + ch);
+
+            let wall = mul(area.read(reg(D_SAME + ch), 0), regs[V_WALL]);
+            let prev = area.read(same, -2);
+            area.write(same, clamp(mul(input + wall - prev, regs[V_IIR]) + prev));
+
+            let wall = mul(area.read(reg(D_DIFF + (1 - ch)), 0), regs[V_WALL]);
+            let prev = area.read(diff, -2);
+            area.write(diff, clamp(mul(input + wall - prev, regs[V_IIR]) + prev));
+        }
+
+        self.output = [0, 1].map(|ch| {
+            // 4つの櫛形フィルタ
+            let comb = [M_COMB1, M_COMB2, M_COMB3, M_COMB4]
+                .iter()
+                .enumerate()
+                .map(|(i, &m)| mul(area.read(reg(m + ch), 0), regs[V_COMB1 + i]))
+                .sum::<i32>();
+
+            // 2段の全域通過フィルタ
+            [(M_APF1, D_APF1, V_APF1), (M_APF2, D_APF2, V_APF2)]
+                .iter()
+                .fold(clamp(comb), |out, &(m, d, v)| {
+                    let delayed = area.read(reg(m + ch), -(reg(d) as i32));
+                    let out = clamp(out - mul(delayed, regs[v]));
+                    area.write(reg(m + ch), out);
+                    clamp(mul(out, regs[v]) + delayed)
+                })
+        });
+
+        let base = area.base;
+        self.address = ((self.address + 2) & (RAM_SIZE as u32 - 2)).max(base);
+
+        self.output
+    }
+}
+
+// 今の位置から見た作業領域。端を越えると mBASE へ戻る
+struct Area<'a> {
+    ram: &'a mut [u8],
+    base: u32,
+    address: u32,
+    write: bool,
+}
+
+impl Area<'_> {
+    fn addr(&self, offset: u32, adjust: i32) -> usize {
+        let size = RAM_SIZE as i64 - self.base as i64;
+        let rel = (self.address as i64 - self.base as i64 + offset as i64 + adjust as i64)
+            .rem_euclid(size);
+
+        (self.base as i64 + rel) as usize & !1
+    }
+
+    fn read(&self, offset: u32, adjust: i32) -> i32 {
+        let addr = self.addr(offset, adjust);
+        i16::from_le_bytes([self.ram[addr], self.ram[addr + 1]]) as i32
+    }
+
+    fn write(&mut self, offset: u32, val: i32) {
+        if !self.write {
+            return;
+        }
+
+        let addr = self.addr(offset, 0);
+        self.ram[addr..addr + 2].copy_from_slice(&(val as i16).to_le_bytes());
+    }
+}
+
+impl Savestate for Reverb {
+    fn save_state(&self, w: &mut Writer) {
+        w.u32(self.address);
+        w.bool(self.odd);
+        for val in self.output {
+            w.u32(val as u32);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.address = r.u32()? & (RAM_SIZE as u32 - 2);
+        self.odd = r.bool()?;
+        for val in &mut self.output {
+            *val = r.u32()? as i32;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn halfword(ram: &[u8], addr: usize) -> i16 {
+        i16::from_le_bytes([ram[addr], ram[addr + 1]])
+    }
+
+    #[test]
+    fn echoes_input_through_the_work_area() {
+        let mut ram = vec![0; RAM_SIZE];
+        let mut reverb = Reverb::new();
+        let base = 0xF000;
+        reverb.set_base(base);
+
+        // 左の入力をそのまま 0x100 byte (8byte x 0x20) 後ろの mLSAME に書き、
+        // 1つ目の櫛形フィルタで今の位置から読む。全域通過フィルタはほぼ素通し
+        let mut regs = [0; 32];
+        regs[V_IIR] = 0x7FFF;
+        regs[V_COMB1] = 0x7FFF;
+        regs[V_APF1] = 0x7FFF;
+        regs[V_APF2] = 0x7FFF;
+        regs[V_IN] = 0x7FFF;
+        regs[V_IN + 1] = 0x7FFF;
+        regs[M_SAME] = 0x20;
+        // ほかの書き込み先は読むところから離しておく
+        for (i, m) in [
+            M_SAME + 1,
+            M_DIFF,
+            M_DIFF + 1,
+            M_APF1,
+            M_APF1 + 1,
+            M_APF2,
+            M_APF2 + 1,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            regs[m] = 0x400 + i as u16 * 0x100;
+        }
+
+        let mut outputs = vec![];
+        for i in 0..0x102 {
+            let input = match i {
+                0 => [0x4000, 0],
+                _ => [0, 0],
+            };
+            outputs.push(reverb.step(&regs, base, &mut ram, input, true)[0]);
+        }
+
+        // 最初のサンプルは作業領域の先頭から 0x100 byte 後ろに書かれる
+        let start = base as usize * 8;
+        assert!(halfword(&ram, start + 0x100) > 0x3F00);
+
+        // 22.05kHz で2byte ずつ進むので、0x80 回 (44.1kHz で 0x100 サンプル) 後に聞こえる
+        assert!(outputs[..0x100].iter().all(|&out| out == 0));
+        assert!(outputs[0x100] > 0x3F00);
+        assert_eq!(outputs[0x101], outputs[0x100]);
+
+        // 書き込みが無効なら作業領域は変わらない
+        let before = ram.clone();
+        reverb.step(&regs, base, &mut ram, [0x4000, 0x4000], false);
+        reverb.step(&regs, base, &mut ram, [0x4000, 0x4000], false);
+        assert_eq!(ram, before);
+    }
+
+    #[test]
+    fn work_area_wraps_to_base() {
+        let mut ram = vec![0; RAM_SIZE];
+        let mut reverb = Reverb::new();
+        let base = 0xFFFE;
+        reverb.set_base(base);
+
+        let regs = [0; 32];
+        // 最後の 16byte を回る
+        for _ in 0..8 {
+            reverb.step(&regs, base, &mut ram, [0; 2], true);
+        }
+        assert_eq!(reverb.address, 0x7FFF0 + 8);
+
+        for _ in 0..8 {
+            reverb.step(&regs, base, &mut ram, [0; 2], true);
+        }
+        assert_eq!(reverb.address, 0x7FFF0);
+    }
+}
@@ -0,0 +1,340 @@
+use anyhow::Result;
+
+use crate::{
+    adpcm::Header,
+    savestate::{Reader, Savestate, Writer},
+};
+
+use super::{
+    adsr::{Envelope, Phase},
+    fixed_volume, RAM_SIZE,
+};
+
+// ADPCM のブロックの大きさとサンプル数
+pub const BLOCK_SIZE: usize = 16;
+pub const BLOCK_SAMPLES: usize = 28;
+
+// ピッチ 0x1000 で 44.1kHz。これより速くはならない
+const MAX_PITCH: u32 = 0x4000;
+// ピッチのカウンタの端数のビット数
+const PITCH_SHIFT: u32 = 12;
+
+// ブロックの2byte目のフラグ
+const LOOP_END: u8 = 0x01;
+const LOOP_REPEAT: u8 = 0x02;
+const LOOP_START: u8 = 0x04;
+
+// 1つのボイスのレジスタ (16byte) と再生位置
+#[derive(Clone)]
+pub struct Voice {
+    volume_left: u16,
+    volume_right: u16,
+    pitch: u16,
+    // 8byte単位
+    start_address: u16,
+    adsr: u32,
+    envelope: Envelope,
+    repeat_address: u16,
+
+    // 今の音量 (左, 右)。スイープはまだないので固定の音量を書いたときだけ変わる
+    current_volume: [i16; 2],
+
+    // 今のブロックのアドレス (byte)
+    address: u32,
+    // 上位がブロック内のサンプル位置、下位12bitが端数
+    counter: u32,
+    samples: [i16; BLOCK_SAMPLES],
+    // 今のブロックのフラグ
+    flags: u8,
+    // 直前の2サンプル (予測フィルタに使う)
+    history: [i16; 2],
+    // ループの終わりを過ぎた (ENDX)。キーオンで消える
+    ended: bool,
+}
+
+impl Voice {
+    pub fn new() -> Voice {
+        Voice {
+            volume_left: 0,
+            volume_right: 0,
+            pitch: 0,
+            start_address: 0,
+            adsr: 0,
+            envelope: Envelope::new(),
+            repeat_address: 0,
+            current_volume: [0; 2],
+            address: 0,
+            counter: 0,
+            samples: [0; BLOCK_SAMPLES],
+            flags: 0,
+            history: [0; 2],
+            ended: false,
+        }
+    }
+
+    pub fn load(&self, offset: u32) -> u16 {
+        match offset {
+            0x0 => self.volume_left,
+            0x2 => self.volume_right,
+            0x4 => self.pitch,
+            0x6 => self.start_address,
+            0x8 => self.adsr as u16,
+            0xA => (self.adsr >> 16) as u16,
+            0xC => self.envelope.level() as u16,
+            0xE => self.repeat_address,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn store(&mut self, offset: u32, val: u16) {
+        match offset {
+            0x0 => {
+                self.volume_left = val;
+                self.set_volume(0, val);
+            }
+            0x2 => {
+                self.volume_right = val;
+                self.set_volume(1, val);
+            }
+            0x4 => self.pitch = val,
+            0x6 => self.start_address = val,
+            0x8 => self.adsr = (self.adsr & 0xFFFF_0000) | val as u32,
+            0xA => self.adsr = (self.adsr & 0x0000_FFFF) | (val as u32) << 16,
+            0xC => self.envelope.set_level(val),
+            0xE => self.repeat_address = val,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_volume(&mut self, channel: usize, val: u16) {
+        if let Some(volume) = fixed_volume(val) {
+            self.current_volume[channel] = volume;
+        }
+    }
+
+    pub fn ended(&self) -> bool {
+        self.ended
+    }
+
+    // ループの終わりならループの先頭へ戻る。繰り返さないブロックならそこで音を止める
+    fn next_block(&mut self, ram: &[u8]) {
+        if self.flags & LOOP_END != 0 {
+            self.ended = true;
+            self.address = block_address(self.repeat_address);
+
+            if self.flags & LOOP_REPEAT == 0 {
+                self.envelope.silence();
+            }
+        } else {
+            self.address = (self.address + BLOCK_SIZE as u32) % RAM_SIZE as u32;
+        }
+
+        self.load_block(ram);
+    }
+
+    // ループの先頭のフラグがあればそこを繰り返す位置にする
+    fn load_block(&mut self, ram: &[u8]) {
+        let block = &ram[self.address as usize..][..BLOCK_SIZE];
+
+        self.flags = block[1];
+        if self.flags & LOOP_START != 0 {
+            self.repeat_address = (self.address / 8) as u16;
+        }
+
+        self.samples = decode_block(block, &mut self.history);
+    }
+
+    pub fn current_volume(&self) -> [i16; 2] {
+        self.current_volume
+    }
+
+    pub fn key_on(&mut self, ram: &[u8]) {
+        self.address = block_address(self.start_address);
+        self.counter = 0;
+        self.history = [0; 2];
+        self.ended = false;
+        self.load_block(ram);
+        self.envelope.key_on();
+    }
+
+    pub fn key_off(&mut self) {
+        self.envelope.key_off();
+    }
+
+    // 44.1kHz の1サンプル分進めて、音量をかけた (左, 右) を返す
+    pub fn next_sample(&mut self, ram: &[u8]) -> [i32; 2] {
+        if self.envelope.phase() == Phase::Off {
+            return [0; 2];
+        }
+
+        let sample = self.samples[(self.counter >> PITCH_SHIFT) as usize] as i32;
+        let sample = (sample * self.envelope.level() as i32) >> 15;
+        self.envelope.tick(self.adsr);
+
+        self.counter += (self.pitch as u32).min(MAX_PITCH);
+        while self.counter >> PITCH_SHIFT >= BLOCK_SAMPLES as u32 {
+            self.counter -= (BLOCK_SAMPLES as u32) << PITCH_SHIFT;
+            self.next_block(ram);
+        }
+
+        self.current_volume
+            .map(|volume| (sample * volume as i32) >> 15)
+    }
+}
+
+impl Savestate for Voice {
+    fn save_state(&self, w: &mut Writer) {
+        w.u16(self.volume_left);
+        w.u16(self.volume_right);
+        w.u16(self.pitch);
+        w.u16(self.start_address);
+        w.u32(self.adsr);
+        self.envelope.save_state(w);
+        w.u16(self.repeat_address);
+        for volume in self.current_volume {
+            w.u16(volume as u16);
+        }
+        w.u32(self.address);
+        w.u32(self.counter);
+        for sample in self.samples {
+            w.u16(sample as u16);
+        }
+        w.u8(self.flags);
+        for sample in self.history {
+            w.u16(sample as u16);
+        }
+        w.bool(self.ended);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.volume_left = r.u16()?;
+        self.volume_right = r.u16()?;
+        self.pitch = r.u16()?;
+        self.start_address = r.u16()?;
+        self.adsr = r.u32()?;
+        self.envelope.load_state(r)?;
+        self.repeat_address = r.u16()?;
+        for volume in &mut self.current_volume {
+            *volume = r.u16()? as i16;
+        }
+        self.address = (r.u32()? % RAM_SIZE as u32) & !(BLOCK_SIZE as u32 - 1);
+        self.counter = r.u32()? % ((BLOCK_SAMPLES as u32) << PITCH_SHIFT);
+        for sample in &mut self.samples {
+            *sample = r.u16()? as i16;
+        }
+        self.flags = r.u8()?;
+        for sample in &mut self.history {
+            *sample = r.u16()? as i16;
+        }
+        self.ended = r.bool()?;
+
+        Ok(())
+    }
+}
+
+// 8byte単位のアドレスをブロックの先頭 (byte) にする。ブロックの途中は指せない
+fn block_address(addr: u16) -> u32 {
+    ((addr as u32 * 8) % RAM_SIZE as u32) & !(BLOCK_SIZE as u32 - 1)
+}
+
+// [フィルタ (bit4-6) とシフト量 (bit0-3), フラグ, 4bit のサンプル x 28 (下位ニブルが先)]
+// history は直前の2サンプルで、デコードしたブロックの最後の2つに置き換わる
+pub fn decode_block(block: &[u8], history: &mut [i16; 2]) -> [i16; BLOCK_SAMPLES] {
+    let header = Header::new(block[0], 0x07);
+
+    std::array::from_fn(|i| {
+        let nibble = (block[2 + i / 2] >> ((i & 1) * 4)) & 0x0F;
+        header.decode(((nibble as u16) << 12) as i16, history)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // サンプルがどれも同じニブルのブロック
+    fn block(ram: &mut [u8], addr: usize, header: u8, flags: u8, nibble: u8) {
+        ram[addr] = header;
+        ram[addr + 1] = flags;
+        ram[addr + 2..addr + BLOCK_SIZE].fill(nibble << 4 | nibble);
+    }
+
+    // アタックも持続も動かない ADSR で、キーオンしてから音量を最大にする
+    fn voice(ram: &[u8], start_address: u16, repeat_address: u16) -> Voice {
+        let mut voice = Voice::new();
+        voice.store(0x0, 0x3FFF);
+        voice.store(0x4, 0x1000);
+        voice.store(0x6, start_address);
+        voice.store(0x8, 0x7FFF);
+        voice.store(0xA, 0x1FC0);
+        voice.store(0xE, repeat_address);
+        voice.key_on(ram);
+        voice.store(0xC, 0x7FFF);
+        voice
+    }
+
+    #[test]
+    fn decodes_low_nibbles_first() {
+        let mut ram = vec![0; RAM_SIZE];
+        let mut history = [0; 2];
+
+        // シフト4, フィルタなし。下位ニブルが 1、上位ニブルが -1
+        block(&mut ram, 0, 0x04, 0, 0);
+        ram[2..BLOCK_SIZE].fill(0xF1);
+        let samples = decode_block(&ram[..BLOCK_SIZE], &mut history);
+
+        assert_eq!(samples[..4], [256, -256, 256, -256]);
+        assert_eq!(history, [samples[27], samples[26]]);
+    }
+
+    #[test]
+    fn loops_back_to_repeat_address() {
+        let mut ram = vec![0; RAM_SIZE];
+        block(&mut ram, 0x1000, 0, LOOP_START, 1);
+        block(&mut ram, 0x1010, 0, LOOP_END | LOOP_REPEAT, 2);
+
+        let mut voice = voice(&ram, 0x1000 / 8, 0);
+        assert_eq!(voice.load(0xE), 0x1000 / 8);
+
+        let samples: Vec<_> = (0..28 * 3).map(|_| voice.next_sample(&ram)[0]).collect();
+        assert_eq!(samples[..28], [4094; 28]);
+        assert_eq!(samples[28..56], [8190; 28]);
+        // 3つ目はループの先頭のブロック
+        assert_eq!(samples[56..], samples[..28]);
+        assert!(voice.ended());
+
+        // キーオンで ENDX は消える
+        voice.key_on(&ram);
+        assert!(!voice.ended());
+    }
+
+    #[test]
+    fn stops_at_loop_end_without_repeat() {
+        let mut ram = vec![0; RAM_SIZE];
+        block(&mut ram, 0x2000, 0, LOOP_END, 1);
+        block(&mut ram, 0x3000, 0, 0, 1);
+
+        let mut voice = voice(&ram, 0x2000 / 8, 0x3000 / 8);
+
+        for _ in 0..28 {
+            assert_ne!(voice.next_sample(&ram), [0, 0]);
+        }
+        assert!(voice.ended());
+        assert_eq!(voice.next_sample(&ram), [0, 0]);
+        assert_eq!(voice.address, 0x3000);
+    }
+
+    #[test]
+    fn unaligned_addresses_round_down_to_blocks() {
+        let mut ram = vec![0; RAM_SIZE];
+        block(&mut ram, 0x7FFF0, 0, LOOP_END | LOOP_REPEAT, 1);
+
+        // 0x7FFF8 は最後のブロックの途中
+        let mut voice = voice(&ram, 0xFFFF, 0xFFFF);
+        assert_eq!(voice.address, 0x7FFF0);
+
+        let samples: Vec<_> = (0..28 * 2).map(|_| voice.next_sample(&ram)[0]).collect();
+        assert_eq!(samples, [4094; 28 * 2]);
+        assert_eq!(voice.address, 0x7FFF0);
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{cpu::cpu::Event, ps::Ps};
+
+// 同梱の一覧 (リポジトリ直下の test-roms.toml)
+pub const REGISTRY: &str = include_str!("../test-roms.toml");
+
+// 省略時のフレーム数 (NTSCで約50秒)
+const DEFAULT_FRAMES: u32 = 3000;
+
+#[derive(Debug, Deserialize)]
+pub struct Registry {
+    #[serde(rename = "rom")]
+    pub roms: Vec<TestRom>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TestRom {
+    pub name: String,
+    pub category: String,
+    pub file: PathBuf,
+    // TTY にこれが出たら成功
+    pub pass: String,
+    #[serde(default)]
+    pub fail: Vec<String>,
+    #[serde(default = "default_frames")]
+    pub frames: u32,
+}
+
+fn default_frames() -> u32 {
+    DEFAULT_FRAMES
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    // 決まらないまま frames を過ぎた
+    Timeout,
+    // CPUが止まった (ウォッチポイントなど)
+    Halted,
+}
+
+impl Registry {
+    pub fn parse(text: &str) -> Result<Registry> {
+        toml::from_str(text).context("Invalid test ROM registry")
+    }
+
+    pub fn load(path: &Path) -> Result<Registry> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Registry::parse(&text)
+    }
+}
+
+impl TestRom {
+    // TTY の出力から判定する。まだ決まらなければ None
+    // 失敗の文字列を先に見る (最後に "Done." を出すテストがある)
+    pub fn judge(&self, tty: &str) -> Option<Outcome> {
+        if self.fail.iter().any(|fail| tty.contains(fail.as_str())) {
+            Some(Outcome::Fail)
+        } else if tty.contains(&self.pass) {
+            Some(Outcome::Pass)
+        } else {
+            None
+        }
+    }
+
+    // EXEを読み込んだマシンを frames フレームまで回す
+    pub fn run(&self, ps: &mut Ps) -> Outcome {
+        for _ in 0..self.frames {
+            if let Some(Event::Halted) = ps.run_frame() {
+                return Outcome::Halted;
+            }
+
+            if let Some(outcome) = self.judge(ps.cpu().tty_output()) {
+                return outcome;
+            }
+        }
+
+        Outcome::Timeout
+    }
+}
+
+// 見つかったROMのうち成功した割合
+pub struct Score {
+    pub passed: usize,
+    pub run: usize,
+    pub skipped: usize,
+}
+
+impl Score {
+    pub fn new(results: &[Option<Outcome>]) -> Score {
+        Score {
+            passed: results
+                .iter()
+                .filter(|&&result| result == Some(Outcome::Pass))
+                .count(),
+            run: results.iter().filter(|result| result.is_some()).count(),
+            skipped: results.iter().filter(|result| result.is_none()).count(),
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        match self.run {
+            0 => 0.0,
+            run => self.passed as f64 / run as f64 * 100.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestMachineBuilder;
+
+    fn rom(pass: &str, fail: &[&str]) -> TestRom {
+        TestRom {
+            name: "test".to_string(),
+            category: "cpu".to_string(),
+            file: PathBuf::from("test.exe"),
+            pass: pass.to_string(),
+            fail: fail.iter().map(|fail| fail.to_string()).collect(),
+            frames: 10,
+        }
+    }
+
+    #[test]
+    fn bundled_registry_parses() {
+        let registry = Registry::parse(REGISTRY).unwrap();
+
+        assert!(!registry.roms.is_empty());
+        for rom in &registry.roms {
+            assert!(!rom.pass.is_empty(), "{}", rom.name);
+            assert!(rom.frames > 0, "{}", rom.name);
+        }
+    }
+
+    #[test]
+    fn judges_tty_output() {
+        let rom = rom("Done.", &["fail"]);
+
+        assert_eq!(rom.judge("test 1 ok\n"), None);
+        assert_eq!(rom.judge("test 1 ok\nDone.\n"), Some(Outcome::Pass));
+        assert_eq!(rom.judge("test 1 fail\nDone.\n"), Some(Outcome::Fail));
+    }
+
+    #[test]
+    fn runs_until_tty_passes() {
+        // putchar (B(0x3D)) で "ok\n" を出して止まる
+        let mut code = vec![];
+        for c in b"ok\n" {
+            code.extend([
+                0x240800B0,             // addiu $t0, $zero, 0xB0
+                0x2409003D,             // addiu $t1, $zero, 0x3D
+                0x24040000 | *c as u32, // addiu $a0, $zero, c
+                0x0100F809,             // jalr $t0
+                0x00000000,             // nop
+            ]);
+        }
+        code.extend([0x1000FFFF, 0x00000000]);
+
+        let mut ps = TestMachineBuilder::new()
+            .program(0xB0, &[0x03E00008, 0x00000000]) // jr $ra
+            .program(0x80010000, &code)
+            .build_ps();
+
+        assert_eq!(rom("ok", &[]).run(&mut ps), Outcome::Pass);
+        assert_eq!(ps.cpu().tty_output(), "ok\n");
+
+        assert_eq!(rom("never", &[]).run(&mut ps), Outcome::Timeout);
+    }
+
+    #[test]
+    fn scores_present_roms() {
+        let score = Score::new(&[
+            Some(Outcome::Pass),
+            Some(Outcome::Fail),
+            None,
+            Some(Outcome::Pass),
+            Some(Outcome::Timeout),
+        ]);
+
+        assert_eq!((score.passed, score.run, score.skipped), (2, 4, 1));
+        assert_eq!(score.percent(), 50.0);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{
+    bios::Bios,
+    cdrom::image::Image,
+    config::{MachineConfig, PowerOnState, Region},
+    cpu::cpu::Cpu,
+    gpu::{gpu::Gpu, renderer::Renderer},
+    interconnect::Interconnect,
+    ps::Ps,
+};
+
+const BIOS_SIZE: usize = 512 * 1024;
+
+// program() を使わない場合の実行開始アドレス
+pub const DEFAULT_ENTRY: u32 = 0x80010000;
+
+// b . / nop
+const SPIN: [u32; 2] = [0x1000FFFF, 0x00000000];
+
+// BEVが0の場合の例外ベクタ
+const EXCEPTION_VECTOR: u32 = 0x80000080;
+
+// 全体が無限ループのBIOS。BIOSコールやBEV=1の例外ベクタに入るとそこで止まる
+pub fn dummy_bios() -> Bios {
+    let data = SPIN
+        .iter()
+        .cycle()
+        .take(BIOS_SIZE / 4)
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+
+    Bios { data }
+}
+
+// BIOSファイルもウィンドウも使わないテスト用のマシン
+// ディスクはなく、RAMは0で初期化される。RAMの例外ベクタにも無限ループを置く
+pub struct TestMachineBuilder {
+    config: MachineConfig,
+    ram: Vec<(u32, Vec<u8>)>,
+    entry: u32,
+}
+
+impl Default for TestMachineBuilder {
+    fn default() -> Self {
+        let mut config = MachineConfig::new(dummy_bios());
+        config.power_on = PowerOnState::Zeros;
+
+        TestMachineBuilder {
+            config,
+            ram: vec![],
+            entry: DEFAULT_ENTRY,
+        }
+        .program(EXCEPTION_VECTOR, &SPIN)
+        .entry(DEFAULT_ENTRY)
+    }
+}
+
+impl TestMachineBuilder {
+    pub fn new() -> TestMachineBuilder {
+        TestMachineBuilder::default()
+    }
+
+    pub fn bios(mut self, bios: Bios) -> TestMachineBuilder {
+        self.config.bios = bios;
+        self
+    }
+
+    pub fn region(mut self, region: Region) -> TestMachineBuilder {
+        self.config.region = region;
+        self
+    }
+
+    pub fn disc(mut self, disc: Image) -> TestMachineBuilder {
+        self.config.disc = Some(disc);
+        self
+    }
+
+    pub fn power_on(mut self, power_on: PowerOnState) -> TestMachineBuilder {
+        self.config.power_on = power_on;
+        self
+    }
+
+    // RAMに置く内容 (アドレスはKUSEG/KSEG0/KSEG1のどれでもよい)
+    pub fn ram(mut self, addr: u32, data: &[u8]) -> TestMachineBuilder {
+        self.ram.push((addr, data.to_vec()));
+        self
+    }
+
+    // 命令列を置き、その先頭から実行する
+    pub fn program(self, addr: u32, code: &[u32]) -> TestMachineBuilder {
+        let data: Vec<u8> = code.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        self.ram(addr, &data).entry(addr)
+    }
+
+    pub fn entry(mut self, pc: u32) -> TestMachineBuilder {
+        self.entry = pc;
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        let power_on = self.config.power_on;
+        let inter = Interconnect::new(self.config, Gpu::new(Renderer::null()));
+
+        let mut cpu = Cpu::new(inter, power_on);
+        load(&mut cpu, &self.ram, self.entry);
+
+        cpu
+    }
+
+    // 埋め込み側と同じ Ps のAPIで操作するためのマシン
+    pub fn build_ps(self) -> Ps {
+        let mut ps = Ps::new(self.config, Gpu::new(Renderer::null())).unwrap();
+        load(ps.cpu_mut(), &self.ram, self.entry);
+
+        ps
+    }
+}
+
+fn load(cpu: &mut Cpu, ram: &[(u32, Vec<u8>)], entry: u32) {
+    for (addr, data) in ram {
+        for (i, byte) in data.iter().enumerate() {
+            cpu.inter.store::<u8>(addr.wrapping_add(i as u32), *byte);
+        }
+    }
+
+    cpu.set_pc(entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::RAM_SIZE_RETAIL, interconnect::map};
+
+    // ストールがあるので命令数ではなく無限ループに入るまで回す
+    fn run_until_spinning(cpu: &mut Cpu) {
+        for _ in 0..1000 {
+            cpu.step();
+
+            if cpu.is_spinning() {
+                return;
+            }
+        }
+
+        panic!("CPU did not reach a spin loop (pc: {:08x})", cpu.pc());
+    }
+
+    #[test]
+    fn runs_program_from_ram() {
+        let mut cpu = TestMachineBuilder::new()
+            .program(
+                0x80010000,
+                &[
+                    0x24081234, // addiu $t0, $zero, 0x1234
+                    0x3C098000, // lui $t1, 0x8000
+                    0xAD280100, // sw $t0, 0x100($t1)
+                    SPIN[0], SPIN[1],
+                ],
+            )
+            .build();
+
+        run_until_spinning(&mut cpu);
+
+        assert_eq!(cpu.inter.peek::<u32>(0x80000100), Some(0x1234));
+    }
+
+    #[test]
+    fn fills_memory_and_registers_on_power_on() {
+        for power_on in [
+            PowerOnState::Zeros,
+            PowerOnState::Garbage,
+            PowerOnState::Pattern,
+        ] {
+            let cpu = TestMachineBuilder::new().power_on(power_on).build();
+
+            let mut ram = vec![0; RAM_SIZE_RETAIL];
+            power_on.fill_bytes(&mut ram);
+            let mut scratchpad = vec![0; map::SCRATCHPAD.length as usize];
+            power_on.fill_bytes(&mut scratchpad);
+            let mut regs = [0; 32];
+            power_on.fill_words(&mut regs);
+            regs[0] = 0;
+
+            let word = |buf: &[u8], offset: usize| {
+                u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+            };
+
+            // プログラムや例外ベクタを置いていない所を見る
+            for offset in [0x1000, RAM_SIZE_RETAIL - 4] {
+                assert_eq!(
+                    cpu.inter.peek::<u32>(offset as u32),
+                    Some(word(&ram, offset))
+                );
+            }
+            for offset in [0, scratchpad.len() - 4] {
+                assert_eq!(
+                    cpu.inter.peek::<u32>(map::SCRATCHPAD.start + offset as u32),
+                    Some(word(&scratchpad, offset))
+                );
+            }
+            assert_eq!(cpu.regs, regs);
+            assert_eq!(cpu.regs[0], 0);
+
+            match power_on {
+                PowerOnState::Zeros => {
+                    assert!(ram.iter().chain(&scratchpad).all(|&b| b == 0));
+                    assert!(regs.iter().all(|&r| r == 0));
+                }
+                PowerOnState::Garbage => {
+                    assert!(ram.iter().any(|&b| b != ram[0]));
+                    assert!(regs[1..].iter().any(|&r| r != regs[1]));
+                }
+                PowerOnState::Pattern => {
+                    assert!(ram.iter().chain(&scratchpad).all(|&b| b == 0xCA));
+                    assert!(regs[1..].iter().all(|&r| r == 0xDEADBEEF));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn preloads_ram() {
+        let cpu = TestMachineBuilder::new()
+            .ram(0xA0000200, &[0x78, 0x56, 0x34, 0x12])
+            .build();
+
+        assert_eq!(cpu.inter.peek::<u32>(0x00000200), Some(0x12345678));
+        assert_eq!(cpu.inter.peek::<u32>(0x00000204), Some(0));
+    }
+
+    #[test]
+    fn exceptions_spin_at_vector() {
+        let mut cpu = TestMachineBuilder::new()
+            .program(0x80010000, &[0x0000000D]) // break
+            .build();
+
+        run_until_spinning(&mut cpu);
+
+        assert_eq!(cpu.pc() & !0xF, EXCEPTION_VECTOR);
+        assert_eq!(cpu.epc, 0x80010000);
+    }
+}
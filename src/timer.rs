@@ -1,6 +1,11 @@
+use anyhow::Result;
 use log::debug;
 
-use crate::addressible::Addressible;
+use crate::{
+    addressible::Addressible,
+    interrupts::{Irq, IrqBus},
+    savestate::{Reader, Savestate, Writer},
+};
 
 pub struct Timer {
     index: u8,
@@ -16,7 +21,7 @@ pub struct Timer {
     irq_toggle: bool,
     clock_source: u8,
 
-    pub n_irq: bool,
+    n_irq: bool,
     raised: bool,
     prev_hblank: bool,
     prev_vblank: bool,
@@ -70,7 +75,7 @@ impl Timer {
         }
     }
 
-    pub fn tick(&mut self, hblank: bool, vblank: bool, dotclock: bool) {
+    pub fn tick(&mut self, hblank: bool, vblank: bool, dotclock: bool, irqs: &mut IrqBus) {
         let prev_vblank = self.prev_vblank;
         self.prev_vblank = vblank;
         let prev_hblank = self.prev_hblank;
@@ -157,7 +162,7 @@ impl Timer {
 
         if self.counter == self.target {
             if self.irq_target {
-                self.raise();
+                self.raise(irqs);
             }
             if self.use_target {
                 self.counter = 0;
@@ -166,7 +171,7 @@ impl Timer {
 
         if self.counter == 0xFFFF {
             if self.irq_full {
-                self.raise();
+                self.raise(irqs);
             }
         }
     }
@@ -201,16 +206,74 @@ impl Timer {
         self.n_irq = (val >> 10) & 1 != 0;
     }
 
-    fn raise(&mut self) {
-        if self.irq_repeat || !self.raised {
-            self.raised = true;
-            if self.irq_toggle {
-                self.n_irq = !self.n_irq;
-                debug!("timer{} irq toggled {}", self.index, !self.n_irq);
-            } else {
-                self.n_irq = false;
-                debug!("timer{} irq raised", self.index);
-            }
+    // n_irq が落ちたときに割り込みが上がる
+    fn raise(&mut self, irqs: &mut IrqBus) {
+        if !self.irq_repeat && self.raised {
+            return;
+        }
+
+        let prev = self.n_irq;
+
+        self.raised = true;
+        if self.irq_toggle {
+            self.n_irq = !self.n_irq;
+            debug!("timer{} irq toggled {}", self.index, !self.n_irq);
+        } else {
+            self.n_irq = false;
+            debug!("timer{} irq raised", self.index);
+        }
+
+        if prev && !self.n_irq {
+            irqs.raise(self.irq());
+        }
+    }
+
+    fn irq(&self) -> Irq {
+        match self.index {
+            0 => Irq::Tmr0,
+            1 => Irq::Tmr1,
+            2 => Irq::Tmr2,
+            _ => unreachable!(),
         }
     }
 }
+
+impl Savestate for Timer {
+    fn save_state(&self, w: &mut Writer) {
+        w.u16(self.counter);
+        w.u32(self.internal_counter);
+        w.bool(self.sync_enable);
+        w.u8(self.sync_mode);
+        w.bool(self.use_target);
+        w.bool(self.irq_target);
+        w.bool(self.irq_full);
+        w.bool(self.irq_repeat);
+        w.bool(self.irq_toggle);
+        w.u8(self.clock_source);
+        w.bool(self.n_irq);
+        w.bool(self.raised);
+        w.bool(self.prev_hblank);
+        w.bool(self.prev_vblank);
+        w.u16(self.target);
+    }
+
+    fn load_state(&mut self, r: &mut Reader) -> Result<()> {
+        self.counter = r.u16()?;
+        self.internal_counter = r.u32()?;
+        self.sync_enable = r.bool()?;
+        self.sync_mode = r.u8()?;
+        self.use_target = r.bool()?;
+        self.irq_target = r.bool()?;
+        self.irq_full = r.bool()?;
+        self.irq_repeat = r.bool()?;
+        self.irq_toggle = r.bool()?;
+        self.clock_source = r.u8()?;
+        self.n_irq = r.bool()?;
+        self.raised = r.bool()?;
+        self.prev_hblank = r.bool()?;
+        self.prev_vblank = r.bool()?;
+        self.target = r.u16()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use crate::interconnect::Interconnect;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Halfword,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Equal(u32),
+    NotEqual(u32),
+    Less(u32),
+    Greater(u32),
+    // 指定したビットが全て立っている
+    BitsSet(u32),
+    // 前のフレームから値が変わった
+    Changed,
+}
+
+impl Condition {
+    fn check(self, val: u32, prev: Option<u32>) -> bool {
+        match self {
+            Condition::Equal(x) => val == x,
+            Condition::NotEqual(x) => val != x,
+            Condition::Less(x) => val < x,
+            Condition::Greater(x) => val > x,
+            Condition::BitsSet(mask) => val & mask == mask,
+            Condition::Changed => prev.is_some_and(|prev| prev != val),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TriggerId(u32);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TriggerEvent {
+    pub id: TriggerId,
+    pub frame: u32,
+    pub value: u32,
+}
+
+pub type TriggerCallback = dyn FnMut(&TriggerEvent) + Send;
+
+struct Trigger {
+    id: TriggerId,
+    addr: u32,
+    width: Width,
+    condition: Condition,
+    callback: Box<TriggerCallback>,
+    prev: Option<u32>,
+    active: bool,
+}
+
+// フロントエンドが登録するメモリの監視条件
+// アクセスごとではなくフレームごとに評価し、条件が偽から真になった時にコールバックを呼ぶ
+#[derive(Default)]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    next_id: u32,
+}
+
+impl Triggers {
+    pub fn new() -> Triggers {
+        Triggers::default()
+    }
+
+    pub fn add(
+        &mut self,
+        addr: u32,
+        width: Width,
+        condition: Condition,
+        callback: Box<TriggerCallback>,
+    ) -> TriggerId {
+        let id = TriggerId(self.next_id);
+        self.next_id += 1;
+
+        self.triggers.push(Trigger {
+            id,
+            addr,
+            width,
+            condition,
+            callback,
+            prev: None,
+            active: false,
+        });
+
+        id
+    }
+
+    pub fn remove(&mut self, id: TriggerId) -> bool {
+        let len = self.triggers.len();
+        self.triggers.retain(|trigger| trigger.id != id);
+
+        self.triggers.len() != len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triggers.is_empty()
+    }
+
+    pub(crate) fn evaluate(&mut self, inter: &Interconnect, frame: u32) {
+        for trigger in &mut self.triggers {
+            // RAMとスクラッチパッド以外は読めない
+            let value = match trigger.width {
+                Width::Byte => inter.peek::<u8>(trigger.addr).map(|v| v as u32),
+                Width::Halfword => inter.peek::<u16>(trigger.addr).map(|v| v as u32),
+                Width::Word => inter.peek::<u32>(trigger.addr),
+            };
+
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let active = trigger.condition.check(value, trigger.prev);
+
+            // Changedは変わるたびに通知する
+            let fire = match trigger.condition {
+                Condition::Changed => active,
+                _ => active && !trigger.active,
+            };
+
+            if fire {
+                (trigger.callback)(&TriggerEvent {
+                    id: trigger.id,
+                    frame,
+                    value,
+                });
+            }
+
+            trigger.prev = Some(value);
+            trigger.active = active;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::testing::TestMachineBuilder;
+
+    const ADDR: u32 = 0x80001000;
+
+    // 呼ばれたコールバックの (フレーム, 値) を記録する
+    fn recorder() -> (Arc<Mutex<Vec<(u32, u32)>>>, Box<TriggerCallback>) {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        let callback = Box::new(move |event: &TriggerEvent| {
+            recorded.lock().unwrap().push((event.frame, event.value));
+        });
+
+        (events, callback)
+    }
+
+    // フレームごとに値を書いてから評価する
+    fn run(triggers: &mut Triggers, width: Width, values: &[u32]) {
+        let mut cpu = TestMachineBuilder::new().build();
+
+        for (frame, &val) in values.iter().enumerate() {
+            match width {
+                Width::Byte => cpu.inter.store::<u8>(ADDR, val as u8),
+                Width::Halfword => cpu.inter.store::<u16>(ADDR, val as u16),
+                Width::Word => cpu.inter.store::<u32>(ADDR, val),
+            }
+            triggers.evaluate(&cpu.inter, frame as u32);
+        }
+    }
+
+    #[test]
+    fn fires_only_when_condition_becomes_true() {
+        let mut triggers = Triggers::new();
+        let (events, callback) = recorder();
+        triggers.add(ADDR, Width::Halfword, Condition::Greater(10), callback);
+
+        // 真の間は呼ばれず、一度偽に戻ってから真になると再び呼ばれる
+        run(
+            &mut triggers,
+            Width::Halfword,
+            &[5, 11, 12, 3, 20, 0x1_0000],
+        );
+
+        assert_eq!(*events.lock().unwrap(), vec![(1, 11), (4, 20)]);
+    }
+
+    #[test]
+    fn changed_fires_on_every_change_but_not_first_frame() {
+        let mut triggers = Triggers::new();
+        let (events, callback) = recorder();
+        triggers.add(ADDR, Width::Byte, Condition::Changed, callback);
+
+        run(&mut triggers, Width::Byte, &[7, 7, 8, 9, 9, 7]);
+
+        assert_eq!(*events.lock().unwrap(), vec![(2, 8), (3, 9), (5, 7)]);
+    }
+
+    #[test]
+    fn skips_unreadable_addresses() {
+        let mut triggers = Triggers::new();
+        let (events, callback) = recorder();
+        // GPU のレジスタは副作用があるので読まない
+        triggers.add(0x1F801814, Width::Word, Condition::NotEqual(1), callback);
+
+        let (ram_events, ram_callback) = recorder();
+        triggers.add(ADDR, Width::Word, Condition::Equal(2), ram_callback);
+
+        run(&mut triggers, Width::Word, &[0, 2]);
+
+        assert!(events.lock().unwrap().is_empty());
+        assert_eq!(*ram_events.lock().unwrap(), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn removed_triggers_stop_firing() {
+        let mut triggers = Triggers::new();
+        let (events, callback) = recorder();
+        let id = triggers.add(ADDR, Width::Word, Condition::BitsSet(0x3), callback);
+        assert!(!triggers.is_empty());
+
+        run(&mut triggers, Width::Word, &[0x7]);
+        assert!(triggers.remove(id));
+        assert!(triggers.is_empty());
+        // 2回目は何もしない
+        assert!(!triggers.remove(id));
+
+        run(&mut triggers, Width::Word, &[0, 0x3]);
+        assert_eq!(*events.lock().unwrap(), vec![(0, 0x7)]);
+    }
+}